@@ -0,0 +1,115 @@
+// Address based access control at accept time.
+//
+// allow-from and deny-from hold lists of CIDR blocks, like 10.0.0.0/8 or
+// fd00::/8, a bare address standing for itself alone. A TCP client whose
+// address is in deny-from, or isn't in a non-empty allow-from, is refused
+// before it can send anything. IPv4 clients reaching a dual-stack listener
+// as IPv4-mapped IPv6 addresses are matched as IPv4.
+
+use std::fmt;
+use std::net::IpAddr;
+
+#[derive(Clone, Copy, PartialEq)]
+pub struct Cidr {
+    net: IpAddr,
+    bits: u8,
+}
+
+impl Cidr {
+    pub fn parse(v: &str) -> Result<Cidr, String> {
+        let err = || format!("invalid address block '{}'", v);
+        let (addr, bits) = match v.find('/') {
+            Some(i) => (&v[..i], Some(&v[i + 1..])),
+            None => (v, None),
+        };
+        let net = unmap(addr.parse::<IpAddr>().map_err(|_| err())?);
+        let max = if net.is_ipv4() { 32 } else { 128 };
+        let bits = match bits {
+            Some(bits) => match bits.parse::<u8>() {
+                Ok(bits) if bits <= max => bits,
+                _ => return Err(err()),
+            },
+            None => max,
+        };
+        Ok(Cidr { net, bits })
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.net, unmap(ip)) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                prefix(u32::from(net) as u128, 32, self.bits) == prefix(u32::from(ip) as u128, 32, self.bits)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                prefix(u128::from(net), 128, self.bits) == prefix(u128::from(ip), 128, self.bits)
+            }
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.net, self.bits)
+    }
+}
+
+// The top bits of an address width wide.
+fn prefix(addr: u128, width: u32, bits: u8) -> u128 {
+    if bits == 0 {
+        0
+    } else {
+        addr >> (width - bits as u32)
+    }
+}
+
+fn unmap(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V4(v4),
+            None => ip,
+        },
+        ip => ip,
+    }
+}
+
+// A space separated list of blocks, as the config takes them.
+pub fn parse_list(v: &str) -> Result<Vec<Cidr>, String> {
+    v.split_whitespace().map(Cidr::parse).collect()
+}
+
+pub fn render_list(list: &[Cidr]) -> String {
+    list.iter().map(|c| c.to_string()).collect::<Vec<String>>().join(" ")
+}
+
+pub fn allowed(ip: IpAddr, allow: &[Cidr], deny: &[Cidr]) -> bool {
+    !deny.iter().any(|c| c.contains(ip)) && (allow.is_empty() || allow.iter().any(|c| c.contains(ip)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{allowed, parse_list, Cidr};
+
+    #[test]
+    fn blocks_contain_their_addresses() {
+        let block = Cidr::parse("10.1.0.0/16").unwrap();
+        assert!(block.contains("10.1.200.3".parse().unwrap()));
+        assert!(!block.contains("10.2.0.1".parse().unwrap()));
+        assert!(block.contains("::ffff:10.1.0.9".parse().unwrap()));
+        assert!(Cidr::parse("0.0.0.0/0").unwrap().contains("1.2.3.4".parse().unwrap()));
+        assert!(Cidr::parse("fd00::/8").unwrap().contains("fd12::1".parse().unwrap()));
+        assert!(!Cidr::parse("fd00::/8").unwrap().contains("10.1.0.0".parse().unwrap()));
+        assert!(Cidr::parse("10.0.0.0/33").is_err());
+        assert!(Cidr::parse("nonsense").is_err());
+    }
+
+    #[test]
+    fn deny_wins_over_allow() {
+        let allow = parse_list("10.0.0.0/8 127.0.0.1").unwrap();
+        let deny = parse_list("10.9.0.0/16").unwrap();
+        assert!(allowed("127.0.0.1".parse().unwrap(), &allow, &deny));
+        assert!(allowed("10.1.1.1".parse().unwrap(), &allow, &deny));
+        assert!(!allowed("10.9.1.1".parse().unwrap(), &allow, &deny));
+        assert!(!allowed("192.168.1.1".parse().unwrap(), &allow, &deny));
+        assert!(allowed("192.168.1.1".parse().unwrap(), &[], &deny));
+    }
+}
@@ -0,0 +1,639 @@
+// Access control lists.
+//
+// Each user carries the set of commands it may run, the key and channel
+// patterns it may touch, its password hashes and optionally rate limits of
+// its own for each of its connections. Rules are applied in order
+// exactly as ACL SETUSER receives them, so "+@all -flushall" and
+// "-flushall +@all" differ. Every command is checked against the caller's
+// user before it is dispatched. With an aclfile configured, ACL LOAD and
+// ACL SAVE read and write the users in ACL LIST format.
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::Write;
+use std::mem;
+use std::sync::RwLock;
+
+use ring::digest;
+use tracing;
+
+use commands;
+use commands::CommandSpec;
+use pattern;
+
+#[derive(Clone)]
+pub struct User {
+    pub name: String,
+    pub enabled: bool,
+    pub nopass: bool,
+    pub passwords: Vec<String>,
+    allowed: HashSet<&'static str>,
+    allowed_sub: HashSet<String>,
+    denied_sub: HashSet<String>,
+    // Command rules as given, restarting at every +@all or -@all, used to
+    // describe the user back.
+    command_rules: Vec<String>,
+    pub keys: Vec<Vec<u8>>,
+    pub channels: Vec<Vec<u8>>,
+    // Set with max-commands-per-sec=<n> and max-bytes-per-sec=<n>, zero
+    // leaving the server's limits in effect.
+    pub max_commands_per_sec: usize,
+    pub max_bytes_per_sec: usize,
+}
+
+pub enum Denied {
+    Auth,
+    Command,
+    Key,
+    Channel,
+}
+
+impl User {
+    pub fn new(name: &str) -> User {
+        User {
+            name: name.to_string(),
+            enabled: false,
+            nopass: false,
+            passwords: Vec::new(),
+            allowed: HashSet::new(),
+            allowed_sub: HashSet::new(),
+            denied_sub: HashSet::new(),
+            command_rules: Vec::new(),
+            keys: Vec::new(),
+            channels: Vec::new(),
+            max_commands_per_sec: 0,
+            max_bytes_per_sec: 0,
+        }
+    }
+
+    pub fn apply(&mut self, rule: &str) -> Result<(), String> {
+        let lower = rule.to_lowercase();
+        match lower.as_str() {
+            "on" => self.enabled = true,
+            "off" => self.enabled = false,
+            "nopass" => {
+                self.nopass = true;
+                self.passwords.clear();
+            }
+            "resetpass" => {
+                self.nopass = false;
+                self.passwords.clear();
+            }
+            "allkeys" => self.keys = vec![b"*".to_vec()],
+            "resetkeys" => self.keys.clear(),
+            "allchannels" => self.channels = vec![b"*".to_vec()],
+            "resetchannels" => self.channels.clear(),
+            "allcommands" => self.apply("+@all")?,
+            "nocommands" => self.apply("-@all")?,
+            "reset" => *self = User::new(&self.name),
+            _ => {
+                if let Some(password) = rule.strip_prefix('>') {
+                    self.add_password(hash_password(password.as_bytes()));
+                } else if let Some(password) = rule.strip_prefix('<') {
+                    let hash = hash_password(password.as_bytes());
+                    self.passwords.retain(|p| *p != hash);
+                } else if let Some(hash) = rule.strip_prefix('#') {
+                    self.add_password(parse_hash(hash)?);
+                } else if let Some(hash) = rule.strip_prefix('!') {
+                    let hash = parse_hash(hash)?;
+                    self.passwords.retain(|p| *p != hash);
+                } else if let Some(pattern) = rule.strip_prefix('~') {
+                    self.keys.push(pattern.as_bytes().to_vec());
+                } else if let Some(pattern) = rule.strip_prefix('&') {
+                    self.channels.push(pattern.as_bytes().to_vec());
+                } else if let Some(limit) = lower.strip_prefix("max-commands-per-sec=") {
+                    self.max_commands_per_sec = parse_limit(limit)?;
+                } else if let Some(limit) = lower.strip_prefix("max-bytes-per-sec=") {
+                    self.max_bytes_per_sec = parse_limit(limit)?;
+                } else if rule.starts_with('+') || rule.starts_with('-') {
+                    self.apply_command_rule(&lower)?;
+                } else {
+                    return Err("Syntax error".to_string());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn add_password(&mut self, hash: String) {
+        self.nopass = false;
+        if !self.passwords.contains(&hash) {
+            self.passwords.push(hash);
+        }
+    }
+
+    fn apply_command_rule(&mut self, rule: &str) -> Result<(), String> {
+        let allow = rule.starts_with('+');
+        let name = &rule[1..];
+        if let Some(category) = name.strip_prefix('@') {
+            let mut matched = false;
+            for spec in commands::all() {
+                if category == "all" || spec.categories.contains(&name) {
+                    matched = true;
+                    self.set_command(spec.name, allow);
+                }
+            }
+            if !matched && !is_category(category) {
+                return Err("Unknown command or category name in ACL".to_string());
+            }
+            if category == "all" {
+                self.command_rules.clear();
+            }
+        } else if let Some(sep) = name.find('|') {
+            let (command, sub) = (&name[..sep], &name[sep + 1..]);
+            let spec = match commands::lookup(command.as_bytes()) {
+                Some(spec) => spec,
+                None => return Err("Unknown command or category name in ACL".to_string()),
+            };
+            if sub.is_empty() {
+                return Err("Unknown command or category name in ACL".to_string());
+            }
+            let full = format!("{}|{}", spec.name, sub);
+            if self.allowed.contains(spec.name) {
+                if allow {
+                    self.denied_sub.remove(&full);
+                } else {
+                    self.denied_sub.insert(full);
+                }
+            } else if allow {
+                self.allowed_sub.insert(full);
+            } else {
+                self.allowed_sub.remove(&full);
+            }
+        } else {
+            match commands::lookup(name.as_bytes()) {
+                Some(spec) => self.set_command(spec.name, allow),
+                None => return Err("Unknown command or category name in ACL".to_string()),
+            }
+        }
+        self.command_rules.push(rule.to_string());
+        Ok(())
+    }
+
+    fn set_command(&mut self, name: &'static str, allow: bool) {
+        let prefix = format!("{}|", name);
+        self.allowed_sub.retain(|s| !s.starts_with(&prefix));
+        self.denied_sub.retain(|s| !s.starts_with(&prefix));
+        if allow {
+            self.allowed.insert(name);
+        } else {
+            self.allowed.remove(name);
+        }
+    }
+
+    // Applies the command rules again, so that categories take in the
+    // commands registered since.
+    fn reapply_command_rules(&mut self) {
+        let rules = mem::take(&mut self.command_rules);
+        self.allowed.clear();
+        self.allowed_sub.clear();
+        self.denied_sub.clear();
+        for rule in &rules {
+            let _ = self.apply_command_rule(rule);
+        }
+    }
+
+    pub fn check_password(&self, password: &[u8]) -> bool {
+        self.nopass || self.passwords.contains(&hash_password(password))
+    }
+
+    // Checks the command, its keys and, for pub/sub commands, its channels.
+    pub fn check(&self, spec: &CommandSpec, args: &[Vec<u8>]) -> Result<(), Denied> {
+        let sub = if args.len() > 1 {
+            format!(
+                "{}|{}",
+                spec.name,
+                String::from_utf8_lossy(&args[1]).to_lowercase()
+            )
+        } else {
+            String::new()
+        };
+        let permitted = if self.allowed.contains(spec.name) {
+            !self.denied_sub.contains(&sub)
+        } else {
+            self.allowed_sub.contains(&sub)
+        };
+        if !permitted {
+            return Err(Denied::Command);
+        }
+        if !spec.arity_ok(args.len()) {
+            // The command fails on its own; there is nothing to check.
+            return Ok(());
+        }
+        for key in spec.keys(args) {
+            if !matches_any(&self.keys, key) {
+                return Err(Denied::Key);
+            }
+        }
+        if spec.name == "publish" || spec.name == "subscribe" || spec.name == "psubscribe" {
+            let channels = if spec.name == "publish" {
+                &args[1..2]
+            } else {
+                &args[1..]
+            };
+            for channel in channels {
+                if !matches_any(&self.channels, channel) {
+                    return Err(Denied::Channel);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Renders the user the way ACL LIST shows it, which is also a valid
+    // ACL SETUSER rule list.
+    pub fn describe(&self) -> String {
+        let mut parts = vec![if self.enabled { "on" } else { "off" }.to_string()];
+        if self.nopass {
+            parts.push("nopass".to_string());
+        }
+        for hash in &self.passwords {
+            parts.push(format!("#{}", hash));
+        }
+        parts.push(self.describe_keys());
+        parts.push(self.describe_channels());
+        parts.push(self.describe_commands());
+        if self.max_commands_per_sec > 0 {
+            parts.push(format!("max-commands-per-sec={}", self.max_commands_per_sec));
+        }
+        if self.max_bytes_per_sec > 0 {
+            parts.push(format!("max-bytes-per-sec={}", self.max_bytes_per_sec));
+        }
+        parts.retain(|p| !p.is_empty());
+        parts.join(" ")
+    }
+
+    pub fn describe_keys(&self) -> String {
+        self.keys
+            .iter()
+            .map(|k| format!("~{}", String::from_utf8_lossy(k)))
+            .collect::<Vec<String>>()
+            .join(" ")
+    }
+
+    pub fn describe_channels(&self) -> String {
+        if self.channels.is_empty() {
+            return "resetchannels".to_string();
+        }
+        self.channels
+            .iter()
+            .map(|c| format!("&{}", String::from_utf8_lossy(c)))
+            .collect::<Vec<String>>()
+            .join(" ")
+    }
+
+    pub fn describe_commands(&self) -> String {
+        let mut rules = self.command_rules.clone();
+        if rules.first().is_none_or(|r| r != "+@all" && r != "-@all") {
+            rules.insert(0, "-@all".to_string());
+        }
+        rules.join(" ")
+    }
+}
+
+// Passwords are kept as the hex SHA-256 of their bytes, like Redis does, so
+// # and ! rules and ACL LIST output carry over between the two.
+fn hash_password(password: &[u8]) -> String {
+    digest::digest(&digest::SHA256, password)
+        .as_ref()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+fn parse_hash(hash: &str) -> Result<String, String> {
+    let hash = hash.to_lowercase();
+    if hash.len() != 64 || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err("The password hash must be exactly 64 characters and contain only lowercase hexadecimal characters".to_string());
+    }
+    Ok(hash)
+}
+
+fn parse_limit(v: &str) -> Result<usize, String> {
+    v.parse::<usize>().map_err(|_| "Syntax error".to_string())
+}
+
+fn matches_any(patterns: &[Vec<u8>], name: &[u8]) -> bool {
+    patterns.iter().any(|p| pattern::matches(p, name, false))
+}
+
+// Every category named in the command table, without the leading @.
+pub fn categories() -> Vec<&'static str> {
+    let mut out: Vec<&'static str> = Vec::new();
+    for spec in commands::all() {
+        for category in spec.categories {
+            let category = &category[1..];
+            if !out.contains(&category) {
+                out.push(category);
+            }
+        }
+    }
+    out.sort();
+    out
+}
+
+fn is_category(name: &str) -> bool {
+    categories().contains(&name)
+}
+
+fn default_user() -> User {
+    let mut user = User::new("default");
+    for rule in &["on", "nopass", "allkeys", "allchannels", "+@all"] {
+        user.apply(rule).unwrap();
+    }
+    user
+}
+
+pub struct Acl {
+    users: RwLock<HashMap<String, User>>,
+}
+
+impl Acl {
+    // Starts with the default user, which can run everything and needs no
+    // password.
+    pub fn new() -> Acl {
+        let mut users = HashMap::new();
+        users.insert("default".to_string(), default_user());
+        Acl {
+            users: RwLock::new(users),
+        }
+    }
+
+    // Replaces every user with the ones declared in the file. Nothing
+    // changes unless the whole file parses.
+    pub fn load(&self, path: &str) -> Result<(), String> {
+        let _span = tracing::info_span!("aclfile_load", path).entered();
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) => return Err(format!("Error loading ACLs, opening file '{}': {}", path, e)),
+        };
+        let mut users = HashMap::new();
+        for (i, line) in text.lines().enumerate() {
+            let words: Vec<&str> = line.split_whitespace().collect();
+            if words.is_empty() {
+                continue;
+            }
+            if words[0] != "user" || words.len() < 2 {
+                return Err(format!("{}:{}: line should start with user keyword", path, i + 1));
+            }
+            if users.contains_key(words[1]) {
+                return Err(format!("{}:{}: duplicate user '{}' found", path, i + 1, words[1]));
+            }
+            let mut user = User::new(words[1]);
+            for rule in &words[2..] {
+                if let Err(e) = user.apply(rule) {
+                    return Err(format!("{}:{}: {}", path, i + 1, e));
+                }
+            }
+            users.insert(words[1].to_string(), user);
+        }
+        if !users.contains_key("default") {
+            users.insert("default".to_string(), default_user());
+        }
+        tracing::info!(users = users.len(), "ACL file loaded");
+        *self.users.write().unwrap() = users;
+        Ok(())
+    }
+
+    // Writes every user to a temporary file and renames it over the ACL
+    // file, so a crash never leaves a truncated file behind.
+    pub fn save(&self, path: &str) -> Result<(), String> {
+        let _span = tracing::info_span!("aclfile_save", path).entered();
+        let mut text = String::new();
+        let users = self.users();
+        for user in &users {
+            text.push_str(&format!("user {} {}\n", user.name, user.describe()));
+        }
+        let tmp = format!("{}.tmp", path);
+        let written = fs::File::create(&tmp)
+            .and_then(|mut file| {
+                file.write_all(text.as_bytes())?;
+                file.sync_all()
+            })
+            .and_then(|_| fs::rename(&tmp, path));
+        match written {
+            Ok(()) => tracing::info!(users = users.len(), "ACL file saved"),
+            Err(ref e) => tracing::warn!(error = %e, "ACL file not saved"),
+        }
+        written.map_err(|e| format!("There was an error trying to save the ACLs: {}", e))
+    }
+
+    pub fn get(&self, name: &str) -> Option<User> {
+        self.users.read().unwrap().get(name).cloned()
+    }
+
+    // A user that was deleted or disabled after logging in can no longer
+    // run anything.
+    pub fn check(&self, name: &str, spec: &CommandSpec, args: &[Vec<u8>]) -> Result<(), Denied> {
+        match self.users.read().unwrap().get(name) {
+            Some(user) if user.enabled => user.check(spec, args),
+            _ => Err(Denied::Auth),
+        }
+    }
+
+    // The user's own (commands, bytes) a second limits, zero where unset.
+    pub fn limits(&self, name: &str) -> (usize, usize) {
+        match self.users.read().unwrap().get(name) {
+            Some(user) => (user.max_commands_per_sec, user.max_bytes_per_sec),
+            None => (0, 0),
+        }
+    }
+
+    // Whether new connections start out logged in as the default user.
+    pub fn auto_auth(&self) -> bool {
+        match self.users.read().unwrap().get("default") {
+            Some(user) => user.enabled && user.nopass,
+            None => false,
+        }
+    }
+
+    pub fn authenticate(&self, name: &str, password: &[u8]) -> bool {
+        match self.users.read().unwrap().get(name) {
+            Some(user) => user.enabled && user.check_password(password),
+            None => false,
+        }
+    }
+
+    // Applies the rules to a copy of the user, creating it if needed, so a
+    // bad rule leaves the user untouched.
+    pub fn set_user(&self, name: &str, rules: &[String]) -> Result<(), String> {
+        let mut users = self.users.write().unwrap();
+        let mut user = match users.get(name) {
+            Some(user) => user.clone(),
+            None => User::new(name),
+        };
+        for rule in rules {
+            if let Err(e) = user.apply(rule) {
+                return Err(format!(
+                    "ERR Error in ACL SETUSER modifier '{}': {}",
+                    rule, e
+                ));
+            }
+        }
+        users.insert(name.to_string(), user);
+        Ok(())
+    }
+
+    // Brings every user's permissions up to date with commands registered
+    // after the user was set up.
+    pub fn refresh_commands(&self) {
+        for user in self.users.write().unwrap().values_mut() {
+            user.reapply_command_rules();
+        }
+    }
+
+    pub fn del_user(&self, name: &str) -> bool {
+        self.users.write().unwrap().remove(name).is_some()
+    }
+
+    // Every user, ordered by name.
+    pub fn users(&self) -> Vec<User> {
+        let mut users: Vec<User> = self.users.read().unwrap().values().cloned().collect();
+        users.sort_by(|a, b| a.name.cmp(&b.name));
+        users
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET_SHA256: &str = "2bb80d537b1da3e38bd30361aa855686bde0eacd7162fef6a25fe97bf527a25b";
+
+    fn user(rules: &[&str]) -> User {
+        let mut user = User::new("alice");
+        for rule in rules {
+            user.apply(rule).unwrap();
+        }
+        user
+    }
+
+    fn args(words: &[&str]) -> Vec<Vec<u8>> {
+        words.iter().map(|w| w.as_bytes().to_vec()).collect()
+    }
+
+    fn allowed(user: &User, words: &[&str]) -> bool {
+        let args = args(words);
+        user.check(commands::lookup(&args[0]).unwrap(), &args).is_ok()
+    }
+
+    #[test]
+    fn rules_parse() {
+        let mut alice = User::new("alice");
+        assert!(alice.apply("ON").is_ok() && alice.enabled);
+        assert!(alice.apply("max-commands-per-sec=10").is_ok());
+        assert_eq!(alice.max_commands_per_sec, 10);
+        assert_eq!(alice.apply("bogus"), Err("Syntax error".to_string()));
+        assert!(alice.apply("max-bytes-per-sec=-1").is_err());
+        assert!(alice.apply("+nosuchcommand").is_err());
+        assert!(alice.apply("+@nosuchcategory").is_err());
+        assert!(alice.apply("+config|").is_err());
+        assert!(alice.apply("#abc").is_err());
+        assert!(alice.apply("off").is_ok() && !alice.enabled);
+    }
+
+    #[test]
+    fn command_rules_apply_in_order() {
+        let alice = user(&["allkeys", "+@all", "-set"]);
+        assert!(allowed(&alice, &["GET", "k"]));
+        assert!(!allowed(&alice, &["SET", "k", "v"]));
+        let alice = user(&["allkeys", "-set", "+@all"]);
+        assert!(allowed(&alice, &["SET", "k", "v"]));
+        assert_eq!(alice.describe_commands(), "+@all");
+
+        let alice = user(&["+@all", "-config|set"]);
+        assert!(allowed(&alice, &["CONFIG", "GET", "maxmemory"]));
+        assert!(!allowed(&alice, &["CONFIG", "SET", "maxmemory", "0"]));
+        let alice = user(&["+config|get"]);
+        assert!(allowed(&alice, &["CONFIG", "GET", "maxmemory"]));
+        assert!(!allowed(&alice, &["CONFIG", "SET", "maxmemory", "0"]));
+        assert_eq!(alice.describe_commands(), "-@all +config|get");
+    }
+
+    #[test]
+    fn key_patterns() {
+        let alice = user(&["+@all", "~user:*", "~cache:?"]);
+        assert!(allowed(&alice, &["GET", "user:1000"]));
+        assert!(allowed(&alice, &["GET", "cache:a"]));
+        assert!(!allowed(&alice, &["GET", "cache:ab"]));
+        assert!(!allowed(&alice, &["UNLINK", "user:1", "admin"]));
+        // Keyless commands need no pattern.
+        assert!(allowed(&alice, &["PING"]));
+        let alice = user(&["+@all", "~user:*", "resetkeys"]);
+        assert!(!allowed(&alice, &["GET", "user:1000"]));
+        assert!(allowed(&user(&["+@all", "allkeys"]), &["GET", "anything"]));
+    }
+
+    #[test]
+    fn passwords() {
+        let alice = user(&[">secret", ">other"]);
+        assert!(alice.check_password(b"secret") && alice.check_password(b"other"));
+        assert!(!alice.check_password(b"wrong"));
+        let alice = user(&[">secret", ">other", "<other"]);
+        assert!(!alice.check_password(b"other"));
+
+        let alice = user(&[">secret", "nopass"]);
+        assert!(alice.passwords.is_empty() && alice.check_password(b"anything"));
+        let alice = user(&["nopass", "resetpass"]);
+        assert!(!alice.nopass && !alice.check_password(b""));
+        // Adding a password ends nopass.
+        let alice = user(&["nopass", ">secret"]);
+        assert!(!alice.check_password(b"anything"));
+    }
+
+    #[test]
+    fn passwords_are_sha256_hashes() {
+        let alice = user(&[">secret"]);
+        assert_eq!(alice.passwords, vec![SECRET_SHA256.to_string()]);
+        assert!(alice.describe().contains(&format!("#{}", SECRET_SHA256)));
+
+        let alice = user(&[&format!("#{}", SECRET_SHA256.to_uppercase())]);
+        assert!(alice.check_password(b"secret"));
+        let alice = user(&[">secret", &format!("!{}", SECRET_SHA256)]);
+        assert!(!alice.check_password(b"secret"));
+        // A SHA-1 hash is the wrong length.
+        let mut alice = User::new("alice");
+        assert!(alice.apply("#e5e9fa1ba31ecd1ae84f75caaa474f3a663f05f4").is_err());
+    }
+
+    #[test]
+    fn save_and_load_round_trip() {
+        let path = std::env::temp_dir().join(format!("cache-server-acl-test-{}.acl", std::process::id()));
+        let path = path.to_str().unwrap();
+        let rules: Vec<String> = ["on", ">secret", "~user:*", "&news", "+@all", "-set", "max-bytes-per-sec=100"]
+            .iter()
+            .map(|r| r.to_string())
+            .collect();
+        let acl = Acl::new();
+        acl.set_user("alice", &rules).unwrap();
+        acl.set_user("bob", &[]).unwrap();
+        acl.save(path).unwrap();
+
+        let loaded = Acl::new();
+        loaded.set_user("carol", &[]).unwrap();
+        loaded.load(path).unwrap();
+        let _ = fs::remove_file(path);
+        let describe = |acl: &Acl| -> Vec<String> {
+            acl.users().iter().map(|u| format!("{} {}", u.name, u.describe())).collect()
+        };
+        assert_eq!(describe(&loaded), describe(&acl));
+        assert!(loaded.get("carol").is_none());
+        assert!(loaded.authenticate("alice", b"secret"));
+        assert!(!loaded.authenticate("bob", b""));
+        assert!(loaded.auto_auth());
+        assert_eq!(loaded.limits("alice"), (0, 100));
+    }
+
+    #[test]
+    fn load_is_all_or_nothing() {
+        let path = std::env::temp_dir().join(format!("cache-server-acl-bad-{}.acl", std::process::id()));
+        let path = path.to_str().unwrap();
+        fs::write(path, "user alice on nopass +@all\nuser bob on bogus\n").unwrap();
+        let acl = Acl::new();
+        let err = acl.load(path).unwrap_err();
+        let _ = fs::remove_file(path);
+        assert!(err.ends_with(":2: Syntax error"), "{}", err);
+        assert!(acl.get("alice").is_none());
+        assert!(acl.get("default").is_some());
+    }
+}
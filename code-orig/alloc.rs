@@ -0,0 +1,118 @@
+// The global allocator.
+//
+// The system allocator is used unless the server is built with the
+// jemalloc or mimalloc feature. Whichever is in use reports how much it
+// has handed out, how much it holds for that and how much of it is
+// resident, which MEMORY STATS turns into allocator fragmentation figures,
+// and MEMORY PURGE asks it to give what it retains back to the OS.
+
+pub use self::imp::{purge, stats};
+
+#[cfg(all(feature = "jemalloc", feature = "mimalloc"))]
+compile_error!("the jemalloc and mimalloc features are mutually exclusive");
+
+pub struct Stats {
+    // Bytes in live allocations.
+    pub allocated: usize,
+    // Bytes in the pages those allocations sit in.
+    pub active: usize,
+    // Bytes the allocator has mapped that are resident.
+    pub resident: usize,
+}
+
+#[cfg(feature = "jemalloc")]
+mod imp {
+    use std::ptr;
+
+    use tikv_jemalloc_ctl::{epoch, stats};
+    use tikv_jemalloc_sys::mallctl;
+    use tikv_jemallocator::Jemalloc;
+
+    use super::Stats;
+
+    #[global_allocator]
+    static GLOBAL: Jemalloc = Jemalloc;
+
+    pub fn stats() -> Option<Stats> {
+        // The figures are cached until the epoch advances.
+        epoch::advance().ok()?;
+        Some(Stats {
+            allocated: stats::allocated::read().ok()?,
+            active: stats::active::read().ok()?,
+            resident: stats::resident::read().ok()?,
+        })
+    }
+
+    pub fn purge() {
+        // Arena 4096 stands for all of them.
+        unsafe {
+            mallctl(
+                b"arena.4096.purge\0".as_ptr() as *const _,
+                ptr::null_mut(),
+                ptr::null_mut(),
+                ptr::null_mut(),
+                0,
+            );
+        }
+    }
+}
+
+#[cfg(all(feature = "mimalloc", not(feature = "jemalloc")))]
+mod imp {
+    use libmimalloc_sys::{mi_collect, mi_process_info};
+    use mimalloc::MiMalloc;
+
+    use super::Stats;
+
+    #[global_allocator]
+    static GLOBAL: MiMalloc = MiMalloc;
+
+    // mimalloc only reports what it has committed, which stands in for the
+    // allocated and active figures alike.
+    pub fn stats() -> Option<Stats> {
+        let mut info = [0usize; 8];
+        unsafe {
+            let p = info.as_mut_ptr();
+            mi_process_info(p, p.add(1), p.add(2), p.add(3), p.add(4), p.add(5), p.add(6), p.add(7));
+        }
+        Some(Stats {
+            allocated: info[5],
+            active: info[5],
+            resident: info[3],
+        })
+    }
+
+    pub fn purge() {
+        unsafe { mi_collect(true) };
+    }
+}
+
+#[cfg(not(any(feature = "jemalloc", feature = "mimalloc")))]
+mod imp {
+    use super::Stats;
+
+    // glibc can't tell which of its pages are resident, so all it holds is
+    // counted as such.
+    #[cfg(all(target_os = "linux", target_env = "gnu"))]
+    pub fn stats() -> Option<Stats> {
+        let info = unsafe { ::libc::mallinfo2() };
+        Some(Stats {
+            allocated: info.uordblks + info.hblkhd,
+            active: info.arena + info.hblkhd,
+            resident: info.arena + info.hblkhd,
+        })
+    }
+
+    #[cfg(all(target_os = "linux", target_env = "gnu"))]
+    pub fn purge() {
+        unsafe { ::libc::malloc_trim(0) };
+    }
+
+    #[cfg(not(all(target_os = "linux", target_env = "gnu")))]
+    pub fn stats() -> Option<Stats> {
+        None
+    }
+
+    #[cfg(not(all(target_os = "linux", target_env = "gnu")))]
+    pub fn purge() {}
+}
@@ -0,0 +1,300 @@
+// `cache-server bench`: load generator for a running server.
+//
+// Each client is a thread on a connection of its own, sending batches of
+// pipeline commands drawn from the mix and waiting for all their replies
+// before sending the next batch. A command's latency is the time from its
+// batch being sent to its reply arriving. Keys are picked at random from
+// keyspace names and SET writes values of value-size bytes.
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// Commands the mix can draw from.
+pub const COMMANDS: &[&str] = &["ping", "set", "get", "del"];
+
+pub struct Options {
+    pub host: String,
+    pub port: usize,
+    pub clients: usize,
+    pub requests: usize,
+    pub pipeline: usize,
+    // Commands with their relative weights.
+    pub mix: Vec<(String, usize)>,
+    pub keyspace: usize,
+    pub value_size: usize,
+}
+
+// Parses a mix such as "set=1,get=9". A command without a weight has
+// weight 1.
+pub fn parse_mix(mix: &str) -> Result<Vec<(String, usize)>, String> {
+    let mut out = Vec::new();
+    for part in mix.split(',').filter(|p| !p.is_empty()) {
+        let mut kv = part.splitn(2, '=');
+        let name = kv.next().unwrap().trim().to_lowercase();
+        if !COMMANDS.contains(&name.as_str()) {
+            return Err(format!("Unsupported command '{}' in mix, expected one of {}", name, COMMANDS.join(", ")));
+        }
+        let weight = match kv.next() {
+            Some(w) => w.trim().parse::<usize>().map_err(|_| format!("Invalid weight in mix: '{}'", part))?,
+            None => 1,
+        };
+        if weight > 0 {
+            out.push((name, weight));
+        }
+    }
+    if out.is_empty() {
+        return Err("The command mix is empty".to_string());
+    }
+    Ok(out)
+}
+
+struct Sample {
+    command: usize,
+    micros: u64,
+}
+
+struct Totals {
+    samples: Vec<Sample>,
+    errors: usize,
+}
+
+pub fn run(opts: &Options) -> Result<(), String> {
+    if opts.clients == 0 || opts.pipeline == 0 || opts.keyspace == 0 {
+        return Err("clients, pipeline and keyspace have to be at least 1".to_string());
+    }
+    let addr = format!("{}:{}", opts.host, opts.port);
+    let mut streams = Vec::new();
+    for _ in 0..opts.clients {
+        let stream = TcpStream::connect(&addr).map_err(|e| format!("Could not connect to {}: {}", addr, e))?;
+        let _ = stream.set_nodelay(true);
+        streams.push(stream);
+    }
+    let value = vec![b'x'; opts.value_size];
+    let remaining = Arc::new(AtomicUsize::new(opts.requests));
+    let start = Instant::now();
+    let mut handles = Vec::new();
+    for (i, stream) in streams.into_iter().enumerate() {
+        let remaining = remaining.clone();
+        let mix = opts.mix.clone();
+        let value = value.clone();
+        let (pipeline, keyspace) = (opts.pipeline, opts.keyspace);
+        handles.push(thread::spawn(move || {
+            client(stream, i, &remaining, &mix, pipeline, keyspace, &value)
+        }));
+    }
+    let mut samples = Vec::new();
+    let mut errors = 0;
+    for handle in handles {
+        let totals = handle.join().map_err(|_| "A client thread panicked".to_string())??;
+        samples.extend(totals.samples);
+        errors += totals.errors;
+    }
+    let elapsed = start.elapsed();
+    report(opts, &samples, errors, elapsed);
+    Ok(())
+}
+
+fn client(
+    mut stream: TcpStream,
+    i: usize,
+    remaining: &AtomicUsize,
+    mix: &[(String, usize)],
+    pipeline: usize,
+    keyspace: usize,
+    value: &[u8],
+) -> Result<Totals, String> {
+    let seed = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().subsec_nanos() as u64;
+    let mut rng = Rng(seed ^ ((i as u64 + 1) * 0x9e37_79b9_7f4a_7c15) | 1);
+    let total_weight: usize = mix.iter().map(|&(_, w)| w).sum();
+    let mut totals = Totals {
+        samples: Vec::new(),
+        errors: 0,
+    };
+    let mut input = Vec::new();
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let batch = take(remaining, pipeline);
+        if batch == 0 {
+            return Ok(totals);
+        }
+        let mut request = Vec::new();
+        let mut commands = Vec::new();
+        for _ in 0..batch {
+            let mut pick = rng.next() as usize % total_weight;
+            let command = mix.iter().position(|&(_, w)| {
+                if pick < w {
+                    true
+                } else {
+                    pick -= w;
+                    false
+                }
+            }).unwrap();
+            let key = format!("key:{}", rng.next() as usize % keyspace);
+            encode(&mut request, &mix[command].0, key.as_bytes(), value);
+            commands.push(COMMANDS.iter().position(|&c| c == mix[command].0).unwrap());
+        }
+        let sent = Instant::now();
+        stream.write_all(&request).map_err(|e| format!("Write failed: {}", e))?;
+        let mut got = 0;
+        while got < batch {
+            match skip_reply(&input) {
+                Some((len, error)) => {
+                    input.drain(..len);
+                    if error {
+                        totals.errors += 1;
+                    }
+                    totals.samples.push(Sample {
+                        command: commands[got],
+                        micros: micros(sent.elapsed()),
+                    });
+                    got += 1;
+                }
+                None => match stream.read(&mut buf) {
+                    Ok(0) => return Err("The server closed the connection".to_string()),
+                    Ok(n) => input.extend_from_slice(&buf[..n]),
+                    Err(e) => return Err(format!("Read failed: {}", e)),
+                },
+            }
+        }
+    }
+}
+
+// Claims up to n of the requests left to send.
+fn take(remaining: &AtomicUsize, n: usize) -> usize {
+    let mut left = remaining.load(Ordering::SeqCst);
+    loop {
+        let batch = left.min(n);
+        if batch == 0 {
+            return 0;
+        }
+        match remaining.compare_exchange(left, left - batch, Ordering::SeqCst, Ordering::SeqCst) {
+            Ok(_) => return batch,
+            Err(now) => left = now,
+        }
+    }
+}
+
+fn encode(out: &mut Vec<u8>, command: &str, key: &[u8], value: &[u8]) {
+    let args: Vec<&[u8]> = match command {
+        "ping" => vec![b"PING"],
+        "set" => vec![b"SET", key, value],
+        "get" => vec![b"GET", key],
+        _ => vec![b"DEL", key],
+    };
+    out.extend(format!("*{}\r\n", args.len()).into_bytes());
+    for arg in args {
+        out.extend(format!("${}\r\n", arg.len()).into_bytes());
+        out.extend_from_slice(arg);
+        out.extend_from_slice(b"\r\n");
+    }
+}
+
+// The length of the complete reply at the start of input, and whether it
+// is an error. None until all of it has arrived.
+fn skip_reply(input: &[u8]) -> Option<(usize, bool)> {
+    let end = input.windows(2).position(|w| w == b"\r\n")?;
+    let line = &input[..end];
+    if line.is_empty() {
+        return None;
+    }
+    let header = end + 2;
+    match line[0] {
+        b'+' | b':' | b',' | b'#' | b'_' | b'(' => Some((header, false)),
+        b'-' => Some((header, true)),
+        // Blob errors ('!') carry their message like a bulk string.
+        b'$' | b'=' | b'!' => {
+            let n: i64 = String::from_utf8_lossy(&line[1..]).parse().ok()?;
+            if n < 0 {
+                return Some((header, false));
+            }
+            let len = header + n as usize + 2;
+            if input.len() < len {
+                return None;
+            }
+            Some((len, line[0] == b'!'))
+        }
+        b'*' | b'~' | b'>' | b'%' | b'|' => {
+            let mut n: i64 = String::from_utf8_lossy(&line[1..]).parse().ok()?;
+            if line[0] == b'%' || line[0] == b'|' {
+                n *= 2;
+            }
+            let mut len = header;
+            for _ in 0..n.max(0) {
+                let (item, _) = skip_reply(&input[len..])?;
+                len += item;
+            }
+            Some((len, false))
+        }
+        _ => Some((header, true)),
+    }
+}
+
+fn report(opts: &Options, samples: &[Sample], errors: usize, elapsed: Duration) {
+    let secs = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+    println!(
+        "{} requests, {} clients, pipeline {}, keyspace {}, {} byte values",
+        samples.len(),
+        opts.clients,
+        opts.pipeline,
+        opts.keyspace,
+        opts.value_size
+    );
+    println!(
+        "{:.2} seconds, {:.0} requests per second, {} errors",
+        secs,
+        samples.len() as f64 / secs,
+        errors
+    );
+    println!(
+        "{:<8} {:>10} {:>10} {:>10} {:>10} {:>10} {:>10}",
+        "command", "requests", "p50 ms", "p95 ms", "p99 ms", "p99.9 ms", "max ms"
+    );
+    let mut all: Vec<u64> = samples.iter().map(|s| s.micros).collect();
+    for (i, name) in COMMANDS.iter().enumerate() {
+        let mut latencies: Vec<u64> = samples.iter().filter(|s| s.command == i).map(|s| s.micros).collect();
+        if !latencies.is_empty() {
+            print_row(name, &mut latencies);
+        }
+    }
+    if !all.is_empty() {
+        print_row("all", &mut all);
+    }
+}
+
+fn print_row(name: &str, latencies: &mut [u64]) {
+    latencies.sort();
+    let ms = |q: f64| {
+        let i = ((latencies.len() as f64 * q).ceil() as usize).max(1) - 1;
+        latencies[i] as f64 / 1000.0
+    };
+    println!(
+        "{:<8} {:>10} {:>10.3} {:>10.3} {:>10.3} {:>10.3} {:>10.3}",
+        name,
+        latencies.len(),
+        ms(0.50),
+        ms(0.95),
+        ms(0.99),
+        ms(0.999),
+        ms(1.0)
+    );
+}
+
+fn micros(d: Duration) -> u64 {
+    d.as_secs() * 1_000_000 + d.subsec_micros() as u64
+}
+
+// xorshift64*, plenty for picking keys and commands.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+}
@@ -0,0 +1,299 @@
+// Connection buffers.
+//
+// A Buffer is a byte vector consumed from the front. Consuming only moves
+// an offset; the remaining bytes are moved back to the start once the
+// consumed part outgrows them, or not at all when everything was consumed,
+// so a busy connection's input is neither reallocated nor shifted on every
+// read. Each worker keeps a Pool of buffers released by closed connections
+// and hands them to the next ones it opens.
+//
+// How much room a read makes adapts to the client: a read that fills all
+// of it doubles it for the next, up to READ_MAX, so a client sending large
+// values takes fewer reads, and one that fills less than a quarter halves
+// it again. Once the socket runs dry with nothing left in the buffer, the
+// room goes back to READ_MIN and capacity the pool wouldn't keep is let go,
+// so an idle connection doesn't hold on to what one large value needed.
+//
+// Replies are queued in an Output instead: each stays the segments its
+// command produced, owned bytes or a value shared with the keyspace, and all
+// of them go out in one vectored write rather than being copied together
+// first. Small segments are still packed into the one before them so
+// pipelined commands don't each cost an iovec.
+
+use std::collections::VecDeque;
+use std::io;
+use std::io::{IoSlice, Read};
+use std::ops::Deref;
+
+use bytes::Bytes;
+
+// Bounds of the room a read makes at the end of the buffer.
+const READ_MIN: usize = 16 * 1024;
+const READ_MAX: usize = 1024 * 1024;
+
+// Replies up to this size are appended to the last segment while it is
+// below SEGMENT_PACK_LIMIT, and at most MAX_IOVECS segments go into a write.
+const SEGMENT_PACK_SIZE: usize = 1024;
+const SEGMENT_PACK_LIMIT: usize = 16 * 1024;
+const MAX_IOVECS: usize = 64;
+
+// Largest buffer worth keeping for reuse and how many are kept. A buffer
+// that grew for one huge reply is dropped rather than pinned in the pool.
+const POOL_MAX_CAPACITY: usize = 64 * 1024;
+const POOL_MAX_BUFFERS: usize = 1024;
+
+pub struct Buffer {
+    data: Vec<u8>,
+    start: usize,
+    // Room the next read makes.
+    room: usize,
+}
+
+impl Buffer {
+    pub fn new() -> Buffer {
+        Buffer {
+            data: Vec::new(),
+            start: 0,
+            room: READ_MIN,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len() - self.start
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.data.capacity()
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        &self.data[self.start..]
+    }
+
+    pub fn clear(&mut self) {
+        self.data.clear();
+        self.start = 0;
+    }
+
+    // Drops n bytes from the front.
+    pub fn consume(&mut self, n: usize) {
+        self.start += n;
+        if self.start >= self.data.len() {
+            self.clear();
+        } else if self.start >= self.data.len() - self.start {
+            self.compact();
+        }
+    }
+
+    pub fn extend_from_slice(&mut self, bytes: &[u8]) {
+        self.data.extend_from_slice(bytes);
+    }
+
+    // Reads once from r straight into the end of the buffer.
+    pub fn read_from<R: Read>(&mut self, r: &mut R) -> io::Result<usize> {
+        let room = self.room;
+        if self.start > 0 && self.data.len() + room > self.data.capacity() {
+            self.compact();
+        }
+        let len = self.data.len();
+        self.data.resize(len + room, 0);
+        let result = r.read(&mut self.data[len..]);
+        let n = *result.as_ref().unwrap_or(&0);
+        self.data.truncate(len + n);
+        if n == room {
+            self.room = (room * 2).min(READ_MAX);
+        } else if n > 0 && n < room / 4 {
+            self.room = (room / 2).max(READ_MIN);
+        }
+        result
+    }
+
+    // For when the socket has nothing more to read for now.
+    pub fn idle(&mut self) {
+        if self.len() > 0 {
+            return;
+        }
+        self.room = READ_MIN;
+        if self.data.capacity() > POOL_MAX_CAPACITY {
+            self.clear();
+            self.data.shrink_to(READ_MIN);
+        }
+    }
+
+    fn compact(&mut self) {
+        self.data.drain(..self.start);
+        self.start = 0;
+    }
+}
+
+pub struct Pool {
+    free: Vec<Vec<u8>>,
+}
+
+impl Pool {
+    pub fn new() -> Pool {
+        Pool { free: Vec::new() }
+    }
+
+    pub fn take(&mut self) -> Buffer {
+        Buffer {
+            data: self.free.pop().unwrap_or_default(),
+            start: 0,
+            room: READ_MIN,
+        }
+    }
+
+    pub fn put(&mut self, buffer: Buffer) {
+        let mut data = buffer.data;
+        if data.capacity() == 0 || data.capacity() > POOL_MAX_CAPACITY {
+            return;
+        }
+        if self.free.len() < POOL_MAX_BUFFERS {
+            data.clear();
+            self.free.push(data);
+        }
+    }
+}
+
+pub enum Segment {
+    Owned(Vec<u8>),
+    Shared(Bytes),
+}
+
+impl Deref for Segment {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match *self {
+            Segment::Owned(ref bytes) => bytes,
+            Segment::Shared(ref bytes) => bytes,
+        }
+    }
+}
+
+impl From<Vec<u8>> for Segment {
+    fn from(bytes: Vec<u8>) -> Segment {
+        Segment::Owned(bytes)
+    }
+}
+
+pub struct Output {
+    segments: VecDeque<Segment>,
+    // Bytes of the front segment already written.
+    start: usize,
+    len: usize,
+}
+
+impl Output {
+    pub fn new() -> Output {
+        Output {
+            segments: VecDeque::new(),
+            start: 0,
+            len: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // Memory the queued segments take up. Shared values are counted too,
+    // though the keyspace may hold on to them as well.
+    pub fn capacity(&self) -> usize {
+        self.segments
+            .iter()
+            .map(|segment| match *segment {
+                Segment::Owned(ref bytes) => bytes.capacity(),
+                Segment::Shared(ref bytes) => bytes.len(),
+            })
+            .sum()
+    }
+
+    pub fn push(&mut self, segment: Segment) {
+        if segment.is_empty() {
+            return;
+        }
+        self.len += segment.len();
+        if segment.len() <= SEGMENT_PACK_SIZE {
+            if let Some(&mut Segment::Owned(ref mut last)) = self.segments.back_mut() {
+                if last.len() < SEGMENT_PACK_LIMIT {
+                    last.extend_from_slice(&segment);
+                    return;
+                }
+            }
+        }
+        self.segments.push_back(segment);
+    }
+
+    pub fn clear(&mut self) {
+        self.segments.clear();
+        self.start = 0;
+        self.len = 0;
+    }
+
+    // The unwritten bytes, front segment first.
+    pub fn slices<'a>(&'a self) -> Vec<IoSlice<'a>> {
+        self.segments
+            .iter()
+            .take(MAX_IOVECS)
+            .enumerate()
+            .map(|(i, segment)| IoSlice::new(if i == 0 { &segment[self.start..] } else { segment }))
+            .collect()
+    }
+
+    // Drops n written bytes from the front.
+    pub fn consume(&mut self, mut n: usize) {
+        self.len -= n;
+        while n > 0 {
+            let left = self.segments[0].len() - self.start;
+            if n < left {
+                self.start += n;
+                return;
+            }
+            n -= left;
+            self.segments.pop_front();
+            self.start = 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Buffer, READ_MAX, READ_MIN};
+
+    #[test]
+    fn reads_grow_for_large_input_and_shrink_when_idle() {
+        let input = vec![b'x'; 4 * READ_MAX];
+        let mut r = &input[..];
+        let mut buffer = Buffer::new();
+        let mut reads = 0;
+        while buffer.read_from(&mut r).unwrap() > 0 {
+            reads += 1;
+        }
+        assert_eq!(buffer.len(), input.len());
+        assert!(reads < input.len() / READ_MIN / 4);
+        assert!(buffer.room >= READ_MAX / 2);
+
+        // Nothing shrinks while input is still waiting.
+        let room = buffer.room;
+        buffer.idle();
+        assert_eq!(buffer.room, room);
+        buffer.consume(input.len());
+        buffer.idle();
+        assert_eq!(buffer.room, READ_MIN);
+        assert!(buffer.capacity() < 2 * READ_MIN);
+    }
+
+    #[test]
+    fn small_reads_halve_the_room() {
+        let mut buffer = Buffer::new();
+        buffer.room = 8 * READ_MIN;
+        let mut r = &b"PING\r\n"[..];
+        buffer.read_from(&mut r).unwrap();
+        assert_eq!(buffer.room, 4 * READ_MIN);
+    }
+}
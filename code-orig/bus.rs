@@ -0,0 +1,296 @@
+// The cluster bus.
+//
+// Nodes talk to each other on their bus port, the client port plus
+// BUS_PORT_OFFSET, one message per line. Each node keeps a link to every
+// other node it knows and pings it every second. A ping carries the
+// sender's slots and epochs and its view of the other nodes, and is
+// answered with a pong carrying the same of the receiver, so what one
+// node learns spreads to the rest. CLUSTER MEET introduces a node to
+// another with a MEET, which is a ping the receiver also learns an unknown
+// sender from.
+//
+// A node whose ping goes unanswered for cluster-node-timeout is flagged
+// as possibly failing, and marked failed once a majority of the nodes
+// serving slots have flagged it. The node that sees the majority first
+// tells the others with a FAIL message. A failed node that answers again
+// is no longer failed.
+
+use std::collections::HashSet;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream};
+use std::os::unix::io::{FromRawFd, IntoRawFd};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use cluster::{self, Cluster, Gossip, Message};
+use stream;
+
+const CRON_INTERVAL: Duration = Duration::from_millis(100);
+const PING_INTERVAL: Duration = Duration::from_secs(1);
+
+// Listens on the bus port of every bind address and starts keeping links
+// to the other nodes.
+pub fn start(cluster: Arc<Cluster>, bind: &str, backlog: i32) -> Result<(), String> {
+    for listener in stream::bind_all(bind, cluster.cport(), backlog, false)? {
+        let listener = unsafe { TcpListener::from_raw_fd(listener.into_raw_fd()) };
+        listener.set_nonblocking(false).map_err(|e| e.to_string())?;
+        let cluster = cluster.clone();
+        spawn("cluster-bus", move || accept(listener, cluster));
+    }
+    spawn("cluster-cron", move || cron(cluster));
+    Ok(())
+}
+
+fn spawn<F: FnOnce() + Send + 'static>(name: &str, f: F) {
+    thread::Builder::new().name(name.to_string()).spawn(f).unwrap();
+}
+
+fn accept(listener: TcpListener, cluster: Arc<Cluster>) {
+    for stream in listener.incoming().flatten() {
+        let cluster = cluster.clone();
+        spawn("cluster-bus", move || serve(stream, &cluster));
+    }
+}
+
+// Answers the messages another node sends over its link to this one.
+fn serve(stream: TcpStream, cluster: &Cluster) {
+    let (peer_ip, local_ip) = ips(&stream);
+    let mut writer = match stream.try_clone() {
+        Ok(writer) => writer,
+        Err(_) => return,
+    };
+    for line in BufReader::new(stream).lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => break,
+        };
+        let words: Vec<&str> = line.split(' ').collect();
+        match words[0] {
+            "FAIL" if words.len() == 3 => {
+                if cluster.knows(words[1]) {
+                    cluster.failed(words[2]);
+                }
+            }
+            "PING" | "MEET" => {
+                let msg = match decode(&words) {
+                    Some(msg) => msg,
+                    None => break,
+                };
+                cluster.receive(&msg, &peer_ip, &local_ip, msg.kind == "MEET");
+                if writer.write_all(encode(&cluster.message("PONG")).as_bytes()).is_err() {
+                    break;
+                }
+            }
+            _ => break,
+        }
+    }
+}
+
+// Introduces nodes given to CLUSTER MEET, starts links to nodes learned
+// since the last run, and checks for failing nodes every CRON_INTERVAL.
+fn cron(cluster: Arc<Cluster>) {
+    let links = Arc::new(Mutex::new(HashSet::new()));
+    loop {
+        for (ip, cport) in cluster.take_meets() {
+            let cluster = cluster.clone();
+            spawn("cluster-meet", move || {
+                let _ = meet(&ip, cport, &cluster);
+            });
+        }
+        for id in cluster.peers() {
+            if links.lock().unwrap().insert(id.clone()) {
+                let cluster = cluster.clone();
+                let links = links.clone();
+                spawn("cluster-link", move || {
+                    link(&id, &cluster);
+                    links.lock().unwrap().remove(&id);
+                });
+            }
+        }
+        for id in cluster.check_failures() {
+            broadcast_fail(&cluster, &id);
+        }
+        thread::sleep(CRON_INTERVAL);
+    }
+}
+
+fn meet(ip: &str, cport: usize, cluster: &Cluster) -> io::Result<()> {
+    let stream = connect(ip, cport, cluster.node_timeout())?;
+    let (peer_ip, local_ip) = ips(&stream);
+    (&stream).write_all(encode(&cluster.message("MEET")).as_bytes())?;
+    let mut line = String::new();
+    BufReader::new(&stream).read_line(&mut line)?;
+    let words: Vec<&str> = line.trim_end().split(' ').collect();
+    match decode(&words) {
+        Some(ref msg) if msg.kind == "PONG" => {
+            cluster.receive(msg, &peer_ip, &local_ip, true);
+            cluster.ponged(&msg.sender);
+            Ok(())
+        }
+        _ => Err(io::Error::new(io::ErrorKind::InvalidData, "bad reply to MEET")),
+    }
+}
+
+// Keeps a link to a node for as long as it is known. While the node can't
+// be reached its ping stays unanswered, which is what flags it failing.
+fn link(id: &str, cluster: &Cluster) {
+    while cluster.knows(id) {
+        if let Some((ip, cport)) = cluster.bus_addr(id) {
+            cluster.pinged(id);
+            if let Ok(stream) = connect(&ip, cport, cluster.node_timeout()) {
+                let _ = exchange(id, stream, cluster);
+            }
+            cluster.disconnected(id);
+        }
+        thread::sleep(PING_INTERVAL);
+    }
+}
+
+// Pings the node every PING_INTERVAL over an open link and takes in its
+// pongs, until the link breaks, the node stops answering or it turns out
+// to be another node.
+fn exchange(id: &str, stream: TcpStream, cluster: &Cluster) -> io::Result<()> {
+    let (peer_ip, local_ip) = ips(&stream);
+    stream.set_read_timeout(Some(Duration::from_millis(cluster.node_timeout().max(1))))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+    let mut line = String::new();
+    while cluster.knows(id) {
+        cluster.pinged(id);
+        writer.write_all(encode(&cluster.message("PING")).as_bytes())?;
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(());
+        }
+        let words: Vec<&str> = line.trim_end().split(' ').collect();
+        match decode(&words) {
+            Some(ref msg) if msg.kind == "PONG" && msg.sender == id => {
+                cluster.receive(msg, &peer_ip, &local_ip, false);
+                cluster.ponged(id);
+            }
+            _ => return Ok(()),
+        }
+        thread::sleep(PING_INTERVAL);
+    }
+    Ok(())
+}
+
+// Tells every other node that a node failed, each on a connection of its
+// own so that unreachable ones hold up nothing.
+fn broadcast_fail(cluster: &Arc<Cluster>, id: &str) {
+    let line = format!("FAIL {} {}\n", cluster.myself(), id);
+    for peer in cluster.peers() {
+        if let Some((ip, cport)) = cluster.bus_addr(&peer) {
+            let timeout = cluster.node_timeout();
+            let line = line.clone();
+            spawn("cluster-fail", move || {
+                if let Ok(mut stream) = connect(&ip, cport, timeout) {
+                    let _ = stream.write_all(line.as_bytes());
+                }
+            });
+        }
+    }
+}
+
+fn connect(ip: &str, cport: usize, timeout: u64) -> io::Result<TcpStream> {
+    let ip = ip
+        .parse::<IpAddr>()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let addr = SocketAddr::new(ip, cport as u16);
+    TcpStream::connect_timeout(&addr, Duration::from_millis(timeout.max(1)))
+}
+
+// The addresses of both ends of a link.
+fn ips(stream: &TcpStream) -> (String, String) {
+    let peer = stream.peer_addr().map(|a| a.ip().to_string());
+    let local = stream.local_addr().map(|a| a.ip().to_string());
+    (peer.unwrap_or_default(), local.unwrap_or_default())
+}
+
+// KIND sender ip port cport current-epoch config-epoch slots gossip...,
+// with slots as comma-separated ranges and each node gossiped about as
+// id,ip,port,cport,state. Unknown ips and empty lists are "-".
+fn encode(msg: &Message) -> String {
+    let slots: Vec<String> = msg
+        .slots
+        .iter()
+        .map(|&(first, last)| format!("{}-{}", first, last))
+        .collect();
+    let mut line = format!(
+        "{} {} {} {} {} {} {} {}",
+        msg.kind,
+        msg.sender,
+        or_dash(&msg.ip),
+        msg.port,
+        msg.cport,
+        msg.current_epoch,
+        msg.config_epoch,
+        or_dash(&slots.join(","))
+    );
+    for gossip in &msg.gossip {
+        line.push_str(&format!(
+            " {},{},{},{},{}",
+            gossip.id,
+            or_dash(&gossip.ip),
+            gossip.port,
+            gossip.cport,
+            if gossip.failing { "fail" } else { "ok" }
+        ));
+    }
+    line.push('\n');
+    line
+}
+
+fn decode(words: &[&str]) -> Option<Message> {
+    if words.len() < 8 {
+        return None;
+    }
+    let mut slots = Vec::new();
+    if words[7] != "-" {
+        for range in words[7].split(',') {
+            slots.push(cluster::parse_range(range)?);
+        }
+    }
+    let mut gossip = Vec::new();
+    for word in &words[8..] {
+        let fields: Vec<&str> = word.split(',').collect();
+        if fields.len() != 5 {
+            return None;
+        }
+        gossip.push(Gossip {
+            id: fields[0].to_string(),
+            ip: from_dash(fields[1]),
+            port: fields[2].parse().ok()?,
+            cport: fields[3].parse().ok()?,
+            failing: fields[4] == "fail",
+        });
+    }
+    Some(Message {
+        kind: words[0].to_string(),
+        sender: words[1].to_string(),
+        ip: from_dash(words[2]),
+        port: words[3].parse().ok()?,
+        cport: words[4].parse().ok()?,
+        current_epoch: words[5].parse().ok()?,
+        config_epoch: words[6].parse().ok()?,
+        slots,
+        gossip,
+    })
+}
+
+fn or_dash(s: &str) -> &str {
+    if s.is_empty() {
+        "-"
+    } else {
+        s
+    }
+}
+
+fn from_dash(s: &str) -> String {
+    if s == "-" {
+        String::new()
+    } else {
+        s.to_string()
+    }
+}
@@ -0,0 +1,337 @@
+// Per-connection metadata shared across worker threads.
+//
+// Each connection owns an Arc<Mutex<Client>> that its worker updates as
+// commands arrive; the registry keeps a second handle so any thread can
+// answer CLIENT LIST. A worker never holds its own client lock while
+// dispatching, so walking the registry cannot deadlock against it.
+
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::Waker;
+use std::time::{Duration, Instant};
+
+use ratelimit;
+
+// CLIENT REPLY state. SKIP suppresses the reply of the SKIP command itself
+// and of the one after it, so it passes through SkipNext then Skip.
+#[derive(Clone, Copy, PartialEq)]
+pub enum ReplyMode {
+    On,
+    Off,
+    SkipNext,
+    Skip,
+}
+
+pub struct Client {
+    pub id: usize,
+    // Index of the worker thread that owns the connection.
+    pub worker: usize,
+    pub addr: String,
+    pub laddr: String,
+    // Connected over the Unix socket rather than TCP.
+    pub unix: bool,
+    // Index of the listener the connection was accepted on.
+    pub listener: usize,
+    // The address the connection is counted under for
+    // max-connections-per-ip, once it is known.
+    pub ip: Option<IpAddr>,
+    pub fd: i32,
+    pub name: Vec<u8>,
+    pub db: usize,
+    pub created: Instant,
+    pub last_interaction: Instant,
+    pub last_cmd: String,
+    pub reply: ReplyMode,
+    // Protocol version negotiated with HELLO, 2 or 3.
+    pub resp: u8,
+    pub user: String,
+    pub authenticated: bool,
+    // Set by ASKING to let the next command into a slot being imported.
+    pub asking: bool,
+    // CLIENT NO-EVICT: never closed to free memory. Nothing closes clients
+    // for memory yet, so it is only reported.
+    pub no_evict: bool,
+    // CLIENT NO-TOUCH: reads leave the keys' LRU/LFU data alone.
+    pub no_touch: bool,
+    // Commands queued since MULTI, None outside a transaction.
+    pub multi: Option<Vec<Vec<Vec<u8>>>>,
+    // A command was refused while queueing, so EXEC aborts.
+    pub multi_failed: bool,
+    pub limiter: ratelimit::Limiter,
+    // Capacities of the query and reply buffers as of the last command.
+    pub qbuf: usize,
+    pub obuf: usize,
+    pub channels: HashSet<Vec<u8>>,
+    pub patterns: HashSet<Vec<u8>>,
+    // Encoded out-of-band frames waiting for the worker to deliver them.
+    pub pushes: Vec<u8>,
+    // Set by another thread to have the worker close the connection.
+    pub kill: bool,
+    // The task serving the connection under the tokio backend, woken for
+    // pushes and shutdown.
+    pub task: Option<Waker>,
+}
+
+impl Client {
+    pub fn new(
+        id: usize,
+        worker: usize,
+        addr: String,
+        laddr: String,
+        fd: i32,
+    ) -> Client {
+        let now = Instant::now();
+        Client {
+            id,
+            worker,
+            addr,
+            laddr,
+            unix: false,
+            listener: 0,
+            ip: None,
+            fd,
+            name: Vec::new(),
+            db: 0,
+            created: now,
+            last_interaction: now,
+            last_cmd: "NULL".to_string(),
+            reply: ReplyMode::On,
+            resp: 2,
+            user: "default".to_string(),
+            authenticated: false,
+            asking: false,
+            no_evict: false,
+            no_touch: false,
+            multi: None,
+            multi_failed: false,
+            limiter: ratelimit::Limiter::new(),
+            qbuf: 0,
+            obuf: 0,
+            channels: HashSet::new(),
+            patterns: HashSet::new(),
+            pushes: Vec::new(),
+            kill: false,
+            task: None,
+        }
+    }
+
+    // Records the command about to run, naming container commands like
+    // CLIENT LIST as "client|list".
+    pub fn touch(&mut self, args: &[Vec<u8>]) {
+        let mut cmd = String::from_utf8_lossy(&args[0]).to_lowercase();
+        if args.len() > 1 && is_container(&cmd) {
+            cmd.push('|');
+            cmd.push_str(&String::from_utf8_lossy(&args[1]).to_lowercase());
+        }
+        self.last_cmd = cmd;
+        self.last_interaction = Instant::now();
+    }
+
+    // Decides whether the reply of the command that just ran is sent, and
+    // advances a pending CLIENT REPLY SKIP.
+    pub fn take_reply(&mut self) -> bool {
+        match self.reply {
+            ReplyMode::On => true,
+            ReplyMode::Off => false,
+            ReplyMode::SkipNext => {
+                self.reply = ReplyMode::Skip;
+                false
+            }
+            ReplyMode::Skip => {
+                self.reply = ReplyMode::On;
+                false
+            }
+        }
+    }
+
+    // The flags field of CLIENT LIST: U or N for the socket type, then x
+    // inside MULTI, e for NO-EVICT and T for NO-TOUCH.
+    fn flags(&self) -> String {
+        let mut flags = String::from(if self.unix { "U" } else { "N" });
+        if self.multi.is_some() {
+            flags.push('x');
+        }
+        if self.no_evict {
+            flags.push('e');
+        }
+        if self.no_touch {
+            flags.push('T');
+        }
+        flags
+    }
+
+    pub fn subscriptions(&self) -> usize {
+        self.channels.len() + self.patterns.len()
+    }
+
+    // Renders the CLIENT LIST / CLIENT INFO line for this connection.
+    pub fn info_line(&self) -> String {
+        format!(
+            "id={} addr={} laddr={} fd={} name={} age={} idle={} flags={} db={} sub={} psub={} multi={} cmd={} user={} resp={}\n",
+            self.id,
+            self.addr,
+            self.laddr,
+            self.fd,
+            String::from_utf8_lossy(&self.name),
+            self.created.elapsed().as_secs(),
+            self.last_interaction.elapsed().as_secs(),
+            self.flags(),
+            self.db,
+            self.channels.len(),
+            self.patterns.len(),
+            self.multi.as_ref().map_or(-1, |queued| queued.len() as i64),
+            self.last_cmd,
+            self.user,
+            self.resp
+        )
+    }
+}
+
+pub fn is_container(cmd: &str) -> bool {
+    matches!(
+        cmd,
+        "acl" | "client" | "cluster" | "config" | "command" | "debug" | "script" | "function"
+            | "latency" | "memory" | "module" | "object"
+    )
+}
+
+pub struct Clients {
+    clients: Mutex<HashMap<usize, Arc<Mutex<Client>>>>,
+    // Sum of every client's qbuf and obuf.
+    buffers: AtomicUsize,
+    // Open connections by client address.
+    per_ip: Mutex<HashMap<IpAddr, usize>>,
+}
+
+impl Clients {
+    pub fn new() -> Clients {
+        Clients {
+            clients: Mutex::new(HashMap::new()),
+            buffers: AtomicUsize::new(0),
+            per_ip: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn register(&self, client: Client) -> Arc<Mutex<Client>> {
+        let id = client.id;
+        let client = Arc::new(Mutex::new(client));
+        self.clients.lock().unwrap().insert(id, client.clone());
+        client
+    }
+
+    pub fn get(&self, id: usize) -> Option<Arc<Mutex<Client>>> {
+        self.clients.lock().unwrap().get(&id).cloned()
+    }
+
+    pub fn unregister(&self, id: usize) {
+        let client = self.clients.lock().unwrap().remove(&id);
+        if let Some(client) = client {
+            let client = client.lock().unwrap();
+            self.buffers.fetch_sub(client.qbuf + client.obuf, Ordering::Relaxed);
+            if let Some(ip) = client.ip {
+                let mut per_ip = self.per_ip.lock().unwrap();
+                let last = per_ip.get_mut(&ip).is_some_and(|count| {
+                    *count -= 1;
+                    *count == 0
+                });
+                if last {
+                    per_ip.remove(&ip);
+                }
+            }
+        }
+    }
+
+    // Counts a new connection from ip, unless max connections from it, if
+    // max isn't zero, are open already.
+    pub fn count_ip(&self, ip: IpAddr, max: usize) -> bool {
+        let mut per_ip = self.per_ip.lock().unwrap();
+        let count = per_ip.get(&ip).cloned().unwrap_or(0);
+        if max > 0 && count >= max {
+            return false;
+        }
+        per_ip.insert(ip, count + 1);
+        true
+    }
+
+    // Records the client's current buffer capacities.
+    pub fn set_buffers(&self, client: &mut Client, qbuf: usize, obuf: usize) {
+        self.buffers.fetch_add(qbuf + obuf, Ordering::Relaxed);
+        self.buffers.fetch_sub(client.qbuf + client.obuf, Ordering::Relaxed);
+        client.qbuf = qbuf;
+        client.obuf = obuf;
+    }
+
+    pub fn buffers(&self) -> usize {
+        self.buffers.load(Ordering::Relaxed)
+    }
+
+    pub fn len(&self) -> usize {
+        self.clients.lock().unwrap().len()
+    }
+
+    // Returns every registered client ordered by id.
+    pub fn list(&self) -> Vec<Arc<Mutex<Client>>> {
+        let clients = self.clients.lock().unwrap();
+        let mut ids: Vec<&usize> = clients.keys().collect();
+        ids.sort();
+        ids.iter().map(|id| clients[*id].clone()).collect()
+    }
+}
+
+// Client names show up in space separated CLIENT LIST output, so they are
+// restricted to printable characters without spaces.
+pub fn valid_name(name: &[u8]) -> bool {
+    name.iter().all(|&b| b > b' ' && b <= b'~')
+}
+
+// CLIENT PAUSE state. `all` pauses every command, otherwise only commands
+// that may write are held back until the deadline passes.
+pub struct Pause {
+    active: AtomicBool,
+    until: Mutex<Option<(Instant, bool)>>,
+}
+
+impl Pause {
+    pub fn new() -> Pause {
+        Pause {
+            active: AtomicBool::new(false),
+            until: Mutex::new(None),
+        }
+    }
+
+    // A new pause never shortens or weakens one already in effect.
+    pub fn pause(&self, duration: Duration, all: bool) {
+        let mut until = self.until.lock().unwrap();
+        let deadline = Instant::now() + duration;
+        *until = match *until {
+            Some((end, prev_all)) if end > Instant::now() => {
+                Some((if end > deadline { end } else { deadline }, all || prev_all))
+            }
+            _ => Some((deadline, all)),
+        };
+        self.active.store(true, Ordering::SeqCst);
+    }
+
+    pub fn unpause(&self) {
+        *self.until.lock().unwrap() = None;
+        self.active.store(false, Ordering::SeqCst);
+    }
+
+    pub fn blocks(&self, write: bool) -> bool {
+        if !self.active.load(Ordering::SeqCst) {
+            return false;
+        }
+        let mut until = self.until.lock().unwrap();
+        match *until {
+            Some((end, all)) if end > Instant::now() => all || write,
+            Some(_) => {
+                *until = None;
+                self.active.store(false, Ordering::SeqCst);
+                false
+            }
+            None => false,
+        }
+    }
+}
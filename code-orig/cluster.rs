@@ -0,0 +1,1055 @@
+// Cluster mode.
+//
+// The keyspace is split into SLOTS hash slots, each served by one node of
+// the cluster. A key belongs to the slot its CRC16 falls in. Commands on
+// keys of a slot another node serves are answered with -MOVED naming that
+// node, which cluster-aware clients follow and remember for the slot, and
+// commands on keys of a slot no node serves fail with -CLUSTERDOWN. A
+// command can only take several keys if they all belong to one slot, which
+// keys sharing a {hash tag} do, and fails with -CROSSSLOT otherwise.
+//
+// Nodes learn of each other, of who serves which slots and of which nodes
+// are failing over the cluster bus. When two nodes claim the same slot the
+// one with the greater config epoch keeps it; nodes never share a config
+// epoch for long, as the one with the smaller id moves to a fresh epoch
+// when they collide.
+//
+// A slot moves between nodes while they keep serving it. The node giving
+// it up marks it migrating and the one taking it marks it importing; keys
+// are then moved over with MIGRATE. Commands on keys the old node no
+// longer holds are answered with -ASK, sending the client to the new node
+// for that one command, which it prefixes with ASKING so the new node
+// serves it ahead of owning the slot. Once the keys are moved both nodes
+// are told the slot's new owner with CLUSTER SETSLOT NODE.
+//
+// A node in cluster mode only has database 0. What it knows of the cluster
+// is kept in its cluster-config-file, rewritten whenever that changes and
+// read back at startup, in the same format CLUSTER NODES replies with.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::{Read, Write};
+use std::sync::{Mutex, RwLock, RwLockReadGuard};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use sha1_smol;
+use tracing;
+
+pub const SLOTS: usize = 16384;
+
+// Cluster bus ports are the client port plus this.
+pub const BUS_PORT_OFFSET: usize = 10000;
+
+// How long a forgotten node is kept from being learned again through
+// gossip, in milliseconds.
+const FORGET_TTL: u64 = 60 * 1000;
+
+pub const DOWN_ERROR: &[u8] = b"-CLUSTERDOWN Hash slot not served\r\n";
+pub const FAIL_ERROR: &[u8] = b"-CLUSTERDOWN The cluster is down\r\n";
+pub const TRYAGAIN_ERROR: &[u8] = b"-TRYAGAIN Multiple keys request during rehashing of slot\r\n";
+pub const CROSSSLOT_ERROR: &[u8] = b"-CROSSSLOT Keys in request don't hash to the same slot\r\n";
+
+pub struct Node {
+    pub ip: String,
+    pub port: usize,
+    pub cport: usize,
+    pub config_epoch: u64,
+    // When the ping still unanswered was sent, or 0, and when the last
+    // pong arrived, in Unix milliseconds.
+    pub ping_sent: u64,
+    pub pong_received: u64,
+    // Unreachable for longer than cluster-node-timeout as seen from here,
+    // and agreed to have failed by a majority of the slot-serving nodes.
+    pub pfail: bool,
+    pub fail: bool,
+    pub connected: bool,
+    // Nodes that gossiped this one as failing, with when they last did.
+    reports: HashMap<String, u64>,
+}
+
+impl Node {
+    pub fn new(ip: &str, port: usize, cport: usize) -> Node {
+        Node {
+            ip: ip.to_string(),
+            port,
+            cport,
+            config_epoch: 0,
+            ping_sent: 0,
+            pong_received: 0,
+            pfail: false,
+            fail: false,
+            connected: false,
+            reports: HashMap::new(),
+        }
+    }
+
+    pub fn addr(&self) -> String {
+        format!("{}:{}", self.ip, self.port)
+    }
+
+    pub fn failing(&self) -> bool {
+        self.pfail || self.fail
+    }
+}
+
+// What a node tells another on the bus about itself and, as gossip, about
+// the nodes it knows.
+pub struct Message {
+    pub kind: String,
+    pub sender: String,
+    // Empty while the sender doesn't know its own ip.
+    pub ip: String,
+    pub port: usize,
+    pub cport: usize,
+    pub current_epoch: u64,
+    pub config_epoch: u64,
+    pub slots: Vec<(usize, usize)>,
+    pub gossip: Vec<Gossip>,
+}
+
+pub struct Gossip {
+    pub id: String,
+    pub ip: String,
+    pub port: usize,
+    pub cport: usize,
+    pub failing: bool,
+}
+
+pub struct State {
+    pub myself: String,
+    pub current_epoch: u64,
+    // Every known node by id, this one included.
+    pub nodes: BTreeMap<String, Node>,
+    // The id of the node serving each slot.
+    pub slots: Vec<Option<String>>,
+    // Slots this node is moving to other nodes and taking from them, with
+    // the other node's id.
+    pub migrating: BTreeMap<usize, String>,
+    pub importing: BTreeMap<usize, String>,
+    // Nodes removed by CLUSTER FORGET, until when gossip may add them back.
+    forgotten: HashMap<String, u64>,
+    // Addresses given to CLUSTER MEET the bus hasn't reached out to yet.
+    meets: Vec<(String, usize)>,
+}
+
+impl State {
+    // Runs of consecutive slots served by the same node, as first slot,
+    // last slot and node id.
+    pub fn ranges(&self) -> Vec<(usize, usize, &str)> {
+        let mut ranges: Vec<(usize, usize, &str)> = Vec::new();
+        for (slot, owner) in self.slots.iter().enumerate() {
+            let id = match *owner {
+                Some(ref id) => id.as_str(),
+                None => continue,
+            };
+            match ranges.last_mut() {
+                Some(last) if last.1 + 1 == slot && last.2 == id => last.1 = slot,
+                _ => ranges.push((slot, slot, id)),
+            }
+        }
+        ranges
+    }
+
+    pub fn assigned(&self) -> usize {
+        self.slots.iter().filter(|owner| owner.is_some()).count()
+    }
+
+    // The number of nodes serving at least one slot, whose majority it
+    // takes to mark a node failed.
+    pub fn size(&self) -> usize {
+        self.nodes.keys().filter(|id| self.serves(id)).count()
+    }
+
+    fn serves(&self, id: &str) -> bool {
+        self.slots.iter().any(|owner| owner.as_ref().map(|o| o.as_str()) == Some(id))
+    }
+
+    // The cluster is ok once every slot is served by a node that hasn't
+    // failed.
+    pub fn is_ok(&self) -> bool {
+        let (_, fail) = self.failing();
+        self.assigned() == SLOTS && fail == 0
+    }
+
+    // Slots served by nodes suspected to have failed, and by nodes that
+    // have.
+    fn failing(&self) -> (usize, usize) {
+        let (mut pfail, mut fail) = (0, 0);
+        for owner in self.slots.iter().filter_map(|owner| owner.as_ref()) {
+            match self.nodes.get(owner) {
+                Some(node) if node.fail => fail += 1,
+                Some(node) if node.pfail => pfail += 1,
+                _ => {}
+            }
+        }
+        (pfail, fail)
+    }
+
+    // The fields of CLUSTER INFO.
+    pub fn info(&self) -> String {
+        let assigned = self.assigned();
+        let (pfail, fail) = self.failing();
+        let ok = self.is_ok();
+        let mut info = String::new();
+        info.push_str(&format!("cluster_state:{}\r\n", if ok { "ok" } else { "fail" }));
+        info.push_str(&format!("cluster_slots_assigned:{}\r\n", assigned));
+        info.push_str(&format!("cluster_slots_ok:{}\r\n", assigned - pfail - fail));
+        info.push_str(&format!("cluster_slots_pfail:{}\r\n", pfail));
+        info.push_str(&format!("cluster_slots_fail:{}\r\n", fail));
+        info.push_str(&format!("cluster_known_nodes:{}\r\n", self.nodes.len()));
+        info.push_str(&format!("cluster_size:{}\r\n", self.size()));
+        info.push_str(&format!("cluster_current_epoch:{}\r\n", self.current_epoch));
+        info.push_str(&format!("cluster_my_epoch:{}\r\n", self.nodes[&self.myself].config_epoch));
+        info
+    }
+
+    // One line per node: id, address and bus port, flags, master, ping
+    // sent and pong received times, config epoch, link state and the
+    // slots it serves.
+    pub fn describe(&self) -> String {
+        let mut ranges: BTreeMap<&str, Vec<String>> = BTreeMap::new();
+        for (first, last, id) in self.ranges() {
+            let range = if first == last {
+                first.to_string()
+            } else {
+                format!("{}-{}", first, last)
+            };
+            ranges.entry(id).or_default().push(range);
+        }
+        let mut text = String::new();
+        for (id, node) in self.nodes.iter() {
+            let myself = *id == self.myself;
+            let mut flags = if myself { "myself,master" } else { "master" }.to_string();
+            if node.fail {
+                flags.push_str(",fail");
+            } else if node.pfail {
+                flags.push_str(",fail?");
+            }
+            let link = if myself || node.connected { "connected" } else { "disconnected" };
+            text.push_str(&format!(
+                "{} {}@{} {} - {} {} {} {}",
+                id,
+                node.addr(),
+                node.cport,
+                flags,
+                node.ping_sent,
+                node.pong_received,
+                node.config_epoch,
+                link
+            ));
+            for range in ranges.get(id.as_str()).into_iter().flatten() {
+                text.push(' ');
+                text.push_str(range);
+            }
+            if myself {
+                for (slot, to) in self.migrating.iter() {
+                    text.push_str(&format!(" [{}->-{}]", slot, to));
+                }
+                for (slot, from) in self.importing.iter() {
+                    text.push_str(&format!(" [{}-<-{}]", slot, from));
+                }
+            }
+            text.push('\n');
+        }
+        text
+    }
+}
+
+pub struct Cluster {
+    path: String,
+    // cluster-node-timeout, in milliseconds.
+    node_timeout: u64,
+    state: RwLock<State>,
+    saving: Mutex<()>,
+}
+
+impl Cluster {
+    // Restores what the node knew of the cluster from its config file, or
+    // starts a cluster of just this node under a fresh id and serving no
+    // slots when there is no file yet. This node's ip stays unknown until
+    // announced or learned from the others.
+    pub fn open(path: &str, port: usize, announce_ip: &str, node_timeout: u64) -> Result<Cluster, String> {
+        let mut state = match fs::read_to_string(path) {
+            Ok(text) => parse(&text).map_err(|e| {
+                format!("Unrecoverable error: corrupted cluster config file \"{}\": {}", path, e)
+            })?,
+            Err(_) => {
+                let id = random_id();
+                let mut nodes = BTreeMap::new();
+                nodes.insert(id.clone(), Node::new("", 0, 0));
+                State {
+                    myself: id,
+                    current_epoch: 0,
+                    nodes,
+                    slots: vec![None; SLOTS],
+                    migrating: BTreeMap::new(),
+                    importing: BTreeMap::new(),
+                    forgotten: HashMap::new(),
+                    meets: Vec::new(),
+                }
+            }
+        };
+        {
+            let myself = state.nodes.get_mut(&state.myself).unwrap();
+            myself.port = port;
+            myself.cport = port + BUS_PORT_OFFSET;
+            if !announce_ip.is_empty() {
+                myself.ip = announce_ip.to_string();
+            }
+        }
+        let cluster = Cluster {
+            path: path.to_string(),
+            node_timeout,
+            state: RwLock::new(state),
+            saving: Mutex::new(()),
+        };
+        cluster.save()?;
+        Ok(cluster)
+    }
+
+    pub fn state(&self) -> RwLockReadGuard<'_, State> {
+        self.state.read().unwrap()
+    }
+
+    pub fn myself(&self) -> String {
+        self.state().myself.clone()
+    }
+
+    pub fn cport(&self) -> usize {
+        let state = self.state();
+        state.nodes[&state.myself].cport
+    }
+
+    // The error a command on keys has to be answered with instead of
+    // running here, if they don't all belong to one slot or that slot is
+    // one this node doesn't serve. Keys of a slot being migrated away that
+    // exists doesn't tell are asked for at the slot's new node, and asking
+    // lets keys of a slot being imported in.
+    pub fn redirect<F>(&self, keys: &[&Vec<u8>], asking: bool, exists: F) -> Option<Vec<u8>>
+    where
+        F: Fn(&[u8]) -> bool,
+    {
+        let slot = match keys.first() {
+            Some(key) => key_slot(key),
+            None => return None,
+        };
+        if keys.iter().any(|key| key_slot(key) != slot) {
+            return Some(CROSSSLOT_ERROR.to_vec());
+        }
+        let state = self.state();
+        match state.slots[slot] {
+            Some(ref id) if *id == state.myself => {}
+            _ if asking && state.importing.contains_key(&slot) => return None,
+            Some(ref id) => {
+                return match state.nodes.get(id) {
+                    Some(node) if node.fail => Some(FAIL_ERROR.to_vec()),
+                    node => {
+                        let addr = node.map_or(String::new(), |n| n.addr());
+                        Some(format!("-MOVED {} {}\r\n", slot, addr).into_bytes())
+                    }
+                }
+            }
+            None => return Some(DOWN_ERROR.to_vec()),
+        }
+        let to = state.migrating.get(&slot)?;
+        match keys.iter().filter(|key| !exists(key)).count() {
+            0 => None,
+            missing if missing < keys.len() => Some(TRYAGAIN_ERROR.to_vec()),
+            _ => {
+                let addr = state.nodes.get(to).map_or(String::new(), |n| n.addr());
+                Some(format!("-ASK {} {}\r\n", slot, addr).into_bytes())
+            }
+        }
+    }
+
+    // Has this node serve the slots, none of which may be served yet.
+    pub fn add_slots(&self, slots: &[usize]) -> Result<(), String> {
+        {
+            let mut state = self.state.write().unwrap();
+            let mut seen = vec![false; SLOTS];
+            for &slot in slots {
+                if state.slots[slot].is_some() {
+                    return Err(format!("Slot {} is already busy", slot));
+                }
+                if seen[slot] {
+                    return Err(format!("Slot {} specified multiple times", slot));
+                }
+                seen[slot] = true;
+            }
+            let myself = state.myself.clone();
+            for &slot in slots {
+                state.slots[slot] = Some(myself.clone());
+            }
+        }
+        self.save()
+    }
+
+    // Leaves the slots unserved. Each has to be served by some node.
+    pub fn del_slots(&self, slots: &[usize]) -> Result<(), String> {
+        {
+            let mut state = self.state.write().unwrap();
+            let mut seen = vec![false; SLOTS];
+            for &slot in slots {
+                if state.slots[slot].is_none() {
+                    return Err(format!("Slot {} is already unassigned", slot));
+                }
+                if seen[slot] {
+                    return Err(format!("Slot {} specified multiple times", slot));
+                }
+                seen[slot] = true;
+            }
+            for &slot in slots {
+                state.slots[slot] = None;
+            }
+        }
+        self.save()
+    }
+
+    // Stops serving every slot this node serves.
+    pub fn flush_slots(&self) -> Result<(), String> {
+        {
+            let mut state = self.state.write().unwrap();
+            let myself = state.myself.clone();
+            for owner in state.slots.iter_mut() {
+                if owner.as_ref() == Some(&myself) {
+                    *owner = None;
+                }
+            }
+        }
+        self.save()
+    }
+
+    // CLUSTER SETSLOT MIGRATING: starts moving a slot this node serves to
+    // another node.
+    pub fn migrate_slot(&self, slot: usize, to: &str) -> Result<(), String> {
+        {
+            let mut state = self.state.write().unwrap();
+            if state.slots[slot].as_ref() != Some(&state.myself) {
+                return Err(format!("I'm not the owner of hash slot {}", slot));
+            }
+            if !state.nodes.contains_key(to) {
+                return Err(format!("I don't know about node {}", to));
+            }
+            state.migrating.insert(slot, to.to_string());
+        }
+        self.save()
+    }
+
+    // CLUSTER SETSLOT IMPORTING: starts taking a slot from the node
+    // serving it.
+    pub fn import_slot(&self, slot: usize, from: &str) -> Result<(), String> {
+        {
+            let mut state = self.state.write().unwrap();
+            if state.slots[slot].as_ref() == Some(&state.myself) {
+                return Err(format!("I'm already the owner of hash slot {}", slot));
+            }
+            if !state.nodes.contains_key(from) {
+                return Err(format!("I don't know about node {}", from));
+            }
+            state.importing.insert(slot, from.to_string());
+        }
+        self.save()
+    }
+
+    // CLUSTER SETSLOT STABLE: gives up moving a slot either way.
+    pub fn stabilize_slot(&self, slot: usize) -> Result<(), String> {
+        {
+            let mut state = self.state.write().unwrap();
+            state.migrating.remove(&slot);
+            state.importing.remove(&slot);
+        }
+        self.save()
+    }
+
+    // CLUSTER SETSLOT NODE: hands a slot to a node, ending its migration.
+    // A node still holding keys of the slot can't give it away. A node
+    // taking a slot it was importing moves to a fresh config epoch so its
+    // claim prevails over the old owner's.
+    pub fn assign_slot(&self, slot: usize, to: &str, holds_keys: bool) -> Result<(), String> {
+        {
+            let mut state = self.state.write().unwrap();
+            if !state.nodes.contains_key(to) {
+                return Err(format!("I don't know about node {}", to));
+            }
+            let myself = state.myself.clone();
+            if state.slots[slot].as_ref() == Some(&myself) && to != myself && holds_keys {
+                return Err(format!(
+                    "Can't assign hashslot {} to a different node while I still hold keys for this hash slot.",
+                    slot
+                ));
+            }
+            if to != myself {
+                state.migrating.remove(&slot);
+            }
+            if to == myself && state.importing.remove(&slot).is_some() {
+                bump(&mut state);
+            }
+            state.slots[slot] = Some(to.to_string());
+        }
+        self.save()
+    }
+
+    // Queues a handshake with the node at ip and bus port cport, which
+    // joins once it answers.
+    pub fn meet(&self, ip: &str, cport: usize) {
+        self.state.write().unwrap().meets.push((ip.to_string(), cport));
+    }
+
+    pub fn take_meets(&self) -> Vec<(String, usize)> {
+        let mut state = self.state.write().unwrap();
+        let meets = &mut state.meets;
+        std::mem::take(meets)
+    }
+
+    // Drops a node, keeping gossip from adding it back for a minute.
+    pub fn forget(&self, id: &str) -> Result<(), String> {
+        {
+            let mut state = self.state.write().unwrap();
+            if id == state.myself {
+                return Err("I tried hard but I can't forget myself...".to_string());
+            }
+            if state.nodes.remove(id).is_none() {
+                return Err(format!("Unknown node {}", id));
+            }
+            for owner in state.slots.iter_mut() {
+                if owner.as_ref().map(|o| o.as_str()) == Some(id) {
+                    *owner = None;
+                }
+            }
+            for node in state.nodes.values_mut() {
+                node.reports.remove(id);
+            }
+            state.migrating.retain(|_, to| to != id);
+            state.importing.retain(|_, from| from != id);
+            state.forgotten.insert(id.to_string(), now() + FORGET_TTL);
+        }
+        self.save()
+    }
+
+    pub fn failure_reports(&self, id: &str) -> Option<usize> {
+        self.state().nodes.get(id).map(|node| node.reports.len())
+    }
+
+    // Sets this node's config epoch, which only a node that knows no other
+    // and has none yet may do.
+    pub fn set_config_epoch(&self, epoch: u64) -> Result<(), String> {
+        {
+            let mut state = self.state.write().unwrap();
+            if state.nodes.len() > 1 {
+                return Err(
+                    "The user can assign a config epoch only when the node does not know any other node."
+                        .to_string(),
+                );
+            }
+            let myself = state.myself.clone();
+            let node = state.nodes.get_mut(&myself).unwrap();
+            if node.config_epoch != 0 {
+                return Err("Node config epoch is already non-zero".to_string());
+            }
+            node.config_epoch = epoch;
+            state.current_epoch = state.current_epoch.max(epoch);
+        }
+        self.save()
+    }
+
+    // Moves this node to a fresh config epoch unless it already has the
+    // greatest one. True if it moved, along with the epoch it has.
+    pub fn bump_epoch(&self) -> Result<(bool, u64), String> {
+        let (bumped, epoch) = bump(&mut self.state.write().unwrap());
+        if bumped {
+            self.save()?;
+        }
+        Ok((bumped, epoch))
+    }
+
+    // The other nodes, which the bus keeps links to.
+    pub fn peers(&self) -> Vec<String> {
+        let state = self.state();
+        state.nodes.keys().filter(|id| **id != state.myself).cloned().collect()
+    }
+
+    // The ip and bus port of a known node whose ip is known.
+    pub fn bus_addr(&self, id: &str) -> Option<(String, usize)> {
+        match self.state().nodes.get(id) {
+            Some(node) if !node.ip.is_empty() => Some((node.ip.clone(), node.cport)),
+            _ => None,
+        }
+    }
+
+    pub fn knows(&self, id: &str) -> bool {
+        self.state().nodes.contains_key(id)
+    }
+
+    pub fn node_timeout(&self) -> u64 {
+        self.node_timeout
+    }
+
+    // This node's view of itself and of every other node, to send.
+    pub fn message(&self, kind: &str) -> Message {
+        let state = self.state();
+        let myself = &state.nodes[&state.myself];
+        let slots = state
+            .ranges()
+            .into_iter()
+            .filter(|r| r.2 == state.myself)
+            .map(|r| (r.0, r.1))
+            .collect();
+        let gossip = state
+            .nodes
+            .iter()
+            .filter(|&(id, _)| *id != state.myself)
+            .map(|(id, node)| Gossip {
+                id: id.clone(),
+                ip: node.ip.clone(),
+                port: node.port,
+                cport: node.cport,
+                failing: node.failing(),
+            })
+            .collect();
+        Message {
+            kind: kind.to_string(),
+            sender: state.myself.clone(),
+            ip: myself.ip.clone(),
+            port: myself.port,
+            cport: myself.cport,
+            current_epoch: state.current_epoch,
+            config_epoch: myself.config_epoch,
+            slots,
+            gossip,
+        }
+    }
+
+    // Takes in a message from the node at peer_ip, received on a link
+    // whose local end is local_ip. Senders this node doesn't know are
+    // ignored, unless the two are meeting.
+    pub fn receive(&self, msg: &Message, peer_ip: &str, local_ip: &str, meeting: bool) {
+        let changed = {
+            let mut state = self.state.write().unwrap();
+            update(&mut state, msg, peer_ip, local_ip, meeting)
+        };
+        if changed {
+            let _ = self.save();
+        }
+    }
+
+    pub fn pinged(&self, id: &str) {
+        if let Some(node) = self.state.write().unwrap().nodes.get_mut(id) {
+            if node.ping_sent == 0 {
+                node.ping_sent = now();
+            }
+        }
+    }
+
+    // A node that answers is no longer failing.
+    pub fn ponged(&self, id: &str) {
+        let changed = {
+            let mut state = self.state.write().unwrap();
+            match state.nodes.get_mut(id) {
+                Some(node) => {
+                    node.ping_sent = 0;
+                    node.pong_received = now();
+                    node.connected = true;
+                    node.pfail = false;
+                    let failed = node.fail;
+                    node.fail = false;
+                    failed
+                }
+                None => false,
+            }
+        };
+        if changed {
+            let _ = self.save();
+        }
+    }
+
+    pub fn disconnected(&self, id: &str) {
+        if let Some(node) = self.state.write().unwrap().nodes.get_mut(id) {
+            node.connected = false;
+        }
+    }
+
+    // Marks a node failed as another node found a majority agreeing.
+    pub fn failed(&self, id: &str) {
+        let changed = {
+            let mut state = self.state.write().unwrap();
+            let myself = state.myself.clone();
+            match state.nodes.get_mut(id) {
+                Some(node) if id != myself && !node.fail => {
+                    node.fail = true;
+                    true
+                }
+                _ => false,
+            }
+        };
+        if changed {
+            let _ = self.save();
+        }
+    }
+
+    // Flags nodes whose ping has gone unanswered for longer than
+    // cluster-node-timeout as possibly failing, and marks failed those a
+    // majority of the slot-serving nodes reported so, this one included.
+    // Returns the ids of the nodes it marked failed, for the bus to tell
+    // the others.
+    pub fn check_failures(&self) -> Vec<String> {
+        let now = now();
+        let mut failed = Vec::new();
+        {
+            let mut state = self.state.write().unwrap();
+            let quorum = state.size() / 2 + 1;
+            let myself = state.myself.clone();
+            let timeout = self.node_timeout;
+            for (id, node) in state.nodes.iter_mut() {
+                if *id == myself {
+                    continue;
+                }
+                if node.ping_sent != 0 && now.saturating_sub(node.ping_sent) > timeout {
+                    node.pfail = true;
+                }
+                node.reports.retain(|_, at| now.saturating_sub(*at) <= timeout * 2);
+                if node.pfail && !node.fail && node.reports.len() + 1 >= quorum {
+                    node.fail = true;
+                    failed.push(id.clone());
+                }
+            }
+        }
+        if !failed.is_empty() {
+            let _ = self.save();
+        }
+        failed
+    }
+
+    // Writes the config file through a temporary file renamed over it, so
+    // a crash never leaves a truncated one behind.
+    pub fn save(&self) -> Result<(), String> {
+        let _span = tracing::info_span!("cluster_config_save", path = %self.path).entered();
+        let _saving = self.saving.lock().unwrap();
+        let text = {
+            let state = self.state();
+            let mut text = state.describe();
+            text.push_str(&format!("vars currentEpoch {} lastVoteEpoch 0\n", state.current_epoch));
+            text
+        };
+        let tmp = format!("{}.tmp", self.path);
+        let written = fs::File::create(&tmp)
+            .and_then(|mut file| {
+                file.write_all(text.as_bytes())?;
+                file.sync_all()
+            })
+            .and_then(|_| fs::rename(&tmp, &self.path));
+        match written {
+            Ok(()) => tracing::debug!("cluster config saved"),
+            Err(ref e) => tracing::warn!(error = %e, "cluster config not saved"),
+        }
+        written.map_err(|e| format!("error saving the cluster node config: {}", e))
+    }
+}
+
+// Moves this node to a fresh config epoch unless it already has the
+// greatest one.
+fn bump(state: &mut State) -> (bool, u64) {
+    let greatest = state.nodes.values().map(|n| n.config_epoch).max().unwrap_or(0);
+    let myself = state.myself.clone();
+    let current = state.nodes[&myself].config_epoch;
+    if current != 0 && current == greatest {
+        return (false, current);
+    }
+    state.current_epoch += 1;
+    let epoch = state.current_epoch;
+    state.nodes.get_mut(&myself).unwrap().config_epoch = epoch;
+    (true, epoch)
+}
+
+// Applies a message to the state. True if anything the config file
+// records changed.
+fn update(state: &mut State, msg: &Message, peer_ip: &str, local_ip: &str, meeting: bool) -> bool {
+    let now = now();
+    let mut changed = false;
+    let myself = state.myself.clone();
+    if msg.sender == myself {
+        return false;
+    }
+    if state.nodes[&myself].ip.is_empty() && !local_ip.is_empty() {
+        state.nodes.get_mut(&myself).unwrap().ip = local_ip.to_string();
+        changed = true;
+    }
+    if !state.nodes.contains_key(&msg.sender) {
+        if !meeting {
+            return changed;
+        }
+        state.forgotten.remove(&msg.sender);
+        state.nodes.insert(msg.sender.clone(), Node::new("", 0, 0));
+    }
+    if msg.current_epoch > state.current_epoch {
+        state.current_epoch = msg.current_epoch;
+        changed = true;
+    }
+    {
+        let ip = if !msg.ip.is_empty() { &msg.ip } else { peer_ip };
+        let node = state.nodes.get_mut(&msg.sender).unwrap();
+        if node.ip != ip || node.port != msg.port || node.cport != msg.cport
+            || node.config_epoch != msg.config_epoch
+        {
+            node.ip = ip.to_string();
+            node.port = msg.port;
+            node.cport = msg.cport;
+            node.config_epoch = msg.config_epoch;
+            changed = true;
+        }
+    }
+
+    // The sender is the authority on the slots it serves, unless
+    // another node claims them under a greater config epoch. Slots being
+    // imported change hands through SETSLOT NODE instead.
+    let mut claimed = vec![false; SLOTS];
+    for &(first, last) in &msg.slots {
+        for claim in &mut claimed[first..=last] {
+            *claim = true;
+        }
+    }
+    for (slot, &claimed) in claimed.iter().enumerate() {
+        let sender = Some(&msg.sender);
+        if state.importing.contains_key(&slot) {
+            continue;
+        }
+        if claimed {
+            let wins = match state.slots[slot] {
+                Some(ref owner) if Some(owner) == sender => false,
+                Some(ref owner) => {
+                    state.nodes.get(owner).map_or(0, |n| n.config_epoch) < msg.config_epoch
+                }
+                None => true,
+            };
+            if wins {
+                state.slots[slot] = Some(msg.sender.clone());
+                changed = true;
+            }
+        } else if state.slots[slot].as_ref() == sender {
+            state.slots[slot] = None;
+            changed = true;
+        }
+    }
+
+    // Of two nodes in the same config epoch, the one with the smaller
+    // id moves on to a new one.
+    if msg.config_epoch == state.nodes[&myself].config_epoch && myself < msg.sender {
+        state.current_epoch += 1;
+        let epoch = state.current_epoch;
+        state.nodes.get_mut(&myself).unwrap().config_epoch = epoch;
+        changed = true;
+    }
+
+    state.forgotten.retain(|_, until| *until > now);
+    let reporter = state.serves(&msg.sender);
+    for gossip in &msg.gossip {
+        if gossip.id == myself {
+            continue;
+        }
+        if let Some(node) = state.nodes.get_mut(&gossip.id) {
+            if gossip.failing && reporter {
+                node.reports.insert(msg.sender.clone(), now);
+            } else {
+                node.reports.remove(&msg.sender);
+            }
+            continue;
+        }
+        if !gossip.failing && !gossip.ip.is_empty() && !state.forgotten.contains_key(&gossip.id) {
+            let node = Node::new(&gossip.ip, gossip.port, gossip.cport);
+            state.nodes.insert(gossip.id.clone(), node);
+            changed = true;
+        }
+    }
+    changed
+}
+
+// Reads back a config file written by save().
+fn parse(text: &str) -> Result<State, String> {
+    let mut myself = None;
+    let mut current_epoch = 0;
+    let mut nodes = BTreeMap::new();
+    let mut slots = vec![None; SLOTS];
+    let mut migrating = BTreeMap::new();
+    let mut importing = BTreeMap::new();
+    for line in text.lines() {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        if words.is_empty() {
+            continue;
+        }
+        if words[0] == "vars" {
+            for pair in words[1..].chunks(2) {
+                if pair.len() == 2 && pair[0] == "currentEpoch" {
+                    current_epoch = pair[1].parse().map_err(|_| format!("invalid line '{}'", line))?;
+                }
+            }
+            continue;
+        }
+        if words.len() < 8 {
+            return Err(format!("invalid line '{}'", line));
+        }
+        let id = words[0].to_string();
+        let mut node = parse_addr(words[1]).ok_or_else(|| format!("invalid address '{}'", words[1]))?;
+        for flag in words[2].split(',') {
+            match flag {
+                "myself" => myself = Some(id.clone()),
+                "fail" => node.fail = true,
+                _ => {}
+            }
+        }
+        node.config_epoch = words[6].parse().map_err(|_| format!("invalid epoch '{}'", words[6]))?;
+        for range in &words[8..] {
+            if range.starts_with('[') {
+                let invalid = || format!("invalid slot '{}'", range);
+                let inner = range.trim_start_matches('[').trim_end_matches(']');
+                if let Some(arrow) = inner.find("->-") {
+                    let slot = parse_slot(&inner[..arrow]).ok_or_else(invalid)?;
+                    migrating.insert(slot, inner[arrow + 3..].to_string());
+                } else if let Some(arrow) = inner.find("-<-") {
+                    let slot = parse_slot(&inner[..arrow]).ok_or_else(invalid)?;
+                    importing.insert(slot, inner[arrow + 3..].to_string());
+                } else {
+                    return Err(invalid());
+                }
+                continue;
+            }
+            let (first, last) = parse_range(range).ok_or_else(|| format!("invalid slot '{}'", range))?;
+            for slot in &mut slots[first..=last] {
+                *slot = Some(id.clone());
+            }
+        }
+        nodes.insert(id, node);
+    }
+    match myself {
+        Some(myself) => Ok(State {
+            myself,
+            current_epoch,
+            nodes,
+            slots,
+            migrating,
+            importing,
+            forgotten: HashMap::new(),
+            meets: Vec::new(),
+        }),
+        None => Err("no node flagged myself".to_string()),
+    }
+}
+
+// ip:port@cport, where the ip may be empty.
+fn parse_addr(addr: &str) -> Option<Node> {
+    let at = addr.rfind('@')?;
+    let colon = addr[..at].rfind(':')?;
+    Some(Node::new(
+        &addr[..colon],
+        addr[colon + 1..at].parse().ok()?,
+        addr[at + 1..].parse().ok()?,
+    ))
+}
+
+// A slot or a first-last range of them.
+pub fn parse_range(range: &str) -> Option<(usize, usize)> {
+    let (first, last) = match range.find('-') {
+        Some(dash) => (range[..dash].parse().ok()?, range[dash + 1..].parse().ok()?),
+        None => {
+            let slot = range.parse().ok()?;
+            (slot, slot)
+        }
+    };
+    if first > last || last >= SLOTS {
+        return None;
+    }
+    Some((first, last))
+}
+
+fn parse_slot(slot: &str) -> Option<usize> {
+    slot.parse().ok().filter(|&slot| slot < SLOTS)
+}
+
+// The hash slot of a key. A key with a hash tag, a non-empty part between
+// its first { and the } after it, is hashed by the tag alone, so keys
+// sharing a tag share a slot.
+pub fn key_slot(key: &[u8]) -> usize {
+    crc16(hash_tag(key)) as usize & (SLOTS - 1)
+}
+
+fn hash_tag(key: &[u8]) -> &[u8] {
+    if let Some(open) = key.iter().position(|&b| b == b'{') {
+        if let Some(len) = key[open + 1..].iter().position(|&b| b == b'}') {
+            if len > 0 {
+                return &key[open + 1..open + 1 + len];
+            }
+        }
+    }
+    key
+}
+
+// CRC16-CCITT (XMODEM): polynomial 0x1021, no reflection, zero initial
+// value, the variant cluster clients compute slots with.
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+    for &b in data {
+        crc ^= (b as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+// 40 random hex characters, falling back to a hash of the clock and pid
+// where /dev/urandom can't be read.
+pub fn random_id() -> String {
+    let mut bytes = [0u8; 20];
+    let read = fs::File::open("/dev/urandom").and_then(|mut f| f.read_exact(&mut bytes));
+    if read.is_err() {
+        let seed = format!("{:?} {}", SystemTime::now(), ::std::process::id());
+        bytes = sha1_smol::Sha1::from(seed).digest().bytes();
+    }
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc16_check_value() {
+        assert_eq!(crc16(b"123456789"), 0x31c3);
+        assert_eq!(crc16(b""), 0);
+    }
+
+    #[test]
+    fn slots_match_redis() {
+        assert_eq!(key_slot(b"123456789"), 0x31c3);
+        assert_eq!(key_slot(b"foo"), 12182);
+        assert_eq!(key_slot(b""), 0);
+        assert!(key_slot(&[0xff; 64]) < SLOTS);
+    }
+
+    #[test]
+    fn hash_tags() {
+        assert_eq!(hash_tag(b"{user1000}.following"), b"user1000");
+        assert_eq!(hash_tag(b"foo{bar}{zap}"), b"bar");
+        assert_eq!(hash_tag(b"foo{{bar}}zap"), b"{bar");
+        // An empty or unclosed tag hashes the whole key.
+        assert_eq!(hash_tag(b"{}"), b"{}");
+        assert_eq!(hash_tag(b"{}{x}"), b"{}{x}");
+        assert_eq!(hash_tag(b"foo{}{bar}"), b"foo{}{bar}");
+        assert_eq!(hash_tag(b"foo{bar"), b"foo{bar");
+        assert_eq!(hash_tag(b"foo}bar{"), b"foo}bar{");
+
+        assert_eq!(key_slot(b"{user1000}.following"), key_slot(b"{user1000}.followers"));
+        assert_eq!(key_slot(b"{user1000}.following"), key_slot(b"user1000"));
+        assert_eq!(key_slot(b"foo{bar}{zap}"), key_slot(b"bar"));
+        assert_ne!(key_slot(b"{}{x}"), key_slot(b"x"));
+    }
+}
@@ -0,0 +1,196 @@
+// Per command and per error statistics.
+//
+// Every command run for a client is counted under its name: calls that ran,
+// how long they took in total and at most, how many of those replied with
+// an error, and how many were refused before running (ACL, OOM, cluster
+// redirections, a busy script). With latency-tracking on, run times also go
+// into a histogram per command for percentiles. Error replies are counted
+// by their code, the first word after the '-'. INFO commandstats,
+// errorstats and latencystats report them, CONFIG RESETSTAT clears them.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use histogram::Histogram;
+
+// Distinct error codes tracked at most, so a client can't grow the table
+// without bound with errors of its own making. Later codes count in the
+// total only.
+const MAX_ERROR_CODES: usize = 128;
+
+struct Counters {
+    calls: u64,
+    usec: u64,
+    usec_max: u64,
+    failed: u64,
+    rejected: u64,
+    // Nanoseconds per call, when latency-tracking is on.
+    latency: Histogram,
+}
+
+impl Counters {
+    fn new() -> Counters {
+        Counters {
+            calls: 0,
+            usec: 0,
+            usec_max: 0,
+            failed: 0,
+            rejected: 0,
+            latency: Histogram::new(),
+        }
+    }
+}
+
+// A command's name, its call count and its latency histogram buckets.
+pub type Latencies = (&'static str, u64, Vec<(u64, u64)>);
+
+pub struct CommandStats {
+    commands: Mutex<HashMap<&'static str, Counters>>,
+    errors: Mutex<HashMap<String, u64>>,
+    total_errors: AtomicUsize,
+    tracking: AtomicBool,
+}
+
+impl CommandStats {
+    pub fn new(tracking: bool) -> CommandStats {
+        CommandStats {
+            commands: Mutex::new(HashMap::new()),
+            errors: Mutex::new(HashMap::new()),
+            total_errors: AtomicUsize::new(0),
+            tracking: AtomicBool::new(tracking),
+        }
+    }
+
+    pub fn set_tracking(&self, tracking: bool) {
+        self.tracking.store(tracking, Ordering::Relaxed);
+    }
+
+    // Records a command that ran, or was rejected before it could. name is
+    // None for unknown commands, which only count towards errors.
+    pub fn record(&self, name: Option<&'static str>, elapsed: Duration, rejected: bool, reply: &[u8]) {
+        let error = error_code(reply);
+        if let Some(name) = name {
+            let mut commands = self.commands.lock().unwrap();
+            let counters = commands.entry(name).or_insert_with(Counters::new);
+            if rejected {
+                counters.rejected += 1;
+            } else {
+                let usec = elapsed.as_secs() * 1_000_000 + elapsed.subsec_micros() as u64;
+                counters.calls += 1;
+                counters.usec += usec;
+                counters.usec_max = counters.usec_max.max(usec);
+                if error.is_some() {
+                    counters.failed += 1;
+                }
+                if self.tracking.load(Ordering::Relaxed) {
+                    counters.latency.record(elapsed.as_secs() * 1_000_000_000 + elapsed.subsec_nanos() as u64);
+                }
+            }
+        }
+        if let Some(code) = error {
+            self.total_errors.fetch_add(1, Ordering::Relaxed);
+            let mut errors = self.errors.lock().unwrap();
+            if errors.len() < MAX_ERROR_CODES || errors.contains_key(code) {
+                *errors.entry(code.to_string()).or_insert(0) += 1;
+            }
+        }
+    }
+
+    pub fn total_errors(&self) -> usize {
+        self.total_errors.load(Ordering::Relaxed)
+    }
+
+    pub fn reset(&self) {
+        self.commands.lock().unwrap().clear();
+        self.errors.lock().unwrap().clear();
+        self.total_errors.store(0, Ordering::Relaxed);
+    }
+
+    // The INFO commandstats section, one line per command seen.
+    pub fn commandstats(&self) -> String {
+        let commands = self.commands.lock().unwrap();
+        let mut names: Vec<&&'static str> = commands.keys().collect();
+        names.sort();
+        let mut out = String::from("# Commandstats\r\n");
+        for name in names {
+            let c = &commands[*name];
+            let per_call = if c.calls == 0 { 0.0 } else { c.usec as f64 / c.calls as f64 };
+            out.push_str(&format!(
+                "cmdstat_{}:calls={},usec={},usec_per_call={:.2},usec_max={},rejected_calls={},failed_calls={}\r\n",
+                name, c.calls, c.usec, per_call, c.usec_max, c.rejected, c.failed
+            ));
+        }
+        out
+    }
+
+    // The given percentiles of each command's run time in microseconds,
+    // for the commands with any recorded, by name.
+    pub fn percentiles(&self, percentiles: &[f64]) -> Vec<(&'static str, Vec<f64>)> {
+        let commands = self.commands.lock().unwrap();
+        let mut out: Vec<(&'static str, Vec<f64>)> = commands
+            .iter()
+            .filter(|&(_, c)| c.latency.total() > 0)
+            .map(|(name, c)| {
+                let values = percentiles.iter().map(|&q| c.latency.percentile(q) as f64 / 1000.0).collect();
+                (*name, values)
+            })
+            .collect();
+        out.sort_by(|a, b| a.0.cmp(b.0));
+        out
+    }
+
+    // The INFO latencystats section.
+    pub fn latencystats(&self, percentiles: &[f64]) -> String {
+        let mut out = String::from("# Latencystats\r\n");
+        for (name, values) in self.percentiles(percentiles) {
+            let fields: Vec<String> = percentiles
+                .iter()
+                .zip(values.iter())
+                .map(|(q, v)| format!("p{}={:.3}", q, v))
+                .collect();
+            out.push_str(&format!("latency_percentiles_usec_{}:{}\r\n", name, fields.join(",")));
+        }
+        out
+    }
+
+    // For LATENCY HISTOGRAM: the named commands, or all of them when none
+    // are, with their calls and cumulative counts at power of two bounds in
+    // microseconds.
+    pub fn histograms(&self, names: &[&'static str]) -> Vec<Latencies> {
+        let commands = self.commands.lock().unwrap();
+        let mut out: Vec<Latencies> = commands
+            .iter()
+            .filter(|&(name, c)| c.latency.total() > 0 && (names.is_empty() || names.contains(name)))
+            .map(|(name, c)| (*name, c.latency.total(), c.latency.cumulative(1000)))
+            .collect();
+        out.sort_by(|a, b| a.0.cmp(b.0));
+        out
+    }
+
+    // The INFO errorstats section, one line per error code seen.
+    pub fn errorstats(&self) -> String {
+        let errors = self.errors.lock().unwrap();
+        let mut codes: Vec<&String> = errors.keys().collect();
+        codes.sort();
+        let mut out = String::from("# Errorstats\r\n");
+        for code in codes {
+            out.push_str(&format!("errorstat_{}:count={}\r\n", code, errors[code]));
+        }
+        out
+    }
+}
+
+// The code of an error reply: the word after the '-', up to a space or the
+// end of the line.
+fn error_code(reply: &[u8]) -> Option<&str> {
+    if reply.first() != Some(&b'-') {
+        return None;
+    }
+    let end = reply
+        .iter()
+        .position(|&b| b == b' ' || b == b'\r' || b == b'\n')
+        .unwrap_or(reply.len());
+    Some(::std::str::from_utf8(&reply[1..end]).unwrap_or("ERR"))
+}
@@ -0,0 +1,600 @@
+// Static command table used by COMMAND introspection, followed by the
+// commands registered at runtime.
+//
+// Arity follows the Redis convention: a positive value is the exact argument
+// count including the command name, a negative value is the minimum. Key
+// positions are (first, last, step) with a negative last counting from the
+// end; commands flagged movablekeys compute their keys from the arguments.
+
+use std::sync::RwLock;
+
+pub struct CommandSpec {
+    pub name: &'static str,
+    pub arity: i64,
+    pub flags: &'static [&'static str],
+    pub first_key: i64,
+    pub last_key: i64,
+    pub step: i64,
+    pub categories: &'static [&'static str],
+    pub group: &'static str,
+    pub summary: &'static str,
+}
+
+pub const COMMANDS: &[CommandSpec] = &[
+    CommandSpec {
+        name: "acl",
+        arity: -2,
+        flags: &["admin", "noscript", "loading", "stale"],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        categories: &["@admin", "@slow", "@dangerous"],
+        group: "server",
+        summary: "Manages users and their permissions.",
+    },
+    CommandSpec {
+        name: "asking",
+        arity: 1,
+        flags: &["fast"],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        categories: &["@fast", "@connection"],
+        group: "cluster",
+        summary: "Lets the next command into a hash slot being imported.",
+    },
+    CommandSpec {
+        name: "auth",
+        arity: -2,
+        flags: &["noscript", "loading", "stale", "fast", "no-auth"],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        categories: &["@fast", "@connection"],
+        group: "connection",
+        summary: "Authenticates the connection.",
+    },
+    CommandSpec {
+        name: "client",
+        arity: -2,
+        flags: &["noscript", "loading", "stale"],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        categories: &["@slow", "@connection"],
+        group: "connection",
+        summary: "Inspects and manages client connections.",
+    },
+    CommandSpec {
+        name: "cluster",
+        arity: -2,
+        flags: &["readonly"],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        categories: &["@slow"],
+        group: "cluster",
+        summary: "Inspects and changes the cluster's hash slot assignments.",
+    },
+    CommandSpec {
+        name: "command",
+        arity: -1,
+        flags: &["loading", "stale"],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        categories: &["@slow", "@connection"],
+        group: "server",
+        summary: "Returns detailed information about all commands.",
+    },
+    CommandSpec {
+        name: "config",
+        arity: -2,
+        flags: &["admin", "noscript", "loading", "stale"],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        categories: &["@admin", "@slow", "@dangerous"],
+        group: "server",
+        summary: "Gets or sets configuration parameters at runtime.",
+    },
+    CommandSpec {
+        name: "dbsize",
+        arity: 1,
+        flags: &["readonly", "fast"],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        categories: &["@keyspace", "@read", "@fast"],
+        group: "server",
+        summary: "Returns the number of keys in the database.",
+    },
+    CommandSpec {
+        name: "debug",
+        arity: -2,
+        flags: &["admin", "noscript", "loading", "stale"],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        categories: &["@admin", "@slow", "@dangerous"],
+        group: "server",
+        summary: "Low level debugging and testing hooks.",
+    },
+    CommandSpec {
+        name: "del",
+        arity: 2,
+        flags: &["write"],
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+        categories: &["@keyspace", "@write", "@slow"],
+        group: "generic",
+        summary: "Deletes a key.",
+    },
+    CommandSpec {
+        name: "discard",
+        arity: 1,
+        flags: &["noscript", "loading", "stale", "fast", "allow_busy"],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        categories: &["@fast", "@transaction"],
+        group: "transactions",
+        summary: "Discards a transaction.",
+    },
+    CommandSpec {
+        name: "eval",
+        arity: -3,
+        flags: &["noscript", "stale", "skip_monitor", "may-replicate", "movablekeys"],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        categories: &["@slow", "@scripting"],
+        group: "scripting",
+        summary: "Executes a server-side Lua script.",
+    },
+    CommandSpec {
+        name: "evalsha",
+        arity: -3,
+        flags: &["noscript", "stale", "skip_monitor", "may-replicate", "movablekeys"],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        categories: &["@slow", "@scripting"],
+        group: "scripting",
+        summary: "Executes a server-side Lua script by SHA1 digest.",
+    },
+    CommandSpec {
+        name: "exec",
+        arity: 1,
+        flags: &["noscript", "loading", "stale", "skip_slowlog"],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        categories: &["@slow", "@transaction"],
+        group: "transactions",
+        summary: "Executes all commands in a transaction.",
+    },
+    CommandSpec {
+        name: "fcall",
+        arity: -3,
+        flags: &["noscript", "stale", "skip_monitor", "may-replicate", "movablekeys"],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        categories: &["@slow", "@scripting"],
+        group: "scripting",
+        summary: "Invokes a function.",
+    },
+    CommandSpec {
+        name: "fcall_ro",
+        arity: -3,
+        flags: &["noscript", "stale", "skip_monitor", "movablekeys"],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        categories: &["@slow", "@scripting"],
+        group: "scripting",
+        summary: "Invokes a read-only function.",
+    },
+    CommandSpec {
+        name: "flushall",
+        arity: -1,
+        flags: &["write"],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        categories: &["@keyspace", "@write", "@slow", "@dangerous"],
+        group: "server",
+        summary: "Removes all keys from all databases.",
+    },
+    CommandSpec {
+        name: "flushdb",
+        arity: -1,
+        flags: &["write"],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        categories: &["@keyspace", "@write", "@slow", "@dangerous"],
+        group: "server",
+        summary: "Removes all keys from the database.",
+    },
+    CommandSpec {
+        name: "function",
+        arity: -2,
+        flags: &["noscript", "may-replicate"],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        categories: &["@slow", "@scripting"],
+        group: "scripting",
+        summary: "Loads, lists and deletes function libraries.",
+    },
+    CommandSpec {
+        name: "get",
+        arity: 2,
+        flags: &["readonly", "fast"],
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+        categories: &["@read", "@string", "@fast"],
+        group: "string",
+        summary: "Returns the string value of a key.",
+    },
+    CommandSpec {
+        name: "hello",
+        arity: -1,
+        flags: &["noscript", "loading", "stale", "fast", "no-auth"],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        categories: &["@fast", "@connection"],
+        group: "connection",
+        summary: "Handshakes with the server, optionally switching protocol version.",
+    },
+    CommandSpec {
+        name: "hotkeys",
+        arity: -1,
+        flags: &["admin", "noscript", "loading", "stale"],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        categories: &["@admin", "@slow"],
+        group: "server",
+        summary: "Reports the most accessed keys and their rates.",
+    },
+    CommandSpec {
+        name: "info",
+        arity: -1,
+        flags: &["loading", "stale"],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        categories: &["@slow", "@dangerous"],
+        group: "server",
+        summary: "Returns information and statistics about the server.",
+    },
+    CommandSpec {
+        name: "keys",
+        arity: 2,
+        flags: &["readonly"],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        categories: &["@keyspace", "@read", "@slow", "@dangerous"],
+        group: "generic",
+        summary: "Returns all key names that match a pattern.",
+    },
+    CommandSpec {
+        name: "latency",
+        arity: -2,
+        flags: &["admin", "noscript", "loading", "stale"],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        categories: &["@admin", "@slow", "@dangerous"],
+        group: "server",
+        summary: "Reports and resets latency spike samples.",
+    },
+    CommandSpec {
+        name: "memory",
+        arity: -2,
+        flags: &["readonly"],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        categories: &["@read", "@slow"],
+        group: "server",
+        summary: "Reports memory usage details.",
+    },
+    CommandSpec {
+        name: "migrate",
+        arity: -6,
+        flags: &["write"],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        categories: &["@keyspace", "@write", "@slow", "@dangerous"],
+        group: "generic",
+        summary: "Atomically transfers keys to another instance.",
+    },
+    CommandSpec {
+        name: "module",
+        arity: -2,
+        flags: &["admin", "noscript"],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        categories: &["@admin", "@slow", "@dangerous"],
+        group: "server",
+        summary: "Loads modules and lists the loaded ones.",
+    },
+    CommandSpec {
+        name: "move",
+        arity: 3,
+        flags: &["write", "fast"],
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+        categories: &["@keyspace", "@write", "@fast"],
+        group: "generic",
+        summary: "Moves a key to another database.",
+    },
+    CommandSpec {
+        name: "multi",
+        arity: 1,
+        flags: &["noscript", "loading", "stale", "fast", "allow_busy"],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        categories: &["@fast", "@transaction"],
+        group: "transactions",
+        summary: "Starts a transaction.",
+    },
+    CommandSpec {
+        name: "object",
+        arity: -2,
+        flags: &["readonly"],
+        first_key: 2,
+        last_key: 2,
+        step: 1,
+        categories: &["@keyspace", "@read", "@slow"],
+        group: "generic",
+        summary: "Inspects the internals of a key's value.",
+    },
+    CommandSpec {
+        name: "ping",
+        arity: -1,
+        flags: &["fast"],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        categories: &["@fast", "@connection"],
+        group: "connection",
+        summary: "Returns the server's liveliness response.",
+    },
+    CommandSpec {
+        name: "psubscribe",
+        arity: -2,
+        flags: &["pubsub", "noscript", "loading", "stale"],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        categories: &["@pubsub", "@slow"],
+        group: "pubsub",
+        summary: "Listens for messages published to channels that match one or more patterns.",
+    },
+    CommandSpec {
+        name: "publish",
+        arity: 3,
+        flags: &["pubsub", "loading", "stale", "fast", "may-replicate"],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        categories: &["@pubsub", "@fast"],
+        group: "pubsub",
+        summary: "Posts a message to a channel.",
+    },
+    CommandSpec {
+        name: "punsubscribe",
+        arity: -1,
+        flags: &["pubsub", "noscript", "loading", "stale"],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        categories: &["@pubsub", "@slow"],
+        group: "pubsub",
+        summary: "Stops listening to messages published to channels that match one or more patterns.",
+    },
+    CommandSpec {
+        name: "quit",
+        arity: -1,
+        flags: &["noscript", "loading", "stale", "fast", "no-auth"],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        categories: &["@fast", "@connection"],
+        group: "connection",
+        summary: "Closes the connection.",
+    },
+    CommandSpec {
+        name: "restore-asking",
+        arity: -4,
+        flags: &["write", "denyoom", "asking"],
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+        categories: &["@keyspace", "@write", "@slow", "@dangerous"],
+        group: "server",
+        summary: "Creates a key streamed by MIGRATE.",
+    },
+    CommandSpec {
+        name: "role",
+        arity: 1,
+        flags: &["noscript", "loading", "stale", "fast"],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        categories: &["@admin", "@fast", "@dangerous"],
+        group: "server",
+        summary: "Returns the replication role.",
+    },
+    CommandSpec {
+        name: "script",
+        arity: -2,
+        flags: &["noscript", "may-replicate"],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        categories: &["@slow", "@scripting"],
+        group: "scripting",
+        summary: "Manages the server-side Lua script cache.",
+    },
+    CommandSpec {
+        name: "select",
+        arity: 2,
+        flags: &["loading", "stale", "fast"],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        categories: &["@fast", "@connection"],
+        group: "connection",
+        summary: "Changes the selected database.",
+    },
+    CommandSpec {
+        name: "set",
+        arity: 3,
+        flags: &["write", "denyoom"],
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+        categories: &["@write", "@string", "@slow"],
+        group: "string",
+        summary: "Sets the string value of a key.",
+    },
+    CommandSpec {
+        name: "shutdown",
+        arity: -1,
+        flags: &["admin", "noscript", "loading", "stale"],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        categories: &["@admin", "@slow", "@dangerous"],
+        group: "server",
+        summary: "Shuts down the server.",
+    },
+    CommandSpec {
+        name: "subscribe",
+        arity: -2,
+        flags: &["pubsub", "noscript", "loading", "stale"],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        categories: &["@pubsub", "@slow"],
+        group: "pubsub",
+        summary: "Listens for messages published to channels.",
+    },
+    CommandSpec {
+        name: "swapdb",
+        arity: 3,
+        flags: &["write", "fast"],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        categories: &["@keyspace", "@write", "@fast", "@dangerous"],
+        group: "server",
+        summary: "Swaps two databases.",
+    },
+    CommandSpec {
+        name: "unlink",
+        arity: -2,
+        flags: &["write", "fast"],
+        first_key: 1,
+        last_key: -1,
+        step: 1,
+        categories: &["@keyspace", "@write", "@fast"],
+        group: "generic",
+        summary: "Deletes keys, freeing large values in the background.",
+    },
+    CommandSpec {
+        name: "unsubscribe",
+        arity: -1,
+        flags: &["pubsub", "noscript", "loading", "stale"],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        categories: &["@pubsub", "@slow"],
+        group: "pubsub",
+        summary: "Stops listening to messages posted to channels.",
+    },
+];
+
+// Specs of the commands registered at runtime. They live as long as the
+// process, as the table's do.
+static REGISTERED: RwLock<Vec<&'static CommandSpec>> = RwLock::new(Vec::new());
+
+pub fn register(spec: CommandSpec) {
+    REGISTERED.write().unwrap().push(Box::leak(Box::new(spec)));
+}
+
+pub fn lookup(name: &[u8]) -> Option<&'static CommandSpec> {
+    let name = String::from_utf8_lossy(name).to_lowercase();
+    COMMANDS
+        .iter()
+        .find(|c| c.name == name)
+        .or_else(|| REGISTERED.read().unwrap().iter().cloned().find(|c| c.name == name))
+}
+
+// Every command, the table's first.
+pub fn all() -> Vec<&'static CommandSpec> {
+    let mut all: Vec<&'static CommandSpec> = COMMANDS.iter().collect();
+    all.extend(REGISTERED.read().unwrap().iter().cloned());
+    all
+}
+
+impl CommandSpec {
+    pub fn has_flag(&self, flag: &str) -> bool {
+        self.flags.contains(&flag)
+    }
+
+    // Whether the command can change the dataset, directly or through a
+    // script, which is what CLIENT PAUSE WRITE holds back.
+    pub fn may_write(&self) -> bool {
+        self.has_flag("write") || self.has_flag("may-replicate")
+    }
+
+    pub fn arity_ok(&self, nargs: usize) -> bool {
+        if self.arity >= 0 {
+            nargs as i64 == self.arity
+        } else {
+            nargs as i64 >= -self.arity
+        }
+    }
+
+    // Extracts the key arguments of a full command invocation. Scripting
+    // commands carry their key count in the argument after the script/name.
+    pub fn keys<'a>(&self, args: &'a [Vec<u8>]) -> Vec<&'a Vec<u8>> {
+        if self.has_flag("movablekeys") {
+            let numkeys = args
+                .get(2)
+                .and_then(|n| String::from_utf8_lossy(n).parse::<usize>().ok())
+                .unwrap_or(0);
+            return args.iter().skip(3).take(numkeys).collect();
+        }
+        if self.first_key <= 0 {
+            return Vec::new();
+        }
+        let last = if self.last_key < 0 {
+            args.len() as i64 + self.last_key
+        } else {
+            self.last_key
+        };
+        let mut keys = Vec::new();
+        let mut i = self.first_key;
+        while i <= last && (i as usize) < args.len() {
+            keys.push(&args[i as usize]);
+            i += self.step;
+        }
+        keys
+    }
+}
@@ -0,0 +1,127 @@
+// Transparent value compression.
+//
+// With compression set to lz4 or zstd, SET stores values of at least
+// compression-threshold bytes compressed, unless that wouldn't make them
+// any smaller, and reads decompress them again. Every stored value carries
+// the codec it was written with, so changing the setting only affects
+// values written afterwards. OBJECT ENCODING shows the codec of compressed
+// values and INFO stats how much compression has saved.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use bytes::Bytes;
+use lz4_flex;
+use zstd;
+
+use small::Payload;
+
+pub const CODECS: &[&str] = &["no", "lz4", "zstd"];
+
+// Favours speed, as values are compressed while their shard is locked.
+const ZSTD_LEVEL: i32 = 1;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Codec {
+    Raw,
+    Lz4,
+    Zstd,
+}
+
+pub struct Value {
+    bytes: Payload,
+    codec: Codec,
+}
+
+impl Value {
+    pub fn raw(bytes: Bytes) -> Value {
+        Value {
+            bytes: Payload::new(bytes),
+            codec: Codec::Raw,
+        }
+    }
+
+    pub fn codec(&self) -> Codec {
+        self.codec
+    }
+
+    // The bytes as stored, compressed or not.
+    pub fn stored(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    // Heap bytes of the value, none for a short one kept inline.
+    pub fn heap_size(&self) -> usize {
+        self.bytes.heap_size()
+    }
+
+    // The value as it was written.
+    pub fn decoded(&self) -> Bytes {
+        match self.codec {
+            Codec::Raw => self.bytes.to_bytes(),
+            Codec::Lz4 => Bytes::from(
+                lz4_flex::decompress_size_prepended(&self.bytes).expect("corrupt lz4 value"),
+            ),
+            Codec::Zstd => {
+                Bytes::from(zstd::decode_all(&self.bytes[..]).expect("corrupt zstd value"))
+            }
+        }
+    }
+}
+
+pub struct Compressor {
+    values: AtomicUsize,
+    bytes_in: AtomicUsize,
+    bytes_out: AtomicUsize,
+}
+
+impl Compressor {
+    pub fn new() -> Compressor {
+        Compressor {
+            values: AtomicUsize::new(0),
+            bytes_in: AtomicUsize::new(0),
+            bytes_out: AtomicUsize::new(0),
+        }
+    }
+
+    // Prepares a value for storing under the compression setting.
+    pub fn encode(&self, bytes: Bytes, setting: &str, threshold: usize) -> Value {
+        if bytes.len() < threshold {
+            return Value::raw(bytes);
+        }
+        let (compressed, codec) = match setting {
+            "lz4" => (lz4_flex::compress_prepend_size(&bytes), Codec::Lz4),
+            "zstd" => match zstd::bulk::compress(&bytes, ZSTD_LEVEL) {
+                Ok(compressed) => (compressed, Codec::Zstd),
+                Err(_) => return Value::raw(bytes),
+            },
+            _ => return Value::raw(bytes),
+        };
+        if compressed.len() >= bytes.len() {
+            return Value::raw(bytes);
+        }
+        self.values.fetch_add(1, Ordering::Relaxed);
+        self.bytes_in.fetch_add(bytes.len(), Ordering::Relaxed);
+        self.bytes_out.fetch_add(compressed.len(), Ordering::Relaxed);
+        Value {
+            bytes: Payload::new(Bytes::from(compressed)),
+            codec,
+        }
+    }
+
+    // Values compressed so far, and their bytes before and after.
+    pub fn values(&self) -> usize {
+        self.values.load(Ordering::Relaxed)
+    }
+
+    pub fn bytes_in(&self) -> usize {
+        self.bytes_in.load(Ordering::Relaxed)
+    }
+
+    pub fn bytes_out(&self) -> usize {
+        self.bytes_out.load(Ordering::Relaxed)
+    }
+}
@@ -0,0 +1,779 @@
+// Runtime configuration registry.
+//
+// Every tunable lives in Config and is described by a Param entry giving its
+// name and how to render and parse it. CONFIG GET walks the table with glob
+// patterns; CONFIG SET applies all pairs to a copy first so a bad value
+// leaves the running configuration untouched.
+
+
+use access;
+use compress;
+use log;
+use pattern;
+
+// Client classes output buffer limits are set for, in the order they are
+// stored and rendered.
+pub const OUTPUT_CLASSES: &[&str] = &["normal", "replica", "pubsub"];
+
+// Reply bytes a client may have queued: any amount up to soft, above soft
+// for at most soft_seconds, never above hard. Zero disables a threshold.
+#[derive(Clone, Copy)]
+pub struct OutputLimit {
+    pub hard: usize,
+    pub soft: usize,
+    pub soft_seconds: u64,
+}
+
+#[derive(Clone)]
+pub struct Config {
+    pub bind: String,
+    pub port: usize,
+    pub protected_mode: bool,
+    // Client addresses let in and kept out, see access.rs.
+    pub allow_from: Vec<access::Cidr>,
+    pub deny_from: Vec<access::Cidr>,
+    // Connections a client address may have open, no limit when zero.
+    pub max_connections_per_ip: usize,
+    pub tcp_backlog: usize,
+    pub tcp_keepalive: usize,
+    pub tcp_nodelay: bool,
+    // Port of the HTTP status endpoint, none when zero.
+    pub http_port: usize,
+    pub reuseport: bool,
+    pub proxy_protocol: String,
+    pub unixsocket: String,
+    pub unixsocketperm: u32,
+    pub threads: usize,
+    pub io_backend: String,
+    pub databases: usize,
+    pub shards: usize,
+    pub keyspace_backend: String,
+    pub aclfile: String,
+    pub tls_port: usize,
+    pub tls_cert_file: String,
+    pub tls_key_file: String,
+    pub tls_ca_cert_file: String,
+    pub tls_auth_clients: String,
+    pub tls_auth_clients_user: String,
+    pub maxmemory: usize,
+    pub maxmemory_policy: String,
+    pub maxmemory_samples: usize,
+    pub lfu_log_factor: usize,
+    pub lfu_decay_time: usize,
+    pub compression: String,
+    pub compression_threshold: usize,
+    pub timeout: usize,
+    // Times a second the cron runs.
+    pub hz: usize,
+    // Whether the cron helps move maps being rehashed along.
+    pub activerehashing: bool,
+    pub proto_max_bulk_len: usize,
+    pub max_key_length: usize,
+    pub max_value_size: usize,
+    pub client_query_buffer_limit: usize,
+    // Commands and argument bytes a second each connection may send, no
+    // limit when zero. ACL users can set their own.
+    pub client_max_commands_per_sec: usize,
+    pub client_max_bytes_per_sec: usize,
+    pub client_output_buffer_limit: [OutputLimit; 3],
+    // A connection with this many bytes of replies waiting isn't served
+    // further until they drain, no limit when zero.
+    pub client_output_backlog_limit: usize,
+    pub lua_time_limit: usize,
+    // Milliseconds KEYS and MEMORY BIGKEYS may walk the keyspace before
+    // giving up with an error, no limit when zero.
+    pub command_time_limit: usize,
+    pub lazyfree_lazy_user_flush: bool,
+    pub lazyfree_lazy_user_del: bool,
+    pub lazyfree_lazy_eviction: bool,
+    pub latency_monitor_threshold: usize,
+    // One in this many commands has its keys counted for HOTKEYS, none
+    // when zero.
+    pub hotkeys_sample_ratio: usize,
+    pub latency_tracking: bool,
+    // Percentiles INFO latencystats reports.
+    pub latency_tracking_info_percentiles: Vec<f64>,
+    pub loglevel: String,
+    pub logfile: String,
+    // Commands taking at least this many microseconds are logged, none
+    // when zero.
+    pub log_slower_than: usize,
+    pub shutdown_timeout: usize,
+    pub daemonize: bool,
+    pub pidfile: String,
+    pub save: String,
+    pub appendonly: bool,
+    pub appendfsync: String,
+    pub cluster_enabled: bool,
+    pub cluster_config_file: String,
+    pub cluster_announce_ip: String,
+    pub cluster_node_timeout: usize,
+    // Modules loaded at startup, each as its path followed by the
+    // arguments it is given, separated by spaces.
+    pub loadmodule: Vec<String>,
+    // Who may run MODULE: "no" for nobody, "yes" for everyone, "local" for
+    // loopback and Unix socket clients. Fixed at startup, as loading a
+    // module runs its code inside the server.
+    pub enable_module_command: String,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Config {
+    pub fn new() -> Config {
+        Config {
+            bind: "0.0.0.0".to_string(),
+            port: 6380,
+            protected_mode: true,
+            allow_from: Vec::new(),
+            deny_from: Vec::new(),
+            max_connections_per_ip: 0,
+            tcp_backlog: 511,
+            tcp_keepalive: 300,
+            tcp_nodelay: true,
+            http_port: 0,
+            reuseport: false,
+            proxy_protocol: String::new(),
+            unixsocket: String::new(),
+            unixsocketperm: 0,
+            threads: 1,
+            databases: 16,
+            shards: 16,
+            keyspace_backend: "mutex".to_string(),
+            io_backend: "mio".to_string(),
+            aclfile: String::new(),
+            tls_port: 0,
+            tls_cert_file: String::new(),
+            tls_key_file: String::new(),
+            tls_ca_cert_file: String::new(),
+            tls_auth_clients: "no".to_string(),
+            tls_auth_clients_user: "off".to_string(),
+            maxmemory: 0,
+            maxmemory_policy: "noeviction".to_string(),
+            maxmemory_samples: 5,
+            lfu_log_factor: 10,
+            lfu_decay_time: 1,
+            compression: "no".to_string(),
+            compression_threshold: 1024,
+            timeout: 0,
+            hz: 10,
+            activerehashing: true,
+            proto_max_bulk_len: 512 * 1024 * 1024,
+            max_key_length: 0,
+            max_value_size: 0,
+            client_query_buffer_limit: 1024 * 1024 * 1024,
+            client_max_commands_per_sec: 0,
+            client_max_bytes_per_sec: 0,
+            client_output_buffer_limit: [
+                OutputLimit {
+                    hard: 0,
+                    soft: 0,
+                    soft_seconds: 0,
+                },
+                OutputLimit {
+                    hard: 256 * 1024 * 1024,
+                    soft: 64 * 1024 * 1024,
+                    soft_seconds: 60,
+                },
+                OutputLimit {
+                    hard: 32 * 1024 * 1024,
+                    soft: 8 * 1024 * 1024,
+                    soft_seconds: 60,
+                },
+            ],
+            client_output_backlog_limit: 1024 * 1024,
+            lua_time_limit: 5000,
+            command_time_limit: 0,
+            lazyfree_lazy_user_flush: false,
+            lazyfree_lazy_user_del: false,
+            lazyfree_lazy_eviction: false,
+            latency_monitor_threshold: 0,
+            hotkeys_sample_ratio: 64,
+            latency_tracking: true,
+            latency_tracking_info_percentiles: vec![50.0, 99.0, 99.9],
+            loglevel: "notice".to_string(),
+            logfile: String::new(),
+            log_slower_than: 10000,
+            shutdown_timeout: 10,
+            daemonize: false,
+            pidfile: String::new(),
+            save: "3600 1 300 100 60 10000".to_string(),
+            appendonly: false,
+            appendfsync: "everysec".to_string(),
+            cluster_enabled: false,
+            cluster_config_file: "nodes.conf".to_string(),
+            cluster_announce_ip: String::new(),
+            cluster_node_timeout: 15000,
+            loadmodule: Vec::new(),
+            enable_module_command: "no".to_string(),
+        }
+    }
+
+    pub fn output_limit(&self, class: &str) -> OutputLimit {
+        let i = OUTPUT_CLASSES.iter().position(|&c| c == class).unwrap_or(0);
+        self.client_output_buffer_limit[i]
+    }
+
+    // Returns the (name, value) pairs whose names match any of the patterns.
+    pub fn get(&self, patterns: &[String]) -> Vec<(String, String)> {
+        let mut out = Vec::new();
+        for param in PARAMS {
+            if patterns.iter().any(|p| pattern::matches(p.as_bytes(), param.name.as_bytes(), true)) {
+                out.push((param.name.to_string(), (param.get)(self)));
+            }
+        }
+        out
+    }
+
+    pub fn set(&mut self, pairs: &[(String, String)]) -> Result<(), String> {
+        let mut next = self.clone();
+        for (name, value) in pairs {
+            let name = name.to_lowercase();
+            let param = match PARAMS.iter().find(|p| p.name == name) {
+                Some(param) => param,
+                None => {
+                    return Err(format!(
+                        "ERR Unknown option or number of arguments for CONFIG SET - '{}'",
+                        name
+                    ))
+                }
+            };
+            let set = match param.set {
+                Some(set) => set,
+                None => {
+                    return Err(format!(
+                        "ERR CONFIG SET failed (possibly related to argument '{}') - can't set immutable config",
+                        name
+                    ))
+                }
+            };
+            if let Err(e) = set(&mut next, value) {
+                return Err(format!(
+                    "ERR CONFIG SET failed (possibly related to argument '{}') - {}",
+                    name, e
+                ));
+            }
+        }
+        *self = next;
+        Ok(())
+    }
+}
+
+type Setter = fn(&mut Config, &str) -> Result<(), String>;
+
+struct Param {
+    name: &'static str,
+    get: fn(&Config) -> String,
+    set: Option<Setter>,
+}
+
+const PARAMS: &[Param] = &[
+    Param {
+        name: "bind",
+        get: |c| c.bind.clone(),
+        set: None,
+    },
+    Param {
+        name: "protected-mode",
+        get: |c| yes_no(c.protected_mode),
+        set: Some(|c, v| parse_bool(v).map(|b| c.protected_mode = b)),
+    },
+    Param {
+        name: "allow-from",
+        get: |c| access::render_list(&c.allow_from),
+        set: Some(|c, v| access::parse_list(v).map(|list| c.allow_from = list)),
+    },
+    Param {
+        name: "deny-from",
+        get: |c| access::render_list(&c.deny_from),
+        set: Some(|c, v| access::parse_list(v).map(|list| c.deny_from = list)),
+    },
+    Param {
+        name: "max-connections-per-ip",
+        get: |c| c.max_connections_per_ip.to_string(),
+        set: Some(|c, v| parse_int(v, 0, i32::MAX as usize).map(|n| c.max_connections_per_ip = n)),
+    },
+    Param {
+        name: "port",
+        get: |c| c.port.to_string(),
+        set: None,
+    },
+    Param {
+        name: "tcp-backlog",
+        get: |c| c.tcp_backlog.to_string(),
+        set: None,
+    },
+    Param {
+        name: "tcp-keepalive",
+        get: |c| c.tcp_keepalive.to_string(),
+        set: Some(|c, v| {
+            parse_int(v, 0, i32::MAX as usize).map(|n| c.tcp_keepalive = n)
+        }),
+    },
+    Param {
+        name: "tcp-nodelay",
+        get: |c| yes_no(c.tcp_nodelay),
+        set: Some(|c, v| parse_bool(v).map(|b| c.tcp_nodelay = b)),
+    },
+    Param {
+        name: "http-port",
+        get: |c| c.http_port.to_string(),
+        set: None,
+    },
+    Param {
+        name: "reuseport",
+        get: |c| yes_no(c.reuseport),
+        set: None,
+    },
+    Param {
+        name: "proxy-protocol",
+        get: |c| c.proxy_protocol.clone(),
+        set: None,
+    },
+    Param {
+        name: "unixsocket",
+        get: |c| c.unixsocket.clone(),
+        set: None,
+    },
+    Param {
+        name: "unixsocketperm",
+        get: |c| format!("{:o}", c.unixsocketperm),
+        set: None,
+    },
+    Param {
+        name: "tls-port",
+        get: |c| c.tls_port.to_string(),
+        set: None,
+    },
+    Param {
+        name: "tls-cert-file",
+        get: |c| c.tls_cert_file.clone(),
+        set: None,
+    },
+    Param {
+        name: "tls-key-file",
+        get: |c| c.tls_key_file.clone(),
+        set: None,
+    },
+    Param {
+        name: "tls-ca-cert-file",
+        get: |c| c.tls_ca_cert_file.clone(),
+        set: None,
+    },
+    Param {
+        name: "tls-auth-clients",
+        get: |c| c.tls_auth_clients.clone(),
+        set: None,
+    },
+    Param {
+        name: "tls-auth-clients-user",
+        get: |c| c.tls_auth_clients_user.clone(),
+        set: Some(|c, v| parse_enum(v, &["off", "cn"]).map(|s| c.tls_auth_clients_user = s)),
+    },
+    Param {
+        name: "io-threads",
+        get: |c| c.threads.to_string(),
+        set: None,
+    },
+    Param {
+        name: "aclfile",
+        get: |c| c.aclfile.clone(),
+        set: None,
+    },
+    Param {
+        name: "databases",
+        get: |c| c.databases.to_string(),
+        set: None,
+    },
+    Param {
+        name: "shards",
+        get: |c| c.shards.to_string(),
+        set: None,
+    },
+    Param {
+        name: "keyspace-backend",
+        get: |c| c.keyspace_backend.clone(),
+        set: None,
+    },
+    Param {
+        name: "io-backend",
+        get: |c| c.io_backend.clone(),
+        set: None,
+    },
+    Param {
+        name: "maxmemory",
+        get: |c| c.maxmemory.to_string(),
+        set: Some(|c, v| parse_memory(v).map(|n| c.maxmemory = n)),
+    },
+    Param {
+        name: "maxmemory-policy",
+        get: |c| c.maxmemory_policy.clone(),
+        set: Some(|c, v| {
+            parse_enum(
+                v,
+                &[
+                    "noeviction",
+                    "allkeys-lru",
+                    "volatile-lru",
+                    "allkeys-lfu",
+                    "volatile-lfu",
+                    "allkeys-random",
+                    "volatile-random",
+                    "volatile-ttl",
+                ],
+            ).map(|s| c.maxmemory_policy = s)
+        }),
+    },
+    Param {
+        name: "maxmemory-samples",
+        get: |c| c.maxmemory_samples.to_string(),
+        set: Some(|c, v| parse_int(v, 1, 64).map(|n| c.maxmemory_samples = n)),
+    },
+    Param {
+        name: "lfu-log-factor",
+        get: |c| c.lfu_log_factor.to_string(),
+        set: Some(|c, v| parse_int(v, 0, i32::MAX as usize).map(|n| c.lfu_log_factor = n)),
+    },
+    Param {
+        name: "lfu-decay-time",
+        get: |c| c.lfu_decay_time.to_string(),
+        set: Some(|c, v| parse_int(v, 0, i32::MAX as usize).map(|n| c.lfu_decay_time = n)),
+    },
+    Param {
+        name: "compression",
+        get: |c| c.compression.clone(),
+        set: Some(|c, v| parse_enum(v, compress::CODECS).map(|s| c.compression = s)),
+    },
+    Param {
+        name: "compression-threshold",
+        get: |c| c.compression_threshold.to_string(),
+        set: Some(|c, v| parse_memory(v).map(|n| c.compression_threshold = n)),
+    },
+    Param {
+        name: "timeout",
+        get: |c| c.timeout.to_string(),
+        set: Some(|c, v| parse_int(v, 0, i32::MAX as usize).map(|n| c.timeout = n)),
+    },
+    Param {
+        name: "hz",
+        get: |c| c.hz.to_string(),
+        set: Some(|c, v| parse_int(v, 0, i32::MAX as usize).map(|n| c.hz = n)),
+    },
+    Param {
+        name: "activerehashing",
+        get: |c| yes_no(c.activerehashing),
+        set: Some(|c, v| parse_bool(v).map(|b| c.activerehashing = b)),
+    },
+    Param {
+        name: "proto-max-bulk-len",
+        get: |c| c.proto_max_bulk_len.to_string(),
+        set: Some(|c, v| parse_memory_min(v, 1024 * 1024).map(|n| c.proto_max_bulk_len = n)),
+    },
+    Param {
+        name: "max-key-length",
+        get: |c| c.max_key_length.to_string(),
+        set: Some(|c, v| parse_memory(v).map(|n| c.max_key_length = n)),
+    },
+    Param {
+        name: "max-value-size",
+        get: |c| c.max_value_size.to_string(),
+        set: Some(|c, v| parse_memory(v).map(|n| c.max_value_size = n)),
+    },
+    Param {
+        name: "client-query-buffer-limit",
+        get: |c| c.client_query_buffer_limit.to_string(),
+        set: Some(|c, v| {
+            parse_memory_min(v, 1024 * 1024).map(|n| c.client_query_buffer_limit = n)
+        }),
+    },
+    Param {
+        name: "client-max-commands-per-sec",
+        get: |c| c.client_max_commands_per_sec.to_string(),
+        set: Some(|c, v| parse_int(v, 0, i32::MAX as usize).map(|n| c.client_max_commands_per_sec = n)),
+    },
+    Param {
+        name: "client-max-bytes-per-sec",
+        get: |c| c.client_max_bytes_per_sec.to_string(),
+        set: Some(|c, v| parse_memory(v).map(|n| c.client_max_bytes_per_sec = n)),
+    },
+    Param {
+        name: "client-output-backlog-limit",
+        get: |c| c.client_output_backlog_limit.to_string(),
+        set: Some(|c, v| parse_memory(v).map(|n| c.client_output_backlog_limit = n)),
+    },
+    Param {
+        name: "client-output-buffer-limit",
+        get: |c| {
+            let mut parts = Vec::new();
+            for (class, limit) in OUTPUT_CLASSES.iter().zip(c.client_output_buffer_limit.iter()) {
+                parts.push(format!(
+                    "{} {} {} {}",
+                    class, limit.hard, limit.soft, limit.soft_seconds
+                ));
+            }
+            parts.join(" ")
+        },
+        set: Some(|c, v| parse_output_limits(v, &mut c.client_output_buffer_limit)),
+    },
+    Param {
+        name: "lua-time-limit",
+        get: |c| c.lua_time_limit.to_string(),
+        set: Some(|c, v| {
+            parse_int(v, 0, i32::MAX as usize).map(|n| c.lua_time_limit = n)
+        }),
+    },
+    Param {
+        name: "busy-reply-threshold",
+        get: |c| c.lua_time_limit.to_string(),
+        set: Some(|c, v| {
+            parse_int(v, 0, i32::MAX as usize).map(|n| c.lua_time_limit = n)
+        }),
+    },
+    Param {
+        name: "command-time-limit",
+        get: |c| c.command_time_limit.to_string(),
+        set: Some(|c, v| {
+            parse_int(v, 0, i32::MAX as usize).map(|n| c.command_time_limit = n)
+        }),
+    },
+    Param {
+        name: "lazyfree-lazy-user-flush",
+        get: |c| yes_no(c.lazyfree_lazy_user_flush),
+        set: Some(|c, v| parse_bool(v).map(|b| c.lazyfree_lazy_user_flush = b)),
+    },
+    Param {
+        name: "lazyfree-lazy-user-del",
+        get: |c| yes_no(c.lazyfree_lazy_user_del),
+        set: Some(|c, v| parse_bool(v).map(|b| c.lazyfree_lazy_user_del = b)),
+    },
+    Param {
+        name: "lazyfree-lazy-eviction",
+        get: |c| yes_no(c.lazyfree_lazy_eviction),
+        set: Some(|c, v| parse_bool(v).map(|b| c.lazyfree_lazy_eviction = b)),
+    },
+    Param {
+        name: "latency-monitor-threshold",
+        get: |c| c.latency_monitor_threshold.to_string(),
+        set: Some(|c, v| {
+            parse_int(v, 0, i32::MAX as usize).map(|n| c.latency_monitor_threshold = n)
+        }),
+    },
+    Param {
+        name: "hotkeys-sample-ratio",
+        get: |c| c.hotkeys_sample_ratio.to_string(),
+        set: Some(|c, v| parse_int(v, 0, i32::MAX as usize).map(|n| c.hotkeys_sample_ratio = n)),
+    },
+    Param {
+        name: "latency-tracking",
+        get: |c| yes_no(c.latency_tracking),
+        set: Some(|c, v| parse_bool(v).map(|b| c.latency_tracking = b)),
+    },
+    Param {
+        name: "latency-tracking-info-percentiles",
+        get: |c| {
+            let qs: Vec<String> = c.latency_tracking_info_percentiles.iter().map(|q| q.to_string()).collect();
+            qs.join(" ")
+        },
+        set: Some(|c, v| parse_percentiles(v).map(|qs| c.latency_tracking_info_percentiles = qs)),
+    },
+    Param {
+        name: "loglevel",
+        get: |c| c.loglevel.clone(),
+        set: Some(|c, v| parse_enum(v, log::LEVELS).map(|s| c.loglevel = s)),
+    },
+    Param {
+        name: "logfile",
+        get: |c| c.logfile.clone(),
+        set: None,
+    },
+    Param {
+        name: "log-slower-than",
+        get: |c| c.log_slower_than.to_string(),
+        set: Some(|c, v| {
+            parse_int(v, 0, i32::MAX as usize).map(|n| c.log_slower_than = n)
+        }),
+    },
+    Param {
+        name: "shutdown-timeout",
+        get: |c| c.shutdown_timeout.to_string(),
+        set: Some(|c, v| {
+            parse_int(v, 0, i32::MAX as usize).map(|n| c.shutdown_timeout = n)
+        }),
+    },
+    Param {
+        name: "daemonize",
+        get: |c| yes_no(c.daemonize),
+        set: None,
+    },
+    Param {
+        name: "pidfile",
+        get: |c| c.pidfile.clone(),
+        set: None,
+    },
+    Param {
+        name: "save",
+        get: |c| c.save.clone(),
+        set: Some(|c, v| parse_save(v).map(|s| c.save = s)),
+    },
+    Param {
+        name: "appendonly",
+        get: |c| yes_no(c.appendonly),
+        set: Some(|c, v| parse_bool(v).map(|b| c.appendonly = b)),
+    },
+    Param {
+        name: "appendfsync",
+        get: |c| c.appendfsync.clone(),
+        set: Some(|c, v| {
+            parse_enum(v, &["always", "everysec", "no"]).map(|s| c.appendfsync = s)
+        }),
+    },
+    Param {
+        name: "cluster-enabled",
+        get: |c| yes_no(c.cluster_enabled),
+        set: None,
+    },
+    Param {
+        name: "cluster-config-file",
+        get: |c| c.cluster_config_file.clone(),
+        set: None,
+    },
+    Param {
+        name: "cluster-announce-ip",
+        get: |c| c.cluster_announce_ip.clone(),
+        set: None,
+    },
+    Param {
+        name: "cluster-node-timeout",
+        get: |c| c.cluster_node_timeout.to_string(),
+        set: None,
+    },
+    Param {
+        name: "enable-module-command",
+        get: |c| c.enable_module_command.clone(),
+        set: None,
+    },
+];
+
+fn yes_no(b: bool) -> String {
+    if b { "yes" } else { "no" }.to_string()
+}
+
+fn parse_bool(v: &str) -> Result<bool, String> {
+    match v.to_lowercase().as_str() {
+        "yes" => Ok(true),
+        "no" => Ok(false),
+        _ => Err("argument must be 'yes' or 'no'".to_string()),
+    }
+}
+
+fn parse_int(v: &str, min: usize, max: usize) -> Result<usize, String> {
+    match v.parse::<usize>() {
+        Ok(n) if n >= min && n <= max => Ok(n),
+        Ok(_) => Err(format!("argument must be between {} and {} inclusive", min, max)),
+        Err(_) => Err("argument couldn't be parsed into an integer".to_string()),
+    }
+}
+
+fn parse_percentiles(v: &str) -> Result<Vec<f64>, String> {
+    v.split_whitespace()
+        .map(|q| match q.parse::<f64>() {
+            Ok(q) if (0.0..=100.0).contains(&q) => Ok(q),
+            _ => Err("argument(s) must be percentiles between 0 and 100".to_string()),
+        })
+        .collect()
+}
+
+fn parse_enum(v: &str, allowed: &[&str]) -> Result<String, String> {
+    let v = v.to_lowercase();
+    if allowed.contains(&v.as_str()) {
+        Ok(v)
+    } else {
+        Err("argument(s) must be one of the following: ".to_string() + &allowed.join(", "))
+    }
+}
+
+// Accepts plain byte counts as well as k/kb/m/mb/g/gb suffixes.
+pub fn parse_memory(v: &str) -> Result<usize, String> {
+    let v = v.to_lowercase();
+    let (digits, mul) = if v.ends_with("kb") {
+        (&v[..v.len() - 2], 1024)
+    } else if v.ends_with("mb") {
+        (&v[..v.len() - 2], 1024 * 1024)
+    } else if v.ends_with("gb") {
+        (&v[..v.len() - 2], 1024 * 1024 * 1024)
+    } else if v.ends_with('k') {
+        (&v[..v.len() - 1], 1000)
+    } else if v.ends_with('m') {
+        (&v[..v.len() - 1], 1000 * 1000)
+    } else if v.ends_with('g') {
+        (&v[..v.len() - 1], 1000 * 1000 * 1000)
+    } else if v.ends_with('b') {
+        (&v[..v.len() - 1], 1)
+    } else {
+        (&v[..], 1)
+    };
+    match digits.parse::<usize>() {
+        Ok(n) => Ok(n * mul),
+        Err(_) => Err("argument must be a memory value".to_string()),
+    }
+}
+
+fn parse_memory_min(v: &str, min: usize) -> Result<usize, String> {
+    match parse_memory(v)? {
+        n if n < min => Err(format!("argument must be at least {}", min)),
+        n => Ok(n),
+    }
+}
+
+// Takes "<class> <hard> <soft> <soft seconds>" groups, updating only the
+// classes named.
+fn parse_output_limits(v: &str, limits: &mut [OutputLimit; 3]) -> Result<(), String> {
+    let parts: Vec<&str> = v.split_whitespace().collect();
+    if parts.is_empty() || !parts.len().is_multiple_of(4) {
+        return Err("Wrong number of arguments in buffer limit configuration.".to_string());
+    }
+    let mut next = *limits;
+    for group in parts.chunks(4) {
+        let class = group[0].to_lowercase();
+        let class = if class == "slave" { "replica".to_string() } else { class };
+        let i = match OUTPUT_CLASSES.iter().position(|&c| c == class) {
+            Some(i) => i,
+            None => {
+                return Err("Invalid client class specified in buffer limit configuration.".to_string())
+            }
+        };
+        let (hard, soft, seconds) = match (
+            parse_memory(group[1]),
+            parse_memory(group[2]),
+            group[3].parse::<u64>(),
+        ) {
+            (Ok(hard), Ok(soft), Ok(seconds)) => (hard, soft, seconds),
+            _ => {
+                return Err(
+                    "Error in hard, soft or soft_seconds setting in buffer limit configuration."
+                        .to_string(),
+                )
+            }
+        };
+        next[i] = OutputLimit {
+            hard,
+            soft,
+            soft_seconds: seconds,
+        };
+    }
+    *limits = next;
+    Ok(())
+}
+
+// Save points are "<seconds> <changes>" pairs; an empty string disables them.
+fn parse_save(v: &str) -> Result<String, String> {
+    let parts: Vec<&str> = v.split_whitespace().collect();
+    if !parts.len().is_multiple_of(2) || parts.iter().any(|p| p.parse::<usize>().is_err()) {
+        return Err("Invalid save parameters".to_string());
+    }
+    Ok(parts.join(" "))
+}
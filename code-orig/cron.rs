@@ -0,0 +1,100 @@
+// Periodic housekeeping.
+//
+// A thread of its own wakes hz times a second to run the server's cron:
+// bringing the LRU clock up to date while no commands come in, moving
+// maps being rehashed along, closing clients left idle past timeout, and
+// sampling the command count for instantaneous_ops_per_sec. Keys carry no TTL and there is no persistence
+// or replication, so nothing is actively expired, saved or pinged yet;
+// such jobs belong in the cron too.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// hz is kept within this range, as Redis does.
+const MIN_HZ: usize = 1;
+const MAX_HZ: usize = 500;
+
+// The rate is averaged over this many samples taken SAMPLE_PERIOD apart.
+const SAMPLES: usize = 16;
+const SAMPLE_PERIOD: Duration = Duration::from_millis(100);
+
+struct Samples {
+    last: Instant,
+    last_count: usize,
+    rates: [f64; SAMPLES],
+    next: usize,
+}
+
+pub struct Cron {
+    hz: AtomicUsize,
+    commands: AtomicUsize,
+    samples: Mutex<Samples>,
+}
+
+impl Cron {
+    pub fn new(hz: usize) -> Cron {
+        Cron {
+            hz: AtomicUsize::new(clamp(hz)),
+            commands: AtomicUsize::new(0),
+            samples: Mutex::new(Samples {
+                last: Instant::now(),
+                last_count: 0,
+                rates: [0.0; SAMPLES],
+                next: 0,
+            }),
+        }
+    }
+
+    pub fn set_hz(&self, hz: usize) {
+        self.hz.store(clamp(hz), Ordering::Relaxed);
+    }
+
+    // The hz in effect, which may differ from the configured one.
+    pub fn hz(&self) -> usize {
+        self.hz.load(Ordering::Relaxed)
+    }
+
+    // How long the cron sleeps between runs.
+    pub fn interval(&self) -> Duration {
+        Duration::from_millis(1000 / self.hz() as u64)
+    }
+
+    pub fn count_command(&self) {
+        self.commands.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn commands(&self) -> usize {
+        self.commands.load(Ordering::Relaxed)
+    }
+
+    pub fn reset_commands(&self) {
+        self.commands.store(0, Ordering::Relaxed);
+    }
+
+    // Takes a sample of the command rate once SAMPLE_PERIOD has passed
+    // since the last.
+    pub fn sample(&self) {
+        let mut samples = self.samples.lock().unwrap();
+        let elapsed = samples.last.elapsed();
+        if elapsed < SAMPLE_PERIOD {
+            return;
+        }
+        let count = self.commands();
+        let secs = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+        let i = samples.next;
+        samples.rates[i] = count.saturating_sub(samples.last_count) as f64 / secs;
+        samples.next = (i + 1) % SAMPLES;
+        samples.last = Instant::now();
+        samples.last_count = count;
+    }
+
+    pub fn ops_per_sec(&self) -> usize {
+        let samples = self.samples.lock().unwrap();
+        (samples.rates.iter().sum::<f64>() / SAMPLES as f64).round() as usize
+    }
+}
+
+fn clamp(hz: usize) -> usize {
+    hz.clamp(MIN_HZ, MAX_HZ)
+}
@@ -0,0 +1,73 @@
+// Running under an init system.
+//
+// daemonize() detaches the process from its terminal, and has to run before
+// any thread is started, as only the calling thread survives the fork. The
+// pidfile is written once the server is listening and removed when it
+// stops. notify() tells a systemd Type=notify unit about the server's state,
+// and does nothing when the process isn't run by one.
+
+use std::env;
+use std::fs;
+use std::io;
+use std::os::unix::net::UnixDatagram;
+use std::process;
+
+use libc;
+
+pub fn daemonize() -> Result<(), String> {
+    match unsafe { libc::fork() } {
+        -1 => return Err(format!("Can't fork: {}", io::Error::last_os_error())),
+        0 => {}
+        _ => process::exit(0),
+    }
+    unsafe {
+        libc::setsid();
+        let null = libc::open(b"/dev/null\0".as_ptr() as *const libc::c_char, libc::O_RDWR);
+        if null != -1 {
+            libc::dup2(null, libc::STDIN_FILENO);
+            libc::dup2(null, libc::STDOUT_FILENO);
+            libc::dup2(null, libc::STDERR_FILENO);
+            if null > libc::STDERR_FILENO {
+                libc::close(null);
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn write_pidfile(path: &str) -> Result<(), String> {
+    fs::write(path, format!("{}\n", process::id()))
+        .map_err(|e| format!("Can't write the pidfile {}: {}", path, e))
+}
+
+pub fn remove_pidfile(path: &str) {
+    let _ = fs::remove_file(path);
+}
+
+// Sends state, such as "READY=1", to the socket systemd names in
+// NOTIFY_SOCKET. Names starting with '@' are in the abstract namespace.
+pub fn notify(state: &str) -> Result<(), String> {
+    let path = match env::var("NOTIFY_SOCKET") {
+        Ok(path) => path,
+        Err(_) => return Ok(()),
+    };
+    let socket = UnixDatagram::unbound().map_err(|e| e.to_string())?;
+    let sent = if let Some(name) = path.strip_prefix('@') {
+        abstract_addr(name).and_then(|addr| socket.send_to_addr(state.as_bytes(), &addr))
+    } else {
+        socket.send_to(state.as_bytes(), &path)
+    };
+    sent.map(|_| ())
+        .map_err(|e| format!("Can't notify systemd at {}: {}", path, e))
+}
+
+#[cfg(target_os = "linux")]
+fn abstract_addr(name: &str) -> io::Result<::std::os::unix::net::SocketAddr> {
+    use std::os::linux::net::SocketAddrExt;
+    ::std::os::unix::net::SocketAddr::from_abstract_name(name.as_bytes())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn abstract_addr(_name: &str) -> io::Result<::std::os::unix::net::SocketAddr> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "abstract sockets are Linux only"))
+}
@@ -0,0 +1,391 @@
+// A single logical database.
+//
+// Wraps the key map so every insert and removal also maintains a running
+// count of the bytes held by keys and values, letting MEMORY STATS report
+// the dataset size without walking the keyspace. Short values are kept
+// inline, longer ones as Bytes, possibly compressed, so a reply can share a
+// stored value instead of copying it.
+//
+// Every entry records its accesses as Tracking dictates, and the keys are
+// also kept in a flat list so eviction can sample them at random. Short keys
+// are kept inline in both, longer ones are shared between the map and the
+// list rather than copied.
+//
+// A large map isn't grown in one go, as rehashing millions of entries under
+// the shard's lock would stall every command on the shard. Once it is full
+// a map twice the size takes its place, and the entries of the old one are
+// moved over a few at a time: by every write, and by the cron while
+// activerehashing is on. Lookups look in both until the old one is empty.
+// The sampling list doubles as the cursor of the move.
+
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::mem;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use compress::Value;
+use small::Key;
+
+// Maps below this many entries grow the usual way, all at once.
+const REHASH_MIN: usize = 1024;
+
+// Entries looked at by every write while a rehash is under way.
+const REHASH_STEP: usize = 16;
+
+// Counter value of new keys, so they aren't evicted before they had a
+// chance to be accessed.
+const LFU_INIT_VAL: u32 = 5;
+
+thread_local! {
+    static SEED: Cell<u64> = Cell::new(
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.subsec_nanos() as u64)
+            .unwrap_or(0) | 1
+    );
+}
+
+fn random() -> f64 {
+    SEED.with(|seed| {
+        let mut x = seed.get();
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        seed.set(x);
+        (x >> 11) as f64 / (1u64 << 53) as f64
+    })
+}
+
+// How accesses are recorded in an entry's lru field. Under the LRU
+// policies it holds the keyspace clock of the last access. Under the LFU
+// ones it holds, as in Redis, the minute of the last access in the upper 16
+// bits and an 8 bit access counter that grows logarithmically: the more
+// accesses it already counts the less likely another one increments it.
+// The counter loses one for every lfu-decay-time minutes without access.
+// Entries keep what they recorded across a policy change, so it takes a
+// while for the new policy to see meaningful values.
+#[derive(Clone, Copy)]
+pub enum Tracking {
+    Lru(u32),
+    Lfu {
+        minutes: u16,
+        log_factor: u32,
+        decay_time: u32,
+    },
+}
+
+impl Tracking {
+    fn initial(&self) -> u32 {
+        match *self {
+            Tracking::Lru(clock) => clock,
+            Tracking::Lfu { minutes, .. } => (minutes as u32) << 8 | LFU_INIT_VAL,
+        }
+    }
+
+    fn touched(&self, lru: u32) -> u32 {
+        match *self {
+            Tracking::Lru(clock) => clock,
+            Tracking::Lfu {
+                minutes,
+                log_factor,
+                ..
+            } => {
+                let counter = self.freq(lru);
+                let base = counter.saturating_sub(LFU_INIT_VAL);
+                let counter = if counter < 255 && random() < 1.0 / (base * log_factor + 1) as f64 {
+                    counter + 1
+                } else {
+                    counter
+                };
+                (minutes as u32) << 8 | counter
+            }
+        }
+    }
+
+    // The access counter after decay.
+    pub fn freq(&self, lru: u32) -> u32 {
+        match *self {
+            Tracking::Lru(_) => 0,
+            Tracking::Lfu {
+                minutes,
+                decay_time,
+                ..
+            } => {
+                let counter = lru & 0xff;
+                if decay_time == 0 {
+                    return counter;
+                }
+                let elapsed = minutes.wrapping_sub((lru >> 8) as u16) as u32;
+                counter.saturating_sub(elapsed / decay_time)
+            }
+        }
+    }
+
+    // How good an eviction candidate the entry is, higher being better:
+    // the seconds since its last access, or how rarely it is accessed.
+    pub fn idle(&self, lru: u32) -> u32 {
+        match *self {
+            Tracking::Lru(clock) => clock.saturating_sub(lru),
+            Tracking::Lfu { .. } => 255 - self.freq(lru),
+        }
+    }
+}
+
+pub struct Entry {
+    value: Value,
+    // Accesses as recorded by Tracking. Atomic since reads touch it while
+    // only holding the shard for reading.
+    lru: AtomicU32,
+    // Position of the key in Db::sample.
+    slot: u32,
+}
+
+impl Entry {
+    pub fn value(&self) -> &Value {
+        &self.value
+    }
+
+    pub fn lru(&self) -> u32 {
+        self.lru.load(Ordering::Relaxed)
+    }
+
+    fn touch(&self, tracking: Tracking) {
+        self.lru.store(tracking.touched(self.lru()), Ordering::Relaxed);
+    }
+}
+
+pub struct Db {
+    keys: HashMap<Key, Entry>,
+    // The map being rehashed into keys, while there is one.
+    old: Option<HashMap<Key, Entry>>,
+    // Position in the sampling list up to which keys were moved out of old.
+    rehashed: usize,
+    sample: Vec<Key>,
+    used: usize,
+}
+
+impl Db {
+    pub fn new() -> Db {
+        Db {
+            keys: HashMap::new(),
+            old: None,
+            rehashed: 0,
+            sample: Vec::new(),
+            used: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len() + self.old.as_ref().map_or(0, |old| old.len())
+    }
+
+    fn find(&self, key: &[u8]) -> Option<&Entry> {
+        self.keys
+            .get(key)
+            .or_else(|| self.old.as_ref().and_then(|old| old.get(key)))
+    }
+
+    fn find_mut(&mut self, key: &[u8]) -> Option<&mut Entry> {
+        if self.keys.contains_key(key) {
+            return self.keys.get_mut(key);
+        }
+        self.old.as_mut().and_then(|old| old.get_mut(key))
+    }
+
+    // Looks the key up, recording the access.
+    pub fn get(&self, key: &[u8], tracking: Tracking) -> Option<&Value> {
+        self.find(key).map(|entry| {
+            entry.touch(tracking);
+            &entry.value
+        })
+    }
+
+    // Looks the key up without counting it as an access.
+    pub fn peek(&self, key: &[u8]) -> Option<&Entry> {
+        self.find(key)
+    }
+
+    pub fn contains_key(&self, key: &[u8]) -> bool {
+        self.find(key).is_some()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&[u8], &Value)> {
+        self.keys
+            .iter()
+            .chain(self.old.iter().flat_map(|old| old.iter()))
+            .map(|(key, entry)| (&**key, &entry.value))
+    }
+
+    pub fn insert(&mut self, key: Vec<u8>, value: Value, tracking: Tracking) -> Option<Value> {
+        self.rehash(REHASH_STEP);
+        let added = value.heap_size();
+        if let Some(entry) = self.find_mut(&key[..]) {
+            let old = mem::replace(&mut entry.value, value);
+            entry.touch(tracking);
+            self.used = self.used - old.heap_size() + added;
+            return Some(old);
+        }
+        if self.keys.len() >= REHASH_MIN && self.keys.len() == self.keys.capacity() {
+            // A rehash still going when the new map fills up is finished
+            // first, which the steps taken by writes make unlikely.
+            while self.rehash(usize::MAX) {}
+            let capacity = self.keys.capacity() * 2;
+            self.old = Some(mem::replace(&mut self.keys, HashMap::with_capacity(capacity)));
+            self.rehashed = 0;
+        }
+        let key = Key::new(key);
+        self.used += key.heap_size() + added;
+        self.sample.push(key.clone());
+        self.keys.insert(
+            key,
+            Entry {
+                value,
+                lru: AtomicU32::new(tracking.initial()),
+                slot: (self.sample.len() - 1) as u32,
+            },
+        );
+        None
+    }
+
+    pub fn remove(&mut self, key: &[u8]) -> Option<Value> {
+        self.rehash(REHASH_STEP);
+        let removed = self.keys
+            .remove_entry(key)
+            .or_else(|| self.old.as_mut().and_then(|old| old.remove_entry(key)));
+        let (key, entry) = removed?;
+        self.used -= key.heap_size() + entry.value.heap_size();
+        // The last key moves into the freed slot. If the rehash is past the
+        // slot already, the key is moved to the new map along with it.
+        let slot = entry.slot as usize;
+        self.sample.swap_remove(slot);
+        if slot < self.sample.len() {
+            let moved = self.sample[slot].clone();
+            self.find_mut(&moved).unwrap().slot = slot as u32;
+            if slot < self.rehashed {
+                if let Some((key, entry)) = self.old.as_mut().and_then(|old| old.remove_entry(&moved[..])) {
+                    self.keys.insert(key, entry);
+                }
+            }
+        }
+        Some(entry.value)
+    }
+
+    // Moves the entries of up to n more keys of the sampling list out of
+    // the old map. False once there is no rehash under way.
+    pub fn rehash(&mut self, n: usize) -> bool {
+        let done = match self.old {
+            Some(ref mut old) => {
+                let end = self.rehashed.saturating_add(n).min(self.sample.len());
+                for key in &self.sample[self.rehashed..end] {
+                    if let Some((key, entry)) = old.remove_entry(&key[..]) {
+                        self.keys.insert(key, entry);
+                    }
+                }
+                self.rehashed = end;
+                if self.rehashed == self.sample.len() {
+                    self.keys.extend(old.drain());
+                }
+                old.is_empty()
+            }
+            None => return false,
+        };
+        if done {
+            self.old = None;
+            self.rehashed = 0;
+        }
+        !done
+    }
+
+    // The key at position random % len of the sampling list.
+    pub fn sample(&self, random: usize) -> Option<(&Key, &Entry)> {
+        if self.sample.is_empty() {
+            return None;
+        }
+        let key = &self.sample[random % self.sample.len()];
+        self.find(key).map(|entry| (key, entry))
+    }
+
+    // Up to count keys from position from of the sampling list. Positions
+    // only move when keys are removed.
+    pub fn keys(&self, from: usize, count: usize) -> &[Key] {
+        let from = from.min(self.sample.len());
+        &self.sample[from..(from + count).min(self.sample.len())]
+    }
+
+    pub fn clear(&mut self) {
+        self.keys.clear();
+        self.old = None;
+        self.rehashed = 0;
+        self.sample.clear();
+        self.used = 0;
+    }
+
+    // Swaps in an empty database and returns the old one, so a large
+    // keyspace can be dropped elsewhere.
+    pub fn take(&mut self) -> Db {
+        mem::replace(self, Db::new())
+    }
+
+    // Bytes held by key and value buffers.
+    pub fn used(&self) -> usize {
+        self.used
+    }
+
+    // Bytes held by the hash tables themselves, including empty slots, and
+    // by the sampling list.
+    pub fn overhead(&self) -> usize {
+        let capacity = self.keys.capacity() + self.old.as_ref().map_or(0, |old| old.capacity());
+        capacity * (mem::size_of::<(Key, Entry)>() + 1) + self.sample.capacity() * mem::size_of::<Key>()
+    }
+
+    // Bytes the key's entry accounts for: its map slot and control byte,
+    // its slot in the sampling list, and the key and value buffers.
+    pub fn usage(&self, key: &[u8]) -> Option<usize> {
+        self.keys
+            .get_key_value(key)
+            .or_else(|| self.old.as_ref().and_then(|old| old.get_key_value(key)))
+            .map(|(key, entry)| {
+                mem::size_of::<(Key, Entry)>() + 1 + mem::size_of::<Key>() + key.heap_size()
+                    + entry.value.heap_size()
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use compress::Value;
+
+    use super::{Db, Tracking, REHASH_MIN};
+
+    fn value(i: usize) -> Value {
+        Value::raw(Bytes::from(i.to_string()))
+    }
+
+    #[test]
+    fn keys_stay_reachable_while_rehashing() {
+        let mut db = Db::new();
+        let mut i = 0;
+        while db.old.is_none() {
+            db.insert(format!("key:{}", i).into_bytes(), value(i), Tracking::Lru(0));
+            i += 1;
+            assert!(i < 100 * REHASH_MIN);
+        }
+        // Removals behind the cursor pull keys from the end of the list.
+        db.rehash(REHASH_MIN / 2);
+        for j in 0..10 {
+            assert!(db.remove(format!("key:{}", j * 7).as_bytes()).is_some());
+        }
+        assert_eq!(db.len(), i - 10);
+        for j in 0..i {
+            let found = db.peek(format!("key:{}", j).as_bytes()).is_some();
+            assert_eq!(found, j % 7 != 0 || j >= 70);
+        }
+        while db.rehash(64) {}
+        assert!(db.old.is_none());
+        assert_eq!(db.keys.len(), i - 10);
+        assert_eq!(db.iter().count(), i - 10);
+    }
+}
@@ -0,0 +1,212 @@
+// Eviction under maxmemory.
+//
+// Entries sit on no LRU list; each only records the keyspace clock of its
+// last access, or for the LFU policies a logarithmic access counter. Once
+// the dataset has grown past maxmemory, writes first make room: every round
+// samples maxmemory-samples random keys per database from the next shard
+// into a small pool that keeps the candidates idle the longest, or accessed
+// the least, across rounds, and the best of them is evicted. Like Redis
+// this comes close to true LRU or LFU while an access costs no more than a
+// store.
+//
+// Keys never expire in this server, so the volatile policies find nothing to
+// evict and writes fail as they would under noeviction.
+
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use compress::Value;
+use keyspace::Keyspace;
+use lazyfree::LazyFree;
+use small::Key;
+
+const POOL_SIZE: usize = 16;
+
+pub const OOM_ERROR: &[u8] =
+    b"-OOM command not allowed when used memory > 'maxmemory'.\r\n";
+
+struct Candidate {
+    idle: u32,
+    shard: usize,
+    db: usize,
+    key: Key,
+}
+
+struct Pool {
+    // Sorted by idle time, the best candidate last.
+    candidates: Vec<Candidate>,
+    // Next shard to sample.
+    cursor: usize,
+    seed: u64,
+}
+
+impl Pool {
+    fn random(&mut self) -> usize {
+        self.seed ^= self.seed << 13;
+        self.seed ^= self.seed >> 7;
+        self.seed ^= self.seed << 17;
+        self.seed as usize
+    }
+
+    fn next_shard(&mut self, shards: usize) -> usize {
+        self.cursor = (self.cursor + 1) % shards;
+        self.cursor
+    }
+
+    // Keeps the candidate unless the pool is full of keys idle longer.
+    fn offer(&mut self, candidate: Candidate) {
+        if let Some(i) = self.candidates.iter().position(|c| {
+            c.shard == candidate.shard && c.db == candidate.db && c.key == candidate.key
+        }) {
+            self.candidates.remove(i);
+        }
+        if self.candidates.len() == POOL_SIZE {
+            if candidate.idle <= self.candidates[0].idle {
+                return;
+            }
+            self.candidates.remove(0);
+        }
+        let at = self.candidates
+            .iter()
+            .position(|c| c.idle > candidate.idle)
+            .unwrap_or(self.candidates.len());
+        self.candidates.insert(at, candidate);
+    }
+}
+
+enum Outcome {
+    Evicted,
+    // Nothing left the policy may evict.
+    Nothing,
+    // The shards needed are held by someone else.
+    Busy,
+}
+
+pub struct Evictor {
+    pool: Mutex<Pool>,
+}
+
+impl Evictor {
+    pub fn new() -> Evictor {
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.subsec_nanos() as u64)
+            .unwrap_or(0) | 1;
+        Evictor {
+            pool: Mutex::new(Pool {
+                candidates: Vec::new(),
+                cursor: 0,
+                seed,
+            }),
+        }
+    }
+
+    // Evicts keys under the policy until the keyspace fits in limit bytes
+    // again. False if it doesn't and the policy allows evicting nothing
+    // more. Shards held elsewhere aren't waited for: the command goes ahead
+    // and a later write carries on evicting. Evicted values go to lazyfree
+    // when given.
+    pub fn make_room(
+        &self,
+        keyspace: &Keyspace,
+        limit: usize,
+        policy: &str,
+        samples: usize,
+        lazyfree: Option<&LazyFree>,
+    ) -> bool {
+        while keyspace.used() > limit {
+            let outcome = match policy {
+                "allkeys-lru" | "allkeys-lfu" => self.evict_pooled(keyspace, samples, lazyfree),
+                "allkeys-random" => self.evict_random(keyspace, lazyfree),
+                _ => Outcome::Nothing,
+            };
+            match outcome {
+                Outcome::Evicted => {}
+                Outcome::Nothing => return false,
+                Outcome::Busy => return true,
+            }
+        }
+        true
+    }
+
+    fn evict_pooled(&self, keyspace: &Keyspace, samples: usize, lazyfree: Option<&LazyFree>) -> Outcome {
+        let mut pool = self.pool.lock().unwrap();
+        let tracking = keyspace.tracking();
+        loop {
+            // Shards are sampled until one yields a candidate.
+            let mut busy = false;
+            for _ in 0..keyspace.len() {
+                let shard = pool.next_shard(keyspace.len());
+                let locked = match keyspace.try_lock(&[shard], false) {
+                    Some(locked) => locked,
+                    None => {
+                        busy = true;
+                        continue;
+                    }
+                };
+                for db in 0..locked.databases() {
+                    for _ in 0..samples {
+                        let random = pool.random();
+                        if let Some((key, entry)) = locked.sample(shard, db, random) {
+                            pool.offer(Candidate {
+                                idle: tracking.idle(entry.lru()),
+                                shard,
+                                db,
+                                key: key.clone(),
+                            });
+                        }
+                    }
+                }
+                if !pool.candidates.is_empty() {
+                    break;
+                }
+            }
+            if pool.candidates.is_empty() {
+                return if busy { Outcome::Busy } else { Outcome::Nothing };
+            }
+            // Candidates deleted since they were sampled are skipped.
+            while let Some(candidate) = pool.candidates.pop() {
+                let mut locked = match keyspace.try_lock(&[candidate.shard], true) {
+                    Some(locked) => locked,
+                    None => {
+                        pool.candidates.push(candidate);
+                        return Outcome::Busy;
+                    }
+                };
+                if let Some(value) = locked.remove(candidate.db, &candidate.key) {
+                    free(value, lazyfree);
+                    return Outcome::Evicted;
+                }
+            }
+        }
+    }
+
+    fn evict_random(&self, keyspace: &Keyspace, lazyfree: Option<&LazyFree>) -> Outcome {
+        let mut pool = self.pool.lock().unwrap();
+        for _ in 0..keyspace.len() {
+            let shard = pool.next_shard(keyspace.len());
+            let mut locked = match keyspace.try_lock(&[shard], true) {
+                Some(locked) => locked,
+                None => return Outcome::Busy,
+            };
+            for db in 0..locked.databases() {
+                let random = pool.random();
+                let key = match locked.sample(shard, db, random) {
+                    Some((key, _)) => key.clone(),
+                    None => continue,
+                };
+                if let Some(value) = locked.remove(db, &key) {
+                    free(value, lazyfree);
+                }
+                return Outcome::Evicted;
+            }
+        }
+        Outcome::Nothing
+    }
+}
+
+fn free(value: Value, lazyfree: Option<&LazyFree>) {
+    if let Some(lazyfree) = lazyfree {
+        lazyfree.free_value(value);
+    }
+}
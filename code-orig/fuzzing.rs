@@ -0,0 +1,67 @@
+// Entry points for the fuzz targets in fuzz/, built with the fuzzing
+// feature. Each takes arbitrary bytes and panics only on a bug.
+
+use buffer::Buffer;
+use resp::{self, Parser};
+
+const MAX_BULK: usize = 512 * 1024 * 1024;
+
+// Parses data as requests twice, all at once and split into fragments
+// whose sizes come from the first byte, and checks both yield the same
+// commands and end the same way. Past PROTO_INLINE_MAX_SIZE they may not,
+// as a line too long to wait for can still arrive whole.
+pub fn requests(data: &[u8]) {
+    if data.is_empty() || data.len() > resp::PROTO_INLINE_MAX_SIZE {
+        return;
+    }
+    let step = data[0] as usize % 16 + 1;
+    let data = &data[1..];
+    let whole = parse(data, data.len().max(1));
+    let fragmented = parse(data, step);
+    assert_eq!(whole.0, fragmented.0);
+    assert_eq!(whole.1, fragmented.1);
+}
+
+// The commands found in data fed to a parser step bytes at a time, and
+// whether it stopped at a protocol error.
+fn parse(data: &[u8], step: usize) -> (Vec<Vec<Vec<u8>>>, bool) {
+    let mut parser = Parser::new();
+    let mut input = Buffer::new();
+    let mut commands = Vec::new();
+    for chunk in data.chunks(step) {
+        input.extend_from_slice(chunk);
+        loop {
+            match parser.next(input.as_slice(), MAX_BULK) {
+                Ok(Some(args)) => commands.push(args),
+                Ok(None) => break,
+                Err(_) => return (commands, true),
+            }
+        }
+        parser.consume(&mut input);
+    }
+    (commands, false)
+}
+
+// Splits data as one inline command, ending it with a newline if it has
+// none.
+pub fn inline(data: &[u8]) {
+    let mut line: Vec<u8> = match data.iter().position(|&b| b == b'\n') {
+        Some(n) => data[..n + 1].to_vec(),
+        None => data.to_vec(),
+    };
+    if line.last() != Some(&b'\n') {
+        line.push(b'\n');
+    }
+    if let Ok(args) = resp::take_inline_args(&line) {
+        assert!(args.iter().all(|arg| !arg.is_empty() || line.contains(&b'"') || line.contains(&b'\'')));
+    }
+}
+
+// Reads a quoted argument starting at every position of data.
+pub fn quoted(data: &[u8]) {
+    for i in 0..data.len() + 2 {
+        let (arg, end, _) = resp::parse_quoted_arg(data, i);
+        assert!(arg.len() <= data.len());
+        assert!(end >= i);
+    }
+}
@@ -0,0 +1,129 @@
+// Latency histogram in the spirit of HDR histograms.
+//
+// Values are nanoseconds, bucketed by their power of two and, within it,
+// into SUB_BUCKETS linear steps, so each is kept to within 1/SUB_BUCKETS of
+// itself over the whole range of a u64 in under 8KB. Percentiles report the
+// highest value of the bucket they fall in, never more than the largest
+// value recorded.
+
+const SUB_BITS: u32 = 4;
+const SUB_BUCKETS: u64 = 1 << SUB_BITS;
+const BUCKETS: usize = ((64 - SUB_BITS + 1) as usize) * SUB_BUCKETS as usize;
+
+pub struct Histogram {
+    counts: Vec<u64>,
+    total: u64,
+    max: u64,
+}
+
+impl Histogram {
+    pub fn new() -> Histogram {
+        Histogram {
+            counts: vec![0; BUCKETS],
+            total: 0,
+            max: 0,
+        }
+    }
+
+    pub fn record(&mut self, value: u64) {
+        self.counts[index(value)] += 1;
+        self.total += 1;
+        self.max = self.max.max(value);
+    }
+
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    // The value at or below which q percent of the recorded values fall.
+    pub fn percentile(&self, q: f64) -> u64 {
+        if self.total == 0 {
+            return 0;
+        }
+        let rank = ((q / 100.0 * self.total as f64).ceil() as u64).max(1).min(self.total);
+        let mut seen = 0;
+        for (i, &count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return highest(i).min(self.max);
+            }
+        }
+        self.max
+    }
+
+    // Cumulative counts at each power of two from 1 up, as (bound, count of
+    // values up to bound), for the bounds the count grows at.
+    pub fn cumulative(&self, unit: u64) -> Vec<(u64, u64)> {
+        let mut out = Vec::new();
+        let (mut seen, mut i) = (0, 0);
+        let mut bound: u64 = 1;
+        while seen < self.total {
+            while i < BUCKETS && highest(i) <= bound.saturating_mul(unit) {
+                seen += self.counts[i];
+                i += 1;
+            }
+            if out.last().map_or(seen > 0, |&(_, last)| seen > last) {
+                out.push((bound, seen));
+            }
+            bound *= 2;
+        }
+        out
+    }
+}
+
+fn index(value: u64) -> usize {
+    if value < SUB_BUCKETS {
+        return value as usize;
+    }
+    let shift = 63 - value.leading_zeros() - SUB_BITS;
+    let sub = (value >> shift) - SUB_BUCKETS;
+    ((shift as u64 + 1) * SUB_BUCKETS + sub) as usize
+}
+
+// The largest value that lands in bucket i.
+fn highest(i: usize) -> u64 {
+    let i = i as u64;
+    if i < SUB_BUCKETS {
+        return i;
+    }
+    let shift = i / SUB_BUCKETS - 1;
+    let low = (SUB_BUCKETS + i % SUB_BUCKETS) << shift;
+    low + ((1u64 << shift) - 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{highest, index, Histogram, BUCKETS};
+
+    #[test]
+    fn buckets_hold_their_values() {
+        for &value in &[0, 1, 15, 16, 17, 31, 32, 33, 1000, 123_456_789, u64::MAX] {
+            let i = index(value);
+            assert!(i < BUCKETS);
+            assert!(highest(i) >= value);
+            assert!(i == 0 || highest(i - 1) < value);
+        }
+    }
+
+    #[test]
+    fn percentiles_are_close() {
+        let mut h = Histogram::new();
+        for value in 1..=10_000 {
+            h.record(value * 1000);
+        }
+        for &(q, want) in &[(50.0, 5_000_000f64), (99.0, 9_900_000f64), (99.9, 9_990_000f64)] {
+            let got = h.percentile(q) as f64;
+            assert!(got >= want && got <= want * 1.07, "p{} = {}", q, got);
+        }
+        assert_eq!(h.percentile(100.0), 10_000_000);
+    }
+
+    #[test]
+    fn cumulative_counts_grow_to_the_total() {
+        let mut h = Histogram::new();
+        for &value in &[500, 900, 3_000, 3_500, 100_000] {
+            h.record(value);
+        }
+        assert_eq!(h.cumulative(1000), vec![(1, 2), (4, 4), (128, 5)]);
+    }
+}
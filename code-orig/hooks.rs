@@ -0,0 +1,28 @@
+// Connection lifecycle hooks.
+//
+// A program embedding the server can follow and steer what its clients do
+// by starting it with an implementation of Hooks. Every method has a
+// default that does nothing, so implementations only override the events
+// they care about. Hooks run on the worker serving the connection, in the
+// middle of its event loop, so they should be quick.
+
+pub trait Hooks: Send + Sync {
+    // A client connected from addr, which is empty for Unix sockets. The
+    // bytes returned are sent to it first, say a greeting, and true
+    // closes the connection once they are written.
+    fn on_connect(&self, _id: usize, _addr: &str) -> (Vec<u8>, bool) {
+        (Vec::new(), false)
+    }
+
+    // A command is about to run. Returning a RESP-encoded reply answers it
+    // with that instead, say an error refusing it.
+    fn on_command(&self, _id: usize, _args: &[Vec<u8>]) -> Option<Vec<u8>> {
+        None
+    }
+
+    // A command ran, and this is the reply it got. Commands answered by
+    // on_command don't come here.
+    fn on_reply(&self, _id: usize, _args: &[Vec<u8>], _reply: &[u8]) {}
+
+    fn on_disconnect(&self, _id: usize) {}
+}
@@ -0,0 +1,118 @@
+// Hot key detection.
+//
+// One in hotkeys-sample-ratio commands has its keys counted, in a table of
+// at most CAPACITY keys kept with the Space-Saving algorithm: a key that
+// isn't tracked while the table is full takes the place of the least
+// counted one, inheriting its count. The heaviest keys are found whatever
+// the number of distinct keys, their counts overestimated by at most the
+// count they inherited.
+//
+// Counting is done in windows of WINDOW. HOTKEYS reports rates over the
+// last complete window, or over the current one until there is one.
+
+use std::collections::HashMap;
+use std::mem;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const CAPACITY: usize = 128;
+const WINDOW: Duration = Duration::from_secs(10);
+
+struct Window {
+    started: Instant,
+    counts: HashMap<(usize, Vec<u8>), u64>,
+}
+
+impl Window {
+    fn new() -> Window {
+        Window {
+            started: Instant::now(),
+            counts: HashMap::new(),
+        }
+    }
+
+    fn add(&mut self, db: usize, key: &[u8]) {
+        if let Some(count) = self.counts.get_mut(&(db, key.to_vec())) {
+            *count += 1;
+            return;
+        }
+        let mut inherited = 0;
+        if self.counts.len() >= CAPACITY {
+            let least = self.counts.iter().min_by_key(|&(_, &count)| count).map(|(k, &count)| (k.clone(), count));
+            if let Some((least, count)) = least {
+                self.counts.remove(&least);
+                inherited = count;
+            }
+        }
+        self.counts.insert((db, key.to_vec()), inherited + 1);
+    }
+}
+
+pub struct HotKeys {
+    ratio: AtomicUsize,
+    seen: AtomicUsize,
+    windows: Mutex<(Window, Option<(Window, Duration)>)>,
+}
+
+impl HotKeys {
+    pub fn new(ratio: usize) -> HotKeys {
+        HotKeys {
+            ratio: AtomicUsize::new(ratio),
+            seen: AtomicUsize::new(0),
+            windows: Mutex::new((Window::new(), None)),
+        }
+    }
+
+    // Zero turns sampling off.
+    pub fn set_ratio(&self, ratio: usize) {
+        self.ratio.store(ratio, Ordering::Relaxed);
+    }
+
+    // Whether the command about to be counted is one of those sampled.
+    pub fn sampled(&self) -> bool {
+        let ratio = self.ratio.load(Ordering::Relaxed);
+        ratio > 0 && self.seen.fetch_add(1, Ordering::Relaxed).is_multiple_of(ratio)
+    }
+
+    pub fn record(&self, db: usize, keys: &[&Vec<u8>]) {
+        let mut windows = self.windows.lock().unwrap();
+        rotate(&mut windows);
+        for key in keys {
+            windows.0.add(db, key);
+        }
+    }
+
+    // Up to n of the hottest keys as (db, key, commands per second), the
+    // hottest first.
+    pub fn top(&self, n: usize) -> Vec<(usize, Vec<u8>, f64)> {
+        let ratio = self.ratio.load(Ordering::Relaxed).max(1) as f64;
+        let mut windows = self.windows.lock().unwrap();
+        rotate(&mut windows);
+        let (window, elapsed) = match windows.1 {
+            Some((ref window, elapsed)) => (window, elapsed),
+            None => (&windows.0, windows.0.started.elapsed()),
+        };
+        let secs = (elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9).max(1e-3);
+        let mut keys: Vec<(usize, Vec<u8>, f64)> = window
+            .counts
+            .iter()
+            .map(|(&(db, ref key), &count)| (db, key.clone(), count as f64 * ratio / secs))
+            .collect();
+        keys.sort_by(|a, b| b.2.partial_cmp(&a.2).unwrap().then_with(|| a.1.cmp(&b.1)));
+        keys.truncate(n);
+        keys
+    }
+}
+
+// Starts a new window once the current one has run for WINDOW. One left
+// running for twice that, as nothing was recorded or asked for, is too
+// stale to report and is dropped.
+fn rotate(windows: &mut (Window, Option<(Window, Duration)>)) {
+    let elapsed = windows.0.started.elapsed();
+    if elapsed < WINDOW {
+        return;
+    }
+    let done = mem::replace(&mut windows.0, Window::new());
+    windows.1 = if elapsed < WINDOW * 2 { Some((done, elapsed)) } else { None };
+}
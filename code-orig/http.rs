@@ -0,0 +1,158 @@
+// HTTP status endpoint.
+//
+// With http-port set, probes and load balancers that can't speak RESP get
+// a few read-only pages over plain HTTP:
+//
+//     /healthz  200 while the server runs, 503 once it is shutting down
+//     /readyz   200 while it can serve commands: not shutting down and, in
+//               cluster mode, with every slot covered
+//     /status   a JSON document of what INFO and CLUSTER INFO tell, with
+//               the latencystats percentiles of each command
+//
+// There is no dataset to load and no replication link, so neither holds
+// readiness back. Each request gets its answer and the connection closes.
+
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::io::{FromRawFd, IntoRawFd};
+use std::process;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use memory;
+use stream;
+use used_memory;
+use Server;
+
+// Bytes of request head read at most; the rest is never looked at.
+const MAX_HEAD: usize = 8192;
+
+pub fn start(server: Arc<Server>, bind: &str, port: usize, backlog: i32) -> Result<(), String> {
+    for listener in stream::bind_all(bind, port, backlog, false)? {
+        let listener = unsafe { TcpListener::from_raw_fd(listener.into_raw_fd()) };
+        listener.set_nonblocking(false).map_err(|e| e.to_string())?;
+        let server = server.clone();
+        spawn(move || accept(listener, server));
+    }
+    Ok(())
+}
+
+fn spawn<F: FnOnce() + Send + 'static>(f: F) {
+    thread::Builder::new().name("http".to_string()).spawn(f).unwrap();
+}
+
+fn accept(listener: TcpListener, server: Arc<Server>) {
+    for stream in listener.incoming().flatten() {
+        let server = server.clone();
+        spawn(move || serve(stream, &server));
+    }
+}
+
+fn serve(mut stream: TcpStream, server: &Server) {
+    let _ = stream.set_read_timeout(Some(Duration::from_secs(5)));
+    let mut head = Vec::new();
+    let mut buf = [0; 1024];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") && head.len() < MAX_HEAD {
+        match stream.read(&mut buf) {
+            Ok(0) | Err(_) => break,
+            Ok(n) => head.extend_from_slice(&buf[..n]),
+        }
+    }
+    let line = String::from_utf8_lossy(&head);
+    let mut words = line.split_whitespace();
+    let (method, path) = (words.next().unwrap_or(""), words.next().unwrap_or(""));
+    let (status, body) = if method != "GET" && method != "HEAD" {
+        ("405 Method Not Allowed", "method not allowed\n".to_string())
+    } else {
+        match path.split('?').next().unwrap_or("") {
+            "/healthz" if server.is_shutting_down() => ("503 Service Unavailable", "shutting down\n".to_string()),
+            "/healthz" => ("200 OK", "ok\n".to_string()),
+            "/readyz" if ready(server) => ("200 OK", "ready\n".to_string()),
+            "/readyz" => ("503 Service Unavailable", "not ready\n".to_string()),
+            "/status" => ("200 OK", status(server)),
+            _ => ("404 Not Found", "not found\n".to_string()),
+        }
+    };
+    let kind = if body.starts_with('{') { "application/json" } else { "text/plain" };
+    let mut reply = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        kind,
+        body.len()
+    );
+    if method != "HEAD" {
+        reply.push_str(&body);
+    }
+    let _ = stream.write_all(reply.as_bytes());
+}
+
+fn ready(server: &Server) -> bool {
+    !server.is_shutting_down() && server.cluster.as_ref().is_none_or(|c| c.state().is_ok())
+}
+
+fn status(server: &Server) -> String {
+    let (port, maxmemory, databases, percentiles) = {
+        let config = server.config.read().unwrap();
+        (config.port, config.maxmemory, config.databases, config.latency_tracking_info_percentiles.clone())
+    };
+    let store = server.store();
+    let keys: Vec<String> = (0..databases)
+        .map(|db| (db, store.len(db)))
+        .filter(|&(_, n)| n > 0)
+        .map(|(db, n)| format!("\"db{}\":{}", db, n))
+        .collect();
+    let latency: Vec<String> = server
+        .command_stats
+        .percentiles(&percentiles)
+        .into_iter()
+        .map(|(name, values)| {
+            let fields: Vec<String> = percentiles
+                .iter()
+                .zip(values.iter())
+                .map(|(q, v)| format!("\"p{}\":{:.3}", q, v))
+                .collect();
+            format!("{}:{{{}}}", quote(name), fields.join(","))
+        })
+        .collect();
+    let cluster = match server.cluster {
+        Some(ref cluster) => {
+            let state = if cluster.state().is_ok() { "ok" } else { "fail" };
+            format!("{{\"enabled\":true,\"state\":\"{}\",\"myid\":{}}}", state, quote(&cluster.myself()))
+        }
+        None => "{\"enabled\":false}".to_string(),
+    };
+    format!(
+        "{{\"server\":\"cache-server\",\"version\":{},\"pid\":{},\"port\":{},\"uptime_in_seconds\":{},\
+         \"role\":\"master\",\"ready\":{},\"shutting_down\":{},\"connected_clients\":{},\
+         \"used_memory\":{},\"used_memory_rss\":{},\"maxmemory\":{},\"keyspace\":{{{}}},\
+         \"latency_percentiles_usec\":{{{}}},\"cluster\":{}}}\n",
+        quote(env!("CARGO_PKG_VERSION")),
+        process::id(),
+        port,
+        server.started.elapsed().as_secs(),
+        ready(server),
+        server.is_shutting_down(),
+        server.clients.len(),
+        used_memory(server),
+        memory::rss(),
+        maxmemory,
+        keys.join(","),
+        latency.join(","),
+        cluster
+    )
+}
+
+fn quote(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
@@ -0,0 +1,558 @@
+// Sharded keyspace.
+//
+// Keys are spread over shards by hash, each shard holding its slice of every
+// database behind its own lock, so commands on keys in different shards run
+// in parallel. A key lives in the same shard in every database, which keeps
+// MOVE within one shard. Commands lock the shards they need in ascending
+// order, so two commands spanning several shards can never deadlock.
+//
+// How shards are locked is up to the Backend: "mutex" gives every command
+// exclusive access, "rwlock" lets read-only commands share a shard so
+// read-heavy workloads scale with cores. Commands only ever see a Locked
+// view and work the same with either.
+//
+// Commands waiting for shards held elsewhere sleep until a command lets go
+// of its shards rather than spinning on them.
+//
+// The keyspace also keeps, per shard, the bytes its databases hold in keys
+// and values and in their tables, so maxmemory can be checked without
+// locking anything, the coarse clock and Tracking entries record their
+// accesses with, and how many reads of a key found it.
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::ops::Deref;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use std::sync::{
+    Condvar, Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard, TryLockError,
+};
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+
+use compress::Value;
+use db::{Db, Entry, Tracking};
+use small::Key;
+
+pub const BACKENDS: &[&str] = &["mutex", "rwlock"];
+
+pub struct Shard {
+    dbs: Vec<Db>,
+}
+
+impl Shard {
+    fn new(databases: usize) -> Shard {
+        Shard {
+            dbs: (0..databases).map(|_| Db::new()).collect(),
+        }
+    }
+}
+
+pub enum Guard<'a> {
+    Exclusive(MutexGuard<'a, Shard>),
+    Read(RwLockReadGuard<'a, Shard>),
+    Write(RwLockWriteGuard<'a, Shard>),
+}
+
+impl<'a> Deref for Guard<'a> {
+    type Target = Shard;
+
+    fn deref(&self) -> &Shard {
+        match *self {
+            Guard::Exclusive(ref guard) => guard,
+            Guard::Read(ref guard) => guard,
+            Guard::Write(ref guard) => guard,
+        }
+    }
+}
+
+impl<'a> Guard<'a> {
+    fn get_mut(&mut self) -> &mut Shard {
+        match *self {
+            Guard::Exclusive(ref mut guard) => guard,
+            Guard::Write(ref mut guard) => guard,
+            Guard::Read(_) => panic!("shard written under a read lock"),
+        }
+    }
+}
+
+pub trait Backend: Send + Sync {
+    fn len(&self) -> usize;
+
+    // Tries to lock shard i, exclusively when write is set. None while
+    // someone else holds it in a conflicting way.
+    fn try_lock<'a>(&'a self, i: usize, write: bool) -> Option<Guard<'a>>;
+}
+
+struct MutexBackend {
+    shards: Vec<Mutex<Shard>>,
+}
+
+impl Backend for MutexBackend {
+    fn len(&self) -> usize {
+        self.shards.len()
+    }
+
+    fn try_lock<'a>(&'a self, i: usize, _write: bool) -> Option<Guard<'a>> {
+        match self.shards[i].try_lock() {
+            Ok(guard) => Some(Guard::Exclusive(guard)),
+            Err(TryLockError::WouldBlock) => None,
+            Err(TryLockError::Poisoned(e)) => panic!("shard lock poisoned: {}", e),
+        }
+    }
+}
+
+struct RwLockBackend {
+    shards: Vec<RwLock<Shard>>,
+}
+
+impl Backend for RwLockBackend {
+    fn len(&self) -> usize {
+        self.shards.len()
+    }
+
+    fn try_lock<'a>(&'a self, i: usize, write: bool) -> Option<Guard<'a>> {
+        if write {
+            match self.shards[i].try_write() {
+                Ok(guard) => Some(Guard::Write(guard)),
+                Err(TryLockError::WouldBlock) => None,
+                Err(TryLockError::Poisoned(e)) => panic!("shard lock poisoned: {}", e),
+            }
+        } else {
+            match self.shards[i].try_read() {
+                Ok(guard) => Some(Guard::Read(guard)),
+                Err(TryLockError::WouldBlock) => None,
+                Err(TryLockError::Poisoned(e)) => panic!("shard lock poisoned: {}", e),
+            }
+        }
+    }
+}
+
+pub struct Keyspace {
+    backend: Box<dyn Backend>,
+    databases: usize,
+    dataset: Vec<AtomicUsize>,
+    overhead: Vec<AtomicUsize>,
+    // Seconds since startup, advanced by tick().
+    clock: AtomicU32,
+    started: Instant,
+    lfu: AtomicBool,
+    lfu_log_factor: AtomicU32,
+    lfu_decay_time: AtomicU32,
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+    // Commands sleeping in lock(), woken through released when shards are
+    // let go of.
+    waiters: AtomicUsize,
+    released: Mutex<()>,
+    wake: Condvar,
+}
+
+impl Keyspace {
+    // Builds the keyspace on one of the BACKENDS.
+    pub fn new(backend: &str, shards: usize, databases: usize) -> Keyspace {
+        let backend: Box<dyn Backend> = match backend {
+            "rwlock" => Box::new(RwLockBackend {
+                shards: (0..shards).map(|_| RwLock::new(Shard::new(databases))).collect(),
+            }),
+            _ => Box::new(MutexBackend {
+                shards: (0..shards).map(|_| Mutex::new(Shard::new(databases))).collect(),
+            }),
+        };
+        Keyspace {
+            backend,
+            databases,
+            dataset: (0..shards).map(|_| AtomicUsize::new(0)).collect(),
+            overhead: (0..shards).map(|_| AtomicUsize::new(0)).collect(),
+            clock: AtomicU32::new(0),
+            started: Instant::now(),
+            lfu: AtomicBool::new(false),
+            lfu_log_factor: AtomicU32::new(10),
+            lfu_decay_time: AtomicU32::new(1),
+            hits: AtomicUsize::new(0),
+            misses: AtomicUsize::new(0),
+            waiters: AtomicUsize::new(0),
+            released: Mutex::new(()),
+            wake: Condvar::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.backend.len()
+    }
+
+    // Bytes held by the keyspace: keys, values and tables of every database.
+    pub fn used(&self) -> usize {
+        (0..self.len()).map(|i| self.dataset(i) + self.overhead(i)).sum()
+    }
+
+    // Bytes held by keys and values in shard i.
+    pub fn dataset(&self, i: usize) -> usize {
+        self.dataset[i].load(Ordering::Relaxed)
+    }
+
+    // Bytes held by the tables of shard i.
+    pub fn overhead(&self, i: usize) -> usize {
+        self.overhead[i].load(Ordering::Relaxed)
+    }
+
+    // Brings the clock up to date. Called once per batch of commands rather
+    // than on every access, as a resolution of seconds is all eviction needs.
+    pub fn tick(&self) {
+        let now = self.started.elapsed().as_secs() as u32;
+        self.clock.store(now, Ordering::Relaxed);
+    }
+
+    // Moves entries of maps being rehashed until the deadline, skipping
+    // shards that are busy.
+    pub fn rehash(&self, deadline: Instant) {
+        for i in self.all() {
+            let mut locked = match self.try_lock(&[i], true) {
+                Some(locked) => locked,
+                None => continue,
+            };
+            for db in 0..self.databases {
+                while Instant::now() < deadline && locked.rehash(i, db, 100) {}
+            }
+        }
+    }
+
+    pub fn clock(&self) -> u32 {
+        self.clock.load(Ordering::Relaxed)
+    }
+
+    // Counts a read of a key for a client, as a hit when the key was there.
+    pub fn count_lookup(&self, hit: bool) {
+        let counter = if hit { &self.hits } else { &self.misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn hits(&self) -> usize {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    pub fn misses(&self) -> usize {
+        self.misses.load(Ordering::Relaxed)
+    }
+
+    pub fn reset_lookups(&self) {
+        self.hits.store(0, Ordering::Relaxed);
+        self.misses.store(0, Ordering::Relaxed);
+    }
+
+    // Switches between recording accesses for the LRU and the LFU policies.
+    pub fn set_tracking(&self, lfu: bool, log_factor: usize, decay_time: usize) {
+        self.lfu.store(lfu, Ordering::Relaxed);
+        self.lfu_log_factor.store(log_factor as u32, Ordering::Relaxed);
+        self.lfu_decay_time.store(decay_time as u32, Ordering::Relaxed);
+    }
+
+    pub fn tracking(&self) -> Tracking {
+        if self.lfu.load(Ordering::Relaxed) {
+            Tracking::Lfu {
+                minutes: (self.clock() / 60) as u16,
+                log_factor: self.lfu_log_factor.load(Ordering::Relaxed),
+                decay_time: self.lfu_decay_time.load(Ordering::Relaxed),
+            }
+        } else {
+            Tracking::Lru(self.clock())
+        }
+    }
+
+    // Brings shard i's counters up to date after a change to one of its
+    // databases, given what the database held before.
+    fn account(&self, i: usize, before: (usize, usize), db: &Db) {
+        adjust(&self.dataset[i], before.0, db.used());
+        adjust(&self.overhead[i], before.1, db.overhead());
+    }
+
+    pub fn shard(&self, key: &[u8]) -> usize {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        (hasher.finish() % self.backend.len() as u64) as usize
+    }
+
+    // Sorted, deduplicated shards of the given keys.
+    pub fn shards_of(&self, keys: &[&Vec<u8>]) -> Vec<usize> {
+        let mut shards: Vec<usize> = keys.iter().map(|key| self.shard(key)).collect();
+        shards.sort();
+        shards.dedup();
+        shards
+    }
+
+    pub fn all(&self) -> Vec<usize> {
+        (0..self.backend.len()).collect()
+    }
+
+    // Locks the given shards, which must be sorted, for writing or only
+    // reading. If one is held elsewhere the ones already taken are released
+    // again, so a caller waiting on a busy script never holds up anyone else.
+    pub fn try_lock<'a>(&'a self, shards: &[usize], write: bool) -> Option<Locked<'a>> {
+        match self.acquire(shards, write) {
+            Ok(locked) => Some(locked),
+            Err(taken) => {
+                if taken > 0 {
+                    self.released();
+                }
+                None
+            }
+        }
+    }
+
+    // Like try_lock, but sleeps up to timeout for the shards to be released
+    // when they are held elsewhere. None if they weren't got by then.
+    pub fn lock<'a>(&'a self, shards: &[usize], write: bool, timeout: Duration) -> Option<Locked<'a>> {
+        if let Some(locked) = self.try_lock(shards, write) {
+            return Some(locked);
+        }
+        self.waiters.fetch_add(1, Ordering::SeqCst);
+        // Trying again under the mutex means a release can't slip in
+        // between the try and the wait unnoticed.
+        let released = self.released.lock().unwrap();
+        let locked = match self.acquire(shards, write) {
+            Ok(locked) => Some(locked),
+            Err(taken) => {
+                if taken > 0 {
+                    self.wake.notify_all();
+                }
+                drop(self.wake.wait_timeout(released, timeout).unwrap());
+                None
+            }
+        };
+        self.waiters.fetch_sub(1, Ordering::SeqCst);
+        locked
+    }
+
+    // Locks the shards in order, or gives back the number taken before one
+    // was found held, which are released again.
+    fn acquire<'a>(&'a self, shards: &[usize], write: bool) -> Result<Locked<'a>, usize> {
+        let mut guards: Vec<Option<Guard>> = (0..self.backend.len()).map(|_| None).collect();
+        for (n, &i) in shards.iter().enumerate() {
+            match self.backend.try_lock(i, write) {
+                Some(guard) => guards[i] = Some(guard),
+                None => return Err(n),
+            }
+        }
+        Ok(Locked {
+            keyspace: self,
+            guards,
+            touching: true,
+        })
+    }
+
+    // Wakes the commands sleeping in lock() after shards were let go of.
+    fn released(&self) {
+        if self.waiters.load(Ordering::SeqCst) > 0 {
+            let _released = self.released.lock().unwrap();
+            self.wake.notify_all();
+        }
+    }
+}
+
+fn adjust(counter: &AtomicUsize, before: usize, after: usize) {
+    if after > before {
+        counter.fetch_add(after - before, Ordering::Relaxed);
+    } else {
+        counter.fetch_sub(before - after, Ordering::Relaxed);
+    }
+}
+
+// The shards a command holds. Key operations panic on a key whose shard
+// wasn't locked, or on a write to one only locked for reading, and
+// whole-database operations only see the locked shards, so commands
+// spanning the keyspace must lock all of them.
+pub struct Locked<'a> {
+    keyspace: &'a Keyspace,
+    guards: Vec<Option<Guard<'a>>>,
+    // Whether reads count as accesses, off for CLIENT NO-TOUCH clients.
+    touching: bool,
+}
+
+impl<'a> Drop for Locked<'a> {
+    fn drop(&mut self) {
+        self.guards.clear();
+        self.keyspace.released();
+    }
+}
+
+impl<'a> Locked<'a> {
+    pub fn databases(&self) -> usize {
+        self.keyspace.databases
+    }
+
+    pub fn set_touching(&mut self, touching: bool) {
+        self.touching = touching;
+    }
+
+    fn db(&self, db: usize, key: &[u8]) -> &Db {
+        match self.guards[self.keyspace.shard(key)] {
+            Some(ref shard) => &shard.dbs[db],
+            None => panic!("key accessed without locking its shard"),
+        }
+    }
+
+    fn db_mut(&mut self, db: usize, key: &[u8]) -> &mut Db {
+        match self.guards[self.keyspace.shard(key)] {
+            Some(ref mut shard) => &mut shard.get_mut().dbs[db],
+            None => panic!("key accessed without locking its shard"),
+        }
+    }
+
+    fn locked(&self, db: usize) -> impl Iterator<Item = &Db> {
+        self.guards
+            .iter()
+            .filter_map(move |g| g.as_ref().map(|shard| &shard.dbs[db]))
+    }
+
+    // The value as written, decompressed if it is stored compressed.
+    pub fn get(&self, db: usize, key: &[u8]) -> Option<Bytes> {
+        let value = if self.touching {
+            self.db(db, key).get(key, self.keyspace.tracking())
+        } else {
+            self.db(db, key).peek(key).map(|entry| entry.value())
+        };
+        value.map(|value| value.decoded())
+    }
+
+    // The key's entry, without counting the lookup as an access.
+    pub fn peek(&self, db: usize, key: &[u8]) -> Option<&Entry> {
+        self.db(db, key).peek(key)
+    }
+
+    pub fn contains_key(&self, db: usize, key: &[u8]) -> bool {
+        self.db(db, key).contains_key(key)
+    }
+
+    pub fn usage(&self, db: usize, key: &[u8]) -> Option<usize> {
+        self.db(db, key).usage(key)
+    }
+
+    pub fn insert(&mut self, db: usize, key: Vec<u8>, value: Value) -> Option<Value> {
+        let keyspace = self.keyspace;
+        let shard = keyspace.shard(&key);
+        let db = self.db_mut(db, &key);
+        let before = (db.used(), db.overhead());
+        let old = db.insert(key, value, keyspace.tracking());
+        keyspace.account(shard, before, db);
+        old
+    }
+
+    pub fn remove(&mut self, db: usize, key: &[u8]) -> Option<Value> {
+        let keyspace = self.keyspace;
+        let shard = keyspace.shard(key);
+        let db = self.db_mut(db, key);
+        let before = (db.used(), db.overhead());
+        let old = db.remove(key);
+        keyspace.account(shard, before, db);
+        old
+    }
+
+    // A random key of the database in a locked shard, with its entry.
+    pub fn sample(&self, shard: usize, db: usize, random: usize) -> Option<(&Key, &Entry)> {
+        match self.guards[shard] {
+            Some(ref shard) => shard.dbs[db].sample(random),
+            None => panic!("shard sampled without locking it"),
+        }
+    }
+
+    // Up to count keys of the database in a locked shard, from position from.
+    pub fn keys(&self, shard: usize, db: usize, from: usize, count: usize) -> &[Key] {
+        match self.guards[shard] {
+            Some(ref shard) => shard.dbs[db].keys(from, count),
+            None => panic!("shard listed without locking it"),
+        }
+    }
+
+    // A rehash step on the database in a locked shard. False once it isn't
+    // being rehashed.
+    pub fn rehash(&mut self, shard: usize, db: usize, n: usize) -> bool {
+        let keyspace = self.keyspace;
+        let db = match self.guards[shard] {
+            Some(ref mut guard) => &mut guard.get_mut().dbs[db],
+            None => panic!("shard rehashed without locking it"),
+        };
+        let before = (db.used(), db.overhead());
+        let rehashing = db.rehash(n);
+        keyspace.account(shard, before, db);
+        rehashing
+    }
+
+    pub fn len(&self, db: usize) -> usize {
+        self.locked(db).map(|d| d.len()).sum()
+    }
+
+    pub fn iter(&self, db: usize) -> impl Iterator<Item = (&[u8], &Value)> {
+        self.locked(db).flat_map(|d| d.iter())
+    }
+
+    pub fn clear(&mut self, db: usize) {
+        for (i, guard) in self.guards.iter_mut().enumerate() {
+            if let Some(ref mut shard) = *guard {
+                let db = &mut shard.get_mut().dbs[db];
+                let before = (db.used(), db.overhead());
+                db.clear();
+                self.keyspace.account(i, before, db);
+            }
+        }
+    }
+
+    // Swaps empty maps in for the database and returns the old ones.
+    pub fn take(&mut self, db: usize) -> Vec<Db> {
+        let keyspace = self.keyspace;
+        let mut taken = Vec::new();
+        for (i, guard) in self.guards.iter_mut().enumerate() {
+            if let Some(ref mut shard) = *guard {
+                let db = &mut shard.get_mut().dbs[db];
+                let old = db.take();
+                keyspace.account(i, (old.used(), old.overhead()), db);
+                taken.push(old);
+            }
+        }
+        taken
+    }
+
+    pub fn swap(&mut self, first: usize, second: usize) {
+        for shard in self.guards.iter_mut().filter_map(|g| g.as_mut()) {
+            shard.get_mut().dbs.swap(first, second);
+        }
+    }
+
+    pub fn used(&self, db: usize) -> usize {
+        self.locked(db).map(|d| d.used()).sum()
+    }
+
+    pub fn overhead(&self, db: usize) -> usize {
+        self.locked(db).map(|d| d.overhead()).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use super::Keyspace;
+
+    #[test]
+    fn waiters_wake_when_shards_are_released() {
+        let keyspace = Arc::new(Keyspace::new("mutex", 2, 1));
+        let held = keyspace.try_lock(&[0, 1], true).unwrap();
+        assert!(keyspace.lock(&[1], true, Duration::from_millis(10)).is_none());
+        let waiter = {
+            let keyspace = keyspace.clone();
+            thread::spawn(move || {
+                // Woken waiters go round again, as lock_shards does.
+                let started = Instant::now();
+                let locked = keyspace.lock(&[1], true, Duration::from_secs(10)).is_some()
+                    || keyspace.lock(&[1], true, Duration::from_secs(10)).is_some();
+                (locked, started.elapsed())
+            })
+        };
+        thread::sleep(Duration::from_millis(50));
+        drop(held);
+        let (locked, waited) = waiter.join().unwrap();
+        assert!(locked);
+        assert!(waited < Duration::from_secs(5), "waited {:?}", waited);
+    }
+}
@@ -0,0 +1,177 @@
+// Latency spike monitor.
+//
+// Any event that takes at least latency-monitor-threshold milliseconds is
+// recorded under its event class, keeping the most recent samples per class
+// for LATENCY HISTORY and the all time worst for LATENCY LATEST. A threshold
+// of zero disables monitoring entirely.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const HISTORY_LEN: usize = 160;
+
+pub struct Sample {
+    pub time: u64,
+    pub latency: u64,
+}
+
+pub struct Series {
+    pub samples: VecDeque<Sample>,
+    pub max: u64,
+}
+
+pub struct Monitor {
+    threshold_ms: AtomicUsize,
+    events: Mutex<HashMap<&'static str, Series>>,
+}
+
+impl Monitor {
+    pub fn new(threshold_ms: usize) -> Monitor {
+        Monitor {
+            threshold_ms: AtomicUsize::new(threshold_ms),
+            events: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn set_threshold(&self, threshold_ms: usize) {
+        self.threshold_ms.store(threshold_ms, Ordering::Relaxed);
+    }
+
+    pub fn threshold(&self) -> usize {
+        self.threshold_ms.load(Ordering::Relaxed)
+    }
+
+    // Records the event if it crossed the threshold. Samples landing in the
+    // same second are folded into one, keeping the larger latency.
+    pub fn observe(&self, event: &'static str, elapsed: Duration) {
+        let threshold = self.threshold();
+        let latency = elapsed.as_secs() * 1000 + elapsed.subsec_millis() as u64;
+        if threshold == 0 || latency < threshold as u64 {
+            return;
+        }
+        let time = unix_time();
+        let mut events = self.events.lock().unwrap();
+        let series = events.entry(event).or_insert_with(|| Series {
+            samples: VecDeque::new(),
+            max: 0,
+        });
+        if latency > series.max {
+            series.max = latency;
+        }
+        if let Some(last) = series.samples.back_mut() {
+            if last.time == time {
+                if latency > last.latency {
+                    last.latency = latency;
+                }
+                return;
+            }
+        }
+        series.samples.push_back(Sample {
+            time,
+            latency,
+        });
+        if series.samples.len() > HISTORY_LEN {
+            series.samples.pop_front();
+        }
+    }
+
+    // Returns (event, time, latest, max) for every event seen, by name.
+    pub fn latest(&self) -> Vec<(&'static str, u64, u64, u64)> {
+        let events = self.events.lock().unwrap();
+        let mut out: Vec<(&'static str, u64, u64, u64)> = events
+            .iter()
+            .filter_map(|(name, series)| {
+                series
+                    .samples
+                    .back()
+                    .map(|last| (*name, last.time, last.latency, series.max))
+            })
+            .collect();
+        out.sort_by(|a, b| a.0.cmp(b.0));
+        out
+    }
+
+    pub fn history(&self, event: &str) -> Vec<(u64, u64)> {
+        let events = self.events.lock().unwrap();
+        match events.get(event) {
+            Some(series) => series.samples.iter().map(|s| (s.time, s.latency)).collect(),
+            None => Vec::new(),
+        }
+    }
+
+    // Clears the named events, or every event when none are given, and
+    // returns how many were actually reset.
+    pub fn reset(&self, names: &[String]) -> usize {
+        let mut events = self.events.lock().unwrap();
+        if names.is_empty() {
+            let n = events.len();
+            events.clear();
+            return n;
+        }
+        let mut n = 0;
+        for name in names {
+            if events.remove(name.as_str()).is_some() {
+                n += 1;
+            }
+        }
+        n
+    }
+
+    // Renders the human readable LATENCY DOCTOR report.
+    pub fn doctor(&self) -> String {
+        if self.threshold() == 0 {
+            return "Latency monitoring is disabled. Use CONFIG SET latency-monitor-threshold \
+                    <milliseconds> to enable it.\n"
+                .to_string();
+        }
+        let events = self.events.lock().unwrap();
+        if events.is_empty() {
+            return "No latency spike was observed during the lifetime of this instance.\n"
+                .to_string();
+        }
+        let mut names: Vec<&&'static str> = events.keys().collect();
+        names.sort();
+        let mut report = String::from("Latency spikes were observed for the following events:\n\n");
+        for (i, name) in names.iter().enumerate() {
+            let series = &events[**name];
+            let total: u64 = series.samples.iter().map(|s| s.latency).sum();
+            let avg = total / series.samples.len() as u64;
+            report.push_str(&format!(
+                "{}. {}: {} latency spikes (average {}ms), worst all time event {}ms.\n",
+                i + 1,
+                name,
+                series.samples.len(),
+                avg,
+                series.max
+            ));
+        }
+        report.push_str("\nI have a few advices for you:\n\n");
+        if events.contains_key("command") {
+            report.push_str(
+                "- Slow commands were observed. Avoid O(N) commands such as KEYS or FLUSHALL \
+                 SYNC on large keyspaces, and keep scripts short.\n",
+            );
+        }
+        if events.contains_key("fast-command") {
+            report.push_str(
+                "- Commands flagged as fast were slow to run. This usually means the host is \
+                 overloaded or the process is being swapped out.\n",
+            );
+        }
+        report.push_str(&format!(
+            "- The current threshold is {}ms. Raise latency-monitor-threshold if these \
+             spikes are expected.\n",
+            self.threshold()
+        ));
+        report
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
@@ -0,0 +1,48 @@
+// Background reclamation of large values.
+//
+// Dropping a map with millions of entries can take long enough to stall a
+// worker's event loop, so callers that can afford to let go of a value hand
+// it to a dedicated thread which drops it there instead. The same goes for
+// single values removed by UNLINK or eviction once they are large enough
+// that freeing them costs more than the hand-off.
+
+use std::sync::mpsc::{channel, Sender};
+use std::sync::Mutex;
+use std::thread;
+
+use compress::Value;
+
+// Values below this size are dropped on the spot.
+const THRESHOLD: usize = 64 * 1024;
+
+pub struct LazyFree {
+    sender: Mutex<Sender<Box<dyn Send>>>,
+}
+
+impl LazyFree {
+    pub fn new() -> LazyFree {
+        let (sender, receiver) = channel::<Box<dyn Send>>();
+        thread::Builder::new()
+            .name("lazyfree".to_string())
+            .spawn(move || for value in receiver {
+                drop(value);
+            })
+            .unwrap();
+        LazyFree {
+            sender: Mutex::new(sender),
+        }
+    }
+
+    // Queues the value for dropping. If the thread is gone the value is
+    // simply dropped here.
+    pub fn free<T: Send + 'static>(&self, value: T) {
+        let _ = self.sender.lock().unwrap().send(Box::new(value));
+    }
+
+    // Drops a removed value, queueing it if it is large.
+    pub fn free_value(&self, value: Value) {
+        if value.len() >= THRESHOLD {
+            self.free(value);
+        }
+    }
+}
@@ -0,0 +1,4535 @@
+// The cache server as a library. Server::start() runs one from a Config,
+// serving clients from threads of its own, and Server::store() lets the
+// program embedding it read and write keys directly.
+
+extern crate bytes;
+extern crate chrono;
+extern crate crossbeam;
+extern crate libc;
+extern crate lz4_flex;
+extern crate mio;
+extern crate mlua;
+extern crate sha1_smol;
+extern crate socket2;
+extern crate zstd;
+extern crate rustls;
+extern crate ring;
+extern crate rustls_pemfile;
+extern crate tracing;
+#[cfg(feature = "tokio-backend")]
+extern crate tokio;
+#[cfg(test)]
+extern crate proptest;
+#[cfg(feature = "jemalloc")]
+extern crate tikv_jemalloc_ctl;
+#[cfg(feature = "jemalloc")]
+extern crate tikv_jemalloc_sys;
+#[cfg(feature = "jemalloc")]
+extern crate tikv_jemallocator;
+#[cfg(feature = "mimalloc")]
+extern crate libmimalloc_sys;
+#[cfg(feature = "mimalloc")]
+extern crate mimalloc;
+
+mod access;
+mod acl;
+mod alloc;
+mod buffer;
+mod bus;
+mod clients;
+mod cluster;
+mod cmdstats;
+mod commands;
+mod compress;
+mod config;
+mod cron;
+mod daemon;
+mod db;
+mod evict;
+#[cfg(feature = "fuzzing")]
+pub mod fuzzing;
+mod histogram;
+mod hooks;
+mod hotkeys;
+mod http;
+mod keyspace;
+mod latency;
+mod lazyfree;
+mod log;
+mod memory;
+mod module;
+mod pattern;
+mod proxy;
+mod pubsub;
+mod ratelimit;
+mod registry;
+mod resp;
+mod scripting;
+mod slab;
+mod small;
+mod store;
+mod stream;
+#[cfg(feature = "tokio-backend")]
+mod tokio_backend;
+#[cfg(target_os = "linux")]
+mod uring;
+#[cfg(target_os = "linux")]
+mod uring_backend;
+
+use std::io;
+use std::io::{Read, Write};
+use mio::{Events, Interest, Poll, Registry, Token, Waker};
+use std::collections::HashMap;
+use std::sync::{mpsc, Mutex, RwLock};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use std::net::{IpAddr, ToSocketAddrs};
+use std::os::unix::io::{AsRawFd, RawFd};
+use bytes::Bytes;
+
+pub use config::Config;
+pub use daemon::{daemonize, notify as sd_notify};
+pub use hooks::Hooks;
+pub use commands::CommandSpec;
+pub use keyspace::BACKENDS as KEYSPACE_BACKENDS;
+pub use module::ModuleContext;
+pub use registry::{register as register_command, Command, Context};
+pub use store::Store;
+
+// Poll tokens. Connections are polled under their client id, which counts
+// up from 1, while the waker and the listeners take tokens counting down
+// from the top of the range, so the two never meet.
+const WAKE_TOKEN: Token = Token(usize::MAX - 1);
+
+fn listener_token(i: usize) -> Token {
+    Token(WAKE_TOKEN.0 - 1 - i)
+}
+
+fn listener_index(token: Token, listeners: usize) -> Option<usize> {
+    if token.0 >= WAKE_TOKEN.0 {
+        return None;
+    }
+    let i = WAKE_TOKEN.0 - 1 - token.0;
+    if i < listeners {
+        Some(i)
+    } else {
+        None
+    }
+}
+
+// Event loops connections can be served from. The tokio backend is only
+// built with the tokio-backend feature, the io_uring one on Linux.
+pub fn io_backends() -> Vec<&'static str> {
+    let mut backends = vec!["mio"];
+    if cfg!(feature = "tokio-backend") {
+        backends.push("tokio");
+    }
+    if cfg!(target_os = "linux") {
+        backends.push("io_uring");
+    }
+    backends
+}
+
+struct Scripts {
+    scripts: HashMap<String, Vec<u8>>,
+    libraries: HashMap<String, scripting::Library>,
+}
+
+impl Scripts {
+    pub fn new() -> Scripts {
+        Scripts {
+            scripts: HashMap::new(),
+            libraries: HashMap::new(),
+        }
+    }
+}
+
+pub struct Server {
+    keyspace: keyspace::Keyspace,
+    scripts: Mutex<Scripts>,
+    config: RwLock<config::Config>,
+    clients: clients::Clients,
+    pause: clients::Pause,
+    watchdog: Arc<scripting::Watchdog>,
+    lazyfree: lazyfree::LazyFree,
+    evictor: evict::Evictor,
+    compressor: compress::Compressor,
+    // None unless cluster-enabled.
+    cluster: Option<Arc<cluster::Cluster>>,
+    latency: latency::Monitor,
+    command_stats: cmdstats::CommandStats,
+    hotkeys: hotkeys::HotKeys,
+    cron: cron::Cron,
+    log: log::Log,
+    startup_rss: usize,
+    started: Instant,
+    // Replication ID INFO reports. There are no replicas, so it only
+    // tells restarts apart.
+    replid: String,
+    active_expire: AtomicBool,
+    pubsub: pubsub::PubSub,
+    acl: acl::Acl,
+    shutdown: AtomicBool,
+    // Cleared by SHUTDOWN NOSAVE during a busy script: connections close
+    // without flushing what they are owed.
+    drain: AtomicBool,
+    next_id: AtomicUsize,
+    wakers: Vec<Waker>,
+    // The tokio backend's accept task, woken for shutdown.
+    accept_task: Mutex<Option<std::task::Waker>>,
+    // The thread serving connections until shutdown, taken by wait().
+    runner: Mutex<Option<thread::JoinHandle<()>>>,
+    hooks: Option<Arc<dyn hooks::Hooks>>,
+}
+
+impl Server {
+    // Binds the listeners and sets up what the configuration asks for, then
+    // serves connections from threads of its own until request_shutdown().
+    // Errors are the ones that keep the server from starting, such as a
+    // port already in use.
+    pub fn start(config: config::Config) -> Result<Arc<Server>, String> {
+        Server::launch(config, None)
+    }
+
+    // Like start(), calling hooks as clients connect, send commands and
+    // disconnect.
+    pub fn start_with_hooks(config: config::Config, hooks: Arc<dyn hooks::Hooks>) -> Result<Arc<Server>, String> {
+        Server::launch(config, Some(hooks))
+    }
+
+    fn launch(mut config: config::Config, hooks: Option<Arc<dyn hooks::Hooks>>) -> Result<Arc<Server>, String> {
+        let log = log::Log::open(&config.logfile, &config.loglevel)?;
+        #[cfg(target_os = "linux")]
+        {
+            if config.io_backend == "io_uring" {
+                uring_backend::check(config.tls_port, &config.proxy_protocol)?;
+            }
+        }
+
+        let tls = if config.tls_port != 0 {
+            Some(stream::server_config(
+                &config.tls_cert_file,
+                &config.tls_key_file,
+                &config.tls_ca_cert_file,
+                &config.tls_auth_clients,
+            )?)
+        } else {
+            None
+        };
+
+        // With reuseport every worker binds and accepts on its own TCP
+        // listeners; otherwise the main thread accepts and hands connections
+        // over. The Unix socket is always the main thread's.
+        let threads = config.threads;
+        let mut listeners = Vec::new();
+        let mut worker_listeners: Vec<Vec<stream::Listener>> = Vec::new();
+        // Port 0 is resolved by the first bind, so the other workers'
+        // listeners and CONFIG GET port see the port picked.
+        if config.reuseport {
+            for _ in 0..threads {
+                let bound = tcp_listeners(&config, &tls, true)?;
+                resolve_ports(&mut config, &bound);
+                worker_listeners.push(bound);
+            }
+        } else {
+            listeners = tcp_listeners(&config, &tls, false)?;
+            resolve_ports(&mut config, &listeners);
+            for _ in 0..threads {
+                worker_listeners.push(Vec::new());
+            }
+        }
+
+        if !config.unixsocket.is_empty() {
+            listeners.push(stream::bind_unix(&config.unixsocket, config.unixsocketperm)?);
+        }
+
+        // The tokio backend polls the sockets from its own runtime instead,
+        // and io_uring workers accept on their own but still wait on their
+        // poll's waker.
+        let io_backend = config.io_backend.clone();
+        let io_backend_name = io_backend.clone();
+        let mut main_poll = Poll::new().unwrap();
+        let mut child_polls = Vec::new();
+        let mut wakers = Vec::new();
+        if io_backend == "mio" {
+            for (i, listener) in listeners.iter_mut().enumerate() {
+                main_poll
+                    .registry()
+                    .register(listener, listener_token(i), Interest::READABLE)
+                    .unwrap();
+            }
+        }
+        if io_backend != "tokio" {
+            for _ in 0..threads {
+                child_polls.push(Poll::new().unwrap());
+            }
+            for poll in Some(&main_poll).into_iter().chain(child_polls.iter()) {
+                wakers.push(Waker::new(poll.registry(), WAKE_TOKEN).unwrap());
+            }
+        }
+
+        let unixsocket = config.unixsocket.clone();
+        let pidfile = config.pidfile.clone();
+        let cluster = if config.cluster_enabled {
+            let cluster = cluster::Cluster::open(
+                &config.cluster_config_file,
+                config.port,
+                &config.cluster_announce_ip,
+                config.cluster_node_timeout as u64,
+            )?;
+            let cluster = Arc::new(cluster);
+            bus::start(cluster.clone(), &config.bind, config.tcp_backlog as i32)?;
+            Some(cluster)
+        } else {
+            None
+        };
+        // Modules go first, so the default user's +@all takes in their
+        // commands. One already loaded by another server in the process
+        // is loaded once.
+        for line in &config.loadmodule {
+            let words: Vec<String> = line.split_whitespace().map(|w| w.to_string()).collect();
+            if words.is_empty() || module::list().iter().any(|m| m.path == words[0]) {
+                continue;
+            }
+            module::load(&words[0], &words[1..])?;
+            log.notice("module-loaded", &[("path", &words[0])]);
+        }
+        let acl = acl::Acl::new();
+        if !config.aclfile.is_empty() {
+            acl.load(&config.aclfile)?;
+            log.notice("aclfile-loaded", &[("path", &config.aclfile)]);
+        }
+        let port = config.port;
+        let (bind, http_port, backlog) = (config.bind.clone(), config.http_port, config.tcp_backlog as i32);
+        let mut handoff = Vec::new();
+        let mut accepted = Vec::new();
+        for _ in 0..threads {
+            let (sender, receiver) = mpsc::channel();
+            handoff.push(sender);
+            accepted.push(receiver);
+        }
+        let server = Arc::new(Server {
+            keyspace: keyspace::Keyspace::new(&config.keyspace_backend, config.shards, config.databases),
+            scripts: Mutex::new(Scripts::new()),
+            clients: clients::Clients::new(),
+            pause: clients::Pause::new(),
+            watchdog: Arc::new(scripting::Watchdog::new(config.lua_time_limit)),
+            lazyfree: lazyfree::LazyFree::new(),
+            evictor: evict::Evictor::new(),
+            compressor: compress::Compressor::new(),
+            cluster,
+            latency: latency::Monitor::new(config.latency_monitor_threshold),
+            command_stats: cmdstats::CommandStats::new(config.latency_tracking),
+            hotkeys: hotkeys::HotKeys::new(config.hotkeys_sample_ratio),
+            cron: cron::Cron::new(config.hz),
+            log,
+            config: RwLock::new(config),
+            startup_rss: memory::rss(),
+            started: Instant::now(),
+            replid: cluster::random_id(),
+            active_expire: AtomicBool::new(true),
+            pubsub: pubsub::PubSub::new(),
+            acl,
+            shutdown: AtomicBool::new(false),
+            drain: AtomicBool::new(true),
+            next_id: AtomicUsize::new(0),
+            wakers,
+            accept_task: Mutex::new(None),
+            runner: Mutex::new(None),
+            hooks,
+        });
+
+        if http_port != 0 {
+            http::start(server.clone(), &bind, http_port, backlog)?;
+        }
+        if !pidfile.is_empty() {
+            daemon::write_pidfile(&pidfile)?;
+        }
+        let cron = server.clone();
+        thread::Builder::new()
+            .name("cron".to_string())
+            .spawn(move || {
+                while !cron.shutdown.load(Ordering::SeqCst) {
+                    server_cron(&cron);
+                    thread::sleep(cron.cron.interval());
+                }
+            })
+            .map_err(|e| e.to_string())?;
+        let serving = server.clone();
+        let runner = thread::Builder::new()
+            .name("cache-server".to_string())
+            .spawn(move || {
+                let server = serving;
+                match io_backend.as_str() {
+                    #[cfg(feature = "tokio-backend")]
+                    "tokio" => tokio_backend::run(
+                        threads,
+                        listeners.into_iter().chain(worker_listeners.into_iter().flatten()).collect(),
+                        server.clone(),
+                    ),
+                    // Every io_uring worker accepts on the shared listeners
+                    // as well as its own.
+                    #[cfg(target_os = "linux")]
+                    "io_uring" => crossbeam::scope(|scope| {
+                        let workers = child_polls.into_iter().zip(worker_listeners.iter());
+                        for (worker, (poll, own)) in workers.enumerate() {
+                            let listeners = listeners.iter().chain(own.iter()).collect();
+                            let server = server.clone();
+                            scope.spawn(move || uring_backend::run(worker, listeners, poll, server));
+                        }
+                    }),
+                    _ => crossbeam::scope(|scope| {
+                        let workers = child_polls.into_iter().zip(worker_listeners).zip(accepted);
+                        for (worker, ((poll, listeners), accepted)) in workers.enumerate() {
+                            let server = server.clone();
+                            scope.spawn(move || child_loop(poll, worker, listeners, accepted, server));
+                        }
+                        main_loop(&mut main_poll, &handoff, &listeners, &server)
+                    }),
+                }
+                if !unixsocket.is_empty() {
+                    let _ = std::fs::remove_file(&unixsocket);
+                }
+                if !pidfile.is_empty() {
+                    daemon::remove_pidfile(&pidfile);
+                }
+                server.log.notice("stopped", &[]);
+            })
+            .map_err(|e| e.to_string())?;
+        *server.runner.lock().unwrap() = Some(runner);
+        server.log.notice("ready", &[("port", &port), ("io-backend", &io_backend_name), ("threads", &threads)]);
+        Ok(server)
+    }
+
+    // Blocks until the server has shut down and every connection is closed.
+    pub fn wait(&self) {
+        let runner = self.runner.lock().unwrap().take();
+        if let Some(runner) = runner {
+            let _ = runner.join();
+        }
+    }
+
+    // The TCP port clients connect to, the one picked when configured as 0.
+    pub fn port(&self) -> usize {
+        self.config.read().unwrap().port
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutdown.load(Ordering::SeqCst)
+    }
+
+    // Reads and writes keys in-process, without going through a client.
+    pub fn store(&self) -> store::Store<'_> {
+        store::Store::new(self)
+    }
+
+    // Opens the logfile again, so it can be rotated by renaming it and
+    // then calling this.
+    pub fn reopen_log(&self) -> Result<(), String> {
+        self.log.reopen()?;
+        self.log.notice("log-reopened", &[]);
+        Ok(())
+    }
+
+    // Asks the accept loop and every worker to stop. Workers flush the
+    // replies they still owe before closing their connections.
+    pub fn request_shutdown(&self) {
+        if !self.shutdown.swap(true, Ordering::SeqCst) {
+            self.log.notice("shutdown-requested", &[]);
+        }
+        for waker in &self.wakers {
+            let _ = waker.wake();
+        }
+        if let Some(task) = self.accept_task.lock().unwrap().clone() {
+            task.wake();
+        }
+        for client in self.clients.list() {
+            let task = client.lock().unwrap().task.clone();
+            if let Some(task) = task {
+                task.wake();
+            }
+        }
+    }
+
+    // Shuts down without draining, stopping the running script so its
+    // worker can return to the event loop.
+    fn abort_shutdown(&self) {
+        self.drain.store(false, Ordering::SeqCst);
+        self.watchdog.abort();
+        self.request_shutdown();
+    }
+
+    // How long shutdown waits for slow readers to take their replies.
+    fn shutdown_timeout(&self) -> Duration {
+        if !self.drain.load(Ordering::SeqCst) {
+            return Duration::from_secs(0);
+        }
+        Duration::from_secs(self.config.read().unwrap().shutdown_timeout as u64)
+    }
+
+    // Queues an out-of-band frame, encoded for the receiver's protocol
+    // version, and wakes the worker owning the connection to deliver it.
+    fn push<F: Fn(u8) -> Vec<u8>>(&self, id: usize, frame: F) -> bool {
+        let client = match self.clients.get(id) {
+            Some(client) => client,
+            None => return false,
+        };
+        let (worker, task) = {
+            let mut client = client.lock().unwrap();
+            let frame = frame(client.resp);
+            client.pushes.extend(frame);
+            (client.worker, client.task.clone())
+        };
+        self.wake(worker, task);
+        true
+    }
+
+    // Has the worker owning the connection close it, once the replies it
+    // already owes are out.
+    fn kill_client(&self, id: usize) {
+        if let Some(client) = self.clients.get(id) {
+            let (worker, task) = {
+                let mut client = client.lock().unwrap();
+                client.kill = true;
+                (client.worker, client.task.clone())
+            };
+            self.wake(worker, task);
+        }
+    }
+
+    // Closes the connections logged in as any of the users.
+    fn kill_users(&self, names: &[String]) {
+        let ids: Vec<usize> = self
+            .clients
+            .list()
+            .iter()
+            .map(|client| client.lock().unwrap())
+            .filter(|client| names.contains(&client.user))
+            .map(|client| client.id)
+            .collect();
+        for id in ids {
+            self.kill_client(id);
+        }
+    }
+
+    fn wake(&self, worker: usize, task: Option<std::task::Waker>) {
+        match task {
+            Some(task) => task.wake(),
+            None => {
+                let _ = self.wakers[1 + worker].wake();
+            }
+        }
+    }
+
+    fn unregister_client(&self, id: usize) {
+        if let Some(client) = self.clients.get(id) {
+            let client = client.lock().unwrap();
+            for channel in &client.channels {
+                self.pubsub.unsubscribe(id, channel);
+            }
+            for pattern in &client.patterns {
+                self.pubsub.punsubscribe(id, pattern);
+            }
+        }
+        self.clients.unregister(id);
+    }
+}
+
+struct Conn {
+    stream: stream::Stream,
+    addr: String,
+    client: Arc<Mutex<clients::Client>>,
+    input: buffer::Buffer,
+    parser: resp::Parser,
+    output: buffer::Output,
+    close: bool,
+    paused: bool,
+    peer_checked: bool,
+    // Waiting for the PROXY protocol header that precedes the client's bytes.
+    proxy: bool,
+    // When queued replies last went over the soft output buffer limit.
+    obuf_soft_since: Option<Instant>,
+}
+
+impl AsRawFd for Conn {
+    fn as_raw_fd(&self) -> RawFd {
+        self.stream.as_raw_fd()
+    }
+}
+
+// Binds the plain and TLS listeners on every bind address.
+// Records the ports listeners asked to bind port 0 were given.
+fn resolve_ports(config: &mut config::Config, listeners: &[stream::Listener]) {
+    for listener in listeners {
+        let (port, addr) = match *listener {
+            stream::Listener::Plain(ref l) => (&mut config.port, l.local_addr()),
+            stream::Listener::Tls(ref l, _) => (&mut config.tls_port, l.local_addr()),
+            stream::Listener::Unix(_) => continue,
+        };
+        if *port == 0 {
+            if let Ok(addr) = addr {
+                *port = addr.port() as usize;
+            }
+        }
+    }
+}
+
+fn tcp_listeners(
+    config: &config::Config,
+    tls: &Option<Arc<rustls::ServerConfig>>,
+    reuseport: bool,
+) -> Result<Vec<stream::Listener>, String> {
+    let backlog = config.tcp_backlog as i32;
+    let mut listeners: Vec<stream::Listener> = stream::bind_all(&config.bind, config.port, backlog, reuseport)?
+        .into_iter()
+        .map(stream::Listener::Plain)
+        .collect();
+    if let Some(ref tls) = *tls {
+        let bound = stream::bind_all(&config.bind, config.tls_port, backlog, reuseport)?;
+        listeners.extend(bound.into_iter().map(|l| stream::Listener::Tls(l, tls.clone())));
+    }
+    Ok(listeners)
+}
+
+// One run of the cron, hz times a second.
+fn server_cron(server: &Server) {
+    server.keyspace.tick();
+    if server.config.read().unwrap().activerehashing {
+        server.keyspace.rehash(Instant::now() + Duration::from_millis(1));
+    }
+    server.cron.sample();
+    close_idle_clients(server);
+}
+
+// Closes clients idle for longer than timeout seconds. Subscribers are
+// left alone, as they wait for messages without sending anything.
+fn close_idle_clients(server: &Server) {
+    let timeout = server.config.read().unwrap().timeout as u64;
+    if timeout == 0 {
+        return;
+    }
+    let mut idle = Vec::new();
+    for client in server.clients.list() {
+        let client = client.lock().unwrap();
+        if !client.kill && client.subscriptions() == 0
+            && client.last_interaction.elapsed() > Duration::from_secs(timeout)
+        {
+            idle.push(client.id);
+        }
+    }
+    for id in idle {
+        server.log.verbose("client-timeout", &[("id", &id)]);
+        server.kill_client(id);
+    }
+}
+
+// Waits for events. A signal interrupting the wait just means there are
+// none this time.
+fn wait(poll: &mut Poll, events: &mut Events, timeout: Option<Duration>) {
+    match poll.poll(events, timeout) {
+        Ok(()) => {}
+        Err(ref e) if e.kind() == io::ErrorKind::Interrupted => events.clear(),
+        Err(e) => panic!("encountered IO error: {}", e),
+    }
+}
+
+// Accepts on the main thread's listeners and hands each connection to its
+// worker over that worker's channel, waking it to pick the connection up.
+fn main_loop(
+    main_poll: &mut Poll,
+    handoff: &[mpsc::Sender<(usize, Conn)>],
+    listeners: &[stream::Listener],
+    server: &Arc<Server>,
+) {
+    let mut events = Events::with_capacity(1024);
+
+    loop {
+        wait(main_poll, &mut events, None);
+        if server.shutdown.load(Ordering::SeqCst) {
+            return;
+        }
+
+        for event in events.iter() {
+            let i = match listener_index(event.token(), listeners.len()) {
+                Some(i) => i,
+                None => continue,
+            };
+            // Readiness is edge triggered, so take every pending connection.
+            loop {
+                match accept_connection(&listeners[i], i, None, handoff.len(), server) {
+                    Ok(Some((id, conn))) => {
+                        let worker = conn.client.lock().unwrap().worker;
+                        if handoff[worker].send((id, conn)).is_ok() {
+                            let _ = server.wakers[1 + worker].wake();
+                        }
+                    }
+                    Ok(None) => {}
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                    Err(e) => panic!("encountered IO error: {}", e),
+                }
+            }
+        }
+    }
+}
+
+// Accepts a connection from the listener at index and sets up its client,
+// owned by the given worker or, without one, spread over the workers by id.
+// Ok(None) means the connection was turned away.
+fn accept_connection(
+    listener: &stream::Listener,
+    index: usize,
+    worker: Option<usize>,
+    workers: usize,
+    server: &Server,
+) -> io::Result<Option<(usize, Conn)>> {
+    let stream = listener.accept()?;
+    Ok(setup_connection(stream, index, worker, workers, server))
+}
+
+// Sets up the client of a freshly accepted stream, as accept_connection()
+// does. None means the connection was turned away.
+fn setup_connection(
+    mut stream: stream::Stream,
+    index: usize,
+    worker: Option<usize>,
+    workers: usize,
+    server: &Server,
+) -> Option<(usize, Conn)> {
+    let _span = tracing::debug_span!("accept", listener = index).entered();
+    let (keepalive, nodelay, proxy) = {
+        let config = server.config.read().unwrap();
+        (config.tcp_keepalive, config.tcp_nodelay, expects_proxy(&config, &stream))
+    };
+    let keepalive = if keepalive > 0 {
+        Some(Duration::from_secs(keepalive as u64))
+    } else {
+        None
+    };
+    if let Err(e) = stream.set_keepalive(keepalive).and_then(|_| stream.set_nodelay(nodelay)) {
+        tracing::debug!(error = %e, "socket options not set");
+        return None;
+    }
+    // Behind a proxy the peer is the proxy itself, so admission waits for
+    // the header to learn the client's address.
+    let mut ip = None;
+    if !proxy {
+        match admit(stream.peer_ip(), server) {
+            Ok(counted) => ip = counted,
+            Err(refusal) => {
+                refuse(&mut stream, refusal);
+                return None;
+            }
+        }
+    }
+
+    let id = server.next_id.fetch_add(1, Ordering::SeqCst) + 1;
+    let worker = match worker {
+        Some(worker) => worker,
+        None => id % workers,
+    };
+    let (addr, laddr) = stream.addrs();
+    let mut client = clients::Client::new(id, worker, addr.clone(), laddr, stream.as_raw_fd());
+    client.unix = stream.tcp().is_none();
+    client.listener = index;
+    client.ip = ip;
+    client.authenticated = server.acl.auto_auth();
+    let client = server.clients.register(client);
+    Some((
+        id,
+        Conn {
+            stream,
+            addr,
+            client,
+            close: false,
+            paused: false,
+            peer_checked: false,
+            proxy,
+            obuf_soft_since: None,
+            input: buffer::Buffer::new(),
+            parser: resp::Parser::new(),
+            output: buffer::Output::new(),
+        },
+    ))
+}
+
+// Protected mode only lets loopback and Unix socket clients in while the
+// default user can log in without a password.
+fn is_protected(ip: Option<IpAddr>, server: &Server) -> bool {
+    ip.is_some_and(|ip| !ip.is_loopback()) && server.config.read().unwrap().protected_mode
+        && server.acl.auto_auth()
+}
+
+const PROTECTED_ERROR: &[u8] = b"-DENIED Running in protected mode because protected mode is enabled and no password is set for the default user. In this mode connections are only accepted from the loopback interface. To accept connections from outside, set a password for the default user with ACL SETUSER, or disable protected mode with CONFIG SET protected-mode no.\r\n";
+
+// Decides whether a client from ip may connect: protected mode, then
+// allow-from and deny-from, then max-connections-per-ip, which counts the
+// client in when it may. Returns the address it was counted under, none
+// for Unix socket clients, which are always let in.
+fn admit(ip: Option<IpAddr>, server: &Server) -> Result<Option<IpAddr>, &'static [u8]> {
+    let ip = match ip {
+        Some(ip) => ip,
+        None => return Ok(None),
+    };
+    if is_protected(Some(ip), server) {
+        tracing::debug!("refused by protected mode");
+        return Err(PROTECTED_ERROR);
+    }
+    let max = {
+        let config = server.config.read().unwrap();
+        if !access::allowed(ip, &config.allow_from, &config.deny_from) {
+            tracing::debug!(%ip, "refused by allow-from or deny-from");
+            return Err(b"-DENIED Connections from this address are not allowed\r\n");
+        }
+        config.max_connections_per_ip
+    };
+    if !server.clients.count_ip(ip, max) {
+        tracing::debug!(%ip, "refused by max-connections-per-ip");
+        return Err(b"-ERR max number of connections from this address reached\r\n");
+    }
+    Ok(Some(ip))
+}
+
+// Tells a refused client why before it is dropped, where that can be done
+// without a TLS handshake.
+fn refuse(stream: &mut stream::Stream, refusal: &[u8]) {
+    if let stream::Stream::Plain(_) = *stream {
+        let _ = stream.write(refusal);
+    }
+}
+
+// Whether connections accepted on the stream's local port start with a
+// PROXY protocol header.
+fn expects_proxy(config: &config::Config, stream: &stream::Stream) -> bool {
+    let port = match stream.tcp().and_then(|s| s.local_addr().ok()) {
+        Some(addr) => addr.port().to_string(),
+        None => return false,
+    };
+    config.proxy_protocol.split_whitespace().any(|p| p == port)
+}
+
+// Consumes the PROXY protocol header from the raw socket, underneath any TLS
+// session, and takes the client's addresses from it. The header is peeked
+// first so no byte past it is read. Returns false while it is incomplete.
+fn take_proxy_header(conn: &mut Conn, server: &Server) -> io::Result<bool> {
+    let mut packet = [0; 4096];
+    let header = {
+        let mut tcp = match conn.stream.tcp() {
+            Some(tcp) => tcp,
+            None => return Ok(true),
+        };
+        let n = tcp.peek(&mut packet)?;
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let header = match proxy::parse(&packet[..n]) {
+            Ok(Some(header)) => header,
+            Ok(None) if n < packet.len() => return Ok(false),
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid PROXY header")),
+        };
+        tcp.read_exact(&mut packet[..header.len])?;
+        header
+    };
+    conn.proxy = false;
+    if let Some(source) = header.source {
+        let mut client = conn.client.lock().unwrap();
+        client.addr = source.to_string();
+        if let Some(destination) = header.destination {
+            client.laddr = destination.to_string();
+        }
+        conn.addr = client.addr.clone();
+    }
+    let ip = header.source.map(|a| a.ip()).or(conn.stream.peer_ip());
+    match admit(ip, server) {
+        Ok(counted) => conn.client.lock().unwrap().ip = counted,
+        Err(refusal) => {
+            refuse(&mut conn.stream, refusal);
+            return Err(io::ErrorKind::PermissionDenied.into());
+        }
+    }
+    Ok(true)
+}
+
+fn child_loop(
+    mut child_poll: Poll,
+    worker: usize,
+    mut listeners: Vec<stream::Listener>,
+    accepted: mpsc::Receiver<(usize, Conn)>,
+    server: Arc<Server>,
+) {
+    let mut streams: HashMap<usize, Conn> = HashMap::new();
+    let mut pool = buffer::Pool::new();
+    let mut paused: Vec<usize> = Vec::new();
+    let mut events = Events::with_capacity(1024);
+
+    for (i, listener) in listeners.iter_mut().enumerate() {
+        child_poll
+            .registry()
+            .register(listener, listener_token(i), Interest::READABLE)
+            .unwrap();
+    }
+
+    loop {
+        // Connections held back by CLIENT PAUSE have no socket event to wake
+        // them, so poll with a short timeout and retry them when it fires.
+        let timeout = if paused.is_empty() {
+            None
+        } else {
+            Some(Duration::from_millis(10))
+        };
+        wait(&mut child_poll, &mut events, timeout);
+        if server.shutdown.load(Ordering::SeqCst) {
+            drain_connections(&mut streams, &server);
+            return;
+        }
+
+        for event in events.iter() {
+            let token = event.token();
+            if token == WAKE_TOKEN {
+                while let Ok((id, conn)) = accepted.try_recv() {
+                    open_connection(id, conn, &mut streams, &mut pool, child_poll.registry(), &server);
+                }
+                deliver_pushes(&mut streams, &mut pool, &server);
+                continue;
+            }
+            if let Some(i) = listener_index(token, listeners.len()) {
+                loop {
+                    match accept_connection(&listeners[i], i, Some(worker), 0, &server) {
+                        Ok(Some((id, conn))) => open_connection(
+                            id,
+                            conn,
+                            &mut streams,
+                            &mut pool,
+                            child_poll.registry(),
+                            &server,
+                        ),
+                        Ok(None) => {}
+                        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                        Err(e) => panic!("encountered IO error: {}", e),
+                    }
+                }
+                continue;
+            }
+            let id = token.0;
+
+            let mut close = false;
+
+            if let Some(conn) = streams.get_mut(&id) {
+                handle_existing_connection(conn, &mut close, id, &server);
+                settle(conn, &mut close);
+                if conn.paused && !paused.contains(&id) {
+                    paused.push(id);
+                }
+            }
+
+            if close {
+                close_connection(id, &mut streams, &mut pool, &server);
+            }
+        }
+
+        for id in std::mem::take(&mut paused) {
+            let mut close = false;
+            if let Some(conn) = streams.get_mut(&id) {
+                process_input(conn, id, &server);
+                settle(conn, &mut close);
+                if conn.paused {
+                    paused.push(id);
+                }
+            }
+            if close {
+                close_connection(id, &mut streams, &mut pool, &server);
+            }
+        }
+    }
+}
+
+// Flushes the replies still owed to each client, giving up on slow readers
+// once shutdown-timeout expires, then closes every connection.
+fn drain_connections(streams: &mut HashMap<usize, Conn>, server: &Server) {
+    let deadline = Instant::now() + server.shutdown_timeout();
+    for (id, mut conn) in streams.drain() {
+        loop {
+            let mut close = false;
+            write_output(&mut conn, &mut close);
+            if close || !pending(&conn) || Instant::now() >= deadline {
+                break;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        server.unregister_client(id);
+        event_closed(id, server);
+    }
+}
+
+// Flushes frames published to this worker's connections while they were
+// idle.
+fn deliver_pushes(streams: &mut HashMap<usize, Conn>, pool: &mut buffer::Pool, server: &Server) {
+    let mut closed = Vec::new();
+    for (&id, conn) in streams.iter_mut() {
+        let mut close = false;
+        take_pushes(conn);
+        check_output_limit(conn, server);
+        settle(conn, &mut close);
+        if close {
+            closed.push(id);
+        }
+    }
+    for id in closed {
+        close_connection(id, streams, pool, server);
+    }
+}
+
+// Drops the connection, keeping its buffers for the next one.
+fn close_connection(
+    id: usize,
+    streams: &mut HashMap<usize, Conn>,
+    pool: &mut buffer::Pool,
+    server: &Server,
+) {
+    if let Some(conn) = streams.remove(&id) {
+        pool.put(conn.input);
+    }
+    server.unregister_client(id);
+    event_closed(id, server);
+}
+
+fn take_pushes(conn: &mut Conn) {
+    let (pushes, kill) = {
+        let mut client = conn.client.lock().unwrap();
+        (std::mem::take(&mut client.pushes), client.kill)
+    };
+    conn.output.push(pushes.into());
+    if kill {
+        conn.close = true;
+    }
+}
+
+// Applies client-output-buffer-limit to the replies queued for the
+// connection. Past the hard limit, or above the soft limit for longer than
+// its grace period, the replies are dropped and the client disconnected.
+fn check_output_limit(conn: &mut Conn, server: &Server) {
+    let (class, limit) = output_limit(&conn.client, server);
+    let len = conn.output.len();
+    let soft_exceeded = if limit.soft > 0 && len > limit.soft {
+        let since = *conn.obuf_soft_since.get_or_insert_with(Instant::now);
+        since.elapsed() >= Duration::from_secs(limit.soft_seconds)
+    } else {
+        conn.obuf_soft_since = None;
+        false
+    };
+    if (limit.hard > 0 && len > limit.hard) || soft_exceeded {
+        let id = conn.client.lock().unwrap().id;
+        server.log.warning(
+            "output-limit",
+            &[("id", &id), ("addr", &conn.addr), ("class", &class), ("bytes", &len)],
+        );
+        conn.output.clear();
+        conn.close = true;
+    }
+}
+
+fn output_limit(client: &Mutex<clients::Client>, server: &Server) -> (&'static str, config::OutputLimit) {
+    let class = if client.lock().unwrap().subscriptions() > 0 {
+        "pubsub"
+    } else {
+        "normal"
+    };
+    (class, server.config.read().unwrap().output_limit(class))
+}
+
+// Writes queued replies until they are all out or the socket is full.
+fn write_output(conn: &mut Conn, close: &mut bool) {
+    while !conn.output.is_empty() {
+        match conn.stream.write_vectored(&conn.output.slices()) {
+            Ok(0) => {
+                *close = true;
+                return;
+            }
+            Ok(n) => conn.output.consume(n),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return,
+            Err(_) => {
+                *close = true;
+                return;
+            }
+        }
+    }
+    if conn.stream.wants_write() {
+        match conn.stream.flush() {
+            Ok(()) => {}
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+            Err(_) => *close = true,
+        }
+    }
+}
+
+// Whether the connection still owes the socket bytes.
+fn pending(conn: &Conn) -> bool {
+    !conn.output.is_empty() || conn.stream.wants_write()
+}
+
+// Writes what the connection owes and closes it once a close it asked for
+// has gone out. Connections are registered for both directions, edge
+// triggered, so whatever the socket can't take yet goes out on the next
+// writable event.
+fn settle(conn: &mut Conn, close: &mut bool) {
+    write_output(conn, close);
+    if conn.close && !pending(conn) {
+        *close = true;
+    }
+}
+
+fn process_input(conn: &mut Conn, id: usize, server: &Arc<Server>) {
+    let backlog = conn.output.len();
+    let (output, conn_close, paused) =
+        event_data(id, &mut conn.input, &mut conn.parser, backlog, server, &conn.client);
+    for reply in output {
+        for segment in reply.segments {
+            conn.output.push(segment);
+        }
+    }
+    take_pushes(conn);
+    conn.close |= conn_close;
+    conn.paused = paused;
+    check_output_limit(conn, server);
+    let mut client = conn.client.lock().unwrap();
+    server.clients.set_buffers(&mut client, conn.input.capacity(), conn.output.capacity());
+}
+
+// Logs a TLS client in as the ACL user named by its certificate. The
+// handshake is complete by the time the first plaintext arrives, so this
+// runs once, on the first read.
+fn authenticate_peer(conn: &mut Conn, server: &Server) {
+    conn.peer_checked = true;
+    if server.config.read().unwrap().tls_auth_clients_user != "cn" {
+        return;
+    }
+    if let Some(cn) = conn.stream.peer_cn() {
+        if server.acl.get(&cn).is_some_and(|user| user.enabled) {
+            let mut client = conn.client.lock().unwrap();
+            client.user = cn;
+            client.authenticated = true;
+        }
+    }
+}
+
+// Replies written before reading on, when a pipeline produces this many.
+const COALESCE_LIMIT: usize = 64 * 1024;
+
+fn handle_existing_connection(
+    conn: &mut Conn,
+    close: &mut bool,
+    id: usize,
+    server: &Arc<Server>,
+) {
+    write_output(conn, close);
+    if *close {
+        return;
+    }
+
+    if conn.proxy && !conn.close {
+        match take_proxy_header(conn, server) {
+            Ok(true) => {}
+            Ok(false) => return,
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return,
+            Err(_) => {
+                *close = true;
+                return;
+            }
+        }
+    }
+
+    // Read until the socket runs dry, answering as we go. The replies to
+    // everything read are left for the caller to write in one go, unless
+    // COALESCE_LIMIT bytes of them pile up first: those are written right
+    // away, and if the socket can't take them all it has to drain before
+    // the client is served further. A TLS session may hold decrypted bytes
+    // the socket will never signal again, so stopping early only happens
+    // when a writable event is sure to bring us back here.
+    loop {
+        if *close || conn.close {
+            return;
+        }
+        if conn.output.len() >= COALESCE_LIMIT || conn.stream.wants_write() {
+            write_output(conn, close);
+            if pending(conn) {
+                return;
+            }
+            continue;
+        }
+        match conn.input.read_from(&mut conn.stream) {
+            Ok(0) => *close = true,
+            Ok(_) => {
+                // A client whose unparsed input outgrows the limit is
+                // dropped rather than buffered without bound.
+                let buffered = conn.input.len() + conn.parser.buffered();
+                if buffered > server.config.read().unwrap().client_query_buffer_limit {
+                    *close = true;
+                    return;
+                }
+                if !conn.peer_checked {
+                    authenticate_peer(conn, server);
+                }
+                if !conn.paused {
+                    process_input(conn, id, server);
+                }
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                conn.input.idle();
+                return;
+            }
+            Err(_) => *close = true,
+        }
+    }
+}
+
+fn open_connection(
+    id: usize,
+    mut conn: Conn,
+    streams: &mut HashMap<usize, Conn>,
+    pool: &mut buffer::Pool,
+    registry: &Registry,
+    server: &Arc<Server>,
+) {
+    registry
+        .register(&mut conn.stream, Token(id), Interest::READABLE | Interest::WRITABLE)
+        .unwrap();
+    conn.input = pool.take();
+    let (output, close) = event_opened(id, &conn.addr, server);
+    conn.output.push(output.into());
+    conn.close = close;
+    let mut close = false;
+    settle(&mut conn, &mut close);
+    if close {
+        server.unregister_client(id);
+        event_closed(id, server);
+    } else {
+        streams.insert(id, conn);
+    }
+}
+fn safe_line_from_string(s: String) -> String {
+    safe_line_from_slice(s.as_bytes())
+}
+
+fn safe_line_from_slice(s: &[u8]) -> String {
+    let mut out = Vec::new();
+    for &b in s {
+        if b < b' ' {
+            out.push(b' ')
+        } else {
+            out.push(b);
+        }
+    }
+    String::from_utf8_lossy(out.as_slice()).to_string()
+}
+
+fn arg_match(arg: &[u8], what: &str) -> bool {
+    arg.eq_ignore_ascii_case(what.as_bytes())
+}
+
+fn event_opened(id: usize, addr: &str, server: &Server) -> (Vec<u8>, bool) {
+    server.log.verbose("accept", &[("id", &id), ("addr", &addr)]);
+    tracing::info!(id, addr, "connection opened");
+    match server.hooks {
+        Some(ref hooks) => hooks.on_connect(id, addr),
+        None => (Vec::new(), false),
+    }
+}
+
+fn event_closed(id: usize, server: &Server) {
+    server.log.verbose("close", &[("id", &id)]);
+    tracing::info!(id, "connection closed");
+    if let Some(ref hooks) = server.hooks {
+        hooks.on_disconnect(id);
+    }
+}
+
+// Shards a command locks: those its keys hash to, every shard for commands
+// that span the keyspace or whose keys can't be known before they run, and
+// none for commands that never touch it.
+fn command_shards(args: &[Vec<u8>], server: &Server) -> Vec<usize> {
+    let spec = match commands::lookup(&args[0]) {
+        Some(spec) => spec,
+        None => return Vec::new(),
+    };
+    if spec.first_key > 0 && !spec.has_flag("movablekeys") {
+        return server.keyspace.shards_of(&spec.keys(args));
+    }
+    // KEYS and MEMORY BIGKEYS take the shards themselves, one at a time.
+    if spec.name == "keys" || (spec.name == "memory" && args.len() > 1 && arg_match(&args[1], "BIGKEYS")) {
+        return Vec::new();
+    }
+    if spec.has_flag("readonly") || spec.has_flag("write") || spec.has_flag("movablekeys")
+        || spec.name == "debug" || spec.name == "exec"
+    {
+        return server.keyspace.all();
+    }
+    Vec::new()
+}
+
+// Waits for the command's shards, giving up once a running script has gone
+// past its time limit so the caller can answer -BUSY instead of stalling.
+// Commands that lock no shard are refused too while the script is busy.
+fn lock_store<'a>(server: &'a Server, args: &[Vec<u8>]) -> Option<keyspace::Locked<'a>> {
+    let shards = command_shards(args, server);
+    let write = commands::lookup(&args[0]).is_none_or(|spec| !spec.has_flag("readonly"));
+    lock_shards(server, &shards, write)
+}
+
+// Sleeps until the shards are released. While a script runs the watchdog is
+// looked at every millisecond, otherwise now and then in case a script has
+// started since.
+fn lock_shards<'a>(server: &'a Server, shards: &[usize], write: bool) -> Option<keyspace::Locked<'a>> {
+    loop {
+        if server.watchdog.is_busy() {
+            return None;
+        }
+        let wait = if server.watchdog.is_running() { 1 } else { 100 };
+        if let Some(store) = server.keyspace.lock(shards, write, Duration::from_millis(wait)) {
+            return Some(store);
+        }
+    }
+}
+
+// The command table's name for the command, None when it isn't known.
+fn command_name(args: &[Vec<u8>]) -> Option<&'static str> {
+    commands::lookup(&args[0]).map(|spec| spec.name)
+}
+
+// The commands a busy script leaves room for. Returns the reply and whether
+// the connection closes.
+fn handle_busy_command(args: &[Vec<u8>], server: &Server) -> (Vec<u8>, bool) {
+    if args.len() == 2 && arg_match(&args[0], "SCRIPT") && arg_match(&args[1], "KILL") {
+        (server.watchdog.kill(), false)
+    } else if args.len() == 2 && arg_match(&args[0], "SHUTDOWN") && arg_match(&args[1], "NOSAVE") {
+        // Workers cannot drain while the script holds the shards, so this is
+        // the one shutdown path that skips draining.
+        server.abort_shutdown();
+        (Vec::new(), true)
+    } else {
+        (scripting::BUSY_ERROR.to_vec(), false)
+    }
+}
+
+fn event_data(
+    id: usize,
+    input: &mut buffer::Buffer,
+    parser: &mut resp::Parser,
+    mut backlog: usize,
+    server: &Arc<Server>,
+    client: &Mutex<clients::Client>,
+) -> (Vec<resp::Reply>, bool, bool) {
+    let _span = tracing::debug_span!("connection", id).entered();
+    let mut output = Vec::new();
+    let mut close = false;
+    let mut paused = false;
+    let mut commands = 0;
+    let (max_bulk, slower_than, backlog_limit, mut limits) = {
+        let config = server.config.read().unwrap();
+        let limits = ratelimit::Limits {
+            commands: config.client_max_commands_per_sec,
+            bytes: config.client_max_bytes_per_sec,
+        };
+        (
+            config.proto_max_bulk_len,
+            config.log_slower_than,
+            config.client_output_backlog_limit,
+            limits,
+        )
+    };
+    let user = client.lock().unwrap().user.clone();
+    let (max_commands, max_bytes) = server.acl.limits(&user);
+    if max_commands > 0 {
+        limits.commands = max_commands;
+    }
+    if max_bytes > 0 {
+        limits.bytes = max_bytes;
+    }
+    // Commands run as they are parsed, so the ones after a command that
+    // can't run yet are never taken from the parser.
+    loop {
+        let args = match parser.next(input.as_slice(), max_bulk) {
+            Ok(Some(args)) => args,
+            Ok(None) => break,
+            Err(err) => {
+                let err = format!("ERR Protocol error: {}", safe_line_from_string(err));
+                server.log.verbose("protocol-error", &[("id", &id), ("error", &err)]);
+                tracing::debug!(error = %err, "protocol error");
+                output.push(format!("-{}\r\n", err).into_bytes().into());
+                close = true;
+                break;
+            }
+        };
+        // Commands held back by CLIENT PAUSE or the rate limits, or while
+        // the connection's replies are backed up past
+        // client-output-backlog-limit, stay with the parser along with
+        // everything pipelined after them. The backlog is checked first so
+        // a held command isn't taken from the rate limits twice.
+        if (backlog_limit > 0 && backlog >= backlog_limit) || is_paused(&args, server)
+            || is_throttled(&args, limits, client)
+        {
+            parser.hold(args);
+            paused = true;
+            break;
+        }
+        if commands == 0 {
+            server.keyspace.tick();
+        }
+        commands += 1;
+        let (reply, quit) = run_command(id, &args, slower_than, server, client);
+        if let Some(reply) = reply {
+            backlog += reply.len();
+            output.push(reply);
+        }
+        if quit {
+            close = true;
+            break;
+        }
+    }
+    parser.consume(input);
+    tracing::trace!(commands, paused, "ran");
+    (output, close, paused)
+}
+
+// Runs one command for the connection, returning its reply unless CLIENT
+// REPLY suppresses it, and whether the connection closes after it.
+fn run_command(
+    id: usize,
+    args: &[Vec<u8>],
+    slower_than: usize,
+    server: &Arc<Server>,
+    client: &Mutex<clients::Client>,
+) -> (Option<resp::Reply>, bool) {
+    if let Some(ref hooks) = server.hooks {
+        if let Some(reply) = hooks.on_command(id, args) {
+            let send = client.lock().unwrap().take_reply();
+            return (if send { Some(reply.into()) } else { None }, false);
+        }
+    }
+    let _command = tracing::debug_span!(
+        "command",
+        name = %String::from_utf8_lossy(&args[0]).to_lowercase()
+    ).entered();
+    if let Some(reply) = queue_command(args, server, client) {
+        let send = client.lock().unwrap().take_reply();
+        return (if send { Some(reply.into()) } else { None }, false);
+    }
+    // Room is made before taking the command's shards, as eviction
+    // locks shards of its own.
+    let oom = !make_room(args, server);
+    let mut store = match lock_store(server, args) {
+        Some(store) => store,
+        None => {
+            let (reply, quit) = handle_busy_command(args, server);
+            server.command_stats.record(command_name(args), Duration::from_secs(0), true, &reply);
+            return (Some(reply.into()), quit);
+        }
+    };
+    let no_touch = {
+        let mut client = client.lock().unwrap();
+        client.touch(args);
+        client.no_touch
+    };
+    store.set_touching(!no_touch);
+    let start = Instant::now();
+    let denied = acl_check(args, server, client)
+        .or_else(|| cluster_redirect(args, &store, server, client));
+    let rejected = denied.is_some() || oom;
+    let (hout, write, hclose) = match denied {
+        Some(err) => (err.into(), false, false),
+        None if oom => (evict::OOM_ERROR.to_vec().into(), false, false),
+        None => command_reply(args, &mut store, server, client),
+    };
+    drop(store);
+    let elapsed = start.elapsed();
+    server.latency.observe(latency_event(args), elapsed);
+    let head = hout.segments.first().map_or(&[][..], |s| &s[..]);
+    server.command_stats.record(command_name(args), elapsed, rejected, head);
+    if !rejected {
+        server.cron.count_command();
+    }
+    if !rejected && server.hotkeys.sampled() {
+        // Like ACL checks, a call with the wrong arity has no keys to count.
+        if let Some(spec) = commands::lookup(&args[0]).filter(|spec| spec.arity_ok(args.len())) {
+            let db = client.lock().unwrap().db;
+            server.hotkeys.record(db, &spec.keys(args));
+        }
+    }
+    tracing::debug!(us = elapsed.as_micros() as u64, write, "command finished");
+    if server.log.enabled(log::Level::Debug) {
+        let command = String::from_utf8_lossy(&args[0]).to_lowercase();
+        server.log.debug("command", &[("id", &id), ("command", &command), ("us", &elapsed.as_micros())]);
+    }
+    if slower_than > 0 && elapsed >= Duration::from_micros(slower_than as u64) {
+        server.log.warning(
+            "slow-command",
+            &[
+                ("id", &id),
+                ("command", &String::from_utf8_lossy(&args[0]).to_lowercase()),
+                ("args", &(args.len() - 1)),
+                ("us", &elapsed.as_micros()),
+            ],
+        );
+    }
+    if let Some(ref hooks) = server.hooks {
+        let reply: Vec<u8> = hout.segments.iter().flat_map(|s| s.iter().cloned()).collect();
+        hooks.on_reply(id, args, &reply);
+    }
+    let send = client.lock().unwrap().take_reply();
+    (if send { Some(hout) } else { None }, hclose)
+}
+
+// The -MOVED, -ASK or -CLUSTERDOWN error a command on keys this node
+// doesn't serve gets in cluster mode. Whether the connection sent ASKING
+// is taken along the way, as it only holds for the command right after.
+fn cluster_redirect(
+    args: &[Vec<u8>],
+    store: &keyspace::Locked,
+    server: &Server,
+    client: &Mutex<clients::Client>,
+) -> Option<Vec<u8>> {
+    let cluster = server.cluster.as_ref()?;
+    let asking = {
+        let mut client = client.lock().unwrap();
+        let asking = client.asking;
+        client.asking = false;
+        asking
+    };
+    let spec = commands::lookup(&args[0])?;
+    if !spec.arity_ok(args.len()) {
+        return None;
+    }
+    let asking = asking || spec.has_flag("asking");
+    cluster.redirect(&spec.keys(args), asking, |key| store.contains_key(0, key))
+}
+
+// Inside MULTI, checks a command and queues it for EXEC. One that is
+// unknown, has the wrong number of arguments, is denied by the ACL, belongs
+// on another cluster node or finds the server out of memory is refused and
+// flags the transaction to abort. None for commands that run now, outside
+// MULTI or controlling the transaction itself.
+fn queue_command(args: &[Vec<u8>], server: &Server, client: &Mutex<clients::Client>) -> Option<Vec<u8>> {
+    client.lock().unwrap().multi.as_ref()?;
+    for now in &["EXEC", "DISCARD", "MULTI", "QUIT"] {
+        if arg_match(&args[0], now) {
+            return None;
+        }
+    }
+    let err = match commands::lookup(&args[0]) {
+        None => Some(unknown_command(&args[0])),
+        Some(spec) if !spec.arity_ok(args.len()) => Some(invalid_num_args(&args[0])),
+        Some(_) => acl_check(args, server, client)
+            .or_else(|| queued_redirect(args, server, client))
+            .or_else(|| if make_room(args, server) { None } else { Some(evict::OOM_ERROR.to_vec()) }),
+    };
+    let mut client = client.lock().unwrap();
+    client.touch(args);
+    match err {
+        Some(err) => {
+            client.multi_failed = true;
+            server.command_stats.record(command_name(args), Duration::from_secs(0), true, &err);
+            Some(err)
+        }
+        None => {
+            client.multi.as_mut().unwrap().push(args.to_vec());
+            Some(b"+QUEUED\r\n".to_vec())
+        }
+    }
+}
+
+// The cluster redirect a command being queued gets, taking its shards to
+// tell which keys of a migrating slot are still here.
+fn queued_redirect(args: &[Vec<u8>], server: &Server, client: &Mutex<clients::Client>) -> Option<Vec<u8>> {
+    server.cluster.as_ref()?;
+    match lock_store(server, args) {
+        Some(store) => cluster_redirect(args, &store, server, client),
+        None => Some(scripting::BUSY_ERROR.to_vec()),
+    }
+}
+
+// Evicts keys ahead of a command that may grow the dataset while it is over
+// maxmemory. False if the command has to be refused.
+fn make_room(args: &[Vec<u8>], server: &Server) -> bool {
+    match commands::lookup(&args[0]) {
+        Some(spec) if spec.has_flag("denyoom") => {}
+        _ => return true,
+    }
+    let (maxmemory, policy, samples, lazy) = {
+        let config = server.config.read().unwrap();
+        (
+            config.maxmemory,
+            config.maxmemory_policy.clone(),
+            config.maxmemory_samples,
+            config.lazyfree_lazy_eviction,
+        )
+    };
+    if maxmemory == 0 || used_memory(server) <= maxmemory {
+        return true;
+    }
+    // Client buffers count against maxmemory too, so the keyspace is left
+    // whatever they don't take.
+    let limit = maxmemory.saturating_sub(server.clients.buffers());
+    let lazyfree = if lazy { Some(&server.lazyfree) } else { None };
+    server.evictor.make_room(&server.keyspace, limit, &policy, samples, lazyfree)
+}
+
+// Memory maxmemory is held against: the keyspace and client buffers, both
+// from counters kept as they change.
+fn used_memory(server: &Server) -> usize {
+    server.keyspace.used() + server.clients.buffers()
+}
+
+// Checks the command against the connection's ACL user. Unauthenticated
+// connections may only run commands flagged no-auth.
+fn acl_check(
+    args: &[Vec<u8>],
+    server: &Server,
+    client: &Mutex<clients::Client>,
+) -> Option<Vec<u8>> {
+    let spec = commands::lookup(&args[0])?;
+    let (user, authenticated) = {
+        let client = client.lock().unwrap();
+        (client.user.clone(), client.authenticated)
+    };
+    if !authenticated {
+        if spec.has_flag("no-auth") {
+            return None;
+        }
+        return Some(b"-NOAUTH Authentication required.\r\n".to_vec());
+    }
+    match server.acl.check(&user, spec, args) {
+        Ok(()) => None,
+        Err(acl::Denied::Auth) => Some(b"-NOAUTH Authentication required.\r\n".to_vec()),
+        Err(acl::Denied::Command) => {
+            let mut name = spec.name.to_string();
+            if args.len() > 1 && clients::is_container(spec.name) {
+                name.push('|');
+                name.push_str(&String::from_utf8_lossy(&args[1]).to_lowercase());
+            }
+            Some(
+                format!(
+                    "-NOPERM User {} has no permissions to run the '{}' command\r\n",
+                    user, name
+                ).into_bytes(),
+            )
+        }
+        Err(acl::Denied::Key) => Some(b"-NOPERM No permissions to access a key\r\n".to_vec()),
+        Err(acl::Denied::Channel) => {
+            Some(b"-NOPERM No permissions to access a channel\r\n".to_vec())
+        }
+    }
+}
+
+fn latency_event(args: &[Vec<u8>]) -> &'static str {
+    match commands::lookup(&args[0]) {
+        Some(spec) if spec.has_flag("fast") => "fast-command",
+        _ => "command",
+    }
+}
+
+// CLIENT UNPAUSE always goes through, otherwise a PAUSE ALL could only end
+// by timing out.
+fn is_paused(args: &[Vec<u8>], server: &Server) -> bool {
+    if arg_match(&args[0], "CLIENT") && args.len() > 1 && arg_match(&args[1], "UNPAUSE") {
+        return false;
+    }
+    let write = match commands::lookup(&args[0]) {
+        Some(spec) => spec.may_write(),
+        None => false,
+    };
+    server.pause.blocks(write)
+}
+
+// Takes the command from the connection's rate limits, unless they are
+// used up for now.
+fn is_throttled(args: &[Vec<u8>], limits: ratelimit::Limits, client: &Mutex<clients::Client>) -> bool {
+    if limits.commands == 0 && limits.bytes == 0 {
+        return false;
+    }
+    let bytes = args.iter().map(|arg| arg.len()).sum();
+    !client.lock().unwrap().limiter.admit(limits, bytes)
+}
+
+fn make_bulk(bulk: &[u8]) -> Vec<u8> {
+    let mut resp = Vec::new();
+    resp.push(b'$');
+    resp.extend_from_slice(&bulk.len().to_string().into_bytes());
+    resp.push(b'\r');
+    resp.push(b'\n');
+    resp.extend(bulk);
+    resp.push(b'\r');
+    resp.push(b'\n');
+    resp
+}
+
+fn make_array(count: usize) -> Vec<u8> {
+    let mut resp = Vec::new();
+    resp.push(b'*');
+    resp.extend_from_slice(&count.to_string().into_bytes());
+    resp.push(b'\r');
+    resp.push(b'\n');
+    resp
+}
+
+fn unknown_command(cmd: &[u8]) -> Vec<u8> {
+    format!("-ERR unknown command '{}'\r\n", safe_line_from_slice(cmd)).into_bytes()
+}
+
+fn invalid_num_args(cmd: &[u8]) -> Vec<u8> {
+    format!(
+        "-ERR wrong number of arguments for '{}' command\r\n",
+        String::from_utf8_lossy(cmd)
+    ).into_bytes()
+        .to_vec()
+}
+
+fn parse_numkeys(args: &[Vec<u8>]) -> Result<usize, Vec<u8>> {
+    match String::from_utf8_lossy(&args[2]).parse::<i64>() {
+        Ok(n) if n < 0 => Err(b"-ERR Number of keys can't be negative\r\n".to_vec()),
+        Ok(n) if n as usize > args.len() - 3 => {
+            Err(b"-ERR Number of keys can't be greater than number of args\r\n".to_vec())
+        }
+        Ok(n) => Ok(n as usize),
+        Err(_) => Err(b"-ERR value is not an integer or out of range\r\n".to_vec()),
+    }
+}
+
+// Dispatches a redis.call from inside a script or function. Commands the
+// command table flags noscript are refused, as in Redis, and so are write
+// commands when the script runs read-only (FCALL_RO or a no-writes function).
+fn script_call(
+    args: &[Vec<u8>],
+    store: &mut keyspace::Locked,
+    server: &Server,
+    client: &Mutex<clients::Client>,
+    read_only: bool,
+) -> (Vec<u8>, bool) {
+    let spec = commands::lookup(&args[0]);
+    if spec.is_some_and(|spec| spec.has_flag("noscript")) {
+        return (
+            b"-ERR This Redis command is not allowed from script\r\n".to_vec(),
+            false,
+        );
+    }
+    if read_only && spec.is_some_and(|spec| spec.has_flag("write")) {
+        return (
+            b"-ERR Write commands are not allowed from read-only scripts.\r\n".to_vec(),
+            false,
+        );
+    }
+    if let Some(err) = acl_check(args, server, client) {
+        return (err, false);
+    }
+    // Scripts parse RESP2 replies only, whatever the caller negotiated.
+    let resp = std::mem::replace(&mut client.lock().unwrap().resp, 2);
+    let (out, write, _) = handle_command(args, store, server, client);
+    client.lock().unwrap().resp = resp;
+    (out, write)
+}
+
+fn handle_eval(
+    args: &[Vec<u8>],
+    store: &mut keyspace::Locked,
+    server: &Server,
+    client: &Mutex<clients::Client>,
+) -> (Vec<u8>, bool, bool) {
+    if args.len() < 3 {
+        return (invalid_num_args(&args[0]), false, false);
+    }
+    let numkeys = match parse_numkeys(args) {
+        Ok(n) => n,
+        Err(e) => return (e, false, false),
+    };
+    let script = if arg_match(&args[0], "EVAL") {
+        server
+            .scripts
+            .lock()
+            .unwrap()
+            .scripts
+            .insert(scripting::sha1hex(&args[1]), args[1].clone());
+        args[1].clone()
+    } else {
+        let sha = String::from_utf8_lossy(&args[1]).to_lowercase();
+        match server.scripts.lock().unwrap().scripts.get(&sha) {
+            Some(script) => script.clone(),
+            None => {
+                return (
+                    b"-NOSCRIPT No matching script. Please use EVAL.\r\n".to_vec(),
+                    false,
+                    false,
+                )
+            }
+        }
+    };
+
+    let (output, write) = scripting::eval(
+        &server.watchdog,
+        &script,
+        &args[3..3 + numkeys],
+        &args[3 + numkeys..],
+        |cargs| script_call(cargs, store, server, client, false),
+    );
+    (output, write, false)
+}
+
+fn handle_fcall(
+    args: &[Vec<u8>],
+    store: &mut keyspace::Locked,
+    server: &Server,
+    client: &Mutex<clients::Client>,
+) -> (Vec<u8>, bool, bool) {
+    if args.len() < 3 {
+        return (invalid_num_args(&args[0]), false, false);
+    }
+    let numkeys = match parse_numkeys(args) {
+        Ok(n) => n,
+        Err(e) => return (e, false, false),
+    };
+    let library = match server
+        .scripts
+        .lock()
+        .unwrap()
+        .libraries
+        .values()
+        .find(|lib| lib.function(&args[1]).is_some())
+    {
+        Some(library) => library.clone(),
+        None => return (b"-ERR Function not found\r\n".to_vec(), false, false),
+    };
+    let read_only = library
+        .function(&args[1])
+        .unwrap()
+        .flags
+        .iter()
+        .any(|f| f == "no-writes");
+    if arg_match(&args[0], "FCALL_RO") && !read_only {
+        return (
+            b"-ERR Can not execute a script with write flag using *_ro command.\r\n".to_vec(),
+            false,
+            false,
+        );
+    }
+
+    let (output, write) = scripting::fcall(
+        &server.watchdog,
+        &library,
+        &args[1],
+        &args[3..3 + numkeys],
+        &args[3 + numkeys..],
+        |cargs| script_call(cargs, store, server, client, read_only),
+    );
+    (output, write, false)
+}
+
+fn handle_function(
+    args: &[Vec<u8>],
+    server: &Server,
+    client: &Mutex<clients::Client>,
+) -> (Vec<u8>, bool, bool) {
+    if args.len() < 2 {
+        return (invalid_num_args(&args[0]), false, false);
+    }
+    let mut cache = server.scripts.lock().unwrap();
+    if arg_match(&args[1], "LOAD") && (args.len() == 3 || args.len() == 4) {
+        let replace = args.len() == 4;
+        if replace && !arg_match(&args[2], "REPLACE") {
+            return (b"-ERR syntax error\r\n".to_vec(), false, false);
+        }
+        let library = match scripting::load_library(&args[args.len() - 1]) {
+            Ok(library) => library,
+            Err(e) => return (format!("-{}\r\n", e).into_bytes(), false, false),
+        };
+        if !replace && cache.libraries.contains_key(&library.name) {
+            return (
+                format!("-ERR Library '{}' already exists\r\n", library.name).into_bytes(),
+                false,
+                false,
+            );
+        }
+        for (name, other) in cache.libraries.iter() {
+            if *name == library.name {
+                continue;
+            }
+            for f in &library.functions {
+                if other.function(f.name.as_bytes()).is_some() {
+                    return (
+                        format!("-ERR Function {} already exists\r\n", f.name).into_bytes(),
+                        false,
+                        false,
+                    );
+                }
+            }
+        }
+        let name = library.name.clone();
+        cache.libraries.insert(name.clone(), library);
+        (make_bulk(&name.into_bytes()), true, false)
+    } else if arg_match(&args[1], "DELETE") && args.len() == 3 {
+        match cache
+            .libraries
+            .remove(&String::from_utf8_lossy(&args[2]).to_string())
+        {
+            Some(_) => (b"+OK\r\n".to_vec(), true, false),
+            None => (b"-ERR Library not found\r\n".to_vec(), false, false),
+        }
+    } else if arg_match(&args[1], "FLUSH") && args.len() <= 3 {
+        if args.len() == 3 && !arg_match(&args[2], "ASYNC") && !arg_match(&args[2], "SYNC") {
+            return (b"-ERR syntax error\r\n".to_vec(), false, false);
+        }
+        cache.libraries.clear();
+        (b"+OK\r\n".to_vec(), true, false)
+    } else if arg_match(&args[1], "LIST") {
+        let mut pattern = None;
+        let mut withcode = false;
+        let mut i = 2;
+        while i < args.len() {
+            if arg_match(&args[i], "WITHCODE") {
+                withcode = true;
+            } else if arg_match(&args[i], "LIBRARYNAME") && i + 1 < args.len() {
+                i += 1;
+                pattern = Some(&args[i]);
+            } else {
+                return (b"-ERR syntax error\r\n".to_vec(), false, false);
+            }
+            i += 1;
+        }
+        let mut libraries: Vec<&scripting::Library> = cache
+            .libraries
+            .values()
+            .filter(|lib| pattern.is_none_or(|pat| pattern::matches(pat, lib.name.as_bytes(), true)))
+            .collect();
+        libraries.sort_by(|a, b| a.name.cmp(&b.name));
+        let resp = client.lock().unwrap().resp;
+        let mut output = make_array(libraries.len());
+        for lib in libraries {
+            output.extend(make_map(resp, if withcode { 4 } else { 3 }));
+            output.extend(make_bulk(b"library_name"));
+            output.extend(make_bulk(&lib.name.clone().into_bytes()));
+            output.extend(make_bulk(b"engine"));
+            output.extend(make_bulk(b"LUA"));
+            output.extend(make_bulk(b"functions"));
+            output.extend(make_array(lib.functions.len()));
+            for f in &lib.functions {
+                output.extend(make_map(resp, 3));
+                output.extend(make_bulk(b"name"));
+                output.extend(make_bulk(&f.name.clone().into_bytes()));
+                output.extend(make_bulk(b"description"));
+                output.extend(make_null(resp));
+                output.extend(make_bulk(b"flags"));
+                output.extend(make_set(resp, f.flags.len()));
+                for flag in &f.flags {
+                    output.extend(make_bulk(&flag.clone().into_bytes()));
+                }
+            }
+            if withcode {
+                output.extend(make_bulk(b"library_code"));
+                output.extend(make_bulk(&lib.code));
+            }
+        }
+        (output, false, false)
+    } else {
+        (
+            format!(
+                "-ERR unknown subcommand or wrong number of arguments for '{}'\r\n",
+                safe_line_from_slice(&args[1])
+            ).into_bytes(),
+            false,
+            false,
+        )
+    }
+}
+
+fn handle_script(args: &[Vec<u8>], server: &Server) -> (Vec<u8>, bool, bool) {
+    if args.len() < 2 {
+        return (invalid_num_args(&args[0]), false, false);
+    }
+    let mut cache = server.scripts.lock().unwrap();
+    if arg_match(&args[1], "LOAD") && args.len() == 3 {
+        let sha = scripting::sha1hex(&args[2]);
+        cache.scripts.insert(sha.clone(), args[2].clone());
+        (make_bulk(&sha.into_bytes()), false, false)
+    } else if arg_match(&args[1], "EXISTS") && args.len() > 2 {
+        let mut output = make_array(args.len() - 2);
+        for sha in &args[2..] {
+            let sha = String::from_utf8_lossy(sha).to_lowercase();
+            if cache.scripts.contains_key(&sha) {
+                output.extend_from_slice(b":1\r\n");
+            } else {
+                output.extend_from_slice(b":0\r\n");
+            }
+        }
+        (output, false, false)
+    } else if arg_match(&args[1], "FLUSH") && args.len() <= 3 {
+        if args.len() == 3 && !arg_match(&args[2], "ASYNC") && !arg_match(&args[2], "SYNC") {
+            return (b"-ERR syntax error\r\n".to_vec(), false, false);
+        }
+        cache.scripts.clear();
+        (b"+OK\r\n".to_vec(), false, false)
+    } else if arg_match(&args[1], "KILL") && args.len() == 2 {
+        (server.watchdog.kill(), false, false)
+    } else {
+        (
+            format!(
+                "-ERR unknown subcommand or wrong number of arguments for '{}'\r\n",
+                safe_line_from_slice(&args[1])
+            ).into_bytes(),
+            false,
+            false,
+        )
+    }
+}
+
+fn handle_config(
+    args: &[Vec<u8>],
+    server: &Server,
+    client: &Mutex<clients::Client>,
+) -> (Vec<u8>, bool, bool) {
+    if args.len() < 2 {
+        return (invalid_num_args(&args[0]), false, false);
+    }
+    if arg_match(&args[1], "GET") && args.len() > 2 {
+        let patterns: Vec<String> = args[2..]
+            .iter()
+            .map(|p| String::from_utf8_lossy(p).to_string())
+            .collect();
+        let pairs = server.config.read().unwrap().get(&patterns);
+        let mut output = make_map(client.lock().unwrap().resp, pairs.len());
+        for (name, value) in pairs {
+            output.extend(make_bulk(&name.into_bytes()));
+            output.extend(make_bulk(&value.into_bytes()));
+        }
+        (output, false, false)
+    } else if arg_match(&args[1], "SET") && args.len() > 2 && args.len().is_multiple_of(2) {
+        let pairs: Vec<(String, String)> = args[2..]
+            .chunks(2)
+            .map(|kv| {
+                (
+                    String::from_utf8_lossy(&kv[0]).to_string(),
+                    String::from_utf8_lossy(&kv[1]).to_string(),
+                )
+            })
+            .collect();
+        let mut config = server.config.write().unwrap();
+        match config.set(&pairs) {
+            Ok(()) => {
+                server.watchdog.set_time_limit(config.lua_time_limit);
+                server.latency.set_threshold(config.latency_monitor_threshold);
+                server.command_stats.set_tracking(config.latency_tracking);
+                server.hotkeys.set_ratio(config.hotkeys_sample_ratio);
+                server.cron.set_hz(config.hz);
+                server.log.set_level(&config.loglevel);
+                server.keyspace.set_tracking(
+                    config.maxmemory_policy.ends_with("-lfu"),
+                    config.lfu_log_factor,
+                    config.lfu_decay_time,
+                );
+                (b"+OK\r\n".to_vec(), false, false)
+            }
+            Err(e) => (format!("-{}\r\n", e).into_bytes(), false, false),
+        }
+    } else if arg_match(&args[1], "RESETSTAT") && args.len() == 2 {
+        server.command_stats.reset();
+        server.keyspace.reset_lookups();
+        server.cron.reset_commands();
+        (b"+OK\r\n".to_vec(), false, false)
+    } else {
+        (
+            format!(
+                "-ERR unknown subcommand or wrong number of arguments for '{}'\r\n",
+                safe_line_from_slice(&args[1])
+            ).into_bytes(),
+            false,
+            false,
+        )
+    }
+}
+
+fn make_command_info(spec: &commands::CommandSpec, resp: u8) -> Vec<u8> {
+    let mut output = make_array(10);
+    output.extend(make_bulk(spec.name.as_bytes()));
+    output.extend(format!(":{}\r\n", spec.arity).into_bytes());
+    output.extend(make_set(resp, spec.flags.len()));
+    for flag in spec.flags {
+        output.extend(format!("+{}\r\n", flag).into_bytes());
+    }
+    output.extend(format!(":{}\r\n", spec.first_key).into_bytes());
+    output.extend(format!(":{}\r\n", spec.last_key).into_bytes());
+    output.extend(format!(":{}\r\n", spec.step).into_bytes());
+    output.extend(make_set(resp, spec.categories.len()));
+    for category in spec.categories {
+        output.extend(format!("+{}\r\n", category).into_bytes());
+    }
+    output.extend(make_array(0));
+    output.extend(make_array(0));
+    output.extend(make_array(0));
+    output
+}
+
+fn handle_commands(args: &[Vec<u8>], client: &Mutex<clients::Client>) -> (Vec<u8>, bool, bool) {
+    let resp = client.lock().unwrap().resp;
+    let all = commands::all();
+    if args.len() == 1 {
+        let mut output = make_array(all.len());
+        for spec in all {
+            output.extend(make_command_info(spec, resp));
+        }
+        (output, false, false)
+    } else if arg_match(&args[1], "COUNT") && args.len() == 2 {
+        (
+            format!(":{}\r\n", all.len()).into_bytes(),
+            false,
+            false,
+        )
+    } else if arg_match(&args[1], "LIST") && args.len() == 2 {
+        let mut output = make_array(all.len());
+        for spec in all {
+            output.extend(make_bulk(spec.name.as_bytes()));
+        }
+        (output, false, false)
+    } else if arg_match(&args[1], "INFO") {
+        let mut output = make_array(args.len() - 2);
+        for name in &args[2..] {
+            match commands::lookup(name) {
+                Some(spec) => output.extend(make_command_info(spec, resp)),
+                None if resp == 3 => output.extend(make_null(resp)),
+                None => output.extend_from_slice(b"*-1\r\n"),
+            }
+        }
+        (output, false, false)
+    } else if arg_match(&args[1], "DOCS") {
+        let specs: Vec<&commands::CommandSpec> = if args.len() == 2 {
+            all
+        } else {
+            args[2..].iter().filter_map(|name| commands::lookup(name)).collect()
+        };
+        let mut output = make_map(resp, specs.len());
+        for spec in specs {
+            output.extend(make_bulk(spec.name.as_bytes()));
+            output.extend(make_map(resp, 2));
+            output.extend(make_bulk(b"summary"));
+            output.extend(make_bulk(spec.summary.as_bytes()));
+            output.extend(make_bulk(b"group"));
+            output.extend(make_bulk(spec.group.as_bytes()));
+        }
+        (output, false, false)
+    } else if arg_match(&args[1], "GETKEYS") && args.len() > 2 {
+        let spec = match commands::lookup(&args[2]) {
+            Some(spec) => spec,
+            None => return (b"-ERR Invalid command specified\r\n".to_vec(), false, false),
+        };
+        if !spec.arity_ok(args.len() - 2) {
+            return (
+                b"-ERR Invalid number of arguments specified for command\r\n".to_vec(),
+                false,
+                false,
+            );
+        }
+        let keys = spec.keys(&args[2..]);
+        if keys.is_empty() {
+            return (
+                b"-ERR The command has no key arguments\r\n".to_vec(),
+                false,
+                false,
+            );
+        }
+        let mut output = make_array(keys.len());
+        for key in keys {
+            output.extend(make_bulk(key));
+        }
+        (output, false, false)
+    } else {
+        (
+            format!(
+                "-ERR unknown subcommand or wrong number of arguments for '{}'\r\n",
+                safe_line_from_slice(&args[1])
+            ).into_bytes(),
+            false,
+            false,
+        )
+    }
+}
+
+fn handle_client(
+    args: &[Vec<u8>],
+    server: &Server,
+    client: &Mutex<clients::Client>,
+) -> (Vec<u8>, bool, bool) {
+    if args.len() < 2 {
+        return (invalid_num_args(&args[0]), false, false);
+    }
+    if arg_match(&args[1], "ID") && args.len() == 2 {
+        (
+            format!(":{}\r\n", client.lock().unwrap().id).into_bytes(),
+            false,
+            false,
+        )
+    } else if arg_match(&args[1], "INFO") && args.len() == 2 {
+        let client = client.lock().unwrap();
+        (make_verbatim(client.resp, client.info_line().as_bytes()), false, false)
+    } else if arg_match(&args[1], "LIST") {
+        let mut ids = None;
+        if args.len() > 2 {
+            if arg_match(&args[2], "ID") && args.len() > 3 {
+                let mut list = Vec::new();
+                for id in &args[3..] {
+                    match String::from_utf8_lossy(id).parse::<usize>() {
+                        Ok(id) => list.push(id),
+                        Err(_) => {
+                            return (b"-ERR Invalid client ID\r\n".to_vec(), false, false)
+                        }
+                    }
+                }
+                ids = Some(list);
+            } else if arg_match(&args[2], "TYPE") && args.len() == 4 {
+                if arg_match(&args[3], "NORMAL") {
+                } else if arg_match(&args[3], "MASTER") || arg_match(&args[3], "REPLICA")
+                    || arg_match(&args[3], "PUBSUB")
+                {
+                    ids = Some(Vec::new());
+                } else {
+                    return (
+                        format!(
+                            "-ERR Unknown client type '{}'\r\n",
+                            safe_line_from_slice(&args[3])
+                        ).into_bytes(),
+                        false,
+                        false,
+                    );
+                }
+            } else {
+                return (b"-ERR syntax error\r\n".to_vec(), false, false);
+            }
+        }
+        let mut list = String::new();
+        for other in server.clients.list() {
+            let other = other.lock().unwrap();
+            if ids.as_ref().is_none_or(|ids| ids.contains(&other.id)) {
+                list.push_str(&other.info_line());
+            }
+        }
+        let resp = client.lock().unwrap().resp;
+        (make_verbatim(resp, list.as_bytes()), false, false)
+    } else if arg_match(&args[1], "PAUSE") && (args.len() == 3 || args.len() == 4) {
+        let timeout = match String::from_utf8_lossy(&args[2]).parse::<u64>() {
+            Ok(timeout) => timeout,
+            Err(_) => {
+                return (
+                    b"-ERR timeout is not an integer or out of range\r\n".to_vec(),
+                    false,
+                    false,
+                )
+            }
+        };
+        let all = if args.len() == 3 || arg_match(&args[3], "ALL") {
+            true
+        } else if arg_match(&args[3], "WRITE") {
+            false
+        } else {
+            return (b"-ERR syntax error\r\n".to_vec(), false, false);
+        };
+        server.pause.pause(Duration::from_millis(timeout), all);
+        (b"+OK\r\n".to_vec(), false, false)
+    } else if arg_match(&args[1], "UNPAUSE") && args.len() == 2 {
+        server.pause.unpause();
+        (b"+OK\r\n".to_vec(), false, false)
+    } else if arg_match(&args[1], "REPLY") && args.len() == 3 {
+        let mode = if arg_match(&args[2], "ON") {
+            clients::ReplyMode::On
+        } else if arg_match(&args[2], "OFF") {
+            clients::ReplyMode::Off
+        } else if arg_match(&args[2], "SKIP") {
+            clients::ReplyMode::SkipNext
+        } else {
+            return (b"-ERR syntax error\r\n".to_vec(), false, false);
+        };
+        let mut client = client.lock().unwrap();
+        if client.reply != clients::ReplyMode::Off || mode != clients::ReplyMode::SkipNext {
+            client.reply = mode;
+        }
+        (b"+OK\r\n".to_vec(), false, false)
+    } else if (arg_match(&args[1], "NO-EVICT") || arg_match(&args[1], "NO-TOUCH")) && args.len() == 3 {
+        let on = if arg_match(&args[2], "ON") {
+            true
+        } else if arg_match(&args[2], "OFF") {
+            false
+        } else {
+            return (b"-ERR syntax error\r\n".to_vec(), false, false);
+        };
+        let mut client = client.lock().unwrap();
+        if arg_match(&args[1], "NO-EVICT") {
+            client.no_evict = on;
+        } else {
+            client.no_touch = on;
+        }
+        (b"+OK\r\n".to_vec(), false, false)
+    } else if arg_match(&args[1], "GETNAME") && args.len() == 2 {
+        let (name, resp) = {
+            let client = client.lock().unwrap();
+            (client.name.clone(), client.resp)
+        };
+        if name.is_empty() {
+            (make_null(resp), false, false)
+        } else {
+            (make_bulk(&name), false, false)
+        }
+    } else if arg_match(&args[1], "SETNAME") && args.len() == 3 {
+        if !clients::valid_name(&args[2]) {
+            return (
+                b"-ERR Client names cannot contain spaces, newlines or special characters.\r\n"
+                    .to_vec(),
+                false,
+                false,
+            );
+        }
+        client.lock().unwrap().name = args[2].clone();
+        (b"+OK\r\n".to_vec(), false, false)
+    } else {
+        (
+            format!(
+                "-ERR unknown subcommand or wrong number of arguments for '{}'\r\n",
+                safe_line_from_slice(&args[1])
+            ).into_bytes(),
+            false,
+            false,
+        )
+    }
+}
+
+const MODULE_DISABLED_ERROR: &[u8] = b"-ERR MODULE command not allowed. If the enable-module-command option is set to \"local\", you can run it from a local connection, otherwise you need to set this option in the configuration file, and then restart the server.\r\n";
+
+fn handle_module(
+    args: &[Vec<u8>],
+    server: &Server,
+    client: &Mutex<clients::Client>,
+) -> (Vec<u8>, bool, bool) {
+    if args.len() < 2 {
+        return (invalid_num_args(&args[0]), false, false);
+    }
+    let allowed = match server.config.read().unwrap().enable_module_command.as_str() {
+        "yes" => true,
+        "local" => {
+            let client = client.lock().unwrap();
+            client.unix || client.ip.is_some_and(|ip| ip.is_loopback())
+        }
+        _ => false,
+    };
+    if !allowed {
+        return (MODULE_DISABLED_ERROR.to_vec(), false, false);
+    }
+    if arg_match(&args[1], "LOAD") && args.len() > 2 {
+        let words: Vec<String> = args[2..]
+            .iter()
+            .map(|w| String::from_utf8_lossy(w).to_string())
+            .collect();
+        match module::load(&words[0], &words[1..]) {
+            Ok(()) => {
+                server.acl.refresh_commands();
+                (b"+OK\r\n".to_vec(), false, false)
+            }
+            Err(e) => (format!("-ERR {}\r\n", safe_line_from_string(e)).into_bytes(), false, false),
+        }
+    } else if arg_match(&args[1], "LIST") && args.len() == 2 {
+        let resp = client.lock().unwrap().resp;
+        let modules = module::list();
+        let mut output = make_array(modules.len());
+        for m in modules {
+            output.extend(make_map(resp, 3));
+            output.extend(make_bulk(b"name"));
+            output.extend(make_bulk(m.name.as_bytes()));
+            output.extend(make_bulk(b"path"));
+            output.extend(make_bulk(m.path.as_bytes()));
+            output.extend(make_bulk(b"args"));
+            output.extend(make_array(m.args.len()));
+            for arg in &m.args {
+                output.extend(make_bulk(arg.as_bytes()));
+            }
+        }
+        (output, false, false)
+    } else if arg_match(&args[1], "UNLOAD") && args.len() == 3 {
+        let name = String::from_utf8_lossy(&args[2]).to_string();
+        let err = if module::is_loaded(&name) {
+            "-ERR Error unloading module: modules can't be unloaded\r\n"
+        } else {
+            "-ERR Error unloading module: no such module with that name\r\n"
+        };
+        (err.as_bytes().to_vec(), false, false)
+    } else {
+        (
+            format!(
+                "-ERR unknown subcommand or wrong number of arguments for '{}'\r\n",
+                safe_line_from_slice(&args[1])
+            ).into_bytes(),
+            false,
+            false,
+        )
+    }
+}
+
+fn handle_latency(args: &[Vec<u8>], server: &Server, client: &Mutex<clients::Client>) -> (Vec<u8>, bool, bool) {
+    if args.len() < 2 {
+        return (invalid_num_args(&args[0]), false, false);
+    }
+    if arg_match(&args[1], "LATEST") && args.len() == 2 {
+        let latest = server.latency.latest();
+        let mut output = make_array(latest.len());
+        for (event, time, last, max) in latest {
+            output.extend(make_array(4));
+            output.extend(make_bulk(event.as_bytes()));
+            output.extend(format!(":{}\r\n:{}\r\n:{}\r\n", time, last, max).into_bytes());
+        }
+        (output, false, false)
+    } else if arg_match(&args[1], "HISTORY") && args.len() == 3 {
+        let history = server
+            .latency
+            .history(&String::from_utf8_lossy(&args[2]).to_lowercase());
+        let mut output = make_array(history.len());
+        for (time, latency) in history {
+            output.extend(make_array(2));
+            output.extend(format!(":{}\r\n:{}\r\n", time, latency).into_bytes());
+        }
+        (output, false, false)
+    } else if arg_match(&args[1], "RESET") {
+        let names: Vec<String> = args[2..]
+            .iter()
+            .map(|name| String::from_utf8_lossy(name).to_lowercase())
+            .collect();
+        (
+            format!(":{}\r\n", server.latency.reset(&names)).into_bytes(),
+            false,
+            false,
+        )
+    } else if arg_match(&args[1], "DOCTOR") && args.len() == 2 {
+        let resp = client.lock().unwrap().resp;
+        (make_verbatim(resp, server.latency.doctor().as_bytes()), false, false)
+    } else if arg_match(&args[1], "HISTOGRAM") {
+        // Unknown command names are left out, like commands never run.
+        let resp = client.lock().unwrap().resp;
+        let names: Vec<&'static str> = args[2..]
+            .iter()
+            .filter_map(|name| commands::lookup(name))
+            .map(|spec| spec.name)
+            .collect();
+        if names.is_empty() && args.len() > 2 {
+            return (make_map(resp, 0), false, false);
+        }
+        let histograms = server.command_stats.histograms(&names);
+        let mut output = make_map(resp, histograms.len());
+        for (name, calls, buckets) in histograms {
+            output.extend(make_bulk(name.as_bytes()));
+            output.extend(make_map(resp, 2));
+            output.extend(make_bulk(b"calls"));
+            output.extend(format!(":{}\r\n", calls).into_bytes());
+            output.extend(make_bulk(b"histogram_usec"));
+            output.extend(make_map(resp, buckets.len()));
+            for (bound, count) in buckets {
+                output.extend(format!(":{}\r\n:{}\r\n", bound, count).into_bytes());
+            }
+        }
+        (output, false, false)
+    } else {
+        (
+            format!(
+                "-ERR unknown subcommand or wrong number of arguments for '{}'\r\n",
+                safe_line_from_slice(&args[1])
+            ).into_bytes(),
+            false,
+            false,
+        )
+    }
+}
+
+// HOTKEYS [count]: the hottest keys, ten unless count says otherwise, each
+// as its name, database, shard and sampled commands per second.
+fn handle_hotkeys(
+    args: &[Vec<u8>],
+    server: &Server,
+    client: &Mutex<clients::Client>,
+) -> (Vec<u8>, bool, bool) {
+    let count = match args.len() {
+        1 => 10,
+        2 => match String::from_utf8_lossy(&args[1]).parse::<usize>() {
+            Ok(count) => count,
+            Err(_) => return (b"-ERR value is not an integer or out of range\r\n".to_vec(), false, false),
+        },
+        _ => return (invalid_num_args(&args[0]), false, false),
+    };
+    let keys = server.hotkeys.top(count);
+    let resp = client.lock().unwrap().resp;
+    let mut output = make_array(keys.len());
+    for (db, key, rate) in keys {
+        output.extend(make_array(4));
+        output.extend(make_bulk(&key));
+        output.extend(format!(":{}\r\n:{}\r\n", db, server.keyspace.shard(&key)).into_bytes());
+        output.extend(make_double(resp, rate, 2));
+    }
+    (output, false, false)
+}
+
+fn memory_stats(store: &keyspace::Locked, server: &Server) -> memory::Stats {
+    let clients = server.clients.len()
+        * (std::mem::size_of::<Conn>() + std::mem::size_of::<clients::Client>())
+        + server.clients.buffers();
+    let mut lua_caches = 0;
+    {
+        let scripts = server.scripts.lock().unwrap();
+        for (sha, script) in scripts.scripts.iter() {
+            lua_caches += memory::entry_usage(&sha.as_bytes().to_vec(), script);
+        }
+        for library in scripts.libraries.values() {
+            lua_caches += memory::alloc_size(library.code.len());
+        }
+    }
+    let mut dbs = Vec::new();
+    let mut dataset = 0;
+    for i in 0..store.databases() {
+        let len = store.len(i);
+        if len > 0 {
+            dbs.push((i, len, store.overhead(i)));
+        }
+        dataset += store.used(i);
+    }
+    let shards = (0..server.keyspace.len())
+        .map(|i| (i, server.keyspace.dataset(i), server.keyspace.overhead(i)))
+        .filter(|&(_, dataset, _)| dataset > 0)
+        .collect();
+    memory::Stats {
+        startup: server.startup_rss,
+        clients,
+        lua_caches,
+        dbs,
+        shards,
+        dataset,
+        slab_free: slab::free(),
+        rss: memory::rss(),
+        allocator: alloc::stats(),
+    }
+}
+
+fn make_stat(name: &str, value: usize) -> Vec<u8> {
+    let mut output = make_bulk(name.as_bytes());
+    output.extend(format!(":{}\r\n", value).into_bytes());
+    output
+}
+
+fn handle_memory(
+    args: &[Vec<u8>],
+    store: &keyspace::Locked,
+    server: &Server,
+    client: &Mutex<clients::Client>,
+) -> (Vec<u8>, bool, bool) {
+    if args.len() < 2 {
+        return (invalid_num_args(&args[0]), false, false);
+    }
+    if arg_match(&args[1], "USAGE") && args.len() >= 3 {
+        // Values are flat strings, so SAMPLES is validated but every byte is
+        // always counted.
+        if args.len() > 3 {
+            if args.len() != 5 || !arg_match(&args[3], "SAMPLES") {
+                return (b"-ERR syntax error\r\n".to_vec(), false, false);
+            }
+            if String::from_utf8_lossy(&args[4]).parse::<i64>().is_err() {
+                return (
+                    b"-ERR value is not an integer or out of range\r\n".to_vec(),
+                    false,
+                    false,
+                );
+            }
+        }
+        let (db, resp) = {
+            let client = client.lock().unwrap();
+            (client.db, client.resp)
+        };
+        match store.usage(db, &args[2]) {
+            Some(usage) => (
+                format!(":{}\r\n", usage).into_bytes(),
+                false,
+                false,
+            ),
+            None => (make_null(resp), false, false),
+        }
+    } else if arg_match(&args[1], "STATS") && args.len() == 2 {
+        let resp = client.lock().unwrap().resp;
+        let stats = memory_stats(store, server);
+        let total = stats.total();
+        let keys = stats.keys();
+        let mut body = Vec::new();
+        body.extend(make_stat("total.allocated", total));
+        body.extend(make_stat("startup.allocated", stats.startup));
+        body.extend(make_stat("replication.backlog", 0));
+        body.extend(make_stat("clients.slaves", 0));
+        body.extend(make_stat("clients.normal", stats.clients));
+        body.extend(make_stat("aof.buffer", 0));
+        body.extend(make_stat("lua.caches", stats.lua_caches));
+        body.extend(make_stat("slab.free", stats.slab_free));
+        for &(i, _, overhead) in &stats.dbs {
+            body.extend(make_bulk(&format!("db.{}", i).into_bytes()));
+            body.extend(make_map(resp, 2));
+            body.extend(make_stat("overhead.hashtable.main", overhead));
+            body.extend(make_stat("overhead.hashtable.expires", 0));
+        }
+        for &(i, dataset, overhead) in &stats.shards {
+            body.extend(make_bulk(&format!("shard.{}", i).into_bytes()));
+            body.extend(make_map(resp, 2));
+            body.extend(make_stat("dataset.bytes", dataset));
+            body.extend(make_stat("overhead.hashtable", overhead));
+        }
+        body.extend(make_stat("overhead.total", stats.overhead()));
+        body.extend(make_stat("keys.count", keys));
+        body.extend(make_stat(
+            "keys.bytes-per-key",
+            (total - stats.startup).checked_div(keys).unwrap_or(0),
+        ));
+        body.extend(make_stat("dataset.bytes", stats.dataset));
+        body.extend(make_bulk(b"dataset.percentage"));
+        let percentage = if total == stats.startup {
+            0.0
+        } else {
+            stats.dataset as f64 * 100.0 / (total - stats.startup) as f64
+        };
+        body.extend(make_double(resp, percentage, 4));
+        body.extend(make_bulk(b"fragmentation"));
+        body.extend(make_double(resp, stats.fragmentation(), 4));
+        body.extend(make_stat(
+            "fragmentation.bytes",
+            stats.rss.saturating_sub(total),
+        ));
+        let mut fields = 15 + stats.dbs.len() + stats.shards.len();
+        if let Some(ref allocator) = stats.allocator {
+            body.extend(make_stat("allocator.allocated", allocator.allocated));
+            body.extend(make_stat("allocator.active", allocator.active));
+            body.extend(make_stat("allocator.resident", allocator.resident));
+            body.extend(make_bulk(b"allocator-fragmentation.ratio"));
+            let ratio = if allocator.allocated == 0 {
+                0.0
+            } else {
+                allocator.active as f64 / allocator.allocated as f64
+            };
+            body.extend(make_double(resp, ratio, 4));
+            body.extend(make_stat(
+                "allocator-fragmentation.bytes",
+                allocator.active.saturating_sub(allocator.allocated),
+            ));
+            fields += 5;
+        }
+        let mut output = make_map(resp, fields);
+        output.extend(body);
+        (output, false, false)
+    } else if arg_match(&args[1], "BIGKEYS") {
+        // Scripts hold their shards already, so walk them in one go.
+        let mut biggest = match bigkeys_count(args) {
+            Ok(count) => memory::Biggest::new(count),
+            Err(err) => return (err, false, false),
+        };
+        let db = client.lock().unwrap().db;
+        for (key, value) in store.iter(db) {
+            biggest.add(key, store.usage(db, key).unwrap_or(0), object_encoding(value));
+        }
+        (bigkeys_output(&biggest), false, false)
+    } else if arg_match(&args[1], "PURGE") && args.len() == 2 {
+        alloc::purge();
+        (b"+OK\r\n".to_vec(), false, false)
+    } else if arg_match(&args[1], "DOCTOR") && args.len() == 2 {
+        let stats = memory_stats(store, server);
+        let report = stats.doctor(server.clients.list().len());
+        let resp = client.lock().unwrap().resp;
+        (make_verbatim(resp, report.as_bytes()), false, false)
+    } else {
+        (
+            format!(
+                "-ERR unknown subcommand or wrong number of arguments for '{}'\r\n",
+                safe_line_from_slice(&args[1])
+            ).into_bytes(),
+            false,
+            false,
+        )
+    }
+}
+
+// MEMORY BIGKEYS [COUNT count] for a client: the keys of its database
+// using the most memory, found with walk_keys so writes carry on meanwhile.
+// Every value is a string, so these are the largest keys of each type too.
+fn bigkeys_reply(args: &[Vec<u8>], server: &Server, client: &Mutex<clients::Client>) -> Vec<u8> {
+    let mut biggest = match bigkeys_count(args) {
+        Ok(count) => memory::Biggest::new(count),
+        Err(err) => return err,
+    };
+    let db = client.lock().unwrap().db;
+    let walked = walk_keys(server, db, |store, keys| {
+        for key in keys {
+            if let Some(entry) = store.peek(db, key) {
+                biggest.add(key, store.usage(db, key).unwrap_or(0), object_encoding(entry.value()));
+            }
+        }
+        true
+    });
+    if let Err(err) = walked {
+        return err.to_vec();
+    }
+    bigkeys_output(&biggest)
+}
+
+fn bigkeys_count(args: &[Vec<u8>]) -> Result<usize, Vec<u8>> {
+    match args.len() {
+        2 => Ok(10),
+        4 if arg_match(&args[2], "COUNT") => match String::from_utf8_lossy(&args[3]).parse::<usize>() {
+            Ok(count) => Ok(count),
+            Err(_) => Err(b"-ERR value is not an integer or out of range\r\n".to_vec()),
+        },
+        _ => Err(b"-ERR syntax error\r\n".to_vec()),
+    }
+}
+
+// One [key, type, bytes, encoding] entry per key, the largest first.
+fn bigkeys_output(biggest: &memory::Biggest) -> Vec<u8> {
+    let mut output = make_array(biggest.keys().len());
+    for &(usage, ref key, encoding) in biggest.keys() {
+        output.extend(make_array(4));
+        output.extend(make_bulk(key));
+        output.extend(make_bulk(b"string"));
+        output.extend(format!(":{}\r\n", usage).into_bytes());
+        output.extend(make_bulk(encoding.as_bytes()));
+    }
+    output
+}
+
+// The same encoding names Redis reports, derived from the value alone.
+fn object_encoding(value: &compress::Value) -> &'static str {
+    match value.codec() {
+        compress::Codec::Lz4 => return "lz4",
+        compress::Codec::Zstd => return "zstd",
+        compress::Codec::Raw => {}
+    }
+    let value = value.stored();
+    if value.len() <= 20 && String::from_utf8_lossy(value).parse::<i64>().is_ok() {
+        "int"
+    } else if value.len() <= 44 {
+        "embstr"
+    } else {
+        "raw"
+    }
+}
+
+// Feeds random patterns and subjects drawn from the glob metacharacters to
+// the matcher; surviving the run is the test.
+fn stringmatch_fuzz() {
+    const ALPHABET: &[u8] = b"*?[]\\^-!ab";
+    let mut seed = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.subsec_nanos() as u64)
+        .unwrap_or(0) | 1;
+    let mut next = move |n: usize| {
+        seed ^= seed << 13;
+        seed ^= seed >> 7;
+        seed ^= seed << 17;
+        (seed % n as u64) as usize
+    };
+    for _ in 0..100000 {
+        let mut pattern = Vec::new();
+        for _ in 0..next(32) {
+            pattern.push(ALPHABET[next(ALPHABET.len())]);
+        }
+        let mut subject = Vec::new();
+        for _ in 0..next(32) {
+            subject.push(ALPHABET[next(ALPHABET.len())]);
+        }
+        pattern::matches(&pattern, &subject, next(2) == 1);
+    }
+}
+
+// Sections are picked by name; none, or default, all or everything, picks
+// them all, except that commandstats, errorstats and latencystats only come
+// with all or everything.
+fn handle_info(
+    args: &[Vec<u8>],
+    server: &Server,
+    client: &Mutex<clients::Client>,
+) -> (Vec<u8>, bool, bool) {
+    let wanted: Vec<String> = args[1..]
+        .iter()
+        .map(|arg| String::from_utf8_lossy(arg).to_lowercase())
+        .collect();
+    let everything = wanted.iter().any(|s| s == "all" || s == "everything");
+    let all = everything || wanted.is_empty() || wanted.iter().any(|s| s == "default");
+    let mut sections = Vec::new();
+    if all || wanted.iter().any(|s| s == "server") {
+        let uptime = server.started.elapsed().as_secs();
+        sections.push(format!(
+            "# Server\r\n\
+             uptime_in_seconds:{}\r\n\
+             uptime_in_days:{}\r\n\
+             hz:{}\r\n\
+             configured_hz:{}\r\n",
+            uptime,
+            uptime / 86400,
+            server.cron.hz(),
+            server.config.read().unwrap().hz
+        ));
+    }
+    if all || wanted.iter().any(|s| s == "memory") {
+        let config = server.config.read().unwrap();
+        sections.push(format!(
+            "# Memory\r\n\
+             used_memory:{}\r\n\
+             used_memory_rss:{}\r\n\
+             maxmemory:{}\r\n\
+             maxmemory_policy:{}\r\n",
+            used_memory(server),
+            memory::rss(),
+            config.maxmemory,
+            config.maxmemory_policy
+        ));
+    }
+    if all || wanted.iter().any(|s| s == "stats") {
+        let compressor = &server.compressor;
+        // Read once each, as compressions on other threads move both
+        // between the loads.
+        let (bytes_in, bytes_out) = (compressor.bytes_in(), compressor.bytes_out());
+        sections.push(format!(
+            "# Stats\r\n\
+             total_commands_processed:{}\r\n\
+             instantaneous_ops_per_sec:{}\r\n\
+             keyspace_hits:{}\r\n\
+             keyspace_misses:{}\r\n\
+             total_error_replies:{}\r\n\
+             compressed_values:{}\r\n\
+             compression_bytes_in:{}\r\n\
+             compression_bytes_out:{}\r\n\
+             compression_bytes_saved:{}\r\n",
+            server.cron.commands(),
+            server.cron.ops_per_sec(),
+            server.keyspace.hits(),
+            server.keyspace.misses(),
+            server.command_stats.total_errors(),
+            compressor.values(),
+            bytes_in,
+            bytes_out,
+            bytes_in.saturating_sub(bytes_out)
+        ));
+    }
+    if all || wanted.iter().any(|s| s == "replication") {
+        sections.push(format!(
+            "# Replication\r\n\
+             role:master\r\n\
+             connected_slaves:0\r\n\
+             master_failover_state:no-failover\r\n\
+             master_replid:{}\r\n\
+             master_replid2:{}\r\n\
+             master_repl_offset:0\r\n\
+             second_repl_offset:-1\r\n\
+             repl_backlog_active:0\r\n\
+             repl_backlog_size:0\r\n\
+             repl_backlog_first_byte_offset:0\r\n\
+             repl_backlog_histlen:0\r\n",
+            server.replid,
+            "0".repeat(40)
+        ));
+    }
+    if everything || wanted.iter().any(|s| s == "commandstats") {
+        sections.push(server.command_stats.commandstats());
+    }
+    if everything || wanted.iter().any(|s| s == "errorstats") {
+        sections.push(server.command_stats.errorstats());
+    }
+    if everything || wanted.iter().any(|s| s == "latencystats") {
+        let percentiles = server.config.read().unwrap().latency_tracking_info_percentiles.clone();
+        sections.push(server.command_stats.latencystats(&percentiles));
+    }
+    let resp = client.lock().unwrap().resp;
+    (make_verbatim(resp, sections.join("\r\n").as_bytes()), false, false)
+}
+
+// CLUSTER subcommands, available with cluster-enabled.
+fn handle_cluster(
+    args: &[Vec<u8>],
+    store: &keyspace::Locked,
+    server: &Server,
+    client: &Mutex<clients::Client>,
+) -> (Vec<u8>, bool, bool) {
+    let cluster = match server.cluster {
+        Some(ref cluster) => cluster,
+        None => return (b"-ERR This instance has cluster support disabled\r\n".to_vec(), false, false),
+    };
+    if args.len() < 2 {
+        return (invalid_num_args(&args[0]), false, false);
+    }
+    let resp = client.lock().unwrap().resp;
+    if arg_match(&args[1], "INFO") && args.len() == 2 {
+        (make_verbatim(resp, cluster.state().info().as_bytes()), false, false)
+    } else if arg_match(&args[1], "MYID") && args.len() == 2 {
+        (make_bulk(cluster.myself().as_bytes()), false, false)
+    } else if arg_match(&args[1], "NODES") && args.len() == 2 {
+        (make_verbatim(resp, cluster.state().describe().as_bytes()), false, false)
+    } else if arg_match(&args[1], "SLOTS") && args.len() == 2 {
+        let state = cluster.state();
+        let ranges = state.ranges();
+        let mut output = make_array(ranges.len());
+        for (first, last, id) in ranges {
+            let node = &state.nodes[id];
+            output.extend(format!(":{}\r\n:{}\r\n", first, last).into_bytes());
+            output.extend(make_array(4));
+            output.extend(make_bulk(node.ip.as_bytes()));
+            output.extend(format!(":{}\r\n", node.port).into_bytes());
+            output.extend(make_bulk(id.as_bytes()));
+            output.extend(make_array(0));
+        }
+        (output, false, false)
+    } else if arg_match(&args[1], "SHARDS") && args.len() == 2 {
+        // Every node is a shard of its own, as there are no replicas.
+        let resp = client.lock().unwrap().resp;
+        let state = cluster.state();
+        let ranges = state.ranges();
+        let mut output = make_array(state.nodes.len());
+        for (id, node) in state.nodes.iter() {
+            let owned: Vec<_> = ranges.iter().filter(|r| r.2 == id.as_str()).collect();
+            output.extend(make_map(resp, 2));
+            output.extend(make_bulk(b"slots"));
+            output.extend(make_array(owned.len() * 2));
+            for range in owned {
+                output.extend(format!(":{}\r\n:{}\r\n", range.0, range.1).into_bytes());
+            }
+            output.extend(make_bulk(b"nodes"));
+            output.extend(make_array(1));
+            output.extend(make_map(resp, 7));
+            output.extend(make_bulk(b"id"));
+            output.extend(make_bulk(id.as_bytes()));
+            output.extend(make_bulk(b"port"));
+            output.extend(format!(":{}\r\n", node.port).into_bytes());
+            output.extend(make_bulk(b"ip"));
+            output.extend(make_bulk(node.ip.as_bytes()));
+            output.extend(make_bulk(b"endpoint"));
+            output.extend(make_bulk(node.ip.as_bytes()));
+            output.extend(make_bulk(b"role"));
+            output.extend(make_bulk(b"master"));
+            output.extend(make_bulk(b"replication-offset"));
+            output.extend(b":0\r\n".to_vec());
+            output.extend(make_bulk(b"health"));
+            output.extend(make_bulk(b"online"));
+        }
+        (output, false, false)
+    } else if arg_match(&args[1], "KEYSLOT") && args.len() == 3 {
+        (format!(":{}\r\n", cluster::key_slot(&args[2])).into_bytes(), false, false)
+    } else if arg_match(&args[1], "COUNTKEYSINSLOT") && args.len() == 3 {
+        let slot = match parse_slot(&args[2]) {
+            Ok(slot) => slot,
+            Err(e) => return (e, false, false),
+        };
+        let count = store.iter(0).filter(|&(key, _)| cluster::key_slot(key) == slot).count();
+        (format!(":{}\r\n", count).into_bytes(), false, false)
+    } else if (arg_match(&args[1], "ADDSLOTS") || arg_match(&args[1], "DELSLOTS")) && args.len() > 2 {
+        let mut slots = Vec::new();
+        for arg in &args[2..] {
+            match parse_slot(arg) {
+                Ok(slot) => slots.push(slot),
+                Err(e) => return (e, false, false),
+            }
+        }
+        let result = if arg_match(&args[1], "ADDSLOTS") {
+            cluster.add_slots(&slots)
+        } else {
+            cluster.del_slots(&slots)
+        };
+        (ok_or_err(result), false, false)
+    } else if (arg_match(&args[1], "ADDSLOTSRANGE") || arg_match(&args[1], "DELSLOTSRANGE"))
+        && args.len() > 2 && args.len().is_multiple_of(2)
+    {
+        let mut slots = Vec::new();
+        for pair in args[2..].chunks(2) {
+            let (first, last) = match (parse_slot(&pair[0]), parse_slot(&pair[1])) {
+                (Ok(first), Ok(last)) => (first, last),
+                (Err(e), _) | (_, Err(e)) => return (e, false, false),
+            };
+            if first > last {
+                let err = format!(
+                    "-ERR start slot number {} is greater than end slot number {}\r\n",
+                    first, last
+                );
+                return (err.into_bytes(), false, false);
+            }
+            slots.extend(first..last + 1);
+        }
+        let result = if arg_match(&args[1], "ADDSLOTSRANGE") {
+            cluster.add_slots(&slots)
+        } else {
+            cluster.del_slots(&slots)
+        };
+        (ok_or_err(result), false, false)
+    } else if arg_match(&args[1], "FLUSHSLOTS") && args.len() == 2 {
+        if store.len(0) > 0 {
+            return (b"-ERR DB must be empty to perform CLUSTER FLUSHSLOTS.\r\n".to_vec(), false, false);
+        }
+        (ok_or_err(cluster.flush_slots()), false, false)
+    } else if arg_match(&args[1], "SAVECONFIG") && args.len() == 2 {
+        (ok_or_err(cluster.save()), false, false)
+    } else if arg_match(&args[1], "SETSLOT") && args.len() >= 4 {
+        let slot = match parse_slot(&args[2]) {
+            Ok(slot) => slot,
+            Err(e) => return (e, false, false),
+        };
+        let node = args.get(4).map(|node| String::from_utf8_lossy(node).to_string());
+        let result = match node {
+            Some(ref node) if arg_match(&args[3], "MIGRATING") && args.len() == 5 => {
+                cluster.migrate_slot(slot, node)
+            }
+            Some(ref node) if arg_match(&args[3], "IMPORTING") && args.len() == 5 => {
+                cluster.import_slot(slot, node)
+            }
+            Some(ref node) if arg_match(&args[3], "NODE") && args.len() == 5 => {
+                let holds_keys = store.iter(0).any(|(key, _)| cluster::key_slot(key) == slot);
+                cluster.assign_slot(slot, node, holds_keys)
+            }
+            None if arg_match(&args[3], "STABLE") => cluster.stabilize_slot(slot),
+            _ => Err("Invalid CLUSTER SETSLOT action or number of arguments. Try CLUSTER HELP".to_string()),
+        };
+        (ok_or_err(result), false, false)
+    } else if arg_match(&args[1], "GETKEYSINSLOT") && args.len() == 4 {
+        let slot = match parse_slot(&args[2]) {
+            Ok(slot) => slot,
+            Err(e) => return (e, false, false),
+        };
+        let count = match String::from_utf8_lossy(&args[3]).parse::<usize>() {
+            Ok(count) => count,
+            Err(_) => return (b"-ERR Invalid slot or number of keys\r\n".to_vec(), false, false),
+        };
+        let keys: Vec<&[u8]> = store
+            .iter(0)
+            .map(|(key, _)| key)
+            .filter(|key| cluster::key_slot(key) == slot)
+            .take(count)
+            .collect();
+        let mut output = make_array(keys.len());
+        for key in keys {
+            output.extend(make_bulk(key));
+        }
+        (output, false, false)
+    } else if arg_match(&args[1], "MEET") && (args.len() == 4 || args.len() == 5) {
+        let ip = String::from_utf8_lossy(&args[2]).to_string();
+        let port = String::from_utf8_lossy(&args[3]).parse::<u16>();
+        let cport = match args.get(4) {
+            Some(cport) => String::from_utf8_lossy(cport).parse::<u16>(),
+            None => port.clone().map(|port| port.wrapping_add(cluster::BUS_PORT_OFFSET as u16)),
+        };
+        match (ip.parse::<IpAddr>(), port, cport) {
+            (Ok(_), Ok(_), Ok(cport)) => {
+                cluster.meet(&ip, cport as usize);
+                (b"+OK\r\n".to_vec(), false, false)
+            }
+            (Ok(_), _, _) => {
+                let err = format!(
+                    "-ERR Invalid base port specified: {}\r\n",
+                    safe_line_from_slice(&args[3])
+                );
+                (err.into_bytes(), false, false)
+            }
+            _ => {
+                let err = format!(
+                    "-ERR Invalid node address specified: {}:{}\r\n",
+                    safe_line_from_string(ip),
+                    safe_line_from_slice(&args[3])
+                );
+                (err.into_bytes(), false, false)
+            }
+        }
+    } else if arg_match(&args[1], "FORGET") && args.len() == 3 {
+        (ok_or_err(cluster.forget(&String::from_utf8_lossy(&args[2]))), false, false)
+    } else if arg_match(&args[1], "COUNT-FAILURE-REPORTS") && args.len() == 3 {
+        match cluster.failure_reports(&String::from_utf8_lossy(&args[2])) {
+            Some(count) => (format!(":{}\r\n", count).into_bytes(), false, false),
+            None => {
+                let err = format!("-ERR Unknown node {}\r\n", safe_line_from_slice(&args[2]));
+                (err.into_bytes(), false, false)
+            }
+        }
+    } else if arg_match(&args[1], "SET-CONFIG-EPOCH") && args.len() == 3 {
+        match String::from_utf8_lossy(&args[2]).parse::<u64>() {
+            Ok(epoch) => (ok_or_err(cluster.set_config_epoch(epoch)), false, false),
+            Err(_) => {
+                let err = format!(
+                    "-ERR Invalid config epoch specified: {}\r\n",
+                    safe_line_from_slice(&args[2])
+                );
+                (err.into_bytes(), false, false)
+            }
+        }
+    } else if arg_match(&args[1], "BUMPEPOCH") && args.len() == 2 {
+        match cluster.bump_epoch() {
+            Ok((true, epoch)) => (format!("+BUMPED {}\r\n", epoch).into_bytes(), false, false),
+            Ok((false, epoch)) => (format!("+STILL {}\r\n", epoch).into_bytes(), false, false),
+            Err(e) => (format!("-ERR {}\r\n", e).into_bytes(), false, false),
+        }
+    } else {
+        (
+            format!(
+                "-ERR unknown subcommand or wrong number of arguments for '{}'\r\n",
+                safe_line_from_slice(&args[1])
+            ).into_bytes(),
+            false,
+            false,
+        )
+    }
+}
+
+fn parse_slot(arg: &[u8]) -> Result<usize, Vec<u8>> {
+    match String::from_utf8_lossy(arg).parse::<usize>() {
+        Ok(slot) if slot < cluster::SLOTS => Ok(slot),
+        _ => Err(b"-ERR Invalid or out of range slot\r\n".to_vec()),
+    }
+}
+
+fn ok_or_err(result: Result<(), String>) -> Vec<u8> {
+    match result {
+        Ok(()) => b"+OK\r\n".to_vec(),
+        Err(e) => format!("-ERR {}\r\n", e).into_bytes(),
+    }
+}
+
+// MIGRATE host port key|"" destination-db timeout [COPY] [REPLACE]
+// [AUTH password] [AUTH2 username password] [KEYS key ...]: streams keys
+// to another instance as RESTORE-ASKING commands, which reach it even
+// while it is still importing their slot, and deletes them here once it
+// took them unless COPY is given.
+fn handle_migrate(
+    args: &[Vec<u8>],
+    store: &mut keyspace::Locked,
+    client: &Mutex<clients::Client>,
+) -> (Vec<u8>, bool, bool) {
+    if args.len() < 6 {
+        return (invalid_num_args(&args[0]), false, false);
+    }
+    let (mut copy, mut replace, mut auth) = (false, false, Vec::new());
+    let mut keys = vec![args[3].clone()];
+    let mut i = 6;
+    while i < args.len() {
+        if arg_match(&args[i], "COPY") {
+            copy = true;
+        } else if arg_match(&args[i], "REPLACE") {
+            replace = true;
+        } else if arg_match(&args[i], "AUTH") && i + 1 < args.len() {
+            auth = vec![args[i + 1].clone()];
+            i += 1;
+        } else if arg_match(&args[i], "AUTH2") && i + 2 < args.len() {
+            auth = vec![args[i + 1].clone(), args[i + 2].clone()];
+            i += 2;
+        } else if arg_match(&args[i], "KEYS") {
+            if !args[3].is_empty() {
+                return (
+                    b"-ERR When using MIGRATE KEYS option, the key argument must be set to the empty string\r\n"
+                        .to_vec(),
+                    false,
+                    false,
+                );
+            }
+            keys = args[i + 1..].to_vec();
+            break;
+        } else {
+            return (b"-ERR syntax error\r\n".to_vec(), false, false);
+        }
+        i += 1;
+    }
+    let port = String::from_utf8_lossy(&args[2]).parse::<u16>();
+    let dest_db = String::from_utf8_lossy(&args[4]).parse::<usize>();
+    let timeout = String::from_utf8_lossy(&args[5]).parse::<i64>();
+    let (port, dest_db, timeout) = match (port, dest_db, timeout) {
+        (Ok(port), Ok(db), Ok(timeout)) => (port, db, if timeout <= 0 { 1000 } else { timeout as u64 }),
+        _ => return (b"-ERR value is not an integer or out of range\r\n".to_vec(), false, false),
+    };
+
+    let db = client.lock().unwrap().db;
+    let found: Vec<(Vec<u8>, Bytes)> = keys
+        .into_iter()
+        .filter_map(|key| store.get(db, &key).map(|value| (key, value)))
+        .collect();
+    if found.is_empty() {
+        return (b"+NOKEY\r\n".to_vec(), false, false);
+    }
+
+    let mut request = Vec::new();
+    let mut commands: Vec<Vec<Vec<u8>>> = Vec::new();
+    if !auth.is_empty() {
+        let mut command = vec![b"AUTH".to_vec()];
+        command.extend(auth);
+        commands.push(command);
+    }
+    if dest_db != 0 {
+        commands.push(vec![b"SELECT".to_vec(), dest_db.to_string().into_bytes()]);
+    }
+    let preamble = commands.len();
+    for (key, value) in &found {
+        let mut command = vec![b"RESTORE-ASKING".to_vec(), key.clone(), b"0".to_vec(), value.to_vec()];
+        if replace {
+            command.push(b"REPLACE".to_vec());
+        }
+        commands.push(command);
+    }
+    for command in &commands {
+        request.extend(make_array(command.len()));
+        for arg in command {
+            request.extend(make_bulk(arg));
+        }
+    }
+
+    let replies = match send_pipeline(&args[1], port, timeout, &request, commands.len()) {
+        Ok(replies) => replies,
+        Err(_) => return (b"-IOERR error or timeout connecting to the client\r\n".to_vec(), false, false),
+    };
+    if let Some(err) = replies[..preamble].iter().find(|reply| reply.starts_with('-')) {
+        let err = format!("-ERR Target instance replied with error: {}\r\n", &err[1..]);
+        return (err.into_bytes(), false, false);
+    }
+    let mut error = None;
+    let mut moved = false;
+    for ((key, _), reply) in found.iter().zip(&replies[preamble..]) {
+        if let Some(err) = reply.strip_prefix('-') {
+            error = Some(err.to_string());
+        } else if !copy {
+            store.remove(db, key);
+            moved = true;
+        }
+    }
+    match error {
+        Some(err) => {
+            let err = format!("-ERR Target instance replied with error: {}\r\n", err);
+            (err.into_bytes(), moved, false)
+        }
+        None => (b"+OK\r\n".to_vec(), moved, false),
+    }
+}
+
+// Sends a pipeline of commands to host:port and reads back as many
+// single-line replies, giving up after timeout milliseconds of silence.
+fn send_pipeline(host: &[u8], port: u16, timeout: u64, request: &[u8], count: usize) -> io::Result<Vec<String>> {
+    let host = String::from_utf8_lossy(host).to_string();
+    let timeout = Duration::from_millis(timeout);
+    let addr = (host.as_str(), port)
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no address"))?;
+    let mut stream = std::net::TcpStream::connect_timeout(&addr, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    stream.write_all(request)?;
+    let mut reader = io::BufReader::new(stream);
+    let mut replies = Vec::new();
+    for _ in 0..count {
+        let mut line = String::new();
+        if io::BufRead::read_line(&mut reader, &mut line)? == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed"));
+        }
+        replies.push(line.trim_end().to_string());
+    }
+    Ok(replies)
+}
+
+// RESTORE-ASKING key ttl value [REPLACE]: takes in a key streamed by
+// MIGRATE. Keys don't expire, so the ttl has to be 0.
+fn handle_restore(
+    args: &[Vec<u8>],
+    store: &mut keyspace::Locked,
+    server: &Server,
+    client: &Mutex<clients::Client>,
+) -> (Vec<u8>, bool, bool) {
+    let replace = match args.len() {
+        4 => false,
+        5 if arg_match(&args[4], "REPLACE") => true,
+        5 => return (b"-ERR syntax error\r\n".to_vec(), false, false),
+        _ => return (invalid_num_args(&args[0]), false, false),
+    };
+    match String::from_utf8_lossy(&args[2]).parse::<i64>() {
+        Ok(0) => {}
+        Ok(ttl) if ttl > 0 => return (b"-ERR keys with a TTL are not supported\r\n".to_vec(), false, false),
+        _ => return (b"-ERR Invalid TTL value, must be >= 0\r\n".to_vec(), false, false),
+    }
+    let db = client.lock().unwrap().db;
+    if !replace && store.contains_key(db, &args[1]) {
+        return (b"-BUSYKEY Target key name already exists.\r\n".to_vec(), false, false);
+    }
+    if let Err(e) = check_sizes(&args[1], &args[3], server) {
+        return (e, false, false);
+    }
+    let value = {
+        let config = server.config.read().unwrap();
+        server.compressor.encode(
+            Bytes::from(args[3].clone()),
+            &config.compression,
+            config.compression_threshold,
+        )
+    };
+    store.insert(db, args[1].clone(), value);
+    (b"+OK\r\n".to_vec(), true, false)
+}
+
+fn handle_object(
+    args: &[Vec<u8>],
+    store: &mut keyspace::Locked,
+    server: &Server,
+    client: &Mutex<clients::Client>,
+) -> (Vec<u8>, bool, bool) {
+    if args.len() != 3 {
+        return (invalid_num_args(&args[0]), false, false);
+    }
+    let (db, resp) = {
+        let client = client.lock().unwrap();
+        (client.db, client.resp)
+    };
+    let entry = match store.peek(db, &args[2]) {
+        Some(entry) => entry,
+        None => return (make_null(resp), false, false),
+    };
+    let tracking = server.keyspace.tracking();
+    let lfu = match tracking {
+        db::Tracking::Lfu { .. } => true,
+        db::Tracking::Lru(_) => false,
+    };
+    if arg_match(&args[1], "ENCODING") {
+        (make_bulk(object_encoding(entry.value()).as_bytes()), false, false)
+    } else if arg_match(&args[1], "FREQ") {
+        if !lfu {
+            return (
+                b"-ERR An LFU maxmemory policy is not selected, access frequency not tracked. \
+                  Please note that when switching between policies at runtime LRU and LFU data \
+                  will take some time to adjust.\r\n"
+                    .to_vec(),
+                false,
+                false,
+            );
+        }
+        (format!(":{}\r\n", tracking.freq(entry.lru())).into_bytes(), false, false)
+    } else if arg_match(&args[1], "IDLETIME") {
+        if lfu {
+            return (
+                b"-ERR An LFU maxmemory policy is selected, idle time not tracked. \
+                  Please note that when switching between policies at runtime LRU and LFU data \
+                  will take some time to adjust.\r\n"
+                    .to_vec(),
+                false,
+                false,
+            );
+        }
+        (format!(":{}\r\n", tracking.idle(entry.lru())).into_bytes(), false, false)
+    } else {
+        (
+            format!(
+                "-ERR unknown subcommand or wrong number of arguments for '{}'\r\n",
+                safe_line_from_slice(&args[1])
+            ).into_bytes(),
+            false,
+            false,
+        )
+    }
+}
+
+fn handle_debug(
+    args: &[Vec<u8>],
+    store: &keyspace::Locked,
+    server: &Server,
+    client: &Mutex<clients::Client>,
+) -> (Vec<u8>, bool, bool) {
+    if args.len() < 2 {
+        return (invalid_num_args(&args[0]), false, false);
+    }
+    if arg_match(&args[1], "SLEEP") && args.len() == 3 {
+        // Sleeps with the store locked, stalling every client like a slow
+        // command would.
+        match String::from_utf8_lossy(&args[2]).parse::<f64>() {
+            Ok(secs) if secs >= 0.0 => {
+                thread::sleep(Duration::from_millis((secs * 1000.0) as u64));
+                (b"+OK\r\n".to_vec(), false, false)
+            }
+            _ => (
+                b"-ERR value is not a valid float\r\n".to_vec(),
+                false,
+                false,
+            ),
+        }
+    } else if arg_match(&args[1], "OBJECT") && args.len() == 3 {
+        let db = client.lock().unwrap().db;
+        match store.peek(db, &args[2]) {
+            Some(entry) => (
+                format!(
+                    "+Value at:{:p} refcount:1 encoding:{} serializedlength:{} lru:{} lru_seconds_idle:{}\r\n",
+                    entry.value().stored().as_ptr(),
+                    object_encoding(entry.value()),
+                    entry.value().len(),
+                    entry.lru(),
+                    server.keyspace.clock().saturating_sub(entry.lru())
+                ).into_bytes(),
+                false,
+                false,
+            ),
+            None => (b"-ERR no such key\r\n".to_vec(), false, false),
+        }
+    } else if arg_match(&args[1], "SET-ACTIVE-EXPIRE") && args.len() == 3 {
+        match String::from_utf8_lossy(&args[2]).parse::<i64>() {
+            Ok(flag) => {
+                server.active_expire.store(flag != 0, Ordering::SeqCst);
+                (b"+OK\r\n".to_vec(), false, false)
+            }
+            Err(_) => (
+                b"-ERR value is not an integer or out of range\r\n".to_vec(),
+                false,
+                false,
+            ),
+        }
+    } else if arg_match(&args[1], "LOG") && args.len() == 3 {
+        server.log.warning("debug-log", &[("message", &String::from_utf8_lossy(&args[2]))]);
+        (b"+OK\r\n".to_vec(), false, false)
+    } else if arg_match(&args[1], "JMAP") && args.len() == 2 {
+        // Logs what the allocator holds, for lining memory up with tests.
+        match alloc::stats() {
+            Some(stats) => {
+                server.log.warning(
+                    "jmap",
+                    &[("allocated", &stats.allocated), ("active", &stats.active), ("resident", &stats.resident)],
+                );
+                (b"+OK\r\n".to_vec(), false, false)
+            }
+            None => (
+                b"-ERR the allocator in use keeps no statistics\r\n".to_vec(),
+                false,
+                false,
+            ),
+        }
+    } else if arg_match(&args[1], "HELP") && args.len() == 2 {
+        let lines = [
+            "DEBUG <subcommand> [<arg> [value] [opt] ...]. Subcommands are:",
+            "JMAP",
+            "    Log the allocator's statistics.",
+            "LOG <message>",
+            "    Write message to the server log.",
+            "OBJECT <key>",
+            "    Show low level info about the key and associated value.",
+            "SET-ACTIVE-EXPIRE <0|1>",
+            "    Turn the active expiry cycle off or on.",
+            "SLEEP <seconds>",
+            "    Stall the server for seconds, which can be fractional.",
+            "STRINGMATCH-LEN",
+            "    Run a fuzz tester against the pattern matcher.",
+        ];
+        let mut output = make_array(lines.len());
+        for line in lines.iter() {
+            output.extend(format!("+{}\r\n", line).into_bytes());
+        }
+        (output, false, false)
+    } else if arg_match(&args[1], "STRINGMATCH-LEN") && args.len() == 2 {
+        stringmatch_fuzz();
+        (
+            b"+Apparently the server did not crash: test passed\r\n".to_vec(),
+            false,
+            false,
+        )
+    } else {
+        (
+            format!(
+                "-ERR unknown subcommand or wrong number of arguments for '{}'\r\n",
+                safe_line_from_slice(&args[1])
+            ).into_bytes(),
+            false,
+            false,
+        )
+    }
+}
+
+// AUTH [username] password. The single argument form logs in as the
+// default user.
+fn handle_auth(
+    args: &[Vec<u8>],
+    server: &Server,
+    client: &Mutex<clients::Client>,
+) -> (Vec<u8>, bool, bool) {
+    let (user, password) = match args.len() {
+        2 => ("default".to_string(), &args[1]),
+        3 => (String::from_utf8_lossy(&args[1]).to_string(), &args[2]),
+        _ => return (b"-ERR syntax error\r\n".to_vec(), false, false),
+    };
+    if args.len() == 2 && server.acl.get("default").is_some_and(|u| u.nopass) {
+        return (
+            b"-ERR AUTH <password> called without any password configured for the default user. Are you sure your configuration is correct?\r\n".to_vec(),
+            false,
+            false,
+        );
+    }
+    if !server.acl.authenticate(&user, password) {
+        return (
+            b"-WRONGPASS invalid username-password pair or user is disabled.\r\n".to_vec(),
+            false,
+            false,
+        );
+    }
+    let mut client = client.lock().unwrap();
+    client.user = user;
+    client.authenticated = true;
+    (b"+OK\r\n".to_vec(), false, false)
+}
+
+fn make_acl_user(user: &acl::User, resp: u8) -> Vec<u8> {
+    let mut output = if resp == 3 {
+        b"%6\r\n".to_vec()
+    } else {
+        make_array(12)
+    };
+    output.extend(make_bulk(b"flags"));
+    let mut flags = vec![if user.enabled { "on" } else { "off" }];
+    if user.nopass {
+        flags.push("nopass");
+    }
+    output.extend(make_array(flags.len()));
+    for flag in flags {
+        output.extend(make_bulk(flag.as_bytes()));
+    }
+    output.extend(make_bulk(b"passwords"));
+    output.extend(make_array(user.passwords.len()));
+    for hash in &user.passwords {
+        output.extend(make_bulk(hash.as_bytes()));
+    }
+    output.extend(make_bulk(b"commands"));
+    output.extend(make_bulk(&user.describe_commands().into_bytes()));
+    output.extend(make_bulk(b"keys"));
+    output.extend(make_bulk(&user.describe_keys().into_bytes()));
+    output.extend(make_bulk(b"channels"));
+    output.extend(make_bulk(&user.describe_channels().into_bytes()));
+    output.extend(make_bulk(b"selectors"));
+    output.extend(make_array(0));
+    output
+}
+
+fn handle_acl(
+    args: &[Vec<u8>],
+    server: &Server,
+    client: &Mutex<clients::Client>,
+) -> (Vec<u8>, bool, bool) {
+    if args.len() < 2 {
+        return (invalid_num_args(&args[0]), false, false);
+    }
+    if arg_match(&args[1], "SETUSER") && args.len() >= 3 {
+        let name = String::from_utf8_lossy(&args[2]).to_string();
+        let rules: Vec<String> = args[3..]
+            .iter()
+            .map(|r| String::from_utf8_lossy(r).to_string())
+            .collect();
+        match server.acl.set_user(&name, &rules) {
+            Ok(()) => (b"+OK\r\n".to_vec(), false, false),
+            Err(e) => (format!("-{}\r\n", e).into_bytes(), false, false),
+        }
+    } else if arg_match(&args[1], "GETUSER") && args.len() == 3 {
+        let resp = client.lock().unwrap().resp;
+        match server.acl.get(&String::from_utf8_lossy(&args[2])) {
+            Some(user) => (make_acl_user(&user, resp), false, false),
+            None => (make_null(resp), false, false),
+        }
+    } else if arg_match(&args[1], "DELUSER") && args.len() >= 3 {
+        for name in &args[2..] {
+            let name = String::from_utf8_lossy(name);
+            if name == "default" {
+                return (
+                    b"-ERR The 'default' user cannot be removed\r\n".to_vec(),
+                    false,
+                    false,
+                );
+            }
+        }
+        let mut deleted = Vec::new();
+        for name in &args[2..] {
+            let name = String::from_utf8_lossy(name).to_string();
+            if server.acl.del_user(&name) {
+                deleted.push(name);
+            }
+        }
+        server.kill_users(&deleted);
+        (format!(":{}\r\n", deleted.len()).into_bytes(), false, false)
+    } else if arg_match(&args[1], "LIST") && args.len() == 2 {
+        let users = server.acl.users();
+        let mut output = make_array(users.len());
+        for user in users {
+            output.extend(make_bulk(
+                &format!("user {} {}", user.name, user.describe()).into_bytes(),
+            ));
+        }
+        (output, false, false)
+    } else if arg_match(&args[1], "USERS") && args.len() == 2 {
+        let users = server.acl.users();
+        let mut output = make_array(users.len());
+        for user in users {
+            output.extend(make_bulk(&user.name.into_bytes()));
+        }
+        (output, false, false)
+    } else if (arg_match(&args[1], "LOAD") || arg_match(&args[1], "SAVE")) && args.len() == 2 {
+        let aclfile = server.config.read().unwrap().aclfile.clone();
+        if aclfile.is_empty() {
+            return (
+                b"-ERR This instance is not configured to use an ACL file. You may want to specify users via the ACL SETUSER command.\r\n".to_vec(),
+                false,
+                false,
+            );
+        }
+        let (event, result) = if arg_match(&args[1], "LOAD") {
+            ("aclfile-loaded", server.acl.load(&aclfile))
+        } else {
+            ("aclfile-saved", server.acl.save(&aclfile))
+        };
+        match result {
+            Ok(()) => {
+                server.log.notice(event, &[("path", &aclfile)]);
+                (b"+OK\r\n".to_vec(), false, false)
+            }
+            Err(e) => (
+                format!("-ERR {}\r\n", safe_line_from_string(e)).into_bytes(),
+                false,
+                false,
+            ),
+        }
+    } else if arg_match(&args[1], "WHOAMI") && args.len() == 2 {
+        let user = client.lock().unwrap().user.clone();
+        (make_bulk(&user.into_bytes()), false, false)
+    } else if arg_match(&args[1], "CAT") && args.len() <= 3 {
+        if args.len() == 2 {
+            let categories = acl::categories();
+            let mut output = make_array(categories.len());
+            for category in categories {
+                output.extend(make_bulk(category.as_bytes()));
+            }
+            return (output, false, false);
+        }
+        let category = format!("@{}", String::from_utf8_lossy(&args[2]).to_lowercase());
+        if !acl::categories().contains(&&category[1..]) {
+            return (
+                format!(
+                    "-ERR Unknown category '{}'\r\n",
+                    safe_line_from_slice(&args[2])
+                ).into_bytes(),
+                false,
+                false,
+            );
+        }
+        let names: Vec<&str> = commands::all()
+            .into_iter()
+            .filter(|spec| spec.categories.contains(&category.as_str()))
+            .map(|spec| spec.name)
+            .collect();
+        let mut output = make_array(names.len());
+        for name in names {
+            output.extend(make_bulk(name.as_bytes()));
+        }
+        (output, false, false)
+    } else {
+        (
+            format!(
+                "-ERR unknown subcommand or wrong number of arguments for '{}'\r\n",
+                safe_line_from_slice(&args[1])
+            ).into_bytes(),
+            false,
+            false,
+        )
+    }
+}
+
+// HELLO [protover [AUTH username password] [SETNAME clientname]]. Every
+// option is validated before anything is applied, so a failed handshake
+// leaves the connection as it was.
+fn handle_hello(
+    args: &[Vec<u8>],
+    server: &Server,
+    client: &Mutex<clients::Client>,
+) -> (Vec<u8>, bool, bool) {
+    let mut resp = None;
+    let mut auth = None;
+    let mut name = None;
+    if args.len() > 1 {
+        match String::from_utf8_lossy(&args[1]).parse::<i64>() {
+            Ok(ver) if ver == 2 || ver == 3 => resp = Some(ver as u8),
+            Ok(_) => {
+                return (
+                    b"-NOPROTO unsupported protocol version\r\n".to_vec(),
+                    false,
+                    false,
+                )
+            }
+            Err(_) => {
+                return (
+                    b"-ERR Protocol version is not an integer or out of range\r\n".to_vec(),
+                    false,
+                    false,
+                )
+            }
+        }
+    }
+    let mut i = 2;
+    while i < args.len() {
+        if arg_match(&args[i], "AUTH") && i + 2 < args.len() {
+            let user = String::from_utf8_lossy(&args[i + 1]).to_string();
+            if !server.acl.authenticate(&user, &args[i + 2]) {
+                return (
+                    b"-WRONGPASS invalid username-password pair or user is disabled.\r\n"
+                        .to_vec(),
+                    false,
+                    false,
+                );
+            }
+            auth = Some(user);
+            i += 3;
+        } else if arg_match(&args[i], "SETNAME") && i + 1 < args.len() {
+            if !clients::valid_name(&args[i + 1]) {
+                return (
+                    b"-ERR Client names cannot contain spaces, newlines or special characters.\r\n"
+                        .to_vec(),
+                    false,
+                    false,
+                );
+            }
+            name = Some(args[i + 1].clone());
+            i += 2;
+        } else {
+            return (
+                format!(
+                    "-ERR Syntax error in HELLO option '{}'\r\n",
+                    safe_line_from_slice(&args[i])
+                ).into_bytes(),
+                false,
+                false,
+            );
+        }
+    }
+
+    let mut client = client.lock().unwrap();
+    if !client.authenticated && auth.is_none() {
+        return (
+            b"-NOAUTH HELLO must be called with the client already authenticated, otherwise the HELLO <proto> AUTH <user> <pass> option can be used to authenticate the client and select the RESP protocol version at the same time\r\n".to_vec(),
+            false,
+            false,
+        );
+    }
+    if let Some(user) = auth {
+        client.user = user;
+        client.authenticated = true;
+    }
+    if let Some(resp) = resp {
+        client.resp = resp;
+    }
+    if let Some(name) = name {
+        client.name = name;
+    }
+    let mut output = if client.resp == 3 {
+        b"%7\r\n".to_vec()
+    } else {
+        make_array(14)
+    };
+    output.extend(make_bulk(b"server"));
+    output.extend(make_bulk(b"cache-server"));
+    output.extend(make_bulk(b"version"));
+    output.extend(make_bulk(env!("CARGO_PKG_VERSION").as_bytes()));
+    output.extend(make_bulk(b"proto"));
+    output.extend(format!(":{}\r\n", client.resp).into_bytes());
+    output.extend(make_bulk(b"id"));
+    output.extend(format!(":{}\r\n", client.id).into_bytes());
+    output.extend(make_bulk(b"mode"));
+    output.extend(make_bulk(b"standalone"));
+    output.extend(make_bulk(b"role"));
+    output.extend(make_bulk(b"master"));
+    output.extend(make_bulk(b"modules"));
+    output.extend(make_array(0));
+    (output, false, false)
+}
+
+// Maps, sets, doubles, nulls and verbatim text are the RESP3 types for
+// clients that negotiated it with HELLO 3, and what RESP2 flattens them to
+// otherwise: arrays of pairs, arrays, bulk strings and the null bulk string.
+fn make_map(resp: u8, count: usize) -> Vec<u8> {
+    if resp == 3 {
+        format!("%{}\r\n", count).into_bytes()
+    } else {
+        make_array(count * 2)
+    }
+}
+
+fn make_set(resp: u8, count: usize) -> Vec<u8> {
+    if resp == 3 {
+        format!("~{}\r\n", count).into_bytes()
+    } else {
+        make_array(count)
+    }
+}
+
+fn make_double(resp: u8, value: f64, digits: usize) -> Vec<u8> {
+    let text = format!("{:.*}", digits, value);
+    if resp == 3 {
+        format!(",{}\r\n", text).into_bytes()
+    } else {
+        make_bulk(text.as_bytes())
+    }
+}
+
+fn make_null(resp: u8) -> Vec<u8> {
+    if resp == 3 {
+        b"_\r\n".to_vec()
+    } else {
+        b"$-1\r\n".to_vec()
+    }
+}
+
+// Plain text meant to be shown as is, like INFO.
+fn make_verbatim(resp: u8, text: &[u8]) -> Vec<u8> {
+    if resp == 3 {
+        let mut output = format!("={}\r\ntxt:", text.len() + 4).into_bytes();
+        output.extend_from_slice(text);
+        output.extend_from_slice(b"\r\n");
+        output
+    } else {
+        make_bulk(text)
+    }
+}
+
+// Push frames are RESP3 pushes, or plain arrays for RESP2 subscribers.
+fn make_push(resp: u8, count: usize) -> Vec<u8> {
+    let mut frame = make_array(count);
+    if resp == 3 {
+        frame[0] = b'>';
+    }
+    frame
+}
+
+fn make_subscription_reply(resp: u8, kind: &str, name: Option<&[u8]>, count: usize) -> Vec<u8> {
+    let mut output = make_push(resp, 3);
+    output.extend(make_bulk(kind.as_bytes()));
+    match name {
+        Some(name) => output.extend(make_bulk(name)),
+        None => output.extend(make_null(resp)),
+    }
+    output.extend(format!(":{}\r\n", count).into_bytes());
+    output
+}
+
+// SUBSCRIBE, UNSUBSCRIBE, PSUBSCRIBE and PUNSUBSCRIBE. Each channel or
+// pattern gets its own confirmation frame carrying the running count of
+// subscriptions.
+fn handle_subscribe(
+    args: &[Vec<u8>],
+    server: &Server,
+    client: &Mutex<clients::Client>,
+) -> (Vec<u8>, bool, bool) {
+    let pattern = arg_match(&args[0], "PSUBSCRIBE") || arg_match(&args[0], "PUNSUBSCRIBE");
+    let subscribe = arg_match(&args[0], "SUBSCRIBE") || arg_match(&args[0], "PSUBSCRIBE");
+    if subscribe && args.len() < 2 {
+        return (invalid_num_args(&args[0]), false, false);
+    }
+    let kind = String::from_utf8_lossy(&args[0]).to_lowercase();
+    let mut client = client.lock().unwrap();
+    let id = client.id;
+    let names: Vec<Vec<u8>> = if args.len() > 1 {
+        args[1..].to_vec()
+    } else if pattern {
+        client.patterns.iter().cloned().collect()
+    } else {
+        client.channels.iter().cloned().collect()
+    };
+    let mut output = Vec::new();
+    for name in &names {
+        match (subscribe, pattern) {
+            (true, false) => {
+                if client.channels.insert(name.clone()) {
+                    server.pubsub.subscribe(id, name);
+                }
+            }
+            (true, true) => {
+                if client.patterns.insert(name.clone()) {
+                    server.pubsub.psubscribe(id, name);
+                }
+            }
+            (false, false) => {
+                if client.channels.remove(name) {
+                    server.pubsub.unsubscribe(id, name);
+                }
+            }
+            (false, true) => {
+                if client.patterns.remove(name) {
+                    server.pubsub.punsubscribe(id, name);
+                }
+            }
+        }
+        output.extend(make_subscription_reply(
+            client.resp,
+            &kind,
+            Some(name),
+            client.subscriptions(),
+        ));
+    }
+    if names.is_empty() {
+        output.extend(make_subscription_reply(client.resp, &kind, None, client.subscriptions()));
+    }
+    (output, false, false)
+}
+
+fn handle_publish(args: &[Vec<u8>], server: &Server) -> (Vec<u8>, bool, bool) {
+    if args.len() != 3 {
+        return (invalid_num_args(&args[0]), false, false);
+    }
+    let mut count = 0;
+    for (id, pattern) in server.pubsub.receivers(&args[1]) {
+        let delivered = server.push(id, |resp| {
+            let mut frame;
+            match pattern {
+                Some(ref pattern) => {
+                    frame = make_push(resp, 4);
+                    frame.extend(make_bulk(b"pmessage"));
+                    frame.extend(make_bulk(pattern));
+                }
+                None => {
+                    frame = make_push(resp, 3);
+                    frame.extend(make_bulk(b"message"));
+                }
+            }
+            frame.extend(make_bulk(&args[1]));
+            frame.extend(make_bulk(&args[2]));
+            frame
+        });
+        if delivered {
+            count += 1;
+        }
+    }
+    (format!(":{}\r\n", count).into_bytes(), false, false)
+}
+
+// A RESP2 connection with subscriptions can only manage them; RESP3 lets
+// pushes interleave with ordinary replies.
+fn subscribe_context_error(args: &[Vec<u8>], client: &Mutex<clients::Client>) -> Option<Vec<u8>> {
+    let client = client.lock().unwrap();
+    if client.resp == 3 || client.subscriptions() == 0 {
+        return None;
+    }
+    for allowed in &["SUBSCRIBE", "UNSUBSCRIBE", "PSUBSCRIBE", "PUNSUBSCRIBE", "PING", "QUIT"] {
+        if arg_match(&args[0], allowed) {
+            return None;
+        }
+    }
+    Some(
+        format!(
+            "-ERR Can't execute '{}': only (P)SUBSCRIBE / (P)UNSUBSCRIBE / PING / QUIT are allowed in this context\r\n",
+            safe_line_from_slice(&args[0]).to_lowercase()
+        ).into_bytes(),
+    )
+}
+
+// SHUTDOWN [NOSAVE|SAVE]. There is no persistence layer yet, so an explicit
+// SAVE cannot be honoured and the shutdown is refused rather than losing data
+// the caller asked to keep. On success the caller's connection is closed
+// without a reply and the server drains and exits.
+fn handle_shutdown(args: &[Vec<u8>], server: &Server) -> (Vec<u8>, bool, bool) {
+    let mut save = None;
+    for arg in &args[1..] {
+        if arg_match(arg, "NOSAVE") && save.is_none() {
+            save = Some(false);
+        } else if arg_match(arg, "SAVE") && save.is_none() {
+            save = Some(true);
+        } else {
+            return (b"-ERR syntax error\r\n".to_vec(), false, false);
+        }
+    }
+    if save == Some(true) {
+        return (
+            b"-ERR Errors trying to SHUTDOWN. Persistence is not available.\r\n".to_vec(),
+            false,
+            false,
+        );
+    }
+    server.request_shutdown();
+    (Vec::new(), false, true)
+}
+
+// Refuses keys longer than max-key-length and values larger than
+// max-value-size, where those are set.
+fn check_sizes(key: &[u8], value: &[u8], server: &Server) -> Result<(), Vec<u8>> {
+    let config = server.config.read().unwrap();
+    if config.max_key_length > 0 && key.len() > config.max_key_length {
+        return Err(format!(
+            "-ERR key of {} bytes exceeds max-key-length of {}\r\n",
+            key.len(),
+            config.max_key_length
+        ).into_bytes());
+    }
+    if config.max_value_size > 0 && value.len() > config.max_value_size {
+        return Err(format!(
+            "-ERR value of {} bytes exceeds max-value-size of {}\r\n",
+            value.len(),
+            config.max_value_size
+        ).into_bytes());
+    }
+    Ok(())
+}
+
+// Decides whether FLUSHDB/FLUSHALL hand the old keyspace to the lazyfree
+// thread. Without an explicit ASYNC or SYNC the lazyfree-lazy-user-flush
+// setting decides.
+fn parse_flush_mode(args: &[Vec<u8>], server: &Server) -> Result<bool, Vec<u8>> {
+    match args.len() {
+        1 => Ok(server.config.read().unwrap().lazyfree_lazy_user_flush),
+        2 if arg_match(&args[1], "ASYNC") => Ok(true),
+        2 if arg_match(&args[1], "SYNC") => Ok(false),
+        2 => Err(b"-ERR syntax error\r\n".to_vec()),
+        _ => Err(invalid_num_args(&args[0])),
+    }
+}
+
+fn parse_db_index(arg: &[u8], store: &keyspace::Locked) -> Result<usize, Vec<u8>> {
+    match String::from_utf8_lossy(arg).parse::<i64>() {
+        Ok(n) if n >= 0 && (n as usize) < store.databases() => Ok(n as usize),
+        Ok(_) => Err(b"-ERR DB index is out of range\r\n".to_vec()),
+        Err(_) => Err(b"-ERR value is not an integer or out of range\r\n".to_vec()),
+    }
+}
+
+// Values at least this large are sent straight from the keyspace by GET
+// instead of being copied into the reply.
+const SHARED_REPLY_MIN: usize = 4 * 1024;
+
+// Runs a command for a client. A large value read by GET goes out as the
+// stored Bytes, KEYS and MEMORY BIGKEYS walk the keyspace a chunk at a time;
+// everything else is built
+// by handle_command, which scripts call directly.
+fn command_reply(
+    args: &[Vec<u8>],
+    store: &mut keyspace::Locked,
+    server: &Server,
+    client: &Mutex<clients::Client>,
+) -> (resp::Reply, bool, bool) {
+    if args.len() == 2 && arg_match(&args[0], "GET") {
+        let db = client.lock().unwrap().db;
+        let value = store.get(db, &args[1]).filter(|v| v.len() >= SHARED_REPLY_MIN);
+        if let Some(value) = value {
+            if subscribe_context_error(args, client).is_none() {
+                server.keyspace.count_lookup(true);
+                return (resp::Reply::shared_bulk(value), false, false);
+            }
+        }
+    }
+    if args.len() == 2 && arg_match(&args[0], "KEYS") && subscribe_context_error(args, client).is_none() {
+        return (keys_reply(&args[1], server, client), false, false);
+    }
+    if args.len() >= 2 && arg_match(&args[0], "MEMORY") && arg_match(&args[1], "BIGKEYS")
+        && subscribe_context_error(args, client).is_none()
+    {
+        return (bigkeys_reply(args, server, client).into(), false, false);
+    }
+    let (output, write, close) = handle_command(args, store, server, client);
+    (output.into(), write, close)
+}
+
+fn handle_command(
+    args: &[Vec<u8>],
+    store: &mut keyspace::Locked,
+    server: &Server,
+    client: &Mutex<clients::Client>,
+) -> (Vec<u8>, bool, bool) {
+    if let Some(err) = subscribe_context_error(args, client) {
+        return (err, false, false);
+    }
+    match registry::dispatch(args, store, server, client) {
+        Some(reply) => reply,
+        None => (unknown_command(&args[0]), false, false),
+    }
+}
+
+// The built-in commands' handlers, by the names the command table gives
+// them.
+fn builtin_commands() -> Vec<(&'static str, registry::Builtin)> {
+    let mut builtins = Vec::new();
+    {
+        let mut add = |name: &'static str, handler: registry::Builtin| builtins.push((name, handler));
+        add("acl", |args, _, server, client| handle_acl(args, server, client));
+        add("asking", |args, _, server, client| handle_asking(args, server, client));
+        add("auth", |args, _, server, client| handle_auth(args, server, client));
+        add("client", |args, _, server, client| handle_client(args, server, client));
+        add("cluster", |args, store, server, client| handle_cluster(args, store, server, client));
+        add("command", |args, _, _, client| handle_commands(args, client));
+        add("config", |args, _, server, client| handle_config(args, server, client));
+        add("dbsize", handle_dbsize);
+        add("debug", |args, store, server, client| handle_debug(args, store, server, client));
+        add("del", handle_del);
+        add("discard", |_, _, _, client| handle_discard(client));
+        add("eval", handle_eval);
+        add("evalsha", handle_eval);
+        add("exec", handle_exec);
+        add("fcall", handle_fcall);
+        add("fcall_ro", handle_fcall);
+        add("flushall", handle_flushall);
+        add("flushdb", handle_flushdb);
+        add("function", |args, _, server, client| handle_function(args, server, client));
+        add("get", handle_get);
+        add("hello", |args, _, server, client| handle_hello(args, server, client));
+        add("hotkeys", |args, _, server, client| handle_hotkeys(args, server, client));
+        add("info", |args, _, server, client| handle_info(args, server, client));
+        add("keys", handle_keys);
+        add("latency", |args, _, server, client| handle_latency(args, server, client));
+        add("memory", |args, store, server, client| handle_memory(args, store, server, client));
+        add("migrate", |args, store, _, client| handle_migrate(args, store, client));
+        add("module", |args, _, server, client| handle_module(args, server, client));
+        add("move", handle_move);
+        add("multi", |_, _, _, client| handle_multi(client));
+        add("object", handle_object);
+        add("ping", handle_ping);
+        add("psubscribe", |args, _, server, client| handle_subscribe(args, server, client));
+        add("publish", |args, _, server, _| handle_publish(args, server));
+        add("punsubscribe", |args, _, server, client| handle_subscribe(args, server, client));
+        add("quit", |_, _, _, _| (b"+OK\r\n".to_vec(), false, true));
+        add("restore-asking", handle_restore);
+        add("role", |_, _, _, _| handle_role());
+        add("script", |args, _, server, _| handle_script(args, server));
+        add("select", handle_select);
+        add("set", handle_set);
+        add("shutdown", |args, _, server, _| handle_shutdown(args, server));
+        add("subscribe", |args, _, server, client| handle_subscribe(args, server, client));
+        add("swapdb", handle_swapdb);
+        add("unlink", handle_unlink);
+        add("unsubscribe", |args, _, server, client| handle_subscribe(args, server, client));
+    }
+    builtins
+}
+
+// Every server is a master without replicas.
+fn handle_role() -> (Vec<u8>, bool, bool) {
+    let mut output = make_array(3);
+    output.extend(make_bulk(b"master"));
+    output.extend(b":0\r\n");
+    output.extend(make_array(0));
+    (output, false, false)
+}
+
+fn handle_multi(client: &Mutex<clients::Client>) -> (Vec<u8>, bool, bool) {
+    let mut client = client.lock().unwrap();
+    if client.multi.is_some() {
+        return (b"-ERR MULTI calls can not be nested\r\n".to_vec(), false, false);
+    }
+    client.multi = Some(Vec::new());
+    client.multi_failed = false;
+    (b"+OK\r\n".to_vec(), false, false)
+}
+
+fn handle_discard(client: &Mutex<clients::Client>) -> (Vec<u8>, bool, bool) {
+    let mut client = client.lock().unwrap();
+    if client.multi.take().is_none() {
+        return (b"-ERR DISCARD without MULTI\r\n".to_vec(), false, false);
+    }
+    client.multi_failed = false;
+    (b"+OK\r\n".to_vec(), false, false)
+}
+
+// Runs the queued commands with every shard held, so nothing runs in
+// between. A command failing as it runs leaves its error in the reply and
+// the rest still run; there is no rollback.
+fn handle_exec(
+    _args: &[Vec<u8>],
+    store: &mut keyspace::Locked,
+    server: &Server,
+    client: &Mutex<clients::Client>,
+) -> (Vec<u8>, bool, bool) {
+    let queued = {
+        let mut client = client.lock().unwrap();
+        let queued = match client.multi.take() {
+            Some(queued) => queued,
+            None => return (b"-ERR EXEC without MULTI\r\n".to_vec(), false, false),
+        };
+        if client.multi_failed {
+            client.multi_failed = false;
+            return (
+                b"-EXECABORT Transaction discarded because of previous errors.\r\n".to_vec(),
+                false,
+                false,
+            );
+        }
+        queued
+    };
+    let mut output = make_array(queued.len());
+    let mut write = false;
+    for args in &queued {
+        let start = Instant::now();
+        let (reply, w, _) = handle_command(args, store, server, client);
+        server.command_stats.record(command_name(args), start.elapsed(), false, &reply);
+        server.cron.count_command();
+        write |= w;
+        output.extend(reply);
+    }
+    (output, write, false)
+}
+
+fn handle_ping(
+    args: &[Vec<u8>],
+    _store: &mut keyspace::Locked,
+    _server: &Server,
+    client: &Mutex<clients::Client>,
+) -> (Vec<u8>, bool, bool) {
+    let subscribed = {
+        let client = client.lock().unwrap();
+        client.resp == 2 && client.subscriptions() > 0
+    };
+    match args.len() {
+        // Subscribed RESP2 connections get PING replies shaped like
+        // messages so clients can tell them apart.
+        1 if subscribed => {
+            let mut output = make_array(2);
+            output.extend(make_bulk(b"pong"));
+            output.extend(make_bulk(&Vec::new()));
+            (output, false, false)
+        }
+        2 if subscribed => {
+            let mut output = make_array(2);
+            output.extend(make_bulk(b"pong"));
+            output.extend(make_bulk(&args[1]));
+            (output, false, false)
+        }
+        1 => (b"+PONG\r\n".to_vec(), false, false),
+        2 => (make_bulk(&args[1]), false, false),
+        _ => (invalid_num_args(&args[0]), false, false),
+    }
+}
+
+fn handle_set(
+    args: &[Vec<u8>],
+    store: &mut keyspace::Locked,
+    server: &Server,
+    client: &Mutex<clients::Client>,
+) -> (Vec<u8>, bool, bool) {
+    match args.len() {
+        3 => {
+            if let Err(e) = check_sizes(&args[1], &args[2], server) {
+                return (e, false, false);
+            }
+            let value = {
+                let config = server.config.read().unwrap();
+                server.compressor.encode(
+                    Bytes::from(args[2].clone()),
+                    &config.compression,
+                    config.compression_threshold,
+                )
+            };
+            let db = client.lock().unwrap().db;
+            store.insert(db, args[1].clone(), value);
+            (b"+OK\r\n".to_vec(), true, false)
+        }
+        _ => (invalid_num_args(&args[0]), false, false),
+    }
+}
+
+fn handle_flushdb(
+    args: &[Vec<u8>],
+    store: &mut keyspace::Locked,
+    server: &Server,
+    client: &Mutex<clients::Client>,
+) -> (Vec<u8>, bool, bool) {
+    let db = client.lock().unwrap().db;
+    match parse_flush_mode(args, server) {
+        Ok(true) => {
+            server.lazyfree.free(store.take(db));
+            (b"+OK\r\n".to_vec(), true, false)
+        }
+        Ok(false) => {
+            store.clear(db);
+            (b"+OK\r\n".to_vec(), true, false)
+        }
+        Err(e) => (e, false, false),
+    }
+}
+
+fn handle_flushall(
+    args: &[Vec<u8>],
+    store: &mut keyspace::Locked,
+    server: &Server,
+    _client: &Mutex<clients::Client>,
+) -> (Vec<u8>, bool, bool) {
+    match parse_flush_mode(args, server) {
+        Ok(true) => {
+            for db in 0..store.databases() {
+                server.lazyfree.free(store.take(db));
+            }
+            (b"+OK\r\n".to_vec(), true, false)
+        }
+        Ok(false) => {
+            for db in 0..store.databases() {
+                store.clear(db);
+            }
+            (b"+OK\r\n".to_vec(), true, false)
+        }
+        Err(e) => (e, false, false),
+    }
+}
+
+fn handle_dbsize(
+    args: &[Vec<u8>],
+    store: &mut keyspace::Locked,
+    _server: &Server,
+    client: &Mutex<clients::Client>,
+) -> (Vec<u8>, bool, bool) {
+    let db = client.lock().unwrap().db;
+    match args.len() {
+        1 => (format!(":{}\r\n", store.len(db)).into_bytes(), false, false),
+        _ => (invalid_num_args(&args[0]), false, false),
+    }
+}
+
+fn handle_del(
+    args: &[Vec<u8>],
+    store: &mut keyspace::Locked,
+    server: &Server,
+    client: &Mutex<clients::Client>,
+) -> (Vec<u8>, bool, bool) {
+    let db = client.lock().unwrap().db;
+    match args.len() {
+        2 => {
+            if let Some(value) = store.remove(db, &args[1]) {
+                if server.config.read().unwrap().lazyfree_lazy_user_del {
+                    server.lazyfree.free_value(value);
+                }
+                (b":1\r\n".to_vec(), true, false)
+            } else {
+                (b":0\r\n".to_vec(), false, false)
+            }
+        }
+        _ => (invalid_num_args(&args[0]), false, false),
+    }
+}
+
+fn handle_unlink(
+    args: &[Vec<u8>],
+    store: &mut keyspace::Locked,
+    server: &Server,
+    client: &Mutex<clients::Client>,
+) -> (Vec<u8>, bool, bool) {
+    let db = client.lock().unwrap().db;
+    match args.len() {
+        1 => (invalid_num_args(&args[0]), false, false),
+        _ => {
+            let mut removed = 0;
+            for key in &args[1..] {
+                if let Some(value) = store.remove(db, key) {
+                    server.lazyfree.free_value(value);
+                    removed += 1;
+                }
+            }
+            (format!(":{}\r\n", removed).into_bytes(), removed > 0, false)
+        }
+    }
+}
+
+fn handle_get(
+    args: &[Vec<u8>],
+    store: &mut keyspace::Locked,
+    server: &Server,
+    client: &Mutex<clients::Client>,
+) -> (Vec<u8>, bool, bool) {
+    let (db, resp) = {
+        let client = client.lock().unwrap();
+        (client.db, client.resp)
+    };
+    match args.len() {
+        2 => {
+            let value = store.get(db, &args[1]);
+            server.keyspace.count_lookup(value.is_some());
+            match value {
+                Some(v) => (make_bulk(&v), false, false),
+                None => (make_null(resp), false, false),
+            }
+        }
+        _ => (invalid_num_args(&args[0]), false, false),
+    }
+}
+
+fn handle_keys(
+    args: &[Vec<u8>],
+    store: &mut keyspace::Locked,
+    _server: &Server,
+    client: &Mutex<clients::Client>,
+) -> (Vec<u8>, bool, bool) {
+    let db = client.lock().unwrap().db;
+    match args.len() {
+        2 => {
+            let all = args[1] == b"*";
+            let mut res_keys = Vec::new();
+            for (key, _val) in store.iter(db) {
+                if all || pattern::matches(&args[1], key, false) {
+                    res_keys.push(key);
+                }
+            }
+            let mut output = make_array(res_keys.len());
+            for key in res_keys {
+                output.extend(make_bulk(key));
+            }
+            (output, false, false)
+        }
+        _ => (invalid_num_args(&args[0]), false, false),
+    }
+}
+
+// Keys walk_keys reads from a shard before letting go of it again.
+const KEYS_CHUNK: usize = 1024;
+
+const TIME_LIMIT_ERROR: &[u8] = b"-ERR command ran past command-time-limit\r\n";
+
+// Walks the client's database a chunk of a shard at a time, holding only
+// that shard while visit looks at the chunk, so a large keyspace doesn't
+// stall everyone else for the whole walk. Keys written meanwhile may or may
+// not be visited. visit returns false to stop early. The error to reply
+// with is returned if a script went past its time limit while a shard was
+// waited for, or if the walk itself went past command-time-limit.
+fn walk_keys<F>(server: &Server, db: usize, mut visit: F) -> Result<(), &'static [u8]>
+where
+    F: FnMut(&keyspace::Locked, &[small::Key]) -> bool,
+{
+    let limit = server.config.read().unwrap().command_time_limit as u64;
+    let deadline = if limit > 0 {
+        Some(Instant::now() + Duration::from_millis(limit))
+    } else {
+        None
+    };
+    for shard in 0..server.keyspace.len() {
+        let mut from = 0;
+        loop {
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                return Err(TIME_LIMIT_ERROR);
+            }
+            let store = match lock_shards(server, &[shard], false) {
+                Some(store) => store,
+                None => return Err(scripting::BUSY_ERROR),
+            };
+            let keys = store.keys(shard, db, from, KEYS_CHUNK);
+            if !visit(&store, keys) {
+                return Ok(());
+            }
+            if keys.len() < KEYS_CHUNK {
+                break;
+            }
+            from += keys.len();
+        }
+    }
+    Ok(())
+}
+
+// KEYS for a client, walked by walk_keys. The reply is kept in a segment
+// per chunk, and building it stops once it is past the client's hard output
+// limit, as the connection is closed then.
+fn keys_reply(pattern: &[u8], server: &Server, client: &Mutex<clients::Client>) -> resp::Reply {
+    let db = client.lock().unwrap().db;
+    let (_, limit) = output_limit(client, server);
+    let all = pattern == b"*";
+    let mut segments: Vec<buffer::Segment> = vec![Vec::new().into()];
+    let (mut count, mut len) = (0, 0);
+    let walked = walk_keys(server, db, |_, keys| {
+        let mut chunk = Vec::new();
+        for key in keys {
+            if all || pattern::matches(pattern, key, false) {
+                chunk.extend(make_bulk(key));
+                count += 1;
+            }
+        }
+        len += chunk.len();
+        if !chunk.is_empty() {
+            segments.push(chunk.into());
+        }
+        limit.hard == 0 || len <= limit.hard
+    });
+    if let Err(err) = walked {
+        return err.to_vec().into();
+    }
+    segments[0] = make_array(count).into();
+    resp::Reply { segments }
+}
+
+fn handle_select(
+    args: &[Vec<u8>],
+    store: &mut keyspace::Locked,
+    server: &Server,
+    client: &Mutex<clients::Client>,
+) -> (Vec<u8>, bool, bool) {
+    match args.len() {
+        2 => match parse_db_index(&args[1], store) {
+            Ok(index) if index != 0 && server.cluster.is_some() => {
+                (b"-ERR SELECT is not allowed in cluster mode\r\n".to_vec(), false, false)
+            }
+            Ok(index) => {
+                client.lock().unwrap().db = index;
+                (b"+OK\r\n".to_vec(), false, false)
+            }
+            Err(e) => (e, false, false),
+        },
+        _ => (invalid_num_args(&args[0]), false, false),
+    }
+}
+
+// Cluster nodes only have database 0, which SWAPDB and MOVE need more than.
+fn cluster_single_db(args: &[Vec<u8>], server: &Server) -> Option<Vec<u8>> {
+    server.cluster.as_ref()?;
+    let name = String::from_utf8_lossy(&args[0]).to_uppercase();
+    Some(format!("-ERR {} is not allowed in cluster mode\r\n", name).into_bytes())
+}
+
+fn handle_swapdb(
+    args: &[Vec<u8>],
+    store: &mut keyspace::Locked,
+    server: &Server,
+    _client: &Mutex<clients::Client>,
+) -> (Vec<u8>, bool, bool) {
+    if let Some(err) = cluster_single_db(args, server) {
+        return (err, false, false);
+    }
+    match args.len() {
+        3 => {
+            let first = match String::from_utf8_lossy(&args[1]).parse::<usize>() {
+                Ok(index) => index,
+                Err(_) => return (b"-ERR invalid first DB index\r\n".to_vec(), false, false),
+            };
+            let second = match String::from_utf8_lossy(&args[2]).parse::<usize>() {
+                Ok(index) => index,
+                Err(_) => {
+                    return (b"-ERR invalid second DB index\r\n".to_vec(), false, false)
+                }
+            };
+            if first >= store.databases() || second >= store.databases() {
+                return (b"-ERR DB index is out of range\r\n".to_vec(), false, false);
+            }
+            // Every shard is swapped under its lock, all held at once, so
+            // no client ever observes a half-swapped pair.
+            store.swap(first, second);
+            (b"+OK\r\n".to_vec(), true, false)
+        }
+        _ => (invalid_num_args(&args[0]), false, false),
+    }
+}
+
+fn handle_move(
+    args: &[Vec<u8>],
+    store: &mut keyspace::Locked,
+    server: &Server,
+    client: &Mutex<clients::Client>,
+) -> (Vec<u8>, bool, bool) {
+    if let Some(err) = cluster_single_db(args, server) {
+        return (err, false, false);
+    }
+    let db = client.lock().unwrap().db;
+    match args.len() {
+        3 => {
+            let dst = match parse_db_index(&args[2], store) {
+                Ok(index) => index,
+                Err(e) => return (e, false, false),
+            };
+            if dst == db {
+                return (
+                    b"-ERR source and destination objects are the same\r\n".to_vec(),
+                    false,
+                    false,
+                );
+            }
+            if !store.contains_key(db, &args[1]) || store.contains_key(dst, &args[1]) {
+                return (b":0\r\n".to_vec(), false, false);
+            }
+            let value = store.remove(db, &args[1]).unwrap();
+            store.insert(dst, args[1].clone(), value);
+            (b":1\r\n".to_vec(), true, false)
+        }
+        _ => (invalid_num_args(&args[0]), false, false),
+    }
+}
+
+fn handle_asking(
+    _args: &[Vec<u8>],
+    server: &Server,
+    client: &Mutex<clients::Client>,
+) -> (Vec<u8>, bool, bool) {
+    if server.cluster.is_none() {
+        return (b"-ERR This instance has cluster support disabled\r\n".to_vec(), false, false);
+    }
+    client.lock().unwrap().asking = true;
+    (b"+OK\r\n".to_vec(), false, false)
+}
@@ -0,0 +1,128 @@
+// Server log.
+//
+// Each entry is one line of key=value pairs, in the order they are given,
+// after the time, pid and level:
+//
+//     ts=2026-10-15T09:12:44.301Z pid=4242 level=notice event=ready port=6380
+//
+// Values holding spaces, quotes or '=' are quoted. Entries below loglevel
+// are dropped. With no logfile they go to standard output, otherwise they
+// are appended to it, and reopen() starts a new file at the same path once
+// the old one has been rotated away.
+
+use std::fmt::Display;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use chrono::Utc;
+
+// Levels in increasing severity. "nothing" turns logging off.
+pub const LEVELS: &[&str] = &["debug", "verbose", "notice", "warning", "nothing"];
+
+#[derive(Clone, Copy, PartialEq, PartialOrd)]
+pub enum Level {
+    Debug,
+    Verbose,
+    Notice,
+    Warning,
+}
+
+impl Level {
+    fn name(&self) -> &'static str {
+        LEVELS[*self as usize]
+    }
+}
+
+pub struct Log {
+    level: AtomicUsize,
+    path: String,
+    file: Mutex<Option<File>>,
+}
+
+impl Log {
+    pub fn open(path: &str, level: &str) -> Result<Log, String> {
+        let log = Log {
+            level: AtomicUsize::new(2),
+            path: path.to_string(),
+            file: Mutex::new(None),
+        };
+        log.set_level(level);
+        log.reopen()?;
+        Ok(log)
+    }
+
+    pub fn set_level(&self, level: &str) {
+        let level = LEVELS.iter().position(|&l| l == level).unwrap_or(2);
+        self.level.store(level, Ordering::Relaxed);
+    }
+
+    pub fn enabled(&self, level: Level) -> bool {
+        level as usize >= self.level.load(Ordering::Relaxed)
+    }
+
+    // Opens the logfile again, for when it has been moved or removed.
+    pub fn reopen(&self) -> Result<(), String> {
+        if self.path.is_empty() {
+            return Ok(());
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .map_err(|e| format!("Can't open the log file {}: {}", self.path, e))?;
+        *self.file.lock().unwrap() = Some(file);
+        Ok(())
+    }
+
+    pub fn write(&self, level: Level, event: &str, fields: &[(&str, &dyn Display)]) {
+        if !self.enabled(level) {
+            return;
+        }
+        let mut line = format!(
+            "ts={} pid={} level={} event={}",
+            Utc::now().format("%Y-%m-%dT%H:%M:%S%.3fZ"),
+            process::id(),
+            level.name(),
+            quote(event)
+        );
+        for &(key, value) in fields {
+            line.push(' ');
+            line.push_str(key);
+            line.push('=');
+            line.push_str(&quote(&value.to_string()));
+        }
+        line.push('\n');
+        // A log that can't be written to has nowhere to report it.
+        let mut file = self.file.lock().unwrap();
+        let _ = match *file {
+            Some(ref mut file) => file.write_all(line.as_bytes()),
+            None => io::stdout().write_all(line.as_bytes()),
+        };
+    }
+
+    pub fn debug(&self, event: &str, fields: &[(&str, &dyn Display)]) {
+        self.write(Level::Debug, event, fields);
+    }
+
+    pub fn verbose(&self, event: &str, fields: &[(&str, &dyn Display)]) {
+        self.write(Level::Verbose, event, fields);
+    }
+
+    pub fn notice(&self, event: &str, fields: &[(&str, &dyn Display)]) {
+        self.write(Level::Notice, event, fields);
+    }
+
+    pub fn warning(&self, event: &str, fields: &[(&str, &dyn Display)]) {
+        self.write(Level::Warning, event, fields);
+    }
+}
+
+fn quote(value: &str) -> String {
+    if !value.is_empty() && !value.bytes().any(|b| b <= b' ' || b == b'"' || b == b'=' || b == 127) {
+        return value.to_string();
+    }
+    format!("{:?}", value)
+}
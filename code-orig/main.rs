@@ -1,40 +1,18 @@
-extern crate crossbeam;
-extern crate mio;
-extern crate num_cpus;
+extern crate cache_server;
 extern crate clap;
-extern crate glob;
+extern crate num_cpus;
+extern crate signal_hook;
+extern crate tracing_subscriber;
 
-use std::io;
-use std::io::{Read, Write};
-use mio::*;
-use mio::net::{TcpListener, TcpStream};
-use std::collections::HashMap;
-use std::sync::Mutex;
-use std::sync::Arc;
-use std::net::SocketAddr;
-use clap::{App, Arg};
-use glob::Pattern;
+mod bench;
 
-struct Store {
-    keys: HashMap<Vec<u8>, Vec<u8>>,
-}
-
-impl Store {
-    pub fn new() -> Store {
-        Store { keys: HashMap::new() }
-    }
-}
-
-struct Conn {
-    stream: TcpStream,
-    addr: SocketAddr,
-    input: Vec<u8>,
-    output: Vec<u8>,
-    close: bool,
-    reg_write: bool,
-}
+use std::thread;
+use cache_server::{Config, Server};
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM, SIGUSR1};
+use signal_hook::iterator::Signals;
 
 fn main() {
+    let io_backends = cache_server::io_backends();
     let matches = clap::App::new("cache-server")
         .version("v0.0.1")
         .arg(
@@ -52,8 +30,362 @@ fn main() {
                 .default_value("6380")
                 .takes_value(true),
         )
+        .arg(
+            clap::Arg::with_name("bind")
+                .help("Sets the addresses to listen on, '-' prefixed ones being optional")
+                .long("bind")
+                .default_value("0.0.0.0")
+                .multiple(true)
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("protected-mode")
+                .help("Only accepts loopback connections while the default user has no password")
+                .long("protected-mode")
+                .possible_values(&["yes", "no"])
+                .default_value("yes")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("tcp-backlog")
+                .help("Sets the length of the queue of connections waiting to be accepted")
+                .long("tcp-backlog")
+                .default_value("511")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("tcp-keepalive")
+                .help("Sets the seconds between TCP keepalive probes, 0 to disable")
+                .long("tcp-keepalive")
+                .default_value("300")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("tcp-nodelay")
+                .help("Disables Nagle's algorithm on client sockets")
+                .long("tcp-nodelay")
+                .possible_values(&["yes", "no"])
+                .default_value("yes")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("reuseport")
+                .help("Lets every worker thread accept TCP connections on its own listener")
+                .long("reuseport")
+                .possible_values(&["yes", "no"])
+                .default_value("no")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("cluster-enabled")
+                .help("Serves a share of the hash slots of a cluster")
+                .long("cluster-enabled")
+                .possible_values(&["yes", "no"])
+                .default_value("no")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("cluster-config-file")
+                .help("Where the node keeps its view of the cluster")
+                .long("cluster-config-file")
+                .default_value("nodes.conf")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("cluster-announce-ip")
+                .help("The ip other nodes and clients are told to reach this node at")
+                .long("cluster-announce-ip")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("cluster-node-timeout")
+                .help("Milliseconds a node may go unreachable before it is considered failing")
+                .long("cluster-node-timeout")
+                .default_value("15000")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("proxy-protocol")
+                .help("Sets the ports whose connections start with a PROXY protocol header")
+                .long("proxy-protocol")
+                .multiple(true)
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("unixsocket")
+                .help("Sets the path of a Unix socket to also listen on")
+                .long("unixsocket")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("unixsocketperm")
+                .help("Sets the octal permissions of the Unix socket file")
+                .long("unixsocketperm")
+                .default_value("0")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("databases")
+                .help("Sets the number of logical databases")
+                .long("databases")
+                .default_value("16")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("shards")
+                .help("Sets the number of independently locked keyspace shards")
+                .long("shards")
+                .default_value("16")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("keyspace-backend")
+                .help("Locks shards exclusively (mutex) or lets readers share them (rwlock)")
+                .long("keyspace-backend")
+                .possible_values(cache_server::KEYSPACE_BACKENDS)
+                .default_value("mutex")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("io-backend")
+                .help("Serves connections from mio event loops, as tokio tasks or from io_uring")
+                .long("io-backend")
+                .possible_values(&io_backends)
+                .default_value("mio")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("aclfile")
+                .help("Sets the file users are loaded from and saved to")
+                .long("aclfile")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("tls-port")
+                .help("Sets the port TLS connections are accepted on, 0 to disable")
+                .long("tls-port")
+                .default_value("0")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("tls-cert-file")
+                .help("Sets the PEM certificate chain presented to TLS clients")
+                .long("tls-cert-file")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("tls-key-file")
+                .help("Sets the PEM private key of the TLS certificate")
+                .long("tls-key-file")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("tls-ca-cert-file")
+                .help("Sets the PEM CA certificates client certificates are verified against")
+                .long("tls-ca-cert-file")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("tls-auth-clients")
+                .help("Requires (yes), accepts (optional) or ignores (no) client certificates")
+                .long("tls-auth-clients")
+                .possible_values(&["yes", "no", "optional"])
+                .default_value("no")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("tls-auth-clients-user")
+                .help("Logs TLS clients in as the ACL user named by their certificate (CN) or not (off)")
+                .long("tls-auth-clients-user")
+                .possible_values(&["off", "CN", "cn"])
+                .default_value("off")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("loadmodule")
+                .help("Loads the module at the given path, followed by its arguments")
+                .long("loadmodule")
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("enable-module-command")
+                .help("Lets clients run MODULE: no, yes or local (loopback and Unix socket clients)")
+                .long("enable-module-command")
+                .possible_values(&["no", "yes", "local"])
+                .default_value("no")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("http-port")
+                .help("Serves /healthz, /readyz and /status over HTTP on this port, 0 to disable")
+                .long("http-port")
+                .default_value("0")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("daemonize")
+                .help("Runs the server in the background, detached from the terminal")
+                .long("daemonize")
+                .possible_values(&["yes", "no"])
+                .default_value("no")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("pidfile")
+                .help("Writes the server's pid to this file while it runs")
+                .long("pidfile")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("loglevel")
+                .help("Sets the least severe level logged")
+                .long("loglevel")
+                .possible_values(&["debug", "verbose", "notice", "warning", "nothing"])
+                .default_value("notice")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("logfile")
+                .help("Appends the log to this file instead of standard output")
+                .long("logfile")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("log-slower-than")
+                .help("Logs commands taking at least this many microseconds, 0 to disable")
+                .long("log-slower-than")
+                .default_value("10000")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("trace")
+                .help("Prints tracing spans and events to standard error, filtered by directives such as 'cache_server=debug'")
+                .long("trace")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("lua-time-limit")
+                .help("Sets the milliseconds after which a running script makes the server busy")
+                .long("lua-time-limit")
+                .default_value("5000")
+                .takes_value(true),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("bench")
+                .about("Benchmarks a running server")
+                .arg(
+                    clap::Arg::with_name("host")
+                        .help("Sets the server's host")
+                        .short("h")
+                        .long("host")
+                        .default_value("127.0.0.1")
+                        .takes_value(true),
+                )
+                .arg(
+                    clap::Arg::with_name("port")
+                        .help("Sets the server's port")
+                        .short("p")
+                        .long("port")
+                        .default_value("6380")
+                        .takes_value(true),
+                )
+                .arg(
+                    clap::Arg::with_name("clients")
+                        .help("Sets the number of connections sending commands at once")
+                        .short("c")
+                        .long("clients")
+                        .default_value("50")
+                        .takes_value(true),
+                )
+                .arg(
+                    clap::Arg::with_name("requests")
+                        .help("Sets the number of commands sent in total")
+                        .short("n")
+                        .long("requests")
+                        .default_value("100000")
+                        .takes_value(true),
+                )
+                .arg(
+                    clap::Arg::with_name("pipeline")
+                        .help("Sets the number of commands each client sends before reading replies")
+                        .short("P")
+                        .long("pipeline")
+                        .default_value("1")
+                        .takes_value(true),
+                )
+                .arg(
+                    clap::Arg::with_name("mix")
+                        .help("Sets the commands sent and their weights, such as 'set=1,get=9'")
+                        .long("mix")
+                        .default_value("set=1,get=1")
+                        .takes_value(true),
+                )
+                .arg(
+                    clap::Arg::with_name("keyspace")
+                        .help("Sets the number of distinct keys used")
+                        .short("r")
+                        .long("keyspace")
+                        .default_value("10000")
+                        .takes_value(true),
+                )
+                .arg(
+                    clap::Arg::with_name("value-size")
+                        .help("Sets the size of SET values in bytes")
+                        .short("d")
+                        .long("value-size")
+                        .default_value("3")
+                        .takes_value(true),
+                ),
+        )
         .get_matches();
 
+    if let Some(matches) = matches.subcommand_matches("bench") {
+        let number = |name: &str| -> usize {
+            let value = matches.value_of(name).unwrap_or("");
+            match value.parse::<usize>() {
+                Ok(n) => n,
+                Err(_) => {
+                    eprintln!("Invalid {} '{}'", name, value);
+                    std::process::exit(1);
+                }
+            }
+        };
+        let result = bench::parse_mix(matches.value_of("mix").unwrap_or("")).and_then(|mix| {
+            bench::run(&bench::Options {
+                host: matches.value_of("host").unwrap_or("127.0.0.1").to_string(),
+                port: number("port"),
+                clients: number("clients"),
+                requests: number("requests"),
+                pipeline: number("pipeline"),
+                mix,
+                keyspace: number("keyspace"),
+                value_size: number("value-size"),
+            })
+        });
+        if let Err(e) = result {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(directives) = matches.value_of("trace") {
+        let filter = match tracing_subscriber::EnvFilter::try_new(directives) {
+            Ok(filter) => filter,
+            Err(e) => {
+                eprintln!("Invalid trace filter '{}': {}", directives, e);
+                std::process::exit(1);
+            }
+        };
+        tracing_subscriber::fmt()
+            .with_env_filter(filter)
+            .with_span_events(tracing_subscriber::fmt::format::FmtSpan::CLOSE)
+            .with_writer(std::io::stderr)
+            .init();
+    }
+
     let threads = matches
         .value_of("threads")
         .unwrap_or(&num_cpus::get().to_string())
@@ -66,561 +398,152 @@ fn main() {
         .parse::<usize>()
         .unwrap_or(6380);
 
-    let addr = format!("0.0.0.0:{}", port);
-    let server = TcpListener::bind(&addr).await.unwrap();
-
-    let main_poll = Poll::new().unwrap();
-    main_poll
-        .register(&server, MAIN_POLL_TOKEN, Ready::readable(), mio::PollOpt::edge())
-        .unwrap();
-
-    let main_conns = Arc::new(Mutex::new(HashMap::new()));
-    let store = Arc::new(Mutex::new(Store::new()));
-
-    let mut child_polls = Vec::new();
-    for _ in 0..threads {
-        let poll = Poll::new().unwrap();
-        child_polls.push(poll);
-    }
-
-    crossbeam::scope(|scope| {
-        for poll in &child_polls {
-            let main_conns = main_conns.clone();
-            let store = store.clone();
-            scope.spawn(move || child_loop(poll, main_conns, store));
-        }
-        main_loop(&main_poll, &child_polls, main_conns, server)
-    });
-}
-
-fn main_loop(
-    main_poll: &Poll,
-    child_polls: &[Poll],
-    main_conns: Arc<Mutex<HashMap<usize, Conn>>>,
-    server: TcpListener,
-) {
-    let mut id = 0;
-    let mut events = Events::with_capacity(1);
-
-    loop {
-        main_poll.poll(&mut events, None).unwrap();
-        let _ = events.iter().last(); // Ignore the result, as it is not used
-
-        match server.accept() {
-            Ok((stream, addr)) => {
-                stream
-                    .set_keepalive(Some(std::time::Duration::from_secs(300)))
-                    .unwrap();
-
-                id += 1;
-                let child = &child_polls[id % child_polls.len()];
-                child
-                    .register(
-                        &stream,
-                        Token(id),
-                        Ready::readable() | Ready::writable(),
-                        mio::PollOpt::empty(),
-                    )
-                    .unwrap();
-
-                main_conns.lock().unwrap().insert(
-                    id,
-                    Conn {
-                        stream,
-                        addr,
-                        close: false,
-                        reg_write: false,
-                        input: Vec::new(),
-                        output: Vec::new(),
-                    },
-                );
-            }
-            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
-            Err(e) => panic!("encountered IO error: {}", e),
-        }
-    }
-}
-
-fn child_loop(
-    child_poll: &Poll,
-    main_conns: Arc<Mutex<HashMap<usize, Conn>>>,
-    store: Arc<Mutex<Store>>,
-) {
-    let mut packet = [0; 4096];
-    let mut streams: HashMap<usize, Conn> = HashMap::new();
-    let mut events = Events::with_capacity(1);
-
-    loop {
-        child_poll.poll(&mut events, None).unwrap();
-        let event = events.iter().last().unwrap();
-        let id = event.token().0;
-
-        let mut close = false;
-        let mut found = false;
-
-        if let Some(conn) = streams.get_mut(&id) {
-            found = true;
-            handle_existing_connection(conn, &mut close, &packet, id, &store);
-        }
-
-        if close {
-            streams.remove(&id);
-            event_closed(id);
-        } else if !found {
-            handle_new_connection(id, &mut streams, &main_conns, &child_poll, &store);
-        }
-    }
-}
-
-fn handle_existing_connection(
-    conn: &mut Conn,
-    close: &mut bool,
-    packet: &[u8],
-    id: usize,
-    store: &Arc<Mutex<Store>>,
-) {
-    while conn.output.len() > 0 {
-        match conn.stream.write(conn.output.as_slice()) {
-            Ok(n) => {
-                conn.output = conn.output.split_off(n);
-            }
-            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
-            Err(_) => {
-                *close = true;
-            }
-        }
-    }
-
-    if !conn.close && conn.output.len() == 0 {
-        match conn.stream.read(&mut packet[..]) {
-            Ok(n) => {
-                if n == 0 {
-                    *close = true;
-                } else {
-                    conn.input.extend_from_slice(&packet[..n]);
-                    let (output, conn_close) = event_data(id, &mut conn.input, store);
-                    conn.output.extend(output);
-                    conn.close = conn_close;
-                }
-            }
-            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
-            Err(_) => {
-                *close = true;
-            }
-        }
-    }
-}
-
-fn handle_new_connection(
-    id: usize,
-    streams: &mut HashMap<usize, Conn>,
-    main_conns: &Arc<Mutex<HashMap<usize, Conn>>>,
-    child_poll: &Poll,
-    store: &Arc<Mutex<Store>>,
-) {
-    if let Some(mut conn) = main_conns.lock().unwrap().remove(&id) {
-        let (output, close) = event_opened(id, conn.addr);
-
-        if output.len() > 0 {
-            conn.reg_write = true;
-            conn.close = close;
-            conn.output = output;
-            child_poll
-                .reregister(
-                    &conn.stream,
-                    Token(id),
-                    Ready::writable() | Ready::readable(),
-                    mio::PollOpt::empty(),
-                )
-                .unwrap();
-            streams.insert(id, conn);
-        } else if !close {
-            child_poll
-                .reregister(&conn.stream, Token(id), Ready::readable(), mio::PollOpt::empty())
-                .unwrap();
-            streams.insert(id, conn);
-        }
-    }
-}
-fn redcon_take_inline_args(packet: &[u8], mut ni: usize) -> (Vec<Vec<u8>>, String, usize, bool) {
-    let mut i = ni;
-    let mut s = ni;
-    let mut args: Vec<Vec<u8>> = Vec::new();
-
-    while i < packet.len() {
-        match packet[i] {
-            b' ' | b'\n' => {
-                let mut ii = i;
-                if packet[i] == b'\n' && i > s && packet[i - 1] == b'\r' {
-                    ii = i - 1;
-                }
-                if s != ii {
-                    args.push(packet[s..ii].to_vec());
-                }
-                if packet[i] == b'\n' {
-                    return (args, String::default(), i + 1, true);
-                }
-                s = i + 1;
-            }
-            b'"' | b'\'' => {
-                let (arg, new_i, balanced) = parse_quoted_arg(packet, i + 1);
-                if !balanced {
-                    return (
-                        Vec::default(),
-                        "ERR Protocol error: unbalanced quotes in request".to_string(),
-                        ni,
-                        false,
-                    );
-                }
-                args.push(arg);
-                i = new_i;
-                s = i + 1;
-            }
-            _ => {}
-        }
-        i += 1;
-    }
-
-    (Vec::default(), String::default(), ni, false)
-}
+    let databases = matches
+        .value_of("databases")
+        .unwrap_or("16")
+        .parse::<usize>()
+        .ok()
+        .filter(|&n| n > 0)
+        .unwrap_or(16);
 
-fn parse_quoted_arg(packet: &[u8], mut i: usize) -> (Vec<u8>, usize, bool) {
-    let mut arg = Vec::new();
-    let ch = packet[i - 1];
+    let shards = matches
+        .value_of("shards")
+        .unwrap_or("16")
+        .parse::<usize>()
+        .ok()
+        .filter(|&n| n > 0)
+        .unwrap_or(16);
 
-    while i < packet.len() {
-        match packet[i] {
-            b'\n' => return (Vec::default(), i, false),
-            b'\\' => {
-                i += 1;
-                match packet[i] {
-                    b'n' => arg.push(b'\n'),
-                    b'r' => arg.push(b'\r'),
-                    b't' => arg.push(b'\t'),
-                    b'b' => arg.push(0x08),
-                    b'a' => arg.push(0x07),
-                    b'x' => {
-                        if let Some(value) = parse_hex_byte(packet, i + 1) {
-                            arg.push(value);
-                            i += 2;
-                        } else {
-                            arg.push(b'x');
-                        }
-                    }
-                    _ => arg.push(packet[i]),
-                }
-            }
-            ch if packet[i] == ch => return (arg, i + 1, true),
-            _ => arg.push(packet[i]),
+    let lua_time_limit = matches
+        .value_of("lua-time-limit")
+        .unwrap_or("5000")
+        .parse::<usize>()
+        .unwrap_or(5000);
+
+    let mut config = Config::new();
+    config.threads = threads;
+    config.port = port;
+    config.databases = databases;
+    config.shards = shards;
+    config.keyspace_backend = matches.value_of("keyspace-backend").unwrap_or("mutex").to_string();
+    config.io_backend = matches.value_of("io-backend").unwrap_or("mio").to_string();
+    config.bind = matches
+        .values_of("bind")
+        .map(|addrs| addrs.collect::<Vec<_>>().join(" "))
+        .unwrap_or_else(|| "0.0.0.0".to_string());
+    config.protected_mode = matches.value_of("protected-mode") != Some("no");
+    config.aclfile = matches.value_of("aclfile").unwrap_or("").to_string();
+    config.tcp_backlog = matches
+        .value_of("tcp-backlog")
+        .unwrap_or("511")
+        .parse::<usize>()
+        .unwrap_or(511);
+    config.tcp_keepalive = matches
+        .value_of("tcp-keepalive")
+        .unwrap_or("300")
+        .parse::<usize>()
+        .unwrap_or(300);
+    config.tcp_nodelay = matches.value_of("tcp-nodelay") != Some("no");
+    config.reuseport = matches.value_of("reuseport") == Some("yes");
+    config.cluster_enabled = matches.value_of("cluster-enabled") == Some("yes");
+    config.cluster_config_file = matches
+        .value_of("cluster-config-file")
+        .unwrap_or("nodes.conf")
+        .to_string();
+    config.cluster_announce_ip = matches.value_of("cluster-announce-ip").unwrap_or("").to_string();
+    config.cluster_node_timeout = matches
+        .value_of("cluster-node-timeout")
+        .unwrap_or("15000")
+        .parse::<usize>()
+        .unwrap_or(15000);
+    config.proxy_protocol = matches
+        .values_of("proxy-protocol")
+        .map(|ports| ports.collect::<Vec<_>>().join(" "))
+        .unwrap_or_default();
+    config.unixsocket = matches.value_of("unixsocket").unwrap_or("").to_string();
+    config.unixsocketperm = u32::from_str_radix(matches.value_of("unixsocketperm").unwrap_or("0"), 8)
+        .unwrap_or(0);
+    config.lua_time_limit = lua_time_limit;
+    config.http_port = matches
+        .value_of("http-port")
+        .unwrap_or("0")
+        .parse::<usize>()
+        .unwrap_or(0);
+    config.daemonize = matches.value_of("daemonize") == Some("yes");
+    config.pidfile = matches.value_of("pidfile").unwrap_or("").to_string();
+    config.loglevel = matches.value_of("loglevel").unwrap_or("notice").to_string();
+    config.logfile = matches.value_of("logfile").unwrap_or("").to_string();
+    config.log_slower_than = matches
+        .value_of("log-slower-than")
+        .unwrap_or("10000")
+        .parse::<usize>()
+        .unwrap_or(10000);
+    config.loadmodule = matches
+        .values_of("loadmodule")
+        .map(|modules| modules.map(|m| m.to_string()).collect())
+        .unwrap_or_default();
+    config.enable_module_command = matches
+        .value_of("enable-module-command")
+        .unwrap_or("no")
+        .to_string();
+    config.tls_port = matches
+        .value_of("tls-port")
+        .unwrap_or("0")
+        .parse::<usize>()
+        .unwrap_or(0);
+    config.tls_cert_file = matches.value_of("tls-cert-file").unwrap_or("").to_string();
+    config.tls_key_file = matches.value_of("tls-key-file").unwrap_or("").to_string();
+    config.tls_ca_cert_file = matches.value_of("tls-ca-cert-file").unwrap_or("").to_string();
+    config.tls_auth_clients = matches.value_of("tls-auth-clients").unwrap_or("no").to_string();
+    config.tls_auth_clients_user = matches
+        .value_of("tls-auth-clients-user")
+        .unwrap_or("off")
+        .to_lowercase();
+
+    // Forking has to come before the server starts any thread.
+    if config.daemonize {
+        if let Err(e) = cache_server::daemonize() {
+            eprintln!("{}", e);
+            std::process::exit(1);
         }
-        i += 1;
     }
 
-    (Vec::default(), i, false)
-}
-
-fn parse_hex_byte(packet: &[u8], i: usize) -> Option<u8> {
-    if i + 1 < packet.len() {
-        let is_hex = |b: u8| (b >= b'0' && b <= b'9') || (b >= b'a' && b <= b'f') || (b >= b'A' && b <= b'F');
-        if is_hex(packet[i]) && is_hex(packet[i + 1]) {
-            Some((hex_to_digit(packet[i]) << 4) + hex_to_digit(packet[i + 1]))
-        } else {
-            None
+    let server = match Server::start(config) {
+        Ok(server) => server,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
         }
-    } else {
-        None
+    };
+    // Listeners are bound by now, so a systemd unit can count the server
+    // as started.
+    let ready = format!("READY=1\nMAINPID={}", std::process::id());
+    if let Err(e) = cache_server::sd_notify(&ready) {
+        eprintln!("{}", e);
     }
-}
 
-fn hex_to_digit(b: u8) -> u8 {
-    if b <= b'9' {
-        b - b'0'
-    } else if b <= b'F' {
-        b - b'A' + 10
-    } else {
-        b - b'a' + 10
-    }
-}
-
-fn redcon_take_multibulk_args(input: &Vec<u8>, ni: usize) -> (Vec<Vec<u8>>, String, usize, bool) {
-    let mut err = String::default();
-    let mut complete = false;
-    let mut args: Vec<Vec<u8>> = Vec::new();
-    let mut i = ni + 1;
-    let mut s = ni;
-    while i < input.len() {
-        if input[i - 1] == b'\r' && input[i] == b'\n' {
-            match String::from_utf8_lossy(&input[s + 1..i - 1]).parse::<usize>() {
-                Ok(nargs) => {
-                    i += 1;
-                    complete = nargs == 0;
-                    for _ in 0..nargs {
-                        s = i;
-                        while i < input.len() {
-                            if input[i - 1] == b'\r' && input[i] == b'\n' {
-                                if input[s] != b'$' {
-                                    err = format!("expected '$', got '{}'", input[s] as char);
-                                    break;
-                                }
-                                match String::from_utf8_lossy(&input[s + 1..i - 1])
-                                    .parse::<usize>() {
-                                    Ok(nbytes) => {
-                                        if input.len() < i + 1 + nbytes + 2 {
-                                            break;
-                                        }
-                                        let bin = input[i + 1..i + 1 + nbytes].to_vec();
-                                        args.push(bin);
-                                        i = i + 1 + nbytes + 2;
-                                    }
-                                    Err(_) => {
-                                        err = "invalid bulk length".to_string();
-                                    }
-                                }
-                                break;
-                            }
-                            i += 1;
-                        }
-                        if err != "" {
-                            break;
-                        }
-                        if args.len() == nargs {
-                            complete = true;
-                            break;
-                        }
+    // The first SIGTERM/SIGINT starts a graceful shutdown; a second one
+    // while draining exits immediately. SIGHUP and SIGUSR1 reopen the
+    // logfile after it has been rotated.
+    let mut signals = Signals::new([SIGINT, SIGTERM, SIGHUP, SIGUSR1]).unwrap();
+    {
+        let server = server.clone();
+        thread::spawn(move || {
+            for signal in signals.forever() {
+                if signal == SIGHUP || signal == SIGUSR1 {
+                    if let Err(e) = server.reopen_log() {
+                        eprintln!("{}", e);
                     }
+                    continue;
                 }
-                Err(_) => {
-                    err = "invalid multibulk length".to_string();
+                if server.is_shutting_down() {
+                    std::process::exit(1);
                 }
+                let _ = cache_server::sd_notify("STOPPING=1");
+                server.request_shutdown();
             }
-            break;
-        }
-        i += 1;
-    }
-    if err != "" {
-        err = format!("ERR Protocol error: {}", safe_line_from_string(err))
+        });
     }
-    (args, err, i, complete)
-}
 
-fn redcon_take_args(input: &Vec<u8>, ni: usize) -> (Vec<Vec<u8>>, String, usize, bool) {
-    if input.len() > ni {
-        if input[ni] == b'*' {
-            redcon_take_multibulk_args(input, ni)
-        } else {
-            redcon_take_inline_args(input, ni)
-        }
-    } else {
-        (Vec::default(), String::default(), ni, false)
-    }
-}
-
-fn safe_line_from_string(s: String) -> String {
-    safe_line_from_slice(s.as_bytes())
-}
-
-fn safe_line_from_slice(s: &[u8]) -> String {
-    let mut out = Vec::new();
-    for i in 0..s.len() {
-        if s[i] < b' ' {
-            out.push(b' ')
-        } else {
-            out.push(s[i]);
-        }
-    }
-    String::from_utf8_lossy(out.as_slice()).to_string()
-}
-
-fn arg_match(arg: &[u8], what: &str) -> bool {
-    if arg.len() != what.len() {
-        return false;
-    }
-    let what = what.as_bytes();
-    for i in 0..arg.len() {
-        if arg[i] != what[i] {
-            if arg[i] >= b'a' && arg[i] <= b'z' {
-                if arg[i] != what[i] + 32 {
-                    return false;
-                }
-            } else if arg[i] >= b'A' && arg[i] <= b'Z' {
-                if arg[i] != what[i] - 32 {
-                    return false;
-                }
-            }
-        }
-    }
-    return true;
-}
-
-fn event_opened(_id: usize, _addr: SocketAddr) -> (Vec<u8>, bool) {
-    // FUTURE: Hola connection.
-    (Vec::new(), false)
-}
-
-fn event_closed(_id: usize) {
-    // FUTURE: Adios connection.
-}
-
-fn event_data(_id: usize, input: &mut Vec<u8>, store: &Arc<Mutex<Store>>) -> (Vec<u8>, bool) {
-    let mut output = Vec::new();
-    let mut close = false;
-    let mut i = 0;
-    let mut argss = Vec::new();
-    loop {
-        let (args, err, ni, complete) = redcon_take_args(input, i);
-        if err != "" {
-            output.extend(format!("-{}\r\n", err).into_bytes());
-            close = true;
-            break;
-        } else if !complete {
-            break;
-        }
-        i = ni;
-        if args.len() > 0 {
-            argss.push(args);
-        }
-    }
-
-    if !close && argss.len() > 0 {
-        //let mut aof = Vec::new();
-        let mut store = store.lock().unwrap();
-        for args in argss {
-            let (hout, write, hclose) = handle_command(&args, &mut store.keys);
-            output.extend_from_slice(hout.as_slice());
-            if hclose {
-                close = true;
-                break;
-            }
-            if write {
-                //aof.extend(hout);
-            }
-        }
-        // if aof.len() > 0 {
-        //     // FUTURE: persist to disk
-        // }
-    }
-    if i > 0 {
-        if i < input.len() {
-            let mut remain = Vec::new();
-            remain.extend_from_slice(&input[i..input.len()]);
-            input.clear();
-            input.extend(remain)
-        } else {
-            input.clear()
-        }
-    }
-    (output, close)
-}
-
-fn make_bulk(bulk: &Vec<u8>) -> Vec<u8> {
-    let mut resp = Vec::new();
-    resp.push(b'$');
-    resp.extend_from_slice(&bulk.len().to_string().into_bytes());
-    resp.push(b'\r');
-    resp.push(b'\n');
-    resp.extend(bulk);
-    resp.push(b'\r');
-    resp.push(b'\n');
-    resp
-}
-
-fn make_array(count: usize) -> Vec<u8> {
-    let mut resp = Vec::new();
-    resp.push(b'*');
-    resp.extend_from_slice(&count.to_string().into_bytes());
-    resp.push(b'\r');
-    resp.push(b'\n');
-    resp
-}
-
-fn invalid_num_args(cmd: &Vec<u8>) -> Vec<u8> {
-    format!(
-        "-ERR wrong number of arguments for '{}' command\r\n",
-        String::from_utf8_lossy(cmd.as_slice())
-    ).into_bytes()
-        .to_vec()
-}
-
-fn handle_command(
-    args: &Vec<Vec<u8>>,
-    keys: &mut HashMap<Vec<u8>, Vec<u8>>,
-) -> (Vec<u8>, bool, bool) {
-    if arg_match(&args[0], "PING") {
-        match args.len() {
-            1 => (b"+PONG\r\n".to_vec(), false, false),
-            2 => (make_bulk(&args[1]), false, false),
-            _ => (invalid_num_args(&args[0]), false, false),
-        }
-    } else if arg_match(&args[0], "SET") {
-        match args.len() {
-            3 => {
-                keys.insert(args[1].clone(), args[2].clone());
-                (b"+OK\r\n".to_vec(), true, false)
-            }
-            _ => (invalid_num_args(&args[0]), false, false),
-        }
-    } else if arg_match(&args[0], "FLUSHDB") {
-        match args.len() {
-            1 => {
-                keys.clear();
-                (b"+OK\r\n".to_vec(), true, false)
-            }
-            _ => (invalid_num_args(&args[0]), false, false),
-        }
-    } else if arg_match(&args[0], "DEL") {
-        match args.len() {
-            2 => {
-                if let Some(_) = keys.remove(&args[1]) {
-                    (b":1\r\n".to_vec(), true, false)
-                } else {
-                    (b":0\r\n".to_vec(), false, false)
-                }
-            }
-            _ => (invalid_num_args(&args[0]), false, false),
-        }
-    } else if arg_match(&args[0], "GET") {
-        match args.len() {
-            2 => {
-                match keys.get(&args[1]) {
-                    Some(v) => (make_bulk(v), false, false),
-                    None => (b"$-1\r\n".to_vec(), false, false),
-                }
-            }
-            _ => (invalid_num_args(&args[0]), false, false),
-        }
-    } else if arg_match(&args[0], "KEYS") {
-        match args.len() {
-            2 => {
-                match Pattern::new(&String::from_utf8_lossy(args[1].as_slice()).clone()) {
-                    Ok(pat) => {
-                        let mut res_keys = Vec::new();
-                        for (key, _val) in keys.iter() {
-                            if pat.matches(&String::from_utf8_lossy(key)) {
-                                res_keys.push(key);
-                            }
-                        }
-                        let mut output = make_array(res_keys.len());
-                        for key in res_keys {
-                            output.extend(make_bulk(key));
-                        }
-                        (output, false, false)
-                    }
-                    Err(_) => (b"$-1\r\n".to_vec(), false, false),
-                }
-            }
-            _ => (invalid_num_args(&args[0]), false, false),
-        }
-    } else if arg_match(&args[0], "QUIT") {
-        (b"+OK\r\n".to_vec(), false, true)
-    } else {
-        (
-            format!(
-                "-ERR unknown command '{}'\r\n",
-                safe_line_from_slice(&args[0])
-            ).into_bytes()
-                .to_vec(),
-            false,
-            false,
-        )
-    }
+    server.wait();
 }
@@ -0,0 +1,155 @@
+// Memory usage estimates.
+//
+// Sizes are computed from what the value actually holds on the heap plus the
+// bookkeeping the containers add around it, with every allocation rounded up
+// to the allocator's 8 byte granularity. Stats gathers the per-database
+// counters maintained by Db together with client and script overheads.
+
+use std::fs;
+use std::mem;
+
+use bytes::Bytes;
+
+use alloc;
+
+// Bytes used by one hash map entry: the map slot holding the key and value
+// headers, its control byte, and both heap buffers. Keyspace entries are
+// laid out differently and measured by Db::usage.
+pub fn entry_usage(key: &Vec<u8>, value: &[u8]) -> usize {
+    mem::size_of::<(Vec<u8>, Bytes)>() + 1 + alloc_size(key.capacity())
+        + alloc_size(value.len())
+}
+
+pub fn alloc_size(n: usize) -> usize {
+    if n == 0 {
+        0
+    } else {
+        (n + 7) & !7
+    }
+}
+
+// Resident set size of the process, or 0 where /proc is unavailable.
+pub fn rss() -> usize {
+    let statm = match fs::read_to_string("/proc/self/statm") {
+        Ok(statm) => statm,
+        Err(_) => return 0,
+    };
+    match statm.split_whitespace().nth(1).map(|pages| pages.parse::<usize>()) {
+        Some(Ok(pages)) => pages * 4096,
+        _ => 0,
+    }
+}
+
+pub struct Stats {
+    pub startup: usize,
+    pub clients: usize,
+    pub lua_caches: usize,
+    // (index, keys, table overhead) for each non-empty database.
+    pub dbs: Vec<(usize, usize, usize)>,
+    // (index, dataset, table overhead) for each non-empty shard.
+    pub shards: Vec<(usize, usize, usize)>,
+    pub dataset: usize,
+    // Bytes in value buffers freed for reuse.
+    pub slab_free: usize,
+    pub rss: usize,
+    // What the allocator reports, where it does.
+    pub allocator: Option<alloc::Stats>,
+}
+
+impl Stats {
+    pub fn overhead(&self) -> usize {
+        self.startup + self.clients + self.lua_caches + self.slab_free
+            + self.dbs.iter().map(|&(_, _, overhead)| overhead).sum::<usize>()
+    }
+
+    pub fn total(&self) -> usize {
+        self.overhead() + self.dataset
+    }
+
+    pub fn keys(&self) -> usize {
+        self.dbs.iter().map(|&(_, keys, _)| keys).sum()
+    }
+
+    // How much more memory the process holds than is accounted for. Only
+    // meaningful once the dataset dwarfs the startup footprint.
+    pub fn fragmentation(&self) -> f64 {
+        if self.total() == 0 {
+            0.0
+        } else {
+            self.rss as f64 / self.total() as f64
+        }
+    }
+
+    // Heuristic advice for MEMORY DOCTOR.
+    pub fn doctor(&self, nclients: usize) -> String {
+        if self.total() < 5 * 1024 * 1024 {
+            return "This instance is empty or is using very little memory, my issues detector \
+                    can't be used in these conditions.\n"
+                .to_string();
+        }
+        let mut issues = Vec::new();
+        if self.fragmentation() > 1.4 {
+            issues.push(format!(
+                "High fragmentation: the process holds {:.2} times the memory accounted for. \
+                 Long lived processes with heavy churn of differently sized values tend to \
+                 fragment; a restart compacts the heap.",
+                self.fragmentation()
+            ));
+        }
+        if nclients > 0 && self.clients / nclients > 200 * 1024 {
+            issues.push(format!(
+                "Big client buffers: connections use {} bytes on average for query and reply \
+                 buffers. Check for clients pipelining very large batches or not reading their \
+                 replies.",
+                self.clients / nclients
+            ));
+        }
+        if self.lua_caches > 1000 * 1024 {
+            issues.push(format!(
+                "Big script cache: {} bytes of cached scripts. Scripts built with values \
+                 inlined instead of passed as arguments fill the cache; use SCRIPT FLUSH to \
+                 reclaim it.",
+                self.lua_caches
+            ));
+        }
+        if issues.is_empty() {
+            return "No memory issues were detected in this instance.\n".to_string();
+        }
+        let mut report = String::from("The following issues were detected:\n\n");
+        for issue in issues {
+            report.push_str(" * ");
+            report.push_str(&issue);
+            report.push_str("\n\n");
+        }
+        report
+    }
+}
+
+// The count keys using the most memory of those added, for MEMORY BIGKEYS,
+// as (usage, key, encoding) from the largest down.
+pub struct Biggest {
+    count: usize,
+    keys: Vec<(usize, Vec<u8>, &'static str)>,
+}
+
+impl Biggest {
+    pub fn new(count: usize) -> Biggest {
+        Biggest {
+            count,
+            keys: Vec::new(),
+        }
+    }
+
+    pub fn add(&mut self, key: &[u8], usage: usize, encoding: &'static str) {
+        if self.keys.len() == self.count && self.keys.last().is_none_or(|&(last, _, _)| usage <= last) {
+            return;
+        }
+        let at = self.keys.iter().position(|&(u, _, _)| u < usage).unwrap_or(self.keys.len());
+        self.keys.insert(at, (usage, key.to_vec(), encoding));
+        self.keys.truncate(self.count);
+    }
+
+    pub fn keys(&self) -> &[(usize, Vec<u8>, &'static str)] {
+        &self.keys
+    }
+}
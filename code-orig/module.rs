@@ -0,0 +1,134 @@
+// Loadable modules.
+//
+// A module is a shared library that adds commands to the server at
+// runtime, loaded at startup with --loadmodule or later with MODULE LOAD.
+// It is a cdylib built against this crate, with the same compiler, that
+// exports
+//
+//     #[no_mangle]
+//     pub fn cache_server_module_load(ctx: &mut ModuleContext) -> bool
+//
+// which names the module, adds its commands and returns whether it loaded.
+// The commands are registered once the function returns, as the module
+// carries its own copy of this crate and with it its own registry. Module
+// and server share the command objects and their replies, so both have to
+// use the system allocator. Modules can't be unloaded: their code stays
+// mapped for as long as the commands they added can be called.
+
+use std::ffi::{CStr, CString};
+use std::mem;
+use std::sync::{Arc, Mutex};
+
+use libc;
+
+use commands;
+use registry::{self, Command};
+
+const LOAD_SYMBOL: &str = "cache_server_module_load";
+
+type LoadFn = fn(&mut ModuleContext) -> bool;
+
+// What a module's load function sees of the server.
+pub struct ModuleContext<'a> {
+    args: &'a [String],
+    name: Option<&'static str>,
+    commands: Vec<Arc<dyn Command>>,
+}
+
+impl<'a> ModuleContext<'a> {
+    // The arguments given after the module's path.
+    pub fn args(&self) -> &[String] {
+        self.args
+    }
+
+    // Names the module, which every module has to do.
+    pub fn set_name(&mut self, name: &'static str) {
+        self.name = Some(name);
+    }
+
+    pub fn register_command(&mut self, command: Arc<dyn Command>) {
+        self.commands.push(command);
+    }
+}
+
+#[derive(Clone)]
+pub struct Module {
+    pub name: &'static str,
+    pub path: String,
+    pub args: Vec<String>,
+}
+
+static MODULES: Mutex<Vec<Module>> = Mutex::new(Vec::new());
+
+// Loads the module at path, registering its commands. Nothing is
+// registered unless the module loads and all of its commands can be.
+pub fn load(path: &str, args: &[String]) -> Result<(), String> {
+    if cfg!(feature = "jemalloc") || cfg!(feature = "mimalloc") {
+        return Err("Modules can't be loaded by a server built with a replacement allocator".to_string());
+    }
+    let cpath = CString::new(path).map_err(|_| format!("Invalid module path '{}'", path))?;
+    let handle = unsafe { libc::dlopen(cpath.as_ptr(), libc::RTLD_NOW | libc::RTLD_LOCAL) };
+    if handle.is_null() {
+        return Err(format!("Error loading the extension: {}", dlerror()));
+    }
+    let symbol = CString::new(LOAD_SYMBOL).unwrap();
+    let load = unsafe { libc::dlsym(handle, symbol.as_ptr()) };
+    if load.is_null() {
+        unsafe { libc::dlclose(handle) };
+        return Err(format!("Module {} does not export {}()", path, LOAD_SYMBOL));
+    }
+    let load: LoadFn = unsafe { mem::transmute(load) };
+
+    // Once its load function ran, the library stays mapped even if the
+    // module is refused, as what it handed over still points into it.
+    let mut ctx = ModuleContext {
+        args,
+        name: None,
+        commands: Vec::new(),
+    };
+    if !load(&mut ctx) {
+        return Err(format!("Module {} initialization failed", path));
+    }
+    let name = match ctx.name {
+        Some(name) => name,
+        None => return Err(format!("Module {} did not set its name", path)),
+    };
+    let mut modules = MODULES.lock().unwrap();
+    if modules.iter().any(|m| m.name == name) {
+        return Err(format!("Module {} is already loaded", name));
+    }
+    let mut names = Vec::new();
+    for command in &ctx.commands {
+        let spec = command.spec();
+        if commands::lookup(spec.name.as_bytes()).is_some() || names.contains(&spec.name) {
+            return Err(format!("Command '{}' of module {} already exists", spec.name, name));
+        }
+        names.push(spec.name);
+    }
+    for command in ctx.commands {
+        registry::register(command)?;
+    }
+    modules.push(Module {
+        name,
+        path: path.to_string(),
+        args: args.to_vec(),
+    });
+    Ok(())
+}
+
+// The loaded modules, in load order.
+pub fn list() -> Vec<Module> {
+    MODULES.lock().unwrap().iter().cloned().collect()
+}
+
+pub fn is_loaded(name: &str) -> bool {
+    MODULES.lock().unwrap().iter().any(|m| m.name == name)
+}
+
+fn dlerror() -> String {
+    let err = unsafe { libc::dlerror() };
+    if err.is_null() {
+        return "unknown error".to_string();
+    }
+    unsafe { CStr::from_ptr(err) }.to_string_lossy().to_string()
+}
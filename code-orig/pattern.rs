@@ -0,0 +1,170 @@
+// Glob-style pattern matching over raw bytes, as Redis' stringmatchlen()
+// does it, for KEYS, pattern subscriptions, ACL key and channel patterns
+// and CONFIG GET:
+//
+//     *        any run of bytes, including none
+//     ?        any one byte
+//     [abc]    one of the bytes listed; [^abc] one byte not listed, [a-z]
+//              one in the range, either way round. \ escapes inside
+//     \x       x itself
+//
+// Unlike a path glob there is no special '/' or '**', and every pattern is
+// valid: an unclosed '[' matches up to the end of the pattern, and a
+// trailing '\' matches a '\'.
+
+// Patterns nesting '*' deeper than this never match, so a malicious one
+// can't exhaust the stack.
+const MAX_NESTING: usize = 1000;
+
+pub fn matches(pattern: &[u8], string: &[u8], nocase: bool) -> bool {
+    let mut skip_longer = false;
+    matches_from(pattern, string, nocase, &mut skip_longer, 0)
+}
+
+fn matches_from(pattern: &[u8], string: &[u8], nocase: bool, skip_longer: &mut bool, nesting: usize) -> bool {
+    if nesting > MAX_NESTING {
+        return false;
+    }
+    let eq = |a: u8, b: u8| if nocase { a.eq_ignore_ascii_case(&b) } else { a == b };
+    let (mut p, mut s) = (0, 0);
+    while p < pattern.len() && s < string.len() {
+        match pattern[p] {
+            b'*' => {
+                while p + 1 < pattern.len() && pattern[p + 1] == b'*' {
+                    p += 1;
+                }
+                if p + 1 == pattern.len() {
+                    return true;
+                }
+                while s < string.len() {
+                    if matches_from(&pattern[p + 1..], &string[s..], nocase, skip_longer, nesting + 1) {
+                        return true;
+                    }
+                    if *skip_longer {
+                        return false;
+                    }
+                    s += 1;
+                }
+                // The rest of the pattern matches nowhere in the rest of the
+                // string, so no earlier '*' can make it match by taking a
+                // longer run either.
+                *skip_longer = true;
+                return false;
+            }
+            b'?' => s += 1,
+            b'[' => {
+                p += 1;
+                let not = p < pattern.len() && pattern[p] == b'^';
+                if not {
+                    p += 1;
+                }
+                let mut matched = false;
+                loop {
+                    let left = pattern.len() - p;
+                    if left >= 2 && pattern[p] == b'\\' {
+                        p += 1;
+                        matched |= pattern[p] == string[s];
+                    } else if left == 0 {
+                        // Unclosed: stop on the last byte so the step below
+                        // ends the pattern.
+                        p -= 1;
+                        break;
+                    } else if pattern[p] == b']' {
+                        break;
+                    } else if left >= 3 && pattern[p + 1] == b'-' {
+                        let (mut start, mut end, mut c) = (pattern[p], pattern[p + 2], string[s]);
+                        if start > end {
+                            ::std::mem::swap(&mut start, &mut end);
+                        }
+                        if nocase {
+                            start = start.to_ascii_lowercase();
+                            end = end.to_ascii_lowercase();
+                            c = c.to_ascii_lowercase();
+                        }
+                        p += 2;
+                        matched |= c >= start && c <= end;
+                    } else {
+                        matched |= eq(pattern[p], string[s]);
+                    }
+                    p += 1;
+                }
+                if not {
+                    matched = !matched;
+                }
+                if !matched {
+                    return false;
+                }
+                s += 1;
+            }
+            c => {
+                let c = if c == b'\\' && pattern.len() - p >= 2 {
+                    p += 1;
+                    pattern[p]
+                } else {
+                    c
+                };
+                if !eq(c, string[s]) {
+                    return false;
+                }
+                s += 1;
+            }
+        }
+        p += 1;
+        if s == string.len() {
+            while p < pattern.len() && pattern[p] == b'*' {
+                p += 1;
+            }
+        }
+    }
+    p == pattern.len() && s == string.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::matches;
+
+    #[test]
+    fn wildcards() {
+        assert!(matches(b"h?llo", b"hello", false));
+        assert!(matches(b"h*llo", b"heeeello", false));
+        assert!(matches(b"h*llo", b"hllo", false));
+        assert!(!matches(b"h*llo", b"hell", false));
+        assert!(matches(b"a**b", b"a/x/b", false));
+        assert!(matches(b"a*", b"a", false));
+        assert!(!matches(b"*", b"", false));
+    }
+
+    #[test]
+    fn classes() {
+        assert!(matches(b"h[ae]llo", b"hallo", false));
+        assert!(!matches(b"h[ae]llo", b"hillo", false));
+        assert!(matches(b"h[^e]llo", b"hallo", false));
+        assert!(!matches(b"h[^e]llo", b"hello", false));
+        assert!(matches(b"h[a-b]llo", b"hbllo", false));
+        assert!(matches(b"h[b-a]llo", b"hbllo", false));
+        assert!(matches(b"[\\]]", b"]", false));
+        assert!(matches(b"a[bc", b"ab", false));
+    }
+
+    #[test]
+    fn escapes() {
+        assert!(matches(b"\\*", b"*", false));
+        assert!(!matches(b"\\*", b"x", false));
+        assert!(matches(b"a\\", b"a\\", false));
+    }
+
+    #[test]
+    fn binary_and_case() {
+        assert!(matches(b"k\xff*", b"k\xff\x00\xfe", false));
+        assert!(!matches(b"k\xfe*", b"k\xff\x00", false));
+        assert!(matches(b"MAX*", b"maxmemory", true));
+        assert!(!matches(b"MAX*", b"maxmemory", false));
+    }
+
+    #[test]
+    fn abusive_patterns_give_up() {
+        let pattern = [&b"a*".repeat(50)[..], b"b"].concat();
+        assert!(!matches(&pattern, &vec![b'a'; 1000], false));
+        assert!(!matches(&b"*a".repeat(1100), &vec![b'a'; 1100], false));
+    }
+}
@@ -0,0 +1,256 @@
+// HAProxy PROXY protocol headers.
+//
+// A load balancer speaking the PROXY protocol sends one header ahead of the
+// client's bytes naming the original source and destination addresses.
+// Version 1 is a single text line, version 2 a binary block behind a fixed
+// signature. Headers for LOCAL connections (health checks) and for unknown
+// address families are accepted but carry no addresses.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\x00\r\nQUIT\n";
+const V1_MAX_LEN: usize = 107;
+
+pub struct Header {
+    // Bytes the header takes at the start of the stream.
+    pub len: usize,
+    pub source: Option<SocketAddr>,
+    pub destination: Option<SocketAddr>,
+}
+
+// Parses the header at the start of buf. Returns Ok(None) while more bytes
+// are needed to tell, and Err when the stream doesn't start with a valid one.
+pub fn parse(buf: &[u8]) -> Result<Option<Header>, ()> {
+    if buf.len() < V2_SIGNATURE.len() {
+        if V2_SIGNATURE.starts_with(buf) || b"PROXY ".starts_with(&buf[..buf.len().min(6)]) {
+            return Ok(None);
+        }
+        return Err(());
+    }
+    if buf.starts_with(V2_SIGNATURE) {
+        parse_v2(buf)
+    } else if buf.starts_with(b"PROXY ") {
+        parse_v1(buf)
+    } else {
+        Err(())
+    }
+}
+
+fn parse_v1(buf: &[u8]) -> Result<Option<Header>, ()> {
+    let end = match buf.iter().take(V1_MAX_LEN).position(|&b| b == b'\n') {
+        Some(end) if end > 0 && buf[end - 1] == b'\r' => end,
+        Some(_) => return Err(()),
+        None if buf.len() >= V1_MAX_LEN => return Err(()),
+        None => return Ok(None),
+    };
+    let line = match ::std::str::from_utf8(&buf[..end - 1]) {
+        Ok(line) => line,
+        Err(_) => return Err(()),
+    };
+    let parts: Vec<&str> = line.split(' ').collect();
+    let mut header = Header {
+        len: end + 1,
+        source: None,
+        destination: None,
+    };
+    match parts.get(1) {
+        Some(&"UNKNOWN") => return Ok(Some(header)),
+        Some(&"TCP4") | Some(&"TCP6") if parts.len() == 6 => {}
+        _ => return Err(()),
+    }
+    let addr = |ip: &str, port: &str| -> Result<SocketAddr, ()> {
+        let ip = ip.parse::<IpAddr>().map_err(|_| ())?;
+        let port = port.parse::<u16>().map_err(|_| ())?;
+        Ok(SocketAddr::new(ip, port))
+    };
+    header.source = Some(addr(parts[2], parts[4])?);
+    header.destination = Some(addr(parts[3], parts[5])?);
+    Ok(Some(header))
+}
+
+fn parse_v2(buf: &[u8]) -> Result<Option<Header>, ()> {
+    if buf.len() < 16 {
+        return Ok(None);
+    }
+    let version = buf[12] >> 4;
+    let command = buf[12] & 0x0f;
+    if version != 2 || command > 1 {
+        return Err(());
+    }
+    let len = 16 + ((buf[14] as usize) << 8 | buf[15] as usize);
+    if buf.len() < len {
+        return Ok(None);
+    }
+    let mut header = Header {
+        len,
+        source: None,
+        destination: None,
+    };
+    if command == 0 {
+        return Ok(Some(header));
+    }
+    let body = &buf[16..len];
+    let port = |b: &[u8]| (b[0] as u16) << 8 | b[1] as u16;
+    match buf[13] >> 4 {
+        1 if body.len() >= 12 => {
+            let ip = |b: &[u8]| IpAddr::V4(Ipv4Addr::new(b[0], b[1], b[2], b[3]));
+            header.source = Some(SocketAddr::new(ip(&body[0..4]), port(&body[8..10])));
+            header.destination = Some(SocketAddr::new(ip(&body[4..8]), port(&body[10..12])));
+        }
+        2 if body.len() >= 36 => {
+            let ip = |b: &[u8]| {
+                let mut octets = [0; 16];
+                octets.copy_from_slice(b);
+                IpAddr::V6(Ipv6Addr::from(octets))
+            };
+            header.source = Some(SocketAddr::new(ip(&body[0..16]), port(&body[32..34])));
+            header.destination = Some(SocketAddr::new(ip(&body[16..32]), port(&body[34..36])));
+        }
+        1 | 2 => return Err(()),
+        _ => {}
+    }
+    Ok(Some(header))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn v2(command: u8, family: u8, body: &[u8]) -> Vec<u8> {
+        let mut buf = V2_SIGNATURE.to_vec();
+        buf.push(0x20 | command);
+        buf.push(family << 4 | 1);
+        buf.push((body.len() >> 8) as u8);
+        buf.push(body.len() as u8);
+        buf.extend_from_slice(body);
+        buf
+    }
+
+    fn addr(s: &str) -> Option<SocketAddr> {
+        Some(s.parse().unwrap())
+    }
+
+    // Every proper prefix of a valid header needs more bytes, never errors.
+    fn assert_incomplete(header: &[u8]) {
+        for end in 0..header.len() {
+            assert!(matches!(parse(&header[..end]), Ok(None)), "prefix of {}", end);
+        }
+    }
+
+    #[test]
+    fn v1_tcp4() {
+        let line = b"PROXY TCP4 192.168.0.1 192.168.0.11 56324 443\r\n";
+        let mut buf = line.to_vec();
+        buf.extend_from_slice(b"*1\r\n$4\r\nPING\r\n");
+        let header = parse(&buf).unwrap().unwrap();
+        assert_eq!(header.len, line.len());
+        assert_eq!(header.source, addr("192.168.0.1:56324"));
+        assert_eq!(header.destination, addr("192.168.0.11:443"));
+        assert_incomplete(line);
+    }
+
+    #[test]
+    fn v1_tcp6() {
+        let line = b"PROXY TCP6 2001:db8::1 ::1 4000 6379\r\n";
+        let header = parse(line).unwrap().unwrap();
+        assert_eq!(header.len, line.len());
+        assert_eq!(header.source, addr("[2001:db8::1]:4000"));
+        assert_eq!(header.destination, addr("[::1]:6379"));
+        assert_incomplete(line);
+    }
+
+    #[test]
+    fn v1_unknown() {
+        let line = b"PROXY UNKNOWN ffff:f...f:ffff ffff:f...f:ffff 65535 65535\r\n";
+        let header = parse(line).unwrap().unwrap();
+        assert_eq!(header.len, line.len());
+        assert!(header.source.is_none() && header.destination.is_none());
+        assert!(parse(b"PROXY UNKNOWN\r\n").unwrap().is_some());
+    }
+
+    #[test]
+    fn v1_malformed() {
+        assert!(parse(b"GET / HTTP/1.1\r\n").is_err());
+        assert!(parse(b"*1\r\n$4\r\nPING\r\n").is_err());
+        assert!(parse(b"PROXY TCP4 192.168.0.1 192.168.0.11 56324 443\n").is_err());
+        assert!(parse(b"PROXY TCP4 192.168.0.1 192.168.0.11 56324\r\n").is_err());
+        assert!(parse(b"PROXY TCP4 192.168.0.1 192.168.0.300 56324 443\r\n").is_err());
+        assert!(parse(b"PROXY TCP4 192.168.0.1 192.168.0.11 56324 65536\r\n").is_err());
+        assert!(parse(b"PROXY UDP4 192.168.0.1 192.168.0.11 56324 443\r\n").is_err());
+    }
+
+    #[test]
+    fn v1_line_limit() {
+        // The longest valid line is 107 bytes including the CRLF.
+        let mut line = b"PROXY UNKNOWN ".to_vec();
+        line.resize(V1_MAX_LEN - 2, b'x');
+        line.extend_from_slice(b"\r\n");
+        assert_eq!(parse(&line).unwrap().unwrap().len, V1_MAX_LEN);
+        line.truncate(V1_MAX_LEN - 2);
+        assert!(parse(&line).unwrap().is_none());
+        line.extend_from_slice(b"xx\r\n");
+        assert!(parse(&line).is_err());
+    }
+
+    #[test]
+    fn v2_ipv4() {
+        let body = [127, 0, 0, 1, 10, 0, 0, 2, 0xdb, 0xf0, 0x18, 0xeb];
+        let mut buf = v2(1, 1, &body);
+        let len = buf.len();
+        buf.extend_from_slice(b"PING\r\n");
+        let header = parse(&buf).unwrap().unwrap();
+        assert_eq!(header.len, len);
+        assert_eq!(header.source, addr("127.0.0.1:56304"));
+        assert_eq!(header.destination, addr("10.0.0.2:6379"));
+        assert_incomplete(&buf[..len]);
+    }
+
+    #[test]
+    fn v2_ipv6() {
+        let mut body = vec![0; 36];
+        body[0] = 0x20;
+        body[1] = 0x01;
+        body[31] = 1;
+        body[32..].copy_from_slice(&[0x0f, 0xa0, 0x18, 0xeb]);
+        let buf = v2(1, 2, &body);
+        let header = parse(&buf).unwrap().unwrap();
+        assert_eq!(header.len, buf.len());
+        assert_eq!(header.source, addr("[2001::]:4000"));
+        assert_eq!(header.destination, addr("[::1]:6379"));
+        assert_incomplete(&buf);
+    }
+
+    #[test]
+    fn v2_local_and_unspecified() {
+        // LOCAL ignores whatever address block follows.
+        let buf = v2(0, 1, &[0; 12]);
+        let header = parse(&buf).unwrap().unwrap();
+        assert_eq!(header.len, buf.len());
+        assert!(header.source.is_none() && header.destination.is_none());
+        // So does a PROXY header for an unsupported family, such as AF_UNIX.
+        let buf = v2(1, 3, &[0; 216]);
+        let header = parse(&buf).unwrap().unwrap();
+        assert_eq!(header.len, buf.len());
+        assert!(header.source.is_none() && header.destination.is_none());
+    }
+
+    #[test]
+    fn v2_malformed() {
+        let mut buf = v2(1, 1, &[0; 12]);
+        buf[11] = b'X';
+        assert!(parse(&buf).is_err());
+        assert!(parse(&buf[..11]).is_ok());
+        assert!(parse(&buf[..12]).is_err());
+
+        let mut buf = v2(1, 1, &[0; 12]);
+        buf[12] = 0x11;
+        assert!(parse(&buf).is_err());
+        buf[12] = 0x22;
+        assert!(parse(&buf).is_err());
+
+        // The address block must fit the family's addresses.
+        assert!(parse(&v2(1, 1, &[0; 11])).is_err());
+        assert!(parse(&v2(1, 2, &[0; 12])).is_err());
+        assert!(parse(&v2(1, 2, &[0; 35])).is_err());
+    }
+}
@@ -0,0 +1,82 @@
+// Channel and pattern subscriptions.
+//
+// The registry maps each channel or pattern to the ids of the clients
+// subscribed to it; each Client keeps its own set as well so it can report
+// counts and unsubscribe from everything. PUBLISH only looks up receivers
+// here, delivery goes through the receiving client's push queue.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+
+use pattern;
+
+pub struct PubSub {
+    channels: Mutex<HashMap<Vec<u8>, HashSet<usize>>>,
+    patterns: Mutex<HashMap<Vec<u8>, HashSet<usize>>>,
+}
+
+impl PubSub {
+    pub fn new() -> PubSub {
+        PubSub {
+            channels: Mutex::new(HashMap::new()),
+            patterns: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn subscribe(&self, id: usize, channel: &[u8]) {
+        add(&self.channels, id, channel);
+    }
+
+    pub fn unsubscribe(&self, id: usize, channel: &[u8]) {
+        remove(&self.channels, id, channel);
+    }
+
+    pub fn psubscribe(&self, id: usize, pattern: &[u8]) {
+        add(&self.patterns, id, pattern);
+    }
+
+    pub fn punsubscribe(&self, id: usize, pattern: &[u8]) {
+        remove(&self.patterns, id, pattern);
+    }
+
+    // Returns the clients a message on the channel goes to: direct
+    // subscribers first, then one entry per matching pattern subscription.
+    pub fn receivers(&self, channel: &[u8]) -> Vec<(usize, Option<Vec<u8>>)> {
+        let mut out = Vec::new();
+        if let Some(ids) = self.channels.lock().unwrap().get(channel) {
+            for &id in ids {
+                out.push((id, None));
+            }
+        }
+        for (pattern, ids) in self.patterns.lock().unwrap().iter() {
+            if pattern::matches(pattern, channel, false) {
+                for &id in ids {
+                    out.push((id, Some(pattern.clone())));
+                }
+            }
+        }
+        out
+    }
+}
+
+fn add(map: &Mutex<HashMap<Vec<u8>, HashSet<usize>>>, id: usize, name: &[u8]) {
+    map.lock()
+        .unwrap()
+        .entry(name.to_vec())
+        .or_default()
+        .insert(id);
+}
+
+fn remove(map: &Mutex<HashMap<Vec<u8>, HashSet<usize>>>, id: usize, name: &[u8]) {
+    let mut map = map.lock().unwrap();
+    let empty = match map.get_mut(name) {
+        Some(ids) => {
+            ids.remove(&id);
+            ids.is_empty()
+        }
+        None => false,
+    };
+    if empty {
+        map.remove(name);
+    }
+}
@@ -0,0 +1,113 @@
+// Per-connection rate limits.
+//
+// A connection may run at most so many commands, carrying at most so many
+// argument bytes, a second, from client-max-commands-per-sec and
+// client-max-bytes-per-sec or the limits of its ACL user where those are
+// set. Each is a token bucket refilled at the limit and holding one
+// second's worth, so short bursts pass. A command over the limit is held
+// back with the rest of the pipeline, the way CLIENT PAUSE holds commands,
+// until the bucket has refilled enough.
+
+use std::time::Instant;
+
+// Limits in effect for a connection, zero for none.
+#[derive(Clone, Copy)]
+pub struct Limits {
+    pub commands: usize,
+    pub bytes: usize,
+}
+
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+}
+
+impl Bucket {
+    // Full, once the first refill caps it at the rate.
+    fn new() -> Bucket {
+        Bucket {
+            tokens: f64::MAX,
+            last: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, rate: usize) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last);
+        let secs = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+        self.tokens = (self.tokens + secs * rate as f64).min(rate as f64);
+        self.last = now;
+    }
+
+    // Whether cost tokens can be taken. A cost above a full bucket waits
+    // for a full one, and leaves it in debt.
+    fn has(&self, rate: usize, cost: usize) -> bool {
+        self.tokens >= cost.min(rate) as f64
+    }
+}
+
+pub struct Limiter {
+    commands: Bucket,
+    bytes: Bucket,
+}
+
+impl Limiter {
+    pub fn new() -> Limiter {
+        Limiter {
+            commands: Bucket::new(),
+            bytes: Bucket::new(),
+        }
+    }
+
+    // Takes a command of the given argument bytes from the buckets, or
+    // false if it has to wait.
+    pub fn admit(&mut self, limits: Limits, bytes: usize) -> bool {
+        if limits.commands > 0 {
+            self.commands.refill(limits.commands);
+        }
+        if limits.bytes > 0 {
+            self.bytes.refill(limits.bytes);
+        }
+        if (limits.commands > 0 && !self.commands.has(limits.commands, 1))
+            || (limits.bytes > 0 && !self.bytes.has(limits.bytes, bytes))
+        {
+            return false;
+        }
+        if limits.commands > 0 {
+            self.commands.tokens -= 1.0;
+        }
+        if limits.bytes > 0 {
+            self.bytes.tokens -= bytes as f64;
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Limiter, Limits};
+
+    #[test]
+    fn bursts_up_to_the_limit() {
+        let mut limiter = Limiter::new();
+        let limits = Limits {
+            commands: 100,
+            bytes: 0,
+        };
+        for _ in 0..100 {
+            assert!(limiter.admit(limits, 10));
+        }
+        assert!(!limiter.admit(limits, 10));
+    }
+
+    #[test]
+    fn large_commands_wait_for_a_full_bucket() {
+        let mut limiter = Limiter::new();
+        let limits = Limits {
+            commands: 0,
+            bytes: 1000,
+        };
+        assert!(limiter.admit(limits, 5000));
+        assert!(!limiter.admit(limits, 1));
+    }
+}
@@ -0,0 +1,154 @@
+// Command dispatch.
+//
+// Every command a client can send is found by name in the registry: the
+// built-in ones, and those the program embedding the server registers by
+// implementing Command. A registered command is described by a CommandSpec
+// like the built-in ones, so arity checks, key-based shard locking, ACLs,
+// cluster redirects and COMMAND treat it no differently.
+//
+// The registry is process-wide, as the command table is. Commands have to
+// be registered before the servers that run them start, as a user's
+// +@all is resolved against the commands known when the user is set up.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
+
+use bytes::Bytes;
+use tracing;
+
+use clients;
+use commands::{self, CommandSpec};
+use keyspace;
+use Server;
+
+pub type Builtin = fn(
+    &[Vec<u8>],
+    &mut keyspace::Locked,
+    &Server,
+    &Mutex<clients::Client>,
+) -> (Vec<u8>, bool, bool);
+
+pub trait Command: Send + Sync {
+    // The command's name, which has to be lowercase, arity, flags and key
+    // positions. Only the shards of the keys the spec names are locked,
+    // or every shard for a readonly or write command without key
+    // positions.
+    fn spec(&self) -> CommandSpec;
+
+    // Runs the command, whose arity has been checked, and returns the
+    // RESP-encoded reply.
+    fn call(&self, ctx: &mut Context, args: &[Vec<u8>]) -> Vec<u8>;
+}
+
+// What a registered command gets to work with: the keys of the calling
+// client's database, within the shards locked for the command.
+pub struct Context<'a, 'b: 'a> {
+    store: &'a mut keyspace::Locked<'b>,
+    server: &'a Server,
+    client: &'a Mutex<clients::Client>,
+}
+
+impl<'a, 'b> Context<'a, 'b> {
+    pub fn client_id(&self) -> usize {
+        self.client.lock().unwrap().id
+    }
+
+    pub fn db(&self) -> usize {
+        self.client.lock().unwrap().db
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        let value = self.store.get(self.db(), key).map(|value| value.to_vec());
+        self.server.keyspace.count_lookup(value.is_some());
+        value
+    }
+
+    pub fn set(&mut self, key: &[u8], value: &[u8]) {
+        let value = {
+            let config = self.server.config.read().unwrap();
+            self.server.compressor.encode(
+                Bytes::from(value.to_vec()),
+                &config.compression,
+                config.compression_threshold,
+            )
+        };
+        let db = self.db();
+        self.store.insert(db, key.to_vec(), value);
+    }
+
+    // True if the key existed.
+    pub fn del(&mut self, key: &[u8]) -> bool {
+        let db = self.db();
+        self.store.remove(db, key).is_some()
+    }
+
+    pub fn exists(&self, key: &[u8]) -> bool {
+        self.store.contains_key(self.db(), key)
+    }
+}
+
+#[derive(Clone)]
+enum Handler {
+    Builtin(Builtin),
+    Registered(Arc<dyn Command>),
+}
+
+static HANDLERS: OnceLock<RwLock<HashMap<String, Handler>>> = OnceLock::new();
+
+fn handlers() -> &'static RwLock<HashMap<String, Handler>> {
+    HANDLERS.get_or_init(|| {
+        let mut handlers = HashMap::new();
+        for (name, builtin) in ::builtin_commands() {
+            handlers.insert(name.to_string(), Handler::Builtin(builtin));
+        }
+        RwLock::new(handlers)
+    })
+}
+
+// Adds a command to the registry. Names already taken, by a built-in
+// command or an earlier registration, are refused.
+pub fn register(command: Arc<dyn Command>) -> Result<(), String> {
+    let spec = command.spec();
+    if spec.name.is_empty() || spec.name != spec.name.to_lowercase() {
+        return Err(format!("Invalid command name '{}'", spec.name));
+    }
+    if spec.arity == 0 {
+        return Err(format!("Invalid arity for command '{}'", spec.name));
+    }
+    let mut handlers = handlers().write().unwrap();
+    if handlers.contains_key(spec.name) || commands::lookup(spec.name.as_bytes()).is_some() {
+        return Err(format!("Command '{}' already exists", spec.name));
+    }
+    handlers.insert(spec.name.to_string(), Handler::Registered(command));
+    commands::register(spec);
+    Ok(())
+}
+
+// Runs the command named by args[0]. None if there is no such command.
+pub fn dispatch(
+    args: &[Vec<u8>],
+    store: &mut keyspace::Locked,
+    server: &Server,
+    client: &Mutex<clients::Client>,
+) -> Option<(Vec<u8>, bool, bool)> {
+    // The lock isn't held while the command runs, as scripts dispatch
+    // commands of their own.
+    let name = String::from_utf8_lossy(&args[0]).to_lowercase();
+    let handler = handlers().read().unwrap().get(&name)?.clone();
+    let _span = tracing::trace_span!("dispatch", name = %name).entered();
+    let command = match handler {
+        Handler::Builtin(builtin) => return Some(builtin(args, store, server, client)),
+        Handler::Registered(command) => command,
+    };
+    let spec = commands::lookup(&args[0])?;
+    if !spec.arity_ok(args.len()) {
+        return Some((::invalid_num_args(&args[0]), false, false));
+    }
+    let mut ctx = Context {
+        store,
+        server,
+        client,
+    };
+    let reply = command.call(&mut ctx, args);
+    Some((reply, spec.has_flag("write"), false))
+}
@@ -0,0 +1,434 @@
+// Incremental request parser.
+//
+// A Parser belongs to one connection and picks up where the previous read
+// left off: arguments of a multibulk command are copied out as soon as they
+// are complete, and the search for a line end resumes after the bytes
+// already looked at, so a command fragmented over many reads is scanned
+// only once. Consumed bytes are dropped from the input with consume().
+//
+// Replies to clients are handed back as a Reply, the segments they are
+// written from.
+
+use bytes::Bytes;
+
+use buffer::{Buffer, Segment};
+
+// Largest multibulk count accepted, and the longest an inline command or a
+// count line may grow while its newline hasn't arrived.
+pub const PROTO_MAX_MULTIBULK_LEN: usize = 1024 * 1024;
+pub const PROTO_INLINE_MAX_SIZE: usize = 64 * 1024;
+
+pub struct Parser {
+    // Start of the bytes not yet turned into arguments.
+    pos: usize,
+    // Where to resume looking for the end of the line starting at pos.
+    scan: usize,
+    // Arguments still to come for the multibulk command being read.
+    remaining: Option<usize>,
+    // Length of the bulk argument whose payload is awaited.
+    bulk: Option<usize>,
+    args: Vec<Vec<u8>>,
+    // A command handed back with hold(), returned again by the next call.
+    held: Option<Vec<Vec<u8>>>,
+}
+
+impl Parser {
+    pub fn new() -> Parser {
+        Parser {
+            pos: 0,
+            scan: 0,
+            remaining: None,
+            bulk: None,
+            args: Vec::new(),
+            held: None,
+        }
+    }
+
+    // Returns the next complete command in input, Ok(None) while it is still
+    // incomplete, or the protocol error that makes the rest unreadable.
+    // Empty commands are skipped.
+    pub fn next(&mut self, input: &[u8], max_bulk: usize) -> Result<Option<Vec<Vec<u8>>>, String> {
+        if let Some(args) = self.held.take() {
+            return Ok(Some(args));
+        }
+        loop {
+            let remaining = match self.remaining {
+                Some(remaining) => remaining,
+                None => {
+                    if self.pos >= input.len() {
+                        return Ok(None);
+                    }
+                    if input[self.pos] != b'*' {
+                        match self.inline(input)? {
+                            Some(ref args) if args.is_empty() => continue,
+                            args => return Ok(args),
+                        }
+                    }
+                    let (start, end) = match self.line(input, "too big mbulk count string")? {
+                        Some(line) => line,
+                        None => return Ok(None),
+                    };
+                    match parse_len(&input[start + 1..end]) {
+                        Some(0) => continue,
+                        Some(nargs) if nargs <= PROTO_MAX_MULTIBULK_LEN => {
+                            self.args = Vec::with_capacity(nargs.min(1024));
+                            nargs
+                        }
+                        _ => return Err("invalid multibulk length".to_string()),
+                    }
+                }
+            };
+            self.remaining = Some(remaining);
+            while self.args.len() < remaining {
+                match self.bulk {
+                    None => {
+                        let (start, end) = match self.line(input, "too big bulk count string")? {
+                            Some(line) => line,
+                            None => return Ok(None),
+                        };
+                        if start == end || input[start] != b'$' {
+                            let got = if start == end { b'\r' } else { input[start] };
+                            return Err(format!("expected '$', got '{}'", got as char));
+                        }
+                        match parse_len(&input[start + 1..end]) {
+                            Some(nbytes) if nbytes <= max_bulk => self.bulk = Some(nbytes),
+                            _ => return Err("invalid bulk length".to_string()),
+                        }
+                    }
+                    Some(nbytes) => {
+                        if input.len() < self.pos + nbytes + 2 {
+                            return Ok(None);
+                        }
+                        if &input[self.pos + nbytes..self.pos + nbytes + 2] != b"\r\n" {
+                            return Err("expected '\\r\\n'".to_string());
+                        }
+                        self.args.push(input[self.pos..self.pos + nbytes].to_vec());
+                        self.pos += nbytes + 2;
+                        self.scan = self.pos;
+                        self.bulk = None;
+                    }
+                }
+            }
+            self.remaining = None;
+            return Ok(Some(std::mem::take(&mut self.args)));
+        }
+    }
+
+    // Puts a command back so the next call returns it again, for commands
+    // that can't run yet.
+    pub fn hold(&mut self, args: Vec<Vec<u8>>) {
+        self.held = Some(args);
+    }
+
+    // Drops the bytes already parsed from the front of input.
+    pub fn consume(&mut self, input: &mut Buffer) {
+        if self.pos > 0 {
+            input.consume(self.pos);
+            self.scan -= self.pos;
+            self.pos = 0;
+        }
+    }
+
+    // Bytes of arguments copied out of the input for a command that
+    // hasn't completed yet.
+    pub fn buffered(&self) -> usize {
+        self.args.iter().map(|arg| arg.len()).sum()
+    }
+
+    // Finds the CRLF ending the line at pos and moves past it, returning
+    // where the line starts and where its CR is.
+    fn line(&mut self, input: &[u8], too_big: &str) -> Result<Option<(usize, usize)>, String> {
+        let mut i = self.scan.max(self.pos + 1);
+        while i < input.len() {
+            if input[i] == b'\n' && input[i - 1] == b'\r' {
+                let start = self.pos;
+                self.pos = i + 1;
+                self.scan = self.pos;
+                return Ok(Some((start, i - 1)));
+            }
+            i += 1;
+        }
+        self.scan = i;
+        if input.len() - self.pos > PROTO_INLINE_MAX_SIZE {
+            return Err(too_big.to_string());
+        }
+        Ok(None)
+    }
+
+    // Splits the inline command at pos once its newline has arrived.
+    fn inline(&mut self, input: &[u8]) -> Result<Option<Vec<Vec<u8>>>, String> {
+        let from = self.scan.max(self.pos);
+        match input[from..].iter().position(|&b| b == b'\n') {
+            Some(n) => {
+                let end = from + n + 1;
+                let args = take_inline_args(&input[self.pos..end])?;
+                self.pos = end;
+                self.scan = end;
+                Ok(Some(args))
+            }
+            None => {
+                self.scan = input.len();
+                if input.len() - self.pos > PROTO_INLINE_MAX_SIZE {
+                    return Err("too big inline request".to_string());
+                }
+                Ok(None)
+            }
+        }
+    }
+}
+
+pub struct Reply {
+    pub segments: Vec<Segment>,
+}
+
+impl Reply {
+    pub fn len(&self) -> usize {
+        self.segments.iter().map(|segment| segment.len()).sum()
+    }
+
+    // A bulk string carrying value itself rather than a copy.
+    pub fn shared_bulk(value: Bytes) -> Reply {
+        Reply {
+            segments: vec![
+                format!("${}\r\n", value.len()).into_bytes().into(),
+                Segment::Shared(value),
+                b"\r\n".to_vec().into(),
+            ],
+        }
+    }
+}
+
+impl From<Vec<u8>> for Reply {
+    fn from(bytes: Vec<u8>) -> Reply {
+        Reply {
+            segments: vec![bytes.into()],
+        }
+    }
+}
+
+fn parse_len(digits: &[u8]) -> Option<usize> {
+    String::from_utf8_lossy(digits).parse::<usize>().ok()
+}
+
+// Splits one newline-terminated inline command into its arguments.
+pub fn take_inline_args(line: &[u8]) -> Result<Vec<Vec<u8>>, String> {
+    let mut i = 0;
+    let mut s = 0;
+    let mut args: Vec<Vec<u8>> = Vec::new();
+
+    while i < line.len() {
+        match line[i] {
+            b' ' | b'\n' => {
+                let mut ii = i;
+                if line[i] == b'\n' && i > s && line[i - 1] == b'\r' {
+                    ii = i - 1;
+                }
+                if s < ii {
+                    args.push(line[s..ii].to_vec());
+                }
+                if line[i] == b'\n' {
+                    break;
+                }
+                s = i + 1;
+            }
+            // Quotes only open an argument at its start.
+            b'"' | b'\'' if i == s => {
+                let (arg, new_i, balanced) = parse_quoted_arg(line, i + 1);
+                if !balanced {
+                    return Err("unbalanced quotes in request".to_string());
+                }
+                args.push(arg);
+                i = new_i;
+                s = i + 1;
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    Ok(args)
+}
+
+// Reads the quoted argument whose opening quote is at i - 1, returning it
+// with the position just past the closing quote. Unbalanced when the line
+// ends first, or when the closing quote isn't followed by a space or the
+// end of the line.
+pub fn parse_quoted_arg(packet: &[u8], mut i: usize) -> (Vec<u8>, usize, bool) {
+    let mut arg = Vec::new();
+    let quote = match i.checked_sub(1).and_then(|q| packet.get(q)) {
+        Some(&quote) => quote,
+        None => return (Vec::default(), i, false),
+    };
+
+    while i < packet.len() {
+        match packet[i] {
+            b'\n' => return (Vec::default(), i, false),
+            b'\\' if i + 1 < packet.len() => {
+                i += 1;
+                match packet[i] {
+                    b'n' => arg.push(b'\n'),
+                    b'r' => arg.push(b'\r'),
+                    b't' => arg.push(b'\t'),
+                    b'b' => arg.push(0x08),
+                    b'a' => arg.push(0x07),
+                    b'x' => {
+                        if let Some(value) = parse_hex_byte(packet, i + 1) {
+                            arg.push(value);
+                            i += 2;
+                        } else {
+                            arg.push(b'x');
+                        }
+                    }
+                    _ => arg.push(packet[i]),
+                }
+            }
+            b if b == quote => {
+                let balanced = matches!(packet.get(i + 1), None | Some(&b' ') | Some(&b'\r') | Some(&b'\n'));
+                return (arg, i + 1, balanced);
+            }
+            _ => arg.push(packet[i]),
+        }
+        i += 1;
+    }
+
+    (Vec::default(), i, false)
+}
+
+fn parse_hex_byte(packet: &[u8], i: usize) -> Option<u8> {
+    if i + 1 < packet.len() {
+        let is_hex = |b: u8| b.is_ascii_digit() || (b'a'..=b'f').contains(&b) || (b'A'..=b'F').contains(&b);
+        if is_hex(packet[i]) && is_hex(packet[i + 1]) {
+            Some((hex_to_digit(packet[i]) << 4) + hex_to_digit(packet[i + 1]))
+        } else {
+            None
+        }
+    } else {
+        None
+    }
+}
+
+fn hex_to_digit(b: u8) -> u8 {
+    if b <= b'9' {
+        b - b'0'
+    } else if b <= b'F' {
+        b - b'A' + 10
+    } else {
+        b - b'a' + 10
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::collection::vec;
+    use proptest::prelude::*;
+
+    use super::*;
+    use {make_array, make_bulk};
+
+    // Arguments lean on the bytes framing is made of, so payloads holding
+    // CR, LF, NUL and stray '$' or '*' turn up often.
+    fn arg() -> impl Strategy<Value = Vec<u8>> {
+        let byte = prop_oneof![
+            4 => any::<u8>(),
+            1 => Just(b'\r'),
+            1 => Just(b'\n'),
+            1 => Just(0u8),
+            1 => Just(b'$'),
+            1 => Just(b'*'),
+        ];
+        vec(byte, 0..64)
+    }
+
+    fn command() -> impl Strategy<Value = Vec<Vec<u8>>> {
+        vec(arg(), 1..8)
+    }
+
+    fn encode(args: &[Vec<u8>]) -> Vec<u8> {
+        let mut out = make_array(args.len());
+        for arg in args {
+            out.extend(make_bulk(arg));
+        }
+        out
+    }
+
+    // Feeds input to a parser in chunks ending at cuts and collects the
+    // commands, the way a connection sees reads arrive.
+    fn parse(input: &[u8], cuts: &[usize]) -> Result<Vec<Vec<Vec<u8>>>, String> {
+        let mut parser = Parser::new();
+        let mut buf = Buffer::new();
+        let mut commands = Vec::new();
+        let mut ends: Vec<usize> = cuts.iter().map(|&c| c % (input.len() + 1)).collect();
+        ends.push(input.len());
+        ends.sort();
+        let mut from = 0;
+        for end in ends {
+            buf.extend_from_slice(&input[from..end]);
+            from = end;
+            while let Some(args) = parser.next(buf.as_slice(), usize::MAX)? {
+                commands.push(args);
+            }
+            parser.consume(&mut buf);
+        }
+        assert_eq!(buf.as_slice().len(), 0);
+        Ok(commands)
+    }
+
+    // Flattens a reply into the bytes written to the socket.
+    fn flatten(reply: &Reply) -> Vec<u8> {
+        reply.segments.iter().flat_map(|s| s.to_vec()).collect()
+    }
+
+    proptest! {
+        #[test]
+        fn commands_round_trip(commands in vec(command(), 1..8)) {
+            let input: Vec<u8> = commands.iter().flat_map(|args| encode(args)).collect();
+            prop_assert_eq!(parse(&input, &[]), Ok(commands));
+        }
+
+        #[test]
+        fn commands_round_trip_in_fragments(commands in vec(command(), 1..8), cuts in vec(any::<usize>(), 0..16)) {
+            let input: Vec<u8> = commands.iter().flat_map(|args| encode(args)).collect();
+            prop_assert_eq!(parse(&input, &cuts), Ok(commands));
+        }
+
+        // An array of bulks is the same bytes whether it is a reply or a
+        // command, so replies built the way the server builds them, copied
+        // or shared, parse back into the arguments they carry.
+        #[test]
+        fn array_replies_parse_as_commands(
+            args in command(),
+            shared in vec(any::<bool>(), 8),
+            cuts in vec(any::<usize>(), 0..16),
+        ) {
+            let mut out = make_array(args.len());
+            for (arg, &shared) in args.iter().zip(&shared) {
+                if shared {
+                    out.extend(flatten(&Reply::shared_bulk(Bytes::from(arg.clone()))));
+                } else {
+                    out.extend(make_bulk(arg));
+                }
+            }
+            prop_assert_eq!(parse(&out, &cuts), Ok(vec![args]));
+        }
+
+        #[test]
+        fn shared_bulks_encode_like_copies(bulk in arg()) {
+            let reply = Reply::shared_bulk(Bytes::from(bulk.clone()));
+            prop_assert_eq!(flatten(&reply), make_bulk(&bulk));
+        }
+
+        // Inline arguments that need no quoting come back as they were.
+        #[test]
+        fn inline_commands_round_trip(args in vec("[!#-&(-~]{1,16}", 1..8)) {
+            let line = format!("{}\r\n", args.join(" "));
+            let expected: Vec<Vec<u8>> = args.iter().map(|a| a.clone().into_bytes()).collect();
+            prop_assert_eq!(take_inline_args(line.as_bytes()), Ok(expected));
+        }
+    }
+
+    #[test]
+    fn bulk_lengths_must_match_their_payload() {
+        assert_eq!(parse(b"*1\r\n$3\r\nabcd\r\n", &[]), Err("expected '\\r\\n'".to_string()));
+        assert_eq!(parse(b"*1\r\n$5\r\nabcd\r\n*1\r\n", &[]), Err("expected '\\r\\n'".to_string()));
+        assert_eq!(parse(b"*1\r\n$4\r\nabcd\r\n", &[4, 10]), Ok(vec![vec![b"abcd".to_vec()]]));
+    }
+}
@@ -0,0 +1,590 @@
+// Lua scripting for EVAL/EVALSHA.
+//
+// Every script runs in a fresh interpreter with KEYS and ARGV populated and a
+// `redis` table whose call/pcall functions are routed back into the command
+// dispatcher. Replies flow through the interpreter as RESP, so a script sees
+// exactly what a client would.
+//
+// A running script holds every keyspace shard for its whole duration. The
+// Watchdog is shared outside of those locks so other connections can notice a script
+// that overran its time limit and answer -BUSY, and so SCRIPT KILL can reach
+// it.
+//
+// Interpreters are sandboxed like Redis's: only the base, string, table and
+// math libraries are loaded, the base functions that read files are gone, and
+// neither scripts nor loadstring accept precompiled bytecode.
+
+use mlua::{ChunkMode, HookTriggers, Lua, LuaOptions, MultiValue, StdLib, Table, Value};
+use sha1_smol::Sha1;
+use std::cell::{Cell, RefCell};
+use std::error::Error as StdError;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+pub const BUSY_ERROR: &[u8] =
+    b"-BUSY Redis is busy running a script. You can only call SCRIPT KILL or SHUTDOWN NOSAVE.\r\n";
+
+pub struct Watchdog {
+    started: Mutex<Option<Instant>>,
+    time_limit_ms: AtomicUsize,
+    wrote: AtomicBool,
+    kill: AtomicBool,
+}
+
+impl Watchdog {
+    pub fn new(time_limit_ms: usize) -> Watchdog {
+        Watchdog {
+            started: Mutex::new(None),
+            time_limit_ms: AtomicUsize::new(time_limit_ms),
+            wrote: AtomicBool::new(false),
+            kill: AtomicBool::new(false),
+        }
+    }
+
+    pub fn set_time_limit(&self, time_limit_ms: usize) {
+        self.time_limit_ms.store(time_limit_ms, Ordering::Relaxed);
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.started.lock().unwrap().is_some()
+    }
+
+    // A script is busy once it has run past the time limit; from then on
+    // other clients are refused instead of queueing behind the shard locks.
+    pub fn is_busy(&self) -> bool {
+        match *self.started.lock().unwrap() {
+            Some(started) => {
+                let limit = self.time_limit_ms.load(Ordering::Relaxed) as u64;
+                limit > 0 && started.elapsed() >= Duration::from_millis(limit)
+            }
+            None => false,
+        }
+    }
+
+    pub fn kill(&self) -> Vec<u8> {
+        if !self.is_running() {
+            b"-NOTBUSY No scripts in execution right now.\r\n".to_vec()
+        } else if self.wrote.load(Ordering::SeqCst) {
+            b"-UNKILLABLE Sorry the script already executed write commands against the dataset. You can either wait the script termination or kill the server in a hard way using the SHUTDOWN NOSAVE command.\r\n".to_vec()
+        } else {
+            self.kill.store(true, Ordering::SeqCst);
+            b"+OK\r\n".to_vec()
+        }
+    }
+
+    // SHUTDOWN NOSAVE stops the script whether or not it has written.
+    pub fn abort(&self) {
+        self.kill.store(true, Ordering::SeqCst);
+    }
+
+    fn begin(&self) {
+        self.wrote.store(false, Ordering::SeqCst);
+        self.kill.store(false, Ordering::SeqCst);
+        *self.started.lock().unwrap() = Some(Instant::now());
+    }
+
+    fn end(&self) {
+        *self.started.lock().unwrap() = None;
+        self.kill.store(false, Ordering::SeqCst);
+    }
+}
+
+#[derive(Debug)]
+struct ReplyError(String);
+
+impl fmt::Display for ReplyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl StdError for ReplyError {}
+
+pub fn sha1hex(script: &[u8]) -> String {
+    Sha1::from(script).digest().to_string()
+}
+
+// Runs a script and returns its RESP reply along with whether any of the
+// commands it called wrote to the dataset. `call` dispatches a single command
+// and reports (reply, write).
+pub fn eval<F>(
+    watchdog: &Arc<Watchdog>,
+    script: &[u8],
+    keys: &[Vec<u8>],
+    argv: &[Vec<u8>],
+    call: F,
+) -> (Vec<u8>, bool)
+where
+    F: FnMut(&[Vec<u8>]) -> (Vec<u8>, bool),
+{
+    run(watchdog, call, |lua| {
+        let globals = lua.globals();
+        globals.set("KEYS", make_args_table(lua, keys)?)?;
+        globals.set("ARGV", make_args_table(lua, argv)?)?;
+        lua.load(script)
+            .set_name("@user_script")
+            .set_mode(ChunkMode::Text)
+            .call(())
+    })
+}
+
+#[derive(Clone)]
+pub struct Library {
+    pub name: String,
+    pub code: Vec<u8>,
+    pub functions: Vec<FunctionInfo>,
+}
+
+#[derive(Clone)]
+pub struct FunctionInfo {
+    pub name: String,
+    pub flags: Vec<String>,
+}
+
+impl Library {
+    pub fn function(&self, name: &[u8]) -> Option<&FunctionInfo> {
+        self.functions.iter().find(|f| f.name.as_bytes() == name)
+    }
+}
+
+// Compiles a FUNCTION LOAD payload and collects the functions it registers.
+// The code must start with a `#!lua name=<library>` shebang.
+pub fn load_library(code: &[u8]) -> Result<Library, String> {
+    let name = library_name(code)?;
+    let lua = sandbox().map_err(|e| error_message(&e))?;
+    let functions: RefCell<Vec<FunctionInfo>> = RefCell::new(Vec::new());
+    let res = lua.scope(|scope| {
+        let redis = lua.create_table()?;
+        redis.set(
+            "register_function",
+            scope.create_function(|_, args: MultiValue| {
+                let (fname, _, flags) = register_args(args)?;
+                let mut functions = functions.borrow_mut();
+                if functions.iter().any(|f| f.name == fname) {
+                    return Err(mlua::Error::external(ReplyError(
+                        "ERR Function already exists in the library".to_string(),
+                    )));
+                }
+                functions.push(FunctionInfo {
+                    name: fname,
+                    flags,
+                });
+                Ok(())
+            })?,
+        )?;
+        lua.globals().set("redis", redis)?;
+        lua.load(&strip_shebang(code)[..])
+            .set_name("@user_function")
+            .set_mode(ChunkMode::Text)
+            .exec()
+    });
+    if let Err(e) = res {
+        return Err(error_message(&e));
+    }
+    let functions = functions.into_inner();
+    if functions.is_empty() {
+        return Err("ERR No functions registered".to_string());
+    }
+    Ok(Library {
+        name,
+        code: code.to_vec(),
+        functions,
+    })
+}
+
+// Re-runs a library's code and invokes one of the functions it registers
+// with the keys and arguments tables as its two parameters.
+pub fn fcall<F>(
+    watchdog: &Arc<Watchdog>,
+    library: &Library,
+    name: &[u8],
+    keys: &[Vec<u8>],
+    argv: &[Vec<u8>],
+    call: F,
+) -> (Vec<u8>, bool)
+where
+    F: FnMut(&[Vec<u8>]) -> (Vec<u8>, bool),
+{
+    run(watchdog, call, |lua| {
+        lua.set_named_registry_value("functions", lua.create_table()?)?;
+        let redis: Table = lua.globals().get("redis")?;
+        redis.set(
+            "register_function",
+            lua.create_function(|lua, args: MultiValue| {
+                let (fname, callback, _) = register_args(args)?;
+                let functions: Table = lua.named_registry_value("functions")?;
+                functions.set(fname, callback)
+            })?,
+        )?;
+        lua.load(&strip_shebang(&library.code)[..])
+            .set_name("@user_function")
+            .set_mode(ChunkMode::Text)
+            .exec()?;
+        let functions: Table = lua.named_registry_value("functions")?;
+        let callback: mlua::Function = functions.get(lua.create_string(name)?)?;
+        callback.call((make_args_table(lua, keys)?, make_args_table(lua, argv)?))
+    })
+}
+
+// Replaces loadstring with one that refuses bytecode, which could otherwise
+// be crafted to corrupt the interpreter.
+const LOADSTRING: &str = "
+local loadstring = loadstring
+_G.loadstring = function(chunk, name)
+    if type(chunk) == 'string' and chunk:byte(1) == 27 then
+        return nil, 'loading bytecode is not allowed'
+    end
+    return loadstring(chunk, name)
+end
+";
+
+fn sandbox() -> mlua::Result<Lua> {
+    let lua = Lua::new_with(
+        StdLib::STRING | StdLib::TABLE | StdLib::MATH,
+        LuaOptions::default(),
+    )?;
+    {
+        let globals = lua.globals();
+        for name in &["dofile", "loadfile", "load"] {
+            globals.raw_set(*name, Value::Nil)?;
+        }
+        lua.load(LOADSTRING).set_mode(ChunkMode::Text).exec()?;
+    }
+    Ok(lua)
+}
+
+fn run<F, B>(watchdog: &Arc<Watchdog>, mut call: F, body: B) -> (Vec<u8>, bool)
+where
+    F: FnMut(&[Vec<u8>]) -> (Vec<u8>, bool),
+    B: FnOnce(&Lua) -> mlua::Result<Value>,
+{
+    let lua = match sandbox() {
+        Ok(lua) => lua,
+        Err(e) => return (format!("-{}\r\n", error_message(&e)).into_bytes(), false),
+    };
+    let wrote = Cell::new(false);
+    let call = RefCell::new(|args: &[Vec<u8>]| {
+        let (out, write) = (call)(args);
+        if write {
+            wrote.set(true);
+            watchdog.wrote.store(true, Ordering::SeqCst);
+        }
+        out
+    });
+    let hook_watchdog = watchdog.clone();
+    lua.set_hook(
+        HookTriggers::new().every_nth_instruction(1000),
+        move |_, _| {
+            if hook_watchdog.kill.load(Ordering::SeqCst) {
+                Err(mlua::Error::external(ReplyError(
+                    "ERR Script killed by user with SCRIPT KILL...".to_string(),
+                )))
+            } else {
+                Ok(())
+            }
+        },
+    );
+    watchdog.begin();
+    let res = lua.scope(|scope| {
+        let redis = lua.create_table()?;
+        redis.set(
+            "call",
+            scope.create_function(|lua, args: MultiValue| {
+                let out = (call.borrow_mut())(&lua_to_args(args)?);
+                reply_to_lua(lua, &out, true)
+            })?,
+        )?;
+        redis.set(
+            "pcall",
+            scope.create_function(|lua, args: MultiValue| {
+                let out = match lua_to_args(args) {
+                    Ok(args) => (call.borrow_mut())(&args),
+                    Err(e) => format!("-{}\r\n", error_message(&e)).into_bytes(),
+                };
+                reply_to_lua(lua, &out, false)
+            })?,
+        )?;
+        redis.set(
+            "status_reply",
+            lua.create_function(|lua, msg: mlua::String| {
+                let t = lua.create_table()?;
+                t.set("ok", msg)?;
+                Ok(t)
+            })?,
+        )?;
+        redis.set(
+            "error_reply",
+            lua.create_function(|lua, msg: mlua::String| {
+                let t = lua.create_table()?;
+                t.set("err", msg)?;
+                Ok(t)
+            })?,
+        )?;
+        redis.set(
+            "sha1hex",
+            lua.create_function(|_, s: mlua::String| Ok(sha1hex(s.as_bytes())))?,
+        )?;
+        lua.globals().set("redis", redis)?;
+
+        let value = body(&lua)?;
+        Ok(lua_to_reply(&value))
+    });
+    watchdog.end();
+    let output = match res {
+        Ok(output) => output,
+        Err(e) => format!("-{}\r\n", error_message(&e)).into_bytes(),
+    };
+    (output, wrote.get())
+}
+
+
+fn library_name(code: &[u8]) -> Result<String, String> {
+    let line = code.split(|&b| b == b'\n').next().unwrap_or(b"");
+    let line = String::from_utf8_lossy(line);
+    if !line.starts_with("#!") {
+        return Err("ERR Missing library metadata".to_string());
+    }
+    let mut parts = line[2..].split_whitespace();
+    let engine = parts.next().unwrap_or("");
+    if !engine.eq_ignore_ascii_case("lua") {
+        return Err(format!("ERR Engine '{}' not found", engine));
+    }
+    let mut name = None;
+    for part in parts {
+        if let Some(value) = part.strip_prefix("name=") {
+            name = Some(value.to_string());
+        } else {
+            return Err(format!("ERR Invalid metadata value given: {}", part));
+        }
+    }
+    match name {
+        Some(ref name) if valid_name(name) => Ok(name.clone()),
+        Some(_) => Err("ERR Library names can only contain letters, numbers, or underscores(_) and must be at least one character long".to_string()),
+        None => Err("ERR Library name was not given".to_string()),
+    }
+}
+
+// The shebang is not valid Lua; blank it out while keeping line numbers.
+fn strip_shebang(code: &[u8]) -> Vec<u8> {
+    match code.iter().position(|&b| b == b'\n') {
+        Some(i) => code[i..].to_vec(),
+        None => Vec::new(),
+    }
+}
+
+fn valid_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+// Accepts both redis.register_function(name, callback) and the table form
+// redis.register_function{function_name=..., callback=..., flags={...}}.
+fn register_args<'lua>(
+    args: MultiValue<'lua>,
+) -> mlua::Result<(String, mlua::Function<'lua>, Vec<String>)> {
+    let args = args.into_vec();
+    let (name, callback, flags) = match args.len() {
+        1 => match args[0] {
+            Value::Table(ref t) => (
+                t.get::<_, Value>("function_name")?,
+                t.get::<_, Value>("callback")?,
+                t.get::<_, Option<Vec<String>>>("flags")?.unwrap_or_default(),
+            ),
+            _ => {
+                return Err(mlua::Error::external(ReplyError(
+                    "ERR calling redis.register_function with a single argument is only applicable to Lua table (representing named arguments).".to_string(),
+                )))
+            }
+        },
+        2 => (args[0].clone(), args[1].clone(), Vec::new()),
+        _ => {
+            return Err(mlua::Error::external(ReplyError(
+                "ERR wrong number of arguments to redis.register_function".to_string(),
+            )))
+        }
+    };
+    let name = match name {
+        Value::String(ref s) if valid_name(&s.to_string_lossy()) => s.to_string_lossy().to_string(),
+        _ => {
+            return Err(mlua::Error::external(ReplyError(
+                "ERR Function names can only contain letters, numbers, or underscores(_) and must be at least one character long".to_string(),
+            )))
+        }
+    };
+    let callback = match callback {
+        Value::Function(f) => f,
+        _ => {
+            return Err(mlua::Error::external(ReplyError(
+                "ERR callback must be a function".to_string(),
+            )))
+        }
+    };
+    for flag in &flags {
+        if flag != "no-writes" && flag != "allow-oom" && flag != "allow-stale"
+            && flag != "no-cluster" && flag != "allow-cross-slot-keys"
+        {
+            return Err(mlua::Error::external(ReplyError(
+                format!("ERR unknown flag given: {}", flag),
+            )));
+        }
+    }
+    Ok((name, callback, flags))
+}
+
+fn make_args_table<'lua>(lua: &'lua Lua, args: &[Vec<u8>]) -> mlua::Result<Table<'lua>> {
+    let t = lua.create_table()?;
+    for (i, arg) in args.iter().enumerate() {
+        t.set(i + 1, lua.create_string(arg)?)?;
+    }
+    Ok(t)
+}
+
+fn lua_to_args(args: MultiValue) -> mlua::Result<Vec<Vec<u8>>> {
+    if args.is_empty() {
+        return Err(mlua::Error::external(ReplyError(
+            "ERR Please specify at least one argument for this redis lib call".to_string(),
+        )));
+    }
+    let mut out = Vec::new();
+    for arg in args {
+        match arg {
+            Value::String(s) => out.push(s.as_bytes().to_vec()),
+            Value::Integer(n) => out.push(n.to_string().into_bytes()),
+            Value::Number(n) => out.push(n.to_string().into_bytes()),
+            _ => {
+                return Err(mlua::Error::external(ReplyError(
+                    "ERR Lua redis lib command arguments must be strings or integers"
+                        .to_string(),
+                )))
+            }
+        }
+    }
+    Ok(out)
+}
+
+// Converts a RESP reply produced by the dispatcher into a Lua value. When
+// `raise` is set (redis.call) a top level error reply becomes a Lua error,
+// otherwise (redis.pcall) it is returned as an {err=...} table.
+fn reply_to_lua<'lua>(lua: &'lua Lua, reply: &[u8], raise: bool) -> mlua::Result<Value<'lua>> {
+    if raise && reply.first() == Some(&b'-') {
+        let (line, _) = take_line(reply, 1);
+        return Err(mlua::Error::external(ReplyError(
+            String::from_utf8_lossy(line).to_string(),
+        )));
+    }
+    let mut i = 0;
+    take_reply(lua, reply, &mut i)
+}
+
+fn take_line(reply: &[u8], start: usize) -> (&[u8], usize) {
+    let mut i = start;
+    while i + 1 < reply.len() && !(reply[i] == b'\r' && reply[i + 1] == b'\n') {
+        i += 1;
+    }
+    (&reply[start.min(i)..i], i + 2)
+}
+
+fn take_reply<'lua>(lua: &'lua Lua, reply: &[u8], i: &mut usize) -> mlua::Result<Value<'lua>> {
+    if *i >= reply.len() {
+        return Ok(Value::Boolean(false));
+    }
+    let kind = reply[*i];
+    let (line, next) = take_line(reply, *i + 1);
+    *i = next;
+    let num = || String::from_utf8_lossy(line).parse::<i64>().unwrap_or(0);
+    match kind {
+        b'+' => {
+            let t = lua.create_table()?;
+            t.set("ok", lua.create_string(line)?)?;
+            Ok(Value::Table(t))
+        }
+        b'-' => {
+            let t = lua.create_table()?;
+            t.set("err", lua.create_string(line)?)?;
+            Ok(Value::Table(t))
+        }
+        b':' => Ok(Value::Integer(num() as mlua::Integer)),
+        b'$' => {
+            let n = num();
+            if n < 0 {
+                return Ok(Value::Boolean(false));
+            }
+            let end = (*i + n as usize).min(reply.len());
+            let s = lua.create_string(&reply[*i..end])?;
+            *i = end + 2;
+            Ok(Value::String(s))
+        }
+        b'*' => {
+            let n = num();
+            if n < 0 {
+                return Ok(Value::Boolean(false));
+            }
+            let t = lua.create_table()?;
+            for j in 0..n {
+                t.set(j + 1, take_reply(lua, reply, i)?)?;
+            }
+            Ok(Value::Table(t))
+        }
+        _ => Ok(Value::Boolean(false)),
+    }
+}
+
+fn lua_to_reply(value: &Value) -> Vec<u8> {
+    match *value {
+        Value::Boolean(true) => b":1\r\n".to_vec(),
+        Value::Integer(n) => format!(":{}\r\n", n).into_bytes(),
+        Value::Number(n) => format!(":{}\r\n", n as i64).into_bytes(),
+        Value::String(ref s) => {
+            let mut resp = format!("${}\r\n", s.as_bytes().len()).into_bytes();
+            resp.extend_from_slice(s.as_bytes());
+            resp.extend_from_slice(b"\r\n");
+            resp
+        }
+        Value::Table(ref t) => {
+            if let Ok(msg) = t.raw_get::<_, mlua::String>("err") {
+                return format!("-{}\r\n", line_safe(msg.as_bytes())).into_bytes();
+            }
+            if let Ok(msg) = t.raw_get::<_, mlua::String>("ok") {
+                return format!("+{}\r\n", line_safe(msg.as_bytes())).into_bytes();
+            }
+            let mut items = Vec::new();
+            let mut j = 1;
+            loop {
+                match t.raw_get::<_, Value>(j) {
+                    Ok(Value::Nil) | Err(_) => break,
+                    Ok(v) => items.push(lua_to_reply(&v)),
+                }
+                j += 1;
+            }
+            let mut resp = format!("*{}\r\n", items.len()).into_bytes();
+            for item in items {
+                resp.extend(item);
+            }
+            resp
+        }
+        _ => b"$-1\r\n".to_vec(),
+    }
+}
+
+fn line_safe(s: &[u8]) -> String {
+    String::from_utf8_lossy(s).replace(['\r', '\n'], " ")
+}
+
+fn error_message(err: &mlua::Error) -> String {
+    match *err {
+        mlua::Error::CallbackError { ref cause, .. } => error_message(cause),
+        mlua::Error::ExternalError(ref e) => match e.downcast_ref::<ReplyError>() {
+            Some(reply) => reply.0.clone(),
+            None => format!("ERR {}", e),
+        },
+        mlua::Error::SyntaxError { ref message, .. } => {
+            format!("ERR Error compiling script (new function): {}", line_safe(message.as_bytes()))
+        }
+        mlua::Error::RuntimeError(ref msg) => {
+            let msg = msg.split("\nstack traceback:").next().unwrap_or("");
+            format!("ERR Error running script: {}", line_safe(msg.as_bytes()))
+        }
+        ref e => format!("ERR Error running script: {}", line_safe(e.to_string().as_bytes())),
+    }
+}
@@ -0,0 +1,141 @@
+// Size-classed buffers for stored values.
+//
+// Values too long to keep inline but no longer than MAX are stored in
+// buffers of a few fixed sizes, the powers of two from MIN to MAX. When a
+// value is deleted or overwritten its buffer goes back to a free list of
+// the thread dropping it, and the next value of that class written on the
+// thread takes it. SET/DEL churn then keeps reusing the same buffers
+// instead of going back to the global allocator for every value and
+// scattering allocations of every size over its pages. Each thread keeps
+// at most KEEP bytes free; past that buffers are released.
+//
+// Longer values stay as Bytes, so GET can share them with replies.
+
+use std::cell::RefCell;
+use std::mem;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+pub const MIN: usize = 64;
+pub const MAX: usize = 2048;
+
+const CLASSES: usize = 6;
+const KEEP: usize = 4 * 1024 * 1024;
+
+// Bytes in the free lists of all threads.
+static FREE: AtomicUsize = AtomicUsize::new(0);
+
+struct Free {
+    lists: [Vec<Vec<u8>>; CLASSES],
+    bytes: usize,
+}
+
+impl Drop for Free {
+    fn drop(&mut self) {
+        FREE.fetch_sub(self.bytes, Ordering::Relaxed);
+    }
+}
+
+thread_local! {
+    static LISTS: RefCell<Free> = RefCell::new(Free {
+        lists: Default::default(),
+        bytes: 0,
+    });
+}
+
+// The class of a buffer holding len bytes, None above MAX.
+fn class(len: usize) -> Option<usize> {
+    if len > MAX {
+        return None;
+    }
+    let size = len.max(MIN).next_power_of_two();
+    Some(size.trailing_zeros() as usize - MIN.trailing_zeros() as usize)
+}
+
+// A value's buffer, back to the free lists when dropped.
+pub struct Buf(Vec<u8>);
+
+impl Buf {
+    // A buffer of the right class holding a copy of data, None for data
+    // longer than MAX.
+    pub fn new(data: &[u8]) -> Option<Buf> {
+        let class = class(data.len())?;
+        let reused = LISTS
+            .try_with(|free| {
+                let mut free = free.borrow_mut();
+                let buf = free.lists[class].pop();
+                if let Some(ref buf) = buf {
+                    free.bytes -= buf.capacity();
+                    FREE.fetch_sub(buf.capacity(), Ordering::Relaxed);
+                }
+                buf
+            })
+            .ok()
+            .and_then(|buf| buf);
+        let mut buf = reused.unwrap_or_else(|| Vec::with_capacity(MIN << class));
+        buf.extend_from_slice(data);
+        Some(Buf(buf))
+    }
+
+    // Bytes the buffer takes, whatever part of it the value uses.
+    pub fn capacity(&self) -> usize {
+        self.0.capacity()
+    }
+}
+
+impl Deref for Buf {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl Drop for Buf {
+    fn drop(&mut self) {
+        let mut buf = mem::take(&mut self.0);
+        let class = match class(buf.capacity()) {
+            Some(class) if buf.capacity() == MIN << class => class,
+            _ => return,
+        };
+        buf.clear();
+        // A thread being torn down has no lists left; the buffer is freed.
+        let _ = LISTS.try_with(|free| {
+            let mut free = free.borrow_mut();
+            if free.bytes + buf.capacity() <= KEEP {
+                free.bytes += buf.capacity();
+                FREE.fetch_add(buf.capacity(), Ordering::Relaxed);
+                free.lists[class].push(buf);
+            }
+        });
+    }
+}
+
+// Bytes held in free buffers, for MEMORY STATS.
+pub fn free() -> usize {
+    FREE.load(Ordering::Relaxed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Buf, MAX, MIN};
+
+    #[test]
+    fn buffers_come_in_classes() {
+        assert_eq!(Buf::new(&[1; 10]).unwrap().capacity(), MIN);
+        assert_eq!(Buf::new(&[1; MIN + 1]).unwrap().capacity(), 2 * MIN);
+        assert_eq!(Buf::new(&[1; MAX]).unwrap().capacity(), MAX);
+        assert!(Buf::new(&[1; MAX + 1]).is_none());
+        assert_eq!(&*Buf::new(b"value").unwrap(), b"value");
+    }
+
+    #[test]
+    fn dropped_buffers_are_reused() {
+        let buf = Buf::new(&[1; 300]).unwrap();
+        let ptr = buf.as_ptr();
+        drop(buf);
+        let again = Buf::new(&[2; 400]).unwrap();
+        assert_eq!(again.as_ptr(), ptr);
+        assert_eq!(&again[..], &[2; 400][..]);
+    }
+}
@@ -0,0 +1,180 @@
+// Inline storage for short keys and values.
+//
+// Most keys, and the values of a typical cache, are a few dozen bytes at
+// most. Those are kept inside the map slot or the stored value itself
+// rather than in an allocation of their own, which saves the allocation,
+// its rounding and a pointer to chase on every lookup. Longer ones stay on
+// the heap: keys behind an Arc shared by the map and the sampling list,
+// values in a slab buffer or, past slab::MAX, as Bytes a reply can share.
+
+use std::borrow::Borrow;
+use std::hash::{Hash, Hasher};
+use std::mem;
+use std::ops::Deref;
+use std::sync::Arc;
+
+use bytes::Bytes;
+
+use memory;
+use slab;
+
+// As long as fits with its length and the tag in three words, one more
+// than an Arc<[u8]> takes.
+pub const INLINE_KEY: usize = 22;
+
+// Likewise in five words, one more than Bytes takes.
+pub const INLINE_VALUE: usize = 38;
+
+#[derive(Clone)]
+pub enum Key {
+    Inline(u8, [u8; INLINE_KEY]),
+    Shared(Arc<[u8]>),
+}
+
+impl Key {
+    pub fn new(key: Vec<u8>) -> Key {
+        if key.len() <= INLINE_KEY {
+            let mut buf = [0; INLINE_KEY];
+            buf[..key.len()].copy_from_slice(&key);
+            Key::Inline(key.len() as u8, buf)
+        } else {
+            Key::Shared(Arc::from(key))
+        }
+    }
+
+    // Heap bytes of the key, including the reference counts in front of a
+    // shared one.
+    pub fn heap_size(&self) -> usize {
+        match *self {
+            Key::Inline(..) => 0,
+            Key::Shared(ref key) => memory::alloc_size(2 * mem::size_of::<usize>() + key.len()),
+        }
+    }
+}
+
+impl Deref for Key {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match *self {
+            Key::Inline(len, ref buf) => &buf[..len as usize],
+            Key::Shared(ref key) => key,
+        }
+    }
+}
+
+// Keys hash and compare as their bytes, so the map can be searched with a
+// plain slice.
+impl Borrow<[u8]> for Key {
+    fn borrow(&self) -> &[u8] {
+        self
+    }
+}
+
+impl Hash for Key {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        (**self).hash(state)
+    }
+}
+
+impl PartialEq for Key {
+    fn eq(&self, other: &Key) -> bool {
+        **self == **other
+    }
+}
+
+impl Eq for Key {}
+
+pub enum Payload {
+    Inline(u8, [u8; INLINE_VALUE]),
+    Slab(slab::Buf),
+    Heap(Bytes),
+}
+
+impl Payload {
+    pub fn new(bytes: Bytes) -> Payload {
+        if bytes.len() <= INLINE_VALUE {
+            let mut buf = [0; INLINE_VALUE];
+            buf[..bytes.len()].copy_from_slice(&bytes);
+            Payload::Inline(bytes.len() as u8, buf)
+        } else if let Some(buf) = slab::Buf::new(&bytes) {
+            Payload::Slab(buf)
+        } else {
+            Payload::Heap(bytes)
+        }
+    }
+
+    // The payload as Bytes, copied unless it is on the heap already.
+    pub fn to_bytes(&self) -> Bytes {
+        match *self {
+            Payload::Inline(..) | Payload::Slab(_) => Bytes::copy_from_slice(self),
+            Payload::Heap(ref bytes) => bytes.clone(),
+        }
+    }
+
+    pub fn heap_size(&self) -> usize {
+        match *self {
+            Payload::Inline(..) => 0,
+            Payload::Slab(ref buf) => buf.capacity(),
+            Payload::Heap(ref bytes) => memory::alloc_size(bytes.len()),
+        }
+    }
+}
+
+impl Deref for Payload {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match *self {
+            Payload::Inline(len, ref buf) => &buf[..len as usize],
+            Payload::Slab(ref buf) => buf,
+            Payload::Heap(ref bytes) => bytes,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::mem;
+    use std::sync::Arc;
+
+    use bytes::Bytes;
+
+    use super::{Key, Payload, INLINE_KEY, INLINE_VALUE};
+
+    #[test]
+    fn short_keys_are_inline() {
+        let short = Key::new(vec![b'k'; INLINE_KEY]);
+        let long = Key::new(vec![b'k'; INLINE_KEY + 1]);
+        assert_eq!(short.heap_size(), 0);
+        assert!(long.heap_size() > INLINE_KEY);
+        assert_eq!(&*short, &[b'k'; INLINE_KEY][..]);
+        assert_eq!(long.len(), INLINE_KEY + 1);
+        assert!(mem::size_of::<Key>() <= mem::size_of::<Arc<[u8]>>() + 8);
+    }
+
+    #[test]
+    fn keys_are_found_by_their_bytes() {
+        let mut map = HashMap::new();
+        map.insert(Key::new(b"short".to_vec()), 1);
+        map.insert(Key::new(vec![b'x'; 100]), 2);
+        assert_eq!(map.get(&b"short"[..]), Some(&1));
+        assert_eq!(map.get(&vec![b'x'; 100][..]), Some(&2));
+        assert_eq!(map.get(&b"shor"[..]), None);
+    }
+
+    #[test]
+    fn short_values_are_inline() {
+        let short = Payload::new(Bytes::from(vec![b'v'; INLINE_VALUE]));
+        let long = Payload::new(Bytes::from(vec![b'v'; INLINE_VALUE + 1]));
+        let huge = Payload::new(Bytes::from(vec![b'v'; 10000]));
+        assert_eq!(short.heap_size(), 0);
+        assert!(long.heap_size() > INLINE_VALUE);
+        assert_eq!(huge.heap_size(), 10000);
+        assert_eq!(huge.to_bytes(), Bytes::from(vec![b'v'; 10000]));
+        assert_eq!(short.to_bytes(), Bytes::from(vec![b'v'; INLINE_VALUE]));
+        assert_eq!(&*long, &vec![b'v'; INLINE_VALUE + 1][..]);
+        assert!(mem::size_of::<Payload>() <= mem::size_of::<Bytes>() + 8);
+    }
+}
@@ -0,0 +1,69 @@
+// In-process access to a server's keys.
+//
+// A program embedding the server reads and writes keys through a Store
+// instead of a client connection. Keys are locked and values encoded the
+// same way commands do it, but none of what applies to clients does: no
+// ACLs, size limits, eviction or cluster redirects. Databases are numbered
+// as for SELECT, and operations on one that doesn't exist panic.
+
+use std::time::Duration;
+
+use bytes::Bytes;
+
+use keyspace;
+use Server;
+
+pub struct Store<'a> {
+    server: &'a Server,
+}
+
+impl<'a> Store<'a> {
+    pub fn new(server: &'a Server) -> Store<'a> {
+        Store { server }
+    }
+
+    pub fn get(&self, db: usize, key: &[u8]) -> Option<Vec<u8>> {
+        let store = self.lock(key, false);
+        store.get(db, key).map(|value| value.to_vec())
+    }
+
+    pub fn set(&self, db: usize, key: &[u8], value: &[u8]) {
+        let value = {
+            let config = self.server.config.read().unwrap();
+            self.server.compressor.encode(
+                Bytes::from(value.to_vec()),
+                &config.compression,
+                config.compression_threshold,
+            )
+        };
+        self.lock(key, true).insert(db, key.to_vec(), value);
+    }
+
+    // True if the key existed.
+    pub fn del(&self, db: usize, key: &[u8]) -> bool {
+        self.lock(key, true).remove(db, key).is_some()
+    }
+
+    pub fn exists(&self, db: usize, key: &[u8]) -> bool {
+        self.lock(key, false).contains_key(db, key)
+    }
+
+    // The number of keys in the database.
+    pub fn len(&self, db: usize) -> usize {
+        let keyspace = &self.server.keyspace;
+        self.wait(&keyspace.all(), false).len(db)
+    }
+
+    fn lock(&self, key: &[u8], write: bool) -> keyspace::Locked<'a> {
+        self.wait(&[self.server.keyspace.shard(key)], write)
+    }
+
+    // Takes the shards once no command holds them.
+    fn wait(&self, shards: &[usize], write: bool) -> keyspace::Locked<'a> {
+        loop {
+            if let Some(store) = self.server.keyspace.lock(shards, write, Duration::from_millis(100)) {
+                return store;
+            }
+        }
+    }
+}
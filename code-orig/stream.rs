@@ -0,0 +1,509 @@
+// Connection streams.
+//
+// A connection is a plain TCP socket, a TLS session over one, or a Unix
+// domain socket. All are read and written through the same non-blocking
+// Read/Write interface: the TLS side pulls ciphertext off the socket as
+// plaintext is asked for, drives the handshake along the way, and reports
+// WouldBlock exactly when the socket has nothing more to give.
+
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, IoSlice, Read, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net;
+use std::sync::Arc;
+use std::time::Duration;
+
+use mio::event::Source;
+use mio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+use mio::{Interest, Registry, Token};
+use socket2::{Domain, SockRef, Socket, TcpKeepalive, Type};
+use rustls::server::{AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient};
+use rustls::{RootCertStore, ServerConfig, ServerConnection};
+
+pub enum Stream {
+    Plain(TcpStream),
+    Tls(TcpStream, Box<ServerConnection>),
+    Unix(UnixStream),
+}
+
+// A socket new connections are accepted on, along with how to wrap them.
+pub enum Listener {
+    Plain(TcpListener),
+    Tls(TcpListener, Arc<ServerConfig>),
+    Unix(UnixListener),
+}
+
+impl Listener {
+    pub fn accept(&self) -> io::Result<Stream> {
+        match *self {
+            Listener::Plain(ref listener) => listener.accept().map(|(s, _)| Stream::Plain(s)),
+            Listener::Tls(ref listener, ref config) => {
+                listener.accept().and_then(|(s, _)| Stream::tls(s, config))
+            }
+            Listener::Unix(ref listener) => listener.accept().map(|(s, _)| Stream::Unix(s)),
+        }
+    }
+
+    // Wraps a socket accepted on this listener other than through accept(),
+    // taking ownership of it.
+    pub fn wrap(&self, fd: RawFd) -> io::Result<Stream> {
+        unsafe {
+            match *self {
+                Listener::Plain(_) => Ok(Stream::Plain(TcpStream::from_raw_fd(fd))),
+                Listener::Tls(_, ref config) => Stream::tls(TcpStream::from_raw_fd(fd), config),
+                Listener::Unix(_) => Ok(Stream::Unix(UnixStream::from_raw_fd(fd))),
+            }
+        }
+    }
+}
+
+impl AsRawFd for Listener {
+    fn as_raw_fd(&self) -> RawFd {
+        match *self {
+            Listener::Plain(ref l) | Listener::Tls(ref l, _) => l.as_raw_fd(),
+            Listener::Unix(ref l) => l.as_raw_fd(),
+        }
+    }
+}
+
+impl Source for Listener {
+    fn register(&mut self, registry: &Registry, token: Token, interests: Interest) -> io::Result<()> {
+        match *self {
+            Listener::Plain(ref mut l) | Listener::Tls(ref mut l, _) => {
+                l.register(registry, token, interests)
+            }
+            Listener::Unix(ref mut l) => l.register(registry, token, interests),
+        }
+    }
+
+    fn reregister(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        match *self {
+            Listener::Plain(ref mut l) | Listener::Tls(ref mut l, _) => {
+                l.reregister(registry, token, interests)
+            }
+            Listener::Unix(ref mut l) => l.reregister(registry, token, interests),
+        }
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        match *self {
+            Listener::Plain(ref mut l) | Listener::Tls(ref mut l, _) => l.deregister(registry),
+            Listener::Unix(ref mut l) => l.deregister(registry),
+        }
+    }
+}
+
+impl Stream {
+    pub fn tls(stream: TcpStream, config: &Arc<ServerConfig>) -> io::Result<Stream> {
+        match ServerConnection::new(config.clone()) {
+            Ok(session) => Ok(Stream::Tls(stream, Box::new(session))),
+            Err(e) => Err(io::Error::other(e)),
+        }
+    }
+
+    pub fn tcp(&self) -> Option<&TcpStream> {
+        match *self {
+            Stream::Plain(ref stream) => Some(stream),
+            Stream::Tls(ref stream, _) => Some(stream),
+            Stream::Unix(_) => None,
+        }
+    }
+
+    // Address of the remote peer, None for Unix sockets, which always count
+    // as local.
+    pub fn peer_ip(&self) -> Option<IpAddr> {
+        self.tcp().and_then(|s| s.peer_addr().ok()).map(|a| a.ip())
+    }
+
+    // Remote and local addresses as CLIENT LIST shows them. Unix sockets
+    // have no peer name, so both sides report the socket path.
+    pub fn addrs(&self) -> (String, String) {
+        fn show(addr: io::Result<SocketAddr>) -> String {
+            addr.map(|a| a.to_string()).unwrap_or_default()
+        }
+        match *self {
+            Stream::Unix(ref stream) => {
+                let path = stream
+                    .local_addr()
+                    .ok()
+                    .and_then(|a| a.as_pathname().map(|p| p.display().to_string()))
+                    .unwrap_or_default();
+                let addr = format!("{}:0", path);
+                (addr.clone(), addr)
+            }
+            _ => {
+                let stream = self.tcp().unwrap();
+                (show(stream.peer_addr()), show(stream.local_addr()))
+            }
+        }
+    }
+
+    // Common name in the subject of the certificate the TLS client
+    // presented, if it presented one.
+    pub fn peer_cn(&self) -> Option<String> {
+        match *self {
+            Stream::Plain(_) | Stream::Unix(_) => None,
+            Stream::Tls(_, ref session) => session
+                .peer_certificates()
+                .and_then(|certs| certs.first())
+                .and_then(|cert| subject_cn(&cert.0)),
+        }
+    }
+
+    // Whether TLS records are still queued for the socket, which needs
+    // write interest even when no replies are left.
+    pub fn wants_write(&self) -> bool {
+        match *self {
+            Stream::Tls(_, ref session) => session.wants_write(),
+            _ => false,
+        }
+    }
+
+    pub fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        match self.tcp() {
+            Some(stream) => stream.set_nodelay(nodelay),
+            None => Ok(()),
+        }
+    }
+
+    pub fn set_keepalive(&self, keepalive: Option<Duration>) -> io::Result<()> {
+        let socket = match self.tcp() {
+            Some(stream) => SockRef::from(stream),
+            None => return Ok(()),
+        };
+        match keepalive {
+            Some(time) => socket.set_tcp_keepalive(&TcpKeepalive::new().with_time(time)),
+            None => socket.set_keepalive(false),
+        }
+    }
+}
+
+// Writes out the TLS records the session has queued, failing with
+// WouldBlock once the socket is full.
+fn flush_tls(stream: &mut TcpStream, session: &mut ServerConnection) -> io::Result<()> {
+    while session.wants_write() {
+        session.write_tls(stream)?;
+    }
+    Ok(())
+}
+
+// Like flush_tls, but leaves what the socket can't take yet queued in the
+// session for the next writable event.
+fn try_flush_tls(stream: &mut TcpStream, session: &mut ServerConnection) -> io::Result<()> {
+    match flush_tls(stream, session) {
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(()),
+        result => result,
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let (stream, session) = match *self {
+            Stream::Plain(ref mut stream) => return stream.read(buf),
+            Stream::Unix(ref mut stream) => return stream.read(buf),
+            Stream::Tls(ref mut stream, ref mut session) => (stream, session),
+        };
+        loop {
+            match session.reader().read(buf) {
+                Ok(n) => return Ok(n),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+            if session.read_tls(stream)? == 0 {
+                return Ok(0);
+            }
+            if let Err(e) = session.process_new_packets() {
+                // Send the alert explaining why before giving up.
+                let _ = flush_tls(stream, session);
+                return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+            }
+            try_flush_tls(stream, session)?;
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            Stream::Plain(ref mut stream) => stream.write(buf),
+            Stream::Unix(ref mut stream) => stream.write(buf),
+            Stream::Tls(ref mut stream, ref mut session) => {
+                // Records from earlier writes go out first, so the session
+                // never buffers more than one write ahead of the socket.
+                flush_tls(stream, session)?;
+                let n = session.writer().write(buf)?;
+                try_flush_tls(stream, session)?;
+                Ok(n)
+            }
+        }
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice]) -> io::Result<usize> {
+        match *self {
+            Stream::Plain(ref mut stream) => stream.write_vectored(bufs),
+            Stream::Unix(ref mut stream) => stream.write_vectored(bufs),
+            Stream::Tls(ref mut stream, ref mut session) => {
+                flush_tls(stream, session)?;
+                let n = session.writer().write_vectored(bufs)?;
+                try_flush_tls(stream, session)?;
+                Ok(n)
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            Stream::Plain(ref mut stream) => stream.flush(),
+            Stream::Unix(ref mut stream) => stream.flush(),
+            Stream::Tls(ref mut stream, ref mut session) => flush_tls(stream, session),
+        }
+    }
+}
+
+impl AsRawFd for Stream {
+    fn as_raw_fd(&self) -> RawFd {
+        match *self {
+            Stream::Plain(ref stream) | Stream::Tls(ref stream, _) => stream.as_raw_fd(),
+            Stream::Unix(ref stream) => stream.as_raw_fd(),
+        }
+    }
+}
+
+impl Source for Stream {
+    fn register(&mut self, registry: &Registry, token: Token, interests: Interest) -> io::Result<()> {
+        match *self {
+            Stream::Plain(ref mut s) | Stream::Tls(ref mut s, _) => {
+                s.register(registry, token, interests)
+            }
+            Stream::Unix(ref mut s) => s.register(registry, token, interests),
+        }
+    }
+
+    fn reregister(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        match *self {
+            Stream::Plain(ref mut s) | Stream::Tls(ref mut s, _) => {
+                s.reregister(registry, token, interests)
+            }
+            Stream::Unix(ref mut s) => s.reregister(registry, token, interests),
+        }
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        match *self {
+            Stream::Plain(ref mut s) | Stream::Tls(ref mut s, _) => s.deregister(registry),
+            Stream::Unix(ref mut s) => s.deregister(registry),
+        }
+    }
+}
+
+// Binds a TCP listener with the given accept backlog, which mio's own
+// bind leaves at the standard library default. With reuseport several
+// listeners can share the address and the kernel spreads connections
+// between them.
+pub fn bind_tcp(addr: &str, backlog: i32, reuseport: bool) -> Result<TcpListener, String> {
+    let sockaddr: SocketAddr = match addr.parse() {
+        Ok(sockaddr) => sockaddr,
+        Err(e) => return Err(format!("Invalid bind address '{}': {}", addr, e)),
+    };
+    Socket::new(Domain::for_address(sockaddr), Type::STREAM, None)
+        .and_then(|socket| {
+            // Keep IPv6 sockets off IPv4 so "0.0.0.0 ::" can bind both.
+            if sockaddr.is_ipv6() {
+                socket.set_only_v6(true)?;
+            }
+            socket.set_reuse_address(true)?;
+            if reuseport {
+                socket.set_reuse_port(true)?;
+            }
+            socket.bind(&sockaddr.into())?;
+            socket.listen(backlog)?;
+            socket.set_nonblocking(true)?;
+            Ok(TcpListener::from_std(socket.into()))
+        })
+        .map_err(|e| format!("Could not create server TCP listening socket {}: {}", addr, e))
+}
+
+// Binds port on every address of a space separated bind list. Addresses
+// prefixed with '-' are optional and skipped when they can't be bound.
+// Port 0 binds the first address to a free port and the rest to the same.
+pub fn bind_all(
+    bind: &str,
+    mut port: usize,
+    backlog: i32,
+    reuseport: bool,
+) -> Result<Vec<TcpListener>, String> {
+    let mut listeners = Vec::new();
+    for addr in bind.split_whitespace() {
+        let (optional, addr) = match addr.strip_prefix('-') {
+            Some(addr) => (true, addr),
+            None => (false, addr),
+        };
+        let addr = if addr.contains(':') {
+            format!("[{}]:{}", addr, port)
+        } else {
+            format!("{}:{}", addr, port)
+        };
+        match bind_tcp(&addr, backlog, reuseport) {
+            Ok(listener) => {
+                if port == 0 {
+                    port = listener.local_addr().map(|a| a.port() as usize).unwrap_or(0);
+                }
+                listeners.push(listener);
+            }
+            Err(_) if optional => {}
+            Err(e) => return Err(e),
+        }
+    }
+    if listeners.is_empty() {
+        return Err(format!("Failed listening on port {} (tcp), aborting.", port));
+    }
+    Ok(listeners)
+}
+
+// Binds the Unix socket at path, replacing a stale socket file left by a
+// previous run, and applies the octal permissions in perm unless it is 0.
+pub fn bind_unix(path: &str, perm: u32) -> Result<Listener, String> {
+    let _ = fs::remove_file(path);
+    let listener = match net::UnixListener::bind(path) {
+        Ok(listener) => listener,
+        Err(e) => return Err(format!("Failed opening Unix socket '{}': {}", path, e)),
+    };
+    if perm != 0 {
+        if let Err(e) = fs::set_permissions(path, fs::Permissions::from_mode(perm)) {
+            return Err(format!("Failed to set permissions of '{}': {}", path, e));
+        }
+    }
+    if let Err(e) = listener.set_nonblocking(true) {
+        return Err(format!("Failed opening Unix socket '{}': {}", path, e));
+    }
+    Ok(Listener::Unix(UnixListener::from_std(listener)))
+}
+
+// Builds the TLS server configuration from PEM encoded certificate chain
+// and private key files. With a CA file, client certificates are verified
+// against it and, unless auth_clients is "no", requested; "yes" rejects
+// clients that present none.
+pub fn server_config(
+    cert_file: &str,
+    key_file: &str,
+    ca_file: &str,
+    auth_clients: &str,
+) -> Result<Arc<ServerConfig>, String> {
+    let certs = read_pem(cert_file, |r| rustls_pemfile::certs(r))?;
+    if certs.is_empty() {
+        return Err(format!("No certificates found in '{}'", cert_file));
+    }
+    let mut keys = read_pem(key_file, |r| rustls_pemfile::pkcs8_private_keys(r))?;
+    if keys.is_empty() {
+        keys = read_pem(key_file, |r| rustls_pemfile::rsa_private_keys(r))?;
+    }
+    if keys.is_empty() {
+        keys = read_pem(key_file, |r| rustls_pemfile::ec_private_keys(r))?;
+    }
+    let key = match keys.into_iter().next() {
+        Some(key) => rustls::PrivateKey(key),
+        None => return Err(format!("No private key found in '{}'", key_file)),
+    };
+    let certs = certs.into_iter().map(rustls::Certificate).collect();
+    let builder = ServerConfig::builder().with_safe_defaults();
+    let builder = if ca_file.is_empty() || auth_clients == "no" {
+        builder.with_no_client_auth()
+    } else {
+        let mut roots = RootCertStore::empty();
+        for ca in read_pem(ca_file, |r| rustls_pemfile::certs(r))? {
+            if let Err(e) = roots.add(&rustls::Certificate(ca)) {
+                return Err(format!("Invalid CA certificate in '{}': {}", ca_file, e));
+            }
+        }
+        if auth_clients == "optional" {
+            builder.with_client_cert_verifier(AllowAnyAnonymousOrAuthenticatedClient::new(roots).boxed())
+        } else {
+            builder.with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots).boxed())
+        }
+    };
+    builder
+        .with_single_cert(certs, key)
+        .map(Arc::new)
+        .map_err(|e| format!("Failed to configure TLS: {}", e))
+}
+
+fn read_pem<F>(path: &str, parse: F) -> Result<Vec<Vec<u8>>, String>
+where
+    F: FnOnce(&mut dyn io::BufRead) -> io::Result<Vec<Vec<u8>>>,
+{
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) => return Err(format!("Failed to open '{}': {}", path, e)),
+    };
+    parse(&mut BufReader::new(file)).map_err(|e| format!("Failed to parse '{}': {}", path, e))
+}
+
+// Reads one DER element at the start of the input, returning its tag, its
+// contents and whatever follows it.
+fn der_element(der: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    if der.len() < 2 {
+        return None;
+    }
+    let tag = der[0];
+    let (len, start) = if der[1] & 0x80 == 0 {
+        (der[1] as usize, 2)
+    } else {
+        let n = (der[1] & 0x7f) as usize;
+        if n == 0 || n > 4 || der.len() < 2 + n {
+            return None;
+        }
+        let mut len = 0;
+        for &b in &der[2..2 + n] {
+            len = (len << 8) | b as usize;
+        }
+        (len, 2 + n)
+    };
+    if der.len() < start + len {
+        return None;
+    }
+    Some((tag, &der[start..start + len], &der[start + len..]))
+}
+
+// Walks Certificate -> TBSCertificate -> subject and returns the first
+// commonName (OID 2.5.4.3) attribute.
+fn subject_cn(der: &[u8]) -> Option<String> {
+    const CN_OID: &[u8] = &[0x55, 0x04, 0x03];
+    let (_, cert, _) = der_element(der)?;
+    let (_, tbs, _) = der_element(cert)?;
+    let mut rest = tbs;
+    // The version is an optional explicitly tagged [0] element.
+    let (tag, _, after) = der_element(rest)?;
+    if tag == 0xa0 {
+        rest = after;
+    }
+    // Skip serial number, signature algorithm, issuer and validity.
+    for _ in 0..4 {
+        rest = der_element(rest)?.2;
+    }
+    let (_, mut subject, _) = der_element(rest)?;
+    while !subject.is_empty() {
+        let (_, set, next) = der_element(subject)?;
+        let (_, attr, _) = der_element(set)?;
+        let (_, oid, value) = der_element(attr)?;
+        if oid == CN_OID {
+            let (_, value, _) = der_element(value)?;
+            return Some(String::from_utf8_lossy(value).to_string());
+        }
+        subject = next;
+    }
+    None
+}
@@ -0,0 +1,248 @@
+// Connections served as tokio tasks.
+//
+// The sockets stay the non-blocking streams the mio loops use; they are
+// registered with the tokio reactor through AsyncFd instead, and each
+// connection is a task that runs the same read, dispatch and write code
+// whenever its socket turns ready. Commands still go through the sharded
+// keyspace, whose locks are only held for the length of a command.
+//
+// serve() is the whole server as one future, so an application that
+// already runs tokio can spawn it on its own runtime rather than starting
+// the mio threads next to it.
+
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use tokio::io::unix::{AsyncFd, AsyncFdReadyGuard};
+use tokio::runtime;
+use tokio::task::JoinHandle;
+use tokio::time::{self, Sleep};
+
+use stream::Listener;
+use {accept_connection, check_output_limit, event_closed, event_opened, handle_existing_connection,
+     pending, process_input, settle, take_pushes, write_output, Conn, Server};
+
+// Runs the server on a runtime of its own with the given number of worker
+// threads until it shuts down.
+pub fn run(threads: usize, listeners: Vec<Listener>, server: Arc<Server>) {
+    let runtime = runtime::Builder::new_multi_thread()
+        .worker_threads(threads.max(1))
+        .enable_all()
+        .build()
+        .unwrap();
+    let serve = {
+        let _guard = runtime.enter();
+        match serve(listeners, server.clone()) {
+            Ok(serve) => serve,
+            Err(e) => {
+                server.log.warning("tokio-failed", &[("error", &e)]);
+                server.request_shutdown();
+                return;
+            }
+        }
+    };
+    runtime.block_on(serve);
+}
+
+// Accepts on the listeners and spawns a task per connection. The future
+// completes once shutdown was requested and every connection has drained.
+// Must be called from within a runtime.
+pub fn serve(listeners: Vec<Listener>, server: Arc<Server>) -> io::Result<Serve> {
+    let mut fds = Vec::new();
+    for listener in listeners {
+        fds.push(AsyncFd::new(listener)?);
+    }
+    Ok(Serve {
+        listeners: fds,
+        tasks: Vec::new(),
+        server,
+    })
+}
+
+pub struct Serve {
+    listeners: Vec<AsyncFd<Listener>>,
+    tasks: Vec<JoinHandle<()>>,
+    server: Arc<Server>,
+}
+
+impl Future for Serve {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let this = &mut *self;
+        *this.server.accept_task.lock().unwrap() = Some(cx.waker().clone());
+        if this.server.shutdown.load(Ordering::SeqCst) {
+            this.listeners.clear();
+            this.tasks.retain_mut(|task| Pin::new(task).poll(cx).is_pending());
+            return if this.tasks.is_empty() {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            };
+        }
+        this.tasks.retain(|task| !task.is_finished());
+
+        // Readiness is cleared before accepting, so a connection arriving
+        // meanwhile marks the listener ready again.
+        for (i, listener) in this.listeners.iter().enumerate() {
+            loop {
+                match listener.poll_read_ready(cx) {
+                    Poll::Ready(Ok(mut guard)) => guard.clear_ready(),
+                    Poll::Ready(Err(e)) => panic!("encountered IO error: {}", e),
+                    Poll::Pending => break,
+                }
+                loop {
+                    match accept_connection(listener.get_ref(), i, Some(0), 1, &this.server) {
+                        Ok(Some((id, conn))) => {
+                            if let Some(task) = open(id, conn, &this.server) {
+                                this.tasks.push(task);
+                            }
+                        }
+                        Ok(None) => {}
+                        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                        Err(e) => panic!("encountered IO error: {}", e),
+                    }
+                }
+            }
+        }
+        Poll::Pending
+    }
+}
+
+fn open(id: usize, mut conn: Conn, server: &Arc<Server>) -> Option<JoinHandle<()>> {
+    let (output, close) = event_opened(id, &conn.addr, server);
+    conn.output.push(output.into());
+    conn.close = close;
+    match AsyncFd::new(conn) {
+        Ok(conn) => Some(tokio::spawn(Connection {
+            id,
+            conn,
+            server: server.clone(),
+            timer: None,
+            deadline: None,
+        })),
+        Err(_) => {
+            server.unregister_client(id);
+            event_closed(id, server);
+            None
+        }
+    }
+}
+
+struct Connection {
+    id: usize,
+    conn: AsyncFd<Conn>,
+    server: Arc<Server>,
+    // Retries a paused connection, or gives up draining it at shutdown.
+    timer: Option<Pin<Box<Sleep>>>,
+    deadline: Option<Instant>,
+}
+
+impl Future for Connection {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let this = &mut *self;
+        let id = this.id;
+        this.conn.get_ref().client.lock().unwrap().task = Some(cx.waker().clone());
+        if this.server.shutdown.load(Ordering::SeqCst) {
+            return this.drain(cx);
+        }
+
+        let mut close = false;
+        {
+            let conn = this.conn.get_mut();
+            take_pushes(conn);
+            check_output_limit(conn, &this.server);
+            if conn.paused && this.timer.as_mut().is_none_or(|t| t.as_mut().poll(cx).is_ready()) {
+                this.timer = None;
+                process_input(conn, id, &this.server);
+            }
+            settle(conn, &mut close);
+        }
+
+        // Readiness is cleared before the socket is served, so whatever
+        // arrives or drains meanwhile brings the task back.
+        while !close {
+            let read = take_ready(this.conn.poll_read_ready(cx), &mut close);
+            let write = take_ready(this.conn.poll_write_ready(cx), &mut close);
+            if !(read || write) || close {
+                break;
+            }
+            let conn = this.conn.get_mut();
+            handle_existing_connection(conn, &mut close, id, &this.server);
+            settle(conn, &mut close);
+        }
+
+        if close {
+            this.close();
+            return Poll::Ready(());
+        }
+        // A paused connection has nothing to wake it but the timer.
+        if this.conn.get_ref().paused && this.timer.is_none() {
+            let mut timer = Box::pin(time::sleep(Duration::from_millis(10)));
+            if timer.as_mut().poll(cx).is_ready() {
+                cx.waker().wake_by_ref();
+            }
+            this.timer = Some(timer);
+        }
+        Poll::Pending
+    }
+}
+
+impl Connection {
+    // Flushes the replies still owed to the client until shutdown-timeout
+    // expires.
+    fn drain(&mut self, cx: &mut Context) -> Poll<()> {
+        if self.deadline.is_none() {
+            let deadline = Instant::now() + self.server.shutdown_timeout();
+            self.deadline = Some(deadline);
+            self.timer = Some(Box::pin(time::sleep_until(deadline.into())));
+        }
+        let mut close = false;
+        loop {
+            write_output(self.conn.get_mut(), &mut close);
+            if close || !pending(self.conn.get_ref()) {
+                break;
+            }
+            match self.conn.poll_write_ready(cx) {
+                Poll::Ready(Ok(mut guard)) => guard.clear_ready(),
+                Poll::Ready(Err(_)) => break,
+                Poll::Pending => {
+                    let expired = self.timer.as_mut().is_none_or(|t| t.as_mut().poll(cx).is_ready());
+                    if !expired {
+                        return Poll::Pending;
+                    }
+                    break;
+                }
+            }
+        }
+        self.close();
+        Poll::Ready(())
+    }
+
+    fn close(&mut self) {
+        self.server.unregister_client(self.id);
+        event_closed(self.id, &self.server);
+    }
+}
+
+// Clears the readiness a poll reported, telling whether there was any.
+fn take_ready(readiness: Poll<io::Result<AsyncFdReadyGuard<Conn>>>, close: &mut bool) -> bool {
+    match readiness {
+        Poll::Ready(Ok(mut guard)) => {
+            guard.clear_ready();
+            true
+        }
+        Poll::Ready(Err(_)) => {
+            *close = true;
+            false
+        }
+        Poll::Pending => false,
+    }
+}
@@ -0,0 +1,390 @@
+// A minimal io_uring binding over the raw system calls.
+//
+// A Ring maps the submission and completion queues of one io_uring
+// instance. Submissions are queued with push() and handed to the kernel by
+// submit(), which can also wait for completions; completions() then drains
+// what the kernel has posted. A ring can own one group of provided buffers,
+// registered with the kernel as a buffer ring, that receives submitted with
+// BUFFER_SELECT pick from as data arrives instead of each pinning a buffer
+// of its own.
+
+use std::io;
+use std::os::unix::io::RawFd;
+use std::ptr;
+use std::sync::atomic::{AtomicU16, AtomicU32, Ordering};
+
+use libc;
+
+pub const OP_WRITEV: u8 = 2;
+pub const OP_POLL_ADD: u8 = 6;
+pub const OP_TIMEOUT: u8 = 11;
+pub const OP_ACCEPT: u8 = 13;
+pub const OP_RECV: u8 = 27;
+
+// Sqe flags and the ioprio bit that keeps an accept armed.
+pub const SQE_BUFFER_SELECT: u8 = 1 << 5;
+pub const ACCEPT_MULTISHOT: u16 = 1 << 0;
+
+// Cqe flags. The id of the buffer a receive picked sits in the upper half.
+pub const CQE_F_BUFFER: u32 = 1 << 0;
+pub const CQE_F_MORE: u32 = 1 << 1;
+const CQE_BUFFER_SHIFT: u32 = 16;
+
+const ENTER_GETEVENTS: u32 = 1 << 0;
+const REGISTER_PBUF_RING: u32 = 22;
+
+const OFF_SQ_RING: i64 = 0;
+const OFF_CQ_RING: i64 = 0x8000000;
+const OFF_SQES: i64 = 0x10000000;
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct Sqe {
+    pub opcode: u8,
+    pub flags: u8,
+    pub ioprio: u16,
+    pub fd: i32,
+    pub off: u64,
+    pub addr: u64,
+    pub len: u32,
+    pub op_flags: u32,
+    pub user_data: u64,
+    pub buf_group: u16,
+    pub personality: u16,
+    pub splice_fd_in: i32,
+    pub addr3: u64,
+    pub pad: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct Cqe {
+    pub user_data: u64,
+    pub res: i32,
+    pub flags: u32,
+}
+
+impl Cqe {
+    pub fn more(&self) -> bool {
+        self.flags & CQE_F_MORE != 0
+    }
+
+    // The provided buffer the completion filled, if any.
+    pub fn buffer(&self) -> Option<u16> {
+        if self.flags & CQE_F_BUFFER != 0 {
+            Some((self.flags >> CQE_BUFFER_SHIFT) as u16)
+        } else {
+            None
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct SqOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    flags: u32,
+    dropped: u32,
+    array: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct CqOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    overflow: u32,
+    cqes: u32,
+    flags: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct Params {
+    sq_entries: u32,
+    cq_entries: u32,
+    flags: u32,
+    sq_thread_cpu: u32,
+    sq_thread_idle: u32,
+    features: u32,
+    wq_fd: u32,
+    resv: [u32; 3],
+    sq_off: SqOffsets,
+    cq_off: CqOffsets,
+}
+
+#[repr(C)]
+struct BufReg {
+    ring_addr: u64,
+    ring_entries: u32,
+    bgid: u16,
+    flags: u16,
+    resv: [u64; 3],
+}
+
+// An entry of a buffer ring. The ring's tail overlays resv of the first.
+#[repr(C)]
+struct Buf {
+    addr: u64,
+    len: u32,
+    bid: u16,
+    resv: u16,
+}
+
+struct Mmap {
+    ptr: *mut u8,
+    len: usize,
+}
+
+impl Mmap {
+    fn new(fd: RawFd, offset: i64, len: usize) -> io::Result<Mmap> {
+        let (flags, fd) = if fd < 0 {
+            (libc::MAP_PRIVATE | libc::MAP_ANONYMOUS, -1)
+        } else {
+            (libc::MAP_SHARED | libc::MAP_POPULATE, fd)
+        };
+        let ptr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                flags,
+                fd,
+                offset,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Mmap {
+            ptr: ptr as *mut u8,
+            len,
+        })
+    }
+
+    unsafe fn at<T>(&self, offset: u32) -> *mut T {
+        self.ptr.add(offset as usize) as *mut T
+    }
+}
+
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.ptr as *mut libc::c_void, self.len);
+        }
+    }
+}
+
+struct Buffers {
+    ring: Mmap,
+    data: Vec<u8>,
+    size: usize,
+    mask: u16,
+    tail: u16,
+}
+
+pub struct Ring {
+    fd: RawFd,
+    sq_head: *const AtomicU32,
+    sq_tail: *const AtomicU32,
+    sq_mask: u32,
+    sq_entries: u32,
+    sq_array: *mut u32,
+    cq_head: *const AtomicU32,
+    cq_tail: *const AtomicU32,
+    cq_mask: u32,
+    cqes: *const Cqe,
+    // Submissions written to the queue that the kernel hasn't taken yet.
+    queued: u32,
+    tail: u32,
+    buffers: Option<Buffers>,
+    // The mappings stay until the ring is closed in drop().
+    _sq: Mmap,
+    _cq: Mmap,
+    sqes: Mmap,
+}
+
+impl Ring {
+    pub fn new(entries: u32) -> io::Result<Ring> {
+        let mut p = Params::default();
+        let fd = unsafe { libc::syscall(libc::SYS_io_uring_setup, entries, &mut p as *mut Params) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = fd as RawFd;
+        let maps = Mmap::new(fd, OFF_SQ_RING, (p.sq_off.array + p.sq_entries * 4) as usize)
+            .and_then(|sq| {
+                let cq_len = p.cq_off.cqes as usize + p.cq_entries as usize * 16;
+                Mmap::new(fd, OFF_CQ_RING, cq_len).map(|cq| (sq, cq))
+            })
+            .and_then(|(sq, cq)| {
+                Mmap::new(fd, OFF_SQES, p.sq_entries as usize * 64).map(|sqes| (sq, cq, sqes))
+            });
+        let (sq, cq, sqes) = match maps {
+            Ok(maps) => maps,
+            Err(e) => {
+                unsafe { libc::close(fd) };
+                return Err(e);
+            }
+        };
+        unsafe {
+            Ok(Ring {
+                fd,
+                sq_head: sq.at(p.sq_off.head),
+                sq_tail: sq.at(p.sq_off.tail),
+                sq_mask: *sq.at::<u32>(p.sq_off.ring_mask),
+                sq_entries: p.sq_entries,
+                sq_array: sq.at(p.sq_off.array),
+                cq_head: cq.at(p.cq_off.head),
+                cq_tail: cq.at(p.cq_off.tail),
+                cq_mask: *cq.at::<u32>(p.cq_off.ring_mask),
+                cqes: cq.at(p.cq_off.cqes),
+                queued: 0,
+                tail: (*sq.at::<AtomicU32>(p.sq_off.tail)).load(Ordering::Acquire),
+                buffers: None,
+                _sq: sq,
+                _cq: cq,
+                sqes,
+            })
+        }
+    }
+
+    // Queues a submission, first handing queued ones over when the queue
+    // is full.
+    pub fn push(&mut self, sqe: Sqe) -> io::Result<()> {
+        while self.tail.wrapping_sub(unsafe { (*self.sq_head).load(Ordering::Acquire) })
+            >= self.sq_entries
+        {
+            self.submit(0)?;
+        }
+        let index = self.tail & self.sq_mask;
+        unsafe {
+            *self.sqes.at::<Sqe>(index * 64) = sqe;
+            *self.sq_array.add(index as usize) = index;
+        }
+        self.tail = self.tail.wrapping_add(1);
+        unsafe { (*self.sq_tail).store(self.tail, Ordering::Release) };
+        self.queued += 1;
+        Ok(())
+    }
+
+    // Hands queued submissions to the kernel and waits until at least
+    // wait completions are posted. An interrupted wait returns early.
+    pub fn submit(&mut self, wait: u32) -> io::Result<()> {
+        let flags = if wait > 0 { ENTER_GETEVENTS } else { 0 };
+        let n = unsafe {
+            libc::syscall(
+                libc::SYS_io_uring_enter,
+                self.fd,
+                self.queued,
+                wait,
+                flags,
+                ptr::null::<libc::sigset_t>(),
+                0,
+            )
+        };
+        if n < 0 {
+            let err = io::Error::last_os_error();
+            return match err.raw_os_error() {
+                Some(libc::EINTR) | Some(libc::EAGAIN) | Some(libc::EBUSY) => Ok(()),
+                _ => Err(err),
+            };
+        }
+        self.queued -= n as u32;
+        Ok(())
+    }
+
+    // Moves the posted completions into out.
+    pub fn completions(&mut self, out: &mut Vec<Cqe>) {
+        unsafe {
+            let mut head = (*self.cq_head).load(Ordering::Relaxed);
+            let tail = (*self.cq_tail).load(Ordering::Acquire);
+            while head != tail {
+                out.push(*self.cqes.add((head & self.cq_mask) as usize));
+                head = head.wrapping_add(1);
+            }
+            (*self.cq_head).store(head, Ordering::Release);
+        }
+    }
+
+    // Registers count buffers of size bytes as the buffer group receives
+    // select from. count must be a power of two.
+    pub fn provide_buffers(&mut self, group: u16, count: u16, size: usize) -> io::Result<()> {
+        let ring = Mmap::new(-1, 0, count as usize * 16)?;
+        let reg = BufReg {
+            ring_addr: ring.ptr as u64,
+            ring_entries: count as u32,
+            bgid: group,
+            flags: 0,
+            resv: [0; 3],
+        };
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_io_uring_register,
+                self.fd,
+                REGISTER_PBUF_RING,
+                &reg as *const BufReg,
+                1,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut buffers = Buffers {
+            ring,
+            data: vec![0; count as usize * size],
+            size,
+            mask: count - 1,
+            tail: 0,
+        };
+        for bid in 0..count {
+            buffers.put(bid);
+        }
+        self.buffers = Some(buffers);
+        Ok(())
+    }
+
+    // The first len bytes a receive left in buffer bid.
+    pub fn buffer(&self, bid: u16, len: usize) -> &[u8] {
+        let buffers = self.buffers.as_ref().unwrap();
+        let start = bid as usize * buffers.size;
+        &buffers.data[start..start + len]
+    }
+
+    // Gives buffer bid back to the kernel once its bytes were taken.
+    pub fn recycle(&mut self, bid: u16) {
+        self.buffers.as_mut().unwrap().put(bid);
+    }
+}
+
+impl Buffers {
+    fn put(&mut self, bid: u16) {
+        unsafe {
+            let entry = self.ring.at::<Buf>((self.tail & self.mask) as u32 * 16);
+            (*entry).addr = self.data.as_ptr().add(bid as usize * self.size) as u64;
+            (*entry).len = self.size as u32;
+            (*entry).bid = bid;
+            self.tail = self.tail.wrapping_add(1);
+            (*self.ring.at::<AtomicU16>(14)).store(self.tail, Ordering::Release);
+        }
+    }
+}
+
+impl Drop for Ring {
+    // Closing the ring cancels what is still in flight before the memory it
+    // points into is unmapped and freed along with the other fields.
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
@@ -0,0 +1,417 @@
+// Connections served from io_uring (experimental).
+//
+// Each worker thread drives one ring. A multishot accept per listener
+// keeps new connections coming without resubmitting, receives take their
+// buffer from a ring of provided buffers only once data has arrived, and
+// replies go out as one writev of the queued segments. Everything a
+// connection has in flight is submitted together, so a busy worker makes
+// one system call per batch of completions however deep clients pipeline.
+//
+// Sockets are read and written by the kernel directly, bypassing
+// stream::Stream, so TLS and PROXY protocol connections are left to the
+// other backends.
+
+use std::collections::HashMap;
+use std::mem;
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use libc;
+use mio::{Events, Poll};
+
+use buffer;
+use stream::Listener;
+use uring::{self, Cqe, Ring, Sqe};
+use {check_output_limit, event_closed, event_opened, process_input, setup_connection, take_pushes,
+     Conn, Server};
+
+const RING_ENTRIES: u32 = 4096;
+const BUFFER_GROUP: u16 = 0;
+const BUFFER_COUNT: u16 = 256;
+const BUFFER_SIZE: usize = 16 * 1024;
+
+// What a completion is for sits in the top byte of its user data, the
+// listener index or connection id below it.
+const ACCEPT: u64 = 1;
+const RECV: u64 = 2;
+const SEND: u64 = 3;
+const WAKE: u64 = 4;
+const TIMER: u64 = 5;
+
+fn user_data(kind: u64, value: usize) -> u64 {
+    kind << 56 | value as u64
+}
+
+struct Slot {
+    conn: Conn,
+    // Replies handed to the kernel. Those queued in conn.output meanwhile
+    // go after them, and neither is touched while the write is in flight.
+    sending: buffer::Output,
+    iovecs: Vec<libc::iovec>,
+    recv: bool,
+    send: bool,
+    closing: bool,
+}
+
+struct Worker<'a> {
+    worker: usize,
+    ring: Ring,
+    listeners: Vec<&'a Listener>,
+    slots: HashMap<usize, Slot>,
+    pool: buffer::Pool,
+    paused: Vec<usize>,
+    timer: bool,
+    timeout: libc::timespec,
+    server: Arc<Server>,
+}
+
+// Serves the listeners on this worker until shutdown. The worker's poll
+// carries its waker; the ring watches it for pushes and shutdown. A ring
+// that cannot be set up shuts the server down.
+pub fn run(worker: usize, listeners: Vec<&Listener>, mut poll: Poll, server: Arc<Server>) {
+    let mut ring = match Ring::new(RING_ENTRIES) {
+        Ok(ring) => ring,
+        Err(e) => {
+            server.log.warning("io-uring-failed", &[("error", &e)]);
+            server.request_shutdown();
+            return;
+        }
+    };
+    if let Err(e) = ring.provide_buffers(BUFFER_GROUP, BUFFER_COUNT, BUFFER_SIZE) {
+        server.log.warning("io-uring-buffers-failed", &[("error", &e)]);
+        server.request_shutdown();
+        return;
+    }
+    let mut w = Worker {
+        worker,
+        ring,
+        listeners,
+        slots: HashMap::new(),
+        pool: buffer::Pool::new(),
+        paused: Vec::new(),
+        timer: false,
+        timeout: libc::timespec {
+            tv_sec: 0,
+            tv_nsec: 0,
+        },
+        server,
+    };
+    for i in 0..w.listeners.len() {
+        w.arm_accept(i);
+    }
+    w.arm_wake(&poll);
+
+    let mut events = Events::with_capacity(16);
+    let mut cqes = Vec::new();
+    let mut deadline = None;
+    loop {
+        if let Err(e) = w.ring.submit(1) {
+            panic!("encountered IO error: {}", e);
+        }
+        cqes.clear();
+        w.ring.completions(&mut cqes);
+        for cqe in &cqes {
+            let value = (cqe.user_data & ((1 << 56) - 1)) as usize;
+            match cqe.user_data >> 56 {
+                ACCEPT => w.accepted(value, cqe),
+                RECV => w.received(value, cqe),
+                SEND => w.sent(value, cqe),
+                WAKE => {
+                    let _ = poll.poll(&mut events, Some(Duration::from_millis(0)));
+                    w.arm_wake(&poll);
+                    w.deliver_pushes();
+                }
+                TIMER => {
+                    w.timer = false;
+                    if let Some(deadline) = deadline {
+                        let now = Instant::now();
+                        if now >= deadline {
+                            w.close_all();
+                        } else {
+                            w.arm_timer(deadline - now);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        if w.server.shutdown.load(Ordering::SeqCst) {
+            // Flush what each client is owed, give up on slow readers once
+            // shutdown-timeout expires, and stop when every socket is closed.
+            if deadline.is_none() {
+                let timeout = w.server.shutdown_timeout();
+                deadline = Some(Instant::now() + timeout);
+                w.arm_timer(timeout);
+                // The pending accepts keep the listeners open until the
+                // kernel is done tearing the ring down, after we return.
+                for listener in &w.listeners {
+                    unsafe { libc::shutdown(listener.as_raw_fd(), libc::SHUT_RD) };
+                }
+                let ids: Vec<usize> = w.slots.keys().cloned().collect();
+                for id in ids {
+                    w.slots.get_mut(&id).unwrap().conn.close = true;
+                    w.service(id);
+                }
+            }
+            if w.slots.is_empty() {
+                return;
+            }
+            continue;
+        }
+
+        for id in mem::take(&mut w.paused) {
+            if let Some(slot) = w.slots.get_mut(&id) {
+                process_input(&mut slot.conn, id, &w.server);
+                if slot.conn.paused {
+                    w.paused.push(id);
+                }
+            }
+            w.service(id);
+        }
+        if !w.paused.is_empty() && !w.timer {
+            w.arm_timer(Duration::from_millis(10));
+        }
+    }
+}
+
+impl<'a> Worker<'a> {
+    fn submit(&mut self, sqe: Sqe) {
+        if let Err(e) = self.ring.push(sqe) {
+            panic!("encountered IO error: {}", e);
+        }
+    }
+
+    fn arm_accept(&mut self, i: usize) {
+        let fd = self.listeners[i].as_raw_fd();
+        self.submit(Sqe {
+            opcode: uring::OP_ACCEPT,
+            ioprio: uring::ACCEPT_MULTISHOT,
+            fd,
+            op_flags: (libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC) as u32,
+            user_data: user_data(ACCEPT, i),
+            ..Sqe::default()
+        });
+    }
+
+    fn arm_wake(&mut self, poll: &Poll) {
+        self.submit(Sqe {
+            opcode: uring::OP_POLL_ADD,
+            fd: poll.as_raw_fd(),
+            op_flags: libc::POLLIN as u32,
+            user_data: user_data(WAKE, 0),
+            ..Sqe::default()
+        });
+    }
+
+    fn arm_timer(&mut self, after: Duration) {
+        self.timeout = libc::timespec {
+            tv_sec: after.as_secs() as libc::time_t,
+            tv_nsec: after.subsec_nanos() as libc::c_long,
+        };
+        let addr = &self.timeout as *const libc::timespec as u64;
+        self.submit(Sqe {
+            opcode: uring::OP_TIMEOUT,
+            fd: -1,
+            addr,
+            len: 1,
+            user_data: user_data(TIMER, 0),
+            ..Sqe::default()
+        });
+        self.timer = true;
+    }
+
+    fn accepted(&mut self, i: usize, cqe: &Cqe) {
+        if !cqe.more() && !self.server.shutdown.load(Ordering::SeqCst) {
+            self.arm_accept(i);
+        }
+        if cqe.res < 0 {
+            return;
+        }
+        if self.server.shutdown.load(Ordering::SeqCst) {
+            unsafe { libc::close(cqe.res) };
+            return;
+        }
+        let stream = match self.listeners[i].wrap(cqe.res) {
+            Ok(stream) => stream,
+            Err(_) => return,
+        };
+        let (id, mut conn) = match setup_connection(stream, i, Some(self.worker), 0, &self.server) {
+            Some(accepted) => accepted,
+            None => return,
+        };
+        conn.input = self.pool.take();
+        let (output, close) = event_opened(id, &conn.addr, &self.server);
+        conn.output.push(output.into());
+        conn.close = close;
+        self.slots.insert(
+            id,
+            Slot {
+                conn,
+                sending: buffer::Output::new(),
+                iovecs: Vec::new(),
+                recv: false,
+                send: false,
+                closing: false,
+            },
+        );
+        self.service(id);
+    }
+
+    fn received(&mut self, id: usize, cqe: &Cqe) {
+        let bid = cqe.buffer();
+        if let Some(slot) = self.slots.get_mut(&id) {
+            slot.recv = false;
+            match cqe.res {
+                _ if slot.closing => {}
+                0 => slot.closing = true,
+                n if n > 0 => {
+                    let conn = &mut slot.conn;
+                    if let Some(bid) = bid {
+                        conn.input.extend_from_slice(self.ring.buffer(bid, n as usize));
+                    }
+                    // A client whose unparsed input outgrows the limit is
+                    // dropped rather than buffered without bound.
+                    let buffered = conn.input.len() + conn.parser.buffered();
+                    if buffered > self.server.config.read().unwrap().client_query_buffer_limit {
+                        slot.closing = true;
+                    } else if !conn.paused {
+                        process_input(conn, id, &self.server);
+                    }
+                    // Receives land in the ring's own buffers, so only the
+                    // capacity left over from large commands is let go.
+                    conn.input.idle();
+                    if conn.paused && !self.paused.contains(&id) {
+                        self.paused.push(id);
+                    }
+                }
+                // The buffers ran out; the receive is simply submitted again.
+                n if n == -libc::ENOBUFS => {}
+                _ => slot.closing = true,
+            }
+        }
+        if let Some(bid) = bid {
+            self.ring.recycle(bid);
+        }
+        self.service(id);
+    }
+
+    fn sent(&mut self, id: usize, cqe: &Cqe) {
+        if let Some(slot) = self.slots.get_mut(&id) {
+            slot.send = false;
+            match cqe.res {
+                n if n > 0 => slot.sending.consume(n as usize),
+                n if n == -libc::EAGAIN || n == -libc::EINTR => {}
+                _ => slot.closing = true,
+            }
+        }
+        self.service(id);
+    }
+
+    fn deliver_pushes(&mut self) {
+        let ids: Vec<usize> = self.slots.keys().cloned().collect();
+        for id in ids {
+            if let Some(slot) = self.slots.get_mut(&id) {
+                take_pushes(&mut slot.conn);
+                check_output_limit(&mut slot.conn, &self.server);
+            }
+            self.service(id);
+        }
+    }
+
+    // Submits whatever the connection is ready for next: the replies it
+    // owes, then, once they are out, the next receive. A connection being
+    // closed is dropped as soon as nothing of it is in flight.
+    fn service(&mut self, id: usize) {
+        let mut sqes = Vec::new();
+        let remove = {
+            let slot = match self.slots.get_mut(&id) {
+                Some(slot) => slot,
+                None => return,
+            };
+            let fd = slot.conn.stream.as_raw_fd();
+            if !slot.closing && !slot.send {
+                if slot.sending.is_empty() {
+                    mem::swap(&mut slot.sending, &mut slot.conn.output);
+                }
+                if !slot.sending.is_empty() {
+                    slot.iovecs = slot
+                        .sending
+                        .slices()
+                        .iter()
+                        .map(|s| libc::iovec {
+                            iov_base: s.as_ptr() as *mut libc::c_void,
+                            iov_len: s.len(),
+                        })
+                        .collect();
+                    sqes.push(Sqe {
+                        opcode: uring::OP_WRITEV,
+                        fd,
+                        addr: slot.iovecs.as_ptr() as u64,
+                        len: slot.iovecs.len() as u32,
+                        user_data: user_data(SEND, id),
+                        ..Sqe::default()
+                    });
+                    slot.send = true;
+                } else if slot.conn.close {
+                    slot.closing = true;
+                } else if !slot.recv {
+                    // Like the mio loop, a client is only read from again
+                    // once its replies have drained.
+                    sqes.push(Sqe {
+                        opcode: uring::OP_RECV,
+                        flags: uring::SQE_BUFFER_SELECT,
+                        fd,
+                        len: BUFFER_SIZE as u32,
+                        buf_group: BUFFER_GROUP,
+                        user_data: user_data(RECV, id),
+                        ..Sqe::default()
+                    });
+                    slot.recv = true;
+                }
+            }
+            // Shutting the socket down completes what is still in flight.
+            if slot.closing && (slot.recv || slot.send) {
+                unsafe { libc::shutdown(fd, libc::SHUT_RDWR) };
+            }
+            slot.closing && !slot.recv && !slot.send
+        };
+        for sqe in sqes {
+            self.submit(sqe);
+        }
+        if remove {
+            self.close_connection(id);
+        }
+    }
+
+    fn close_all(&mut self) {
+        let ids: Vec<usize> = self.slots.keys().cloned().collect();
+        for id in ids {
+            self.slots.get_mut(&id).unwrap().closing = true;
+            self.service(id);
+        }
+    }
+
+    // Drops the connection, keeping its input buffer for the next one.
+    fn close_connection(&mut self, id: usize) {
+        if let Some(slot) = self.slots.remove(&id) {
+            self.pool.put(slot.conn.input);
+        }
+        self.server.unregister_client(id);
+        event_closed(id, &self.server);
+    }
+}
+
+// Fails when the configuration asks for connections this backend can't
+// serve.
+pub fn check(tls_port: usize, proxy_protocol: &str) -> Result<(), String> {
+    if tls_port != 0 {
+        return Err("The io_uring backend does not serve TLS connections".to_string());
+    }
+    if !proxy_protocol.is_empty() {
+        return Err("The io_uring backend does not serve PROXY protocol connections".to_string());
+    }
+    Ok(())
+}
@@ -0,0 +1,9 @@
+// One inline command through resp::take_inline_args.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    cache_server::fuzzing::inline(data);
+});
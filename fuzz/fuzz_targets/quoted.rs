@@ -0,0 +1,9 @@
+// Quoted inline arguments through resp::parse_quoted_arg.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    cache_server::fuzzing::quoted(data);
+});
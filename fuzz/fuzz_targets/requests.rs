@@ -0,0 +1,9 @@
+// Multibulk and inline requests through resp::Parser, whole and in fragments.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    cache_server::fuzzing::requests(data);
+});
@@ -0,0 +1,190 @@
+// Shared harness for the integration tests: a server started in-process on
+// a free port, and a minimal RESP client to talk to it.
+
+#![allow(dead_code)]
+
+use std::env;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::time::Duration;
+
+use cache_server::{Config, Server};
+
+pub struct TestServer {
+    pub server: Arc<Server>,
+}
+
+impl TestServer {
+    pub fn start() -> TestServer {
+        TestServer::with_config(|_| {})
+    }
+
+    // Starts a server from the default configuration as changed by edit,
+    // listening on a free loopback port. CACHE_SERVER_TEST_IO_BACKEND runs
+    // the tests on another event loop.
+    pub fn with_config<F: FnOnce(&mut Config)>(edit: F) -> TestServer {
+        let mut config = Config::new();
+        config.bind = "127.0.0.1".to_string();
+        config.port = 0;
+        config.threads = 2;
+        if let Ok(backend) = env::var("CACHE_SERVER_TEST_IO_BACKEND") {
+            config.io_backend = backend;
+        }
+        edit(&mut config);
+        let server = Server::start(config).expect("server failed to start");
+        TestServer { server }
+    }
+
+    pub fn port(&self) -> usize {
+        self.server.port()
+    }
+
+    pub fn connect(&self) -> Client {
+        Client::connect(self.port())
+    }
+
+    // Shuts the server down and waits for it to stop.
+    pub fn stop(&self) {
+        self.server.request_shutdown();
+        self.server.wait();
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Reply {
+    Status(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Vec<u8>),
+    Array(Vec<Reply>),
+    Map(Vec<(Reply, Reply)>),
+    Double(f64),
+    // The format, like "txt", and the text.
+    Verbatim(String, Vec<u8>),
+    Nil,
+}
+
+impl Reply {
+    pub fn ok() -> Reply {
+        Reply::Status("OK".to_string())
+    }
+
+    pub fn bulk(s: &str) -> Reply {
+        Reply::Bulk(s.as_bytes().to_vec())
+    }
+
+    pub fn is_error(&self) -> bool {
+        matches!(*self, Reply::Error(_))
+    }
+}
+
+pub struct Client {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl Client {
+    pub fn connect(port: usize) -> Client {
+        let stream = TcpStream::connect(("127.0.0.1", port as u16)).expect("connect failed");
+        stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+        Client {
+            reader: BufReader::new(stream.try_clone().unwrap()),
+            writer: stream,
+        }
+    }
+
+    // Sends one command and reads its reply.
+    pub fn cmd(&mut self, args: &[&[u8]]) -> Reply {
+        self.send(args);
+        self.read().expect("connection closed")
+    }
+
+    // Like cmd(), for arguments that are text.
+    pub fn call(&mut self, args: &[&str]) -> Reply {
+        let args: Vec<&[u8]> = args.iter().map(|a| a.as_bytes()).collect();
+        self.cmd(&args)
+    }
+
+    pub fn send(&mut self, args: &[&[u8]]) {
+        self.write(&encode(args));
+    }
+
+    pub fn write(&mut self, bytes: &[u8]) {
+        self.writer.write_all(bytes).expect("write failed");
+    }
+
+    // The next reply, or None once the server has closed the connection.
+    pub fn read(&mut self) -> Option<Reply> {
+        let mut line = Vec::new();
+        match self.reader.read_until(b'\n', &mut line) {
+            Ok(0) | Err(_) => return None,
+            Ok(_) => {}
+        }
+        assert!(line.ends_with(b"\r\n"), "reply line without CRLF: {:?}", line);
+        let text = String::from_utf8_lossy(&line[1..line.len() - 2]).to_string();
+        let reply = match line[0] {
+            b'+' => Reply::Status(text),
+            b'-' => Reply::Error(text),
+            b':' => Reply::Integer(text.parse().unwrap()),
+            b'_' => Reply::Nil,
+            b',' => Reply::Double(text.parse().unwrap()),
+            b'=' => {
+                let n: usize = text.parse().unwrap();
+                let mut bulk = vec![0; n + 2];
+                self.reader.read_exact(&mut bulk).ok()?;
+                bulk.truncate(n);
+                let text = bulk.split_off(4);
+                Reply::Verbatim(String::from_utf8_lossy(&bulk[..3]).to_string(), text)
+            }
+            b'$' => {
+                let n: i64 = text.parse().unwrap();
+                if n < 0 {
+                    return Some(Reply::Nil);
+                }
+                let mut bulk = vec![0; n as usize + 2];
+                self.reader.read_exact(&mut bulk).ok()?;
+                bulk.truncate(n as usize);
+                Reply::Bulk(bulk)
+            }
+            b'*' | b'~' | b'>' => {
+                let n: i64 = text.parse().unwrap();
+                if n < 0 {
+                    return Some(Reply::Nil);
+                }
+                let mut items = Vec::new();
+                for _ in 0..n {
+                    items.push(self.read()?);
+                }
+                Reply::Array(items)
+            }
+            b'%' => {
+                let n: usize = text.parse().unwrap();
+                let mut pairs = Vec::new();
+                for _ in 0..n {
+                    let key = self.read()?;
+                    pairs.push((key, self.read()?));
+                }
+                Reply::Map(pairs)
+            }
+            other => panic!("unexpected reply type {:?}", other as char),
+        };
+        Some(reply)
+    }
+}
+
+pub fn encode(args: &[&[u8]]) -> Vec<u8> {
+    let mut out = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        out.extend(format!("${}\r\n", arg.len()).into_bytes());
+        out.extend_from_slice(arg);
+        out.extend_from_slice(b"\r\n");
+    }
+    out
+}
@@ -0,0 +1,65 @@
+#!/bin/sh
+# Runs units of the Redis TCL test suite against cache-server and reports
+# how many of their tests pass.
+#
+#     tests/redis-compat/run.sh [unit ...]
+#
+# Without units, those in units.txt are run. REDIS_SRC points at a Redis
+# source tree to take the suite from; unset, REDIS_VERSION (default 7.2.5)
+# is cloned into target/redis-src. The server is built in release mode and
+# started on PORT (default 21111). Each unit's output is kept in
+# target/redis-compat/<unit>.log.
+#
+# runtest is run with --host/--port, so it treats the server as external:
+# tests tagged external:skip are left out, as are those tagged with the
+# needs: tags below for features the server doesn't have.
+
+set -eu
+
+root=$(cd "$(dirname "$0")/../.." && pwd)
+here="$root/tests/redis-compat"
+out="$root/target/redis-compat"
+port=${PORT:-21111}
+version=${REDIS_VERSION:-7.2.5}
+src=${REDIS_SRC:-$root/target/redis-src}
+
+if [ ! -d "$src/tests" ]; then
+    git clone --quiet --depth 1 --branch "$version" https://github.com/redis/redis.git "$src"
+fi
+command -v tclsh >/dev/null || { echo "tclsh is needed to run the suite" >&2; exit 1; }
+
+cargo build --release --manifest-path "$root/Cargo.toml"
+mkdir -p "$out"
+"$root/target/release/cache-server" --port "$port" --bind 127.0.0.1 --databases 16 \
+    >"$out/server.log" 2>&1 &
+server=$!
+trap 'kill $server 2>/dev/null' EXIT INT TERM
+sleep 1
+
+if [ $# -eq 0 ]; then
+    set -- $(grep -v '^#' "$here/units.txt")
+fi
+
+total_ok=0
+total_err=0
+status=0
+printf '%-28s %6s %6s\n' unit ok err
+for unit in "$@"; do
+    log="$out/$(echo "$unit" | tr / _).log"
+    (cd "$src" && tclsh tests/test_helper.tcl --host 127.0.0.1 --port "$port" \
+        --singledb --ignore-encoding --ignore-digest --clients 1 \
+        --tags -needs:repl --tags -needs:save --tags -needs:debug \
+        --tags -needs:config-maxmemory --tags -needs:latency \
+        --single "$unit") >"$log" 2>&1 || status=1
+    ok=$(grep -c '\[ok\]' "$log" || true)
+    err=$(grep -c '\[err\]\|\[exception\]' "$log" || true)
+    printf '%-28s %6d %6d\n' "$unit" "$ok" "$err"
+    total_ok=$((total_ok + ok))
+    total_err=$((total_err + err))
+done
+
+if [ $((total_ok + total_err)) -gt 0 ]; then
+    printf '%-28s %6d %6d  (%d%% passing)\n' total "$total_ok" "$total_err" \
+        $((total_ok * 100 / (total_ok + total_err)))
+fi
+exit $status
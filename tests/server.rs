@@ -0,0 +1,947 @@
+// Boots real servers and checks what clients see over the wire.
+
+extern crate cache_server;
+
+mod common;
+
+use std::io::Write;
+use std::net::TcpStream;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use common::{Client, Reply, TestServer};
+
+#[test]
+fn ping() {
+    let server = TestServer::start();
+    let mut client = server.connect();
+    assert_eq!(client.call(&["PING"]), Reply::Status("PONG".to_string()));
+    assert_eq!(client.call(&["PING", "hello"]), Reply::bulk("hello"));
+}
+
+#[test]
+fn set_get_del() {
+    let server = TestServer::start();
+    let mut client = server.connect();
+    assert_eq!(client.call(&["GET", "k"]), Reply::Nil);
+    assert_eq!(client.call(&["SET", "k", "v1"]), Reply::ok());
+    assert_eq!(client.call(&["GET", "k"]), Reply::bulk("v1"));
+    assert_eq!(client.call(&["SET", "k", "v2"]), Reply::ok());
+    assert_eq!(client.call(&["GET", "k"]), Reply::bulk("v2"));
+    assert_eq!(client.call(&["DEL", "k"]), Reply::Integer(1));
+    assert_eq!(client.call(&["GET", "k"]), Reply::Nil);
+    assert_eq!(client.call(&["DEL", "k"]), Reply::Integer(0));
+}
+
+#[test]
+fn values_are_binary_safe() {
+    let server = TestServer::start();
+    let mut client = server.connect();
+    let value: Vec<u8> = (0..=255).collect();
+    assert_eq!(client.cmd(&[b"SET", b"bin\r\n\0", &value]), Reply::ok());
+    assert_eq!(client.cmd(&[b"GET", b"bin\r\n\0"]), Reply::Bulk(value));
+}
+
+#[test]
+fn keys_matches_binary_keys() {
+    let server = TestServer::start();
+    let mut client = server.connect();
+    assert_eq!(client.cmd(&[b"SET", b"k\xff\x00", b"v"]), Reply::ok());
+    assert_eq!(client.cmd(&[b"SET", b"k/a/b", b"v"]), Reply::ok());
+    assert_eq!(client.cmd(&[b"KEYS", b"k\xff*"]), Reply::Array(vec![Reply::Bulk(b"k\xff\x00".to_vec())]));
+    assert_eq!(client.cmd(&[b"KEYS", b"k/*"]), Reply::Array(vec![Reply::Bulk(b"k/a/b".to_vec())]));
+    assert_eq!(client.cmd(&[b"KEYS", b"k[\\/]a?b"]), Reply::Array(vec![Reply::Bulk(b"k/a/b".to_vec())]));
+}
+
+#[test]
+fn keys_lists_large_keyspaces() {
+    let server = TestServer::start();
+    let mut client = server.connect();
+    let mut batch = Vec::new();
+    for i in 0..5000 {
+        batch.extend(common::encode(&[b"SET", format!("key:{}", i).as_bytes(), b"v"]));
+    }
+    client.write(&batch);
+    for _ in 0..5000 {
+        assert_eq!(client.read(), Some(Reply::ok()));
+    }
+    let mut keys = match client.call(&["KEYS", "*"]) {
+        Reply::Array(keys) => keys,
+        other => panic!("unexpected reply {:?}", other),
+    };
+    let mut expected: Vec<Reply> = (0..5000).map(|i| Reply::bulk(&format!("key:{}", i))).collect();
+    let order = |reply: &Reply| match *reply {
+        Reply::Bulk(ref key) => key.clone(),
+        _ => Vec::new(),
+    };
+    keys.sort_by_key(&order);
+    expected.sort_by_key(&order);
+    assert_eq!(keys, expected);
+    match client.call(&["KEYS", "key:4?9"]) {
+        Reply::Array(keys) => assert_eq!(keys.len(), 10),
+        other => panic!("unexpected reply {:?}", other),
+    }
+}
+
+#[test]
+fn memory_bigkeys_reports_the_largest_keys() {
+    let server = TestServer::start();
+    let mut client = server.connect();
+    let mut batch = Vec::new();
+    for i in 0..3000 {
+        batch.extend(common::encode(&[b"SET", format!("key:{}", i).as_bytes(), b"v"]));
+    }
+    batch.extend(common::encode(&[b"SET", b"big", &vec![b'x'; 10_000][..]]));
+    batch.extend(common::encode(&[b"SET", b"bigger", &vec![b'x'; 20_000][..]]));
+    client.write(&batch);
+    for _ in 0..3002 {
+        assert_eq!(client.read(), Some(Reply::ok()));
+    }
+    let keys = match client.call(&["MEMORY", "BIGKEYS", "COUNT", "2"]) {
+        Reply::Array(keys) => keys,
+        other => panic!("unexpected reply {:?}", other),
+    };
+    let names: Vec<&Reply> = keys
+        .iter()
+        .map(|entry| match *entry {
+            Reply::Array(ref fields) => {
+                assert_eq!(fields[1], Reply::bulk("string"));
+                &fields[0]
+            }
+            ref other => panic!("unexpected entry {:?}", other),
+        })
+        .collect();
+    assert_eq!(names, vec![&Reply::bulk("bigger"), &Reply::bulk("big")]);
+    assert_eq!(
+        client.call(&["MEMORY", "BIGKEYS", "COUNT", "x"]),
+        Reply::Error("ERR value is not an integer or out of range".to_string())
+    );
+}
+
+#[test]
+fn large_values_round_trip() {
+    let server = TestServer::start();
+    let mut client = server.connect();
+    let value = vec![b'x'; 4 * 1024 * 1024];
+    assert_eq!(client.cmd(&[b"SET", b"big", &value]), Reply::ok());
+    assert_eq!(client.cmd(&[b"GET", b"big"]), Reply::Bulk(value));
+}
+
+#[test]
+fn databases_are_separate() {
+    let server = TestServer::start();
+    let mut client = server.connect();
+    assert_eq!(client.call(&["SET", "k", "zero"]), Reply::ok());
+    assert_eq!(client.call(&["SELECT", "1"]), Reply::ok());
+    assert_eq!(client.call(&["GET", "k"]), Reply::Nil);
+    assert_eq!(client.call(&["DBSIZE"]), Reply::Integer(0));
+    assert_eq!(client.call(&["SET", "k", "one"]), Reply::ok());
+    assert_eq!(client.call(&["SELECT", "0"]), Reply::ok());
+    assert_eq!(client.call(&["GET", "k"]), Reply::bulk("zero"));
+    assert!(client.call(&["SELECT", "100"]).is_error());
+}
+
+#[test]
+fn errors() {
+    let server = TestServer::start();
+    let mut client = server.connect();
+    assert_eq!(
+        client.call(&["NOSUCHCOMMAND"]),
+        Reply::Error("ERR unknown command 'NOSUCHCOMMAND'".to_string())
+    );
+    assert_eq!(
+        client.call(&["get"]),
+        Reply::Error("ERR wrong number of arguments for 'get' command".to_string())
+    );
+    // The connection is still usable after an error.
+    assert_eq!(client.call(&["PING"]), Reply::Status("PONG".to_string()));
+}
+
+#[test]
+fn command_and_error_stats() {
+    let server = TestServer::start();
+    let mut client = server.connect();
+    client.call(&["SET", "k", "v"]);
+    client.call(&["GET", "k"]);
+    client.call(&["GET", "k"]);
+    assert!(client.call(&["GET"]).is_error());
+    assert!(client.call(&["NOSUCHCOMMAND"]).is_error());
+    let info = match client.call(&["INFO", "commandstats", "errorstats"]) {
+        Reply::Bulk(info) => String::from_utf8(info).unwrap(),
+        other => panic!("unexpected reply {:?}", other),
+    };
+    assert!(info.contains("cmdstat_get:calls=3,"), "{}", info);
+    assert!(info.contains("cmdstat_set:calls=1,"), "{}", info);
+    assert!(info.contains("failed_calls=1\r\n"), "{}", info);
+    assert!(info.contains("errorstat_ERR:count=2\r\n"), "{}", info);
+    assert_eq!(client.call(&["CONFIG", "RESETSTAT"]), Reply::ok());
+    let info = match client.call(&["INFO", "commandstats"]) {
+        Reply::Bulk(info) => String::from_utf8(info).unwrap(),
+        other => panic!("unexpected reply {:?}", other),
+    };
+    assert!(!info.contains("cmdstat_get"), "{}", info);
+}
+
+#[test]
+fn latency_percentiles() {
+    let server = TestServer::start();
+    let mut client = server.connect();
+    for _ in 0..100 {
+        client.call(&["GET", "k"]);
+    }
+    let info = match client.call(&["INFO", "latencystats"]) {
+        Reply::Bulk(info) => String::from_utf8(info).unwrap(),
+        other => panic!("unexpected reply {:?}", other),
+    };
+    assert!(info.contains("latency_percentiles_usec_get:p50="), "{}", info);
+    match client.call(&["LATENCY", "HISTOGRAM", "get"]) {
+        Reply::Array(items) => {
+            assert_eq!(items[0], Reply::bulk("get"));
+            match items[1] {
+                Reply::Array(ref fields) => assert_eq!(fields[1], Reply::Integer(100)),
+                ref other => panic!("unexpected reply {:?}", other),
+            }
+        }
+        other => panic!("unexpected reply {:?}", other),
+    }
+}
+
+#[test]
+fn hotkeys_finds_the_hottest_key() {
+    let server = TestServer::with_config(|config| config.hotkeys_sample_ratio = 1);
+    let mut client = server.connect();
+    for i in 0..300 {
+        client.call(&["GET", "hot"]);
+        if i % 10 == 0 {
+            client.call(&["GET", &format!("cold:{}", i)]);
+        }
+    }
+    match client.call(&["HOTKEYS", "1"]) {
+        Reply::Array(keys) => {
+            assert_eq!(keys.len(), 1);
+            match keys[0] {
+                Reply::Array(ref fields) => {
+                    assert_eq!(fields[0], Reply::bulk("hot"));
+                    assert_eq!(fields[1], Reply::Integer(0));
+                }
+                ref other => panic!("unexpected reply {:?}", other),
+            }
+        }
+        other => panic!("unexpected reply {:?}", other),
+    }
+}
+
+#[test]
+fn keyspace_hits_and_misses() {
+    let server = TestServer::start();
+    let mut client = server.connect();
+    let big = vec![b'x'; 64 * 1024];
+    client.call(&["SET", "small", "v"]);
+    client.cmd(&[b"SET", b"big", &big]);
+    client.call(&["GET", "small"]);
+    client.call(&["GET", "big"]);
+    client.call(&["GET", "missing"]);
+    let info = match client.call(&["INFO", "stats"]) {
+        Reply::Bulk(info) => String::from_utf8(info).unwrap(),
+        other => panic!("unexpected reply {:?}", other),
+    };
+    assert!(info.contains("keyspace_hits:2\r\n"), "{}", info);
+    assert!(info.contains("keyspace_misses:1\r\n"), "{}", info);
+}
+
+#[test]
+fn inline_commands() {
+    let server = TestServer::start();
+    let mut client = server.connect();
+    client.write(b"SET \"a key\" 'a value'\r\nGET \"a key\"\r\n");
+    assert_eq!(client.read(), Some(Reply::ok()));
+    assert_eq!(client.read(), Some(Reply::bulk("a value")));
+}
+
+#[test]
+fn pipelining_keeps_order() {
+    let server = TestServer::start();
+    let mut client = server.connect();
+    let mut batch = Vec::new();
+    for i in 0..1000 {
+        let key = format!("key:{}", i);
+        let value = format!("value:{}", i);
+        batch.extend(common::encode(&[b"SET", key.as_bytes(), value.as_bytes()]));
+        batch.extend(common::encode(&[b"GET", key.as_bytes()]));
+    }
+    client.write(&batch);
+    for i in 0..1000 {
+        assert_eq!(client.read(), Some(Reply::ok()));
+        assert_eq!(client.read(), Some(Reply::Bulk(format!("value:{}", i).into_bytes())));
+    }
+}
+
+#[test]
+fn commands_split_across_writes() {
+    let server = TestServer::start();
+    let mut client = server.connect();
+    let command = common::encode(&[b"SET", b"split", b"value"]);
+    for byte in &command {
+        client.write(&[*byte]);
+        thread::sleep(Duration::from_millis(1));
+    }
+    assert_eq!(client.read(), Some(Reply::ok()));
+    assert_eq!(client.call(&["GET", "split"]), Reply::bulk("value"));
+}
+
+#[test]
+fn protocol_errors_close_the_connection() {
+    let server = TestServer::start();
+    let mut client = server.connect();
+    client.write(b"*1\r\n+PING\r\n");
+    match client.read() {
+        Some(Reply::Error(e)) => assert!(e.starts_with("ERR Protocol error"), "{}", e),
+        other => panic!("expected a protocol error, got {:?}", other),
+    }
+    assert_eq!(client.read(), None);
+}
+
+#[test]
+fn keys_outlive_connections() {
+    let server = TestServer::start();
+    {
+        let mut client = server.connect();
+        assert_eq!(client.call(&["SET", "k", "v"]), Reply::ok());
+    }
+    for _ in 0..10 {
+        let mut client = server.connect();
+        assert_eq!(client.call(&["GET", "k"]), Reply::bulk("v"));
+    }
+}
+
+#[test]
+fn quit_closes_after_replying() {
+    let server = TestServer::start();
+    let mut client = server.connect();
+    assert_eq!(client.call(&["QUIT"]), Reply::ok());
+    assert_eq!(client.read(), None);
+    let mut client = server.connect();
+    assert_eq!(client.call(&["PING"]), Reply::Status("PONG".to_string()));
+}
+
+#[test]
+fn concurrent_clients() {
+    let server = TestServer::start();
+    let port = server.port();
+    let threads: Vec<_> = (0..8)
+        .map(|t| {
+            thread::spawn(move || {
+                let mut client = Client::connect(port);
+                for i in 0..200 {
+                    let key = format!("{}:{}", t, i);
+                    assert_eq!(client.call(&["SET", &key, &key]), Reply::ok());
+                }
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    let mut client = server.connect();
+    assert_eq!(client.call(&["DBSIZE"]), Reply::Integer(8 * 200));
+    assert_eq!(client.call(&["GET", "7:199"]), Reply::bulk("7:199"));
+}
+
+#[test]
+fn store_is_shared_with_clients() {
+    let server = TestServer::start();
+    server.server.store().set(0, b"embedded", b"yes");
+    let mut client = server.connect();
+    assert_eq!(client.call(&["GET", "embedded"]), Reply::bulk("yes"));
+    assert_eq!(client.call(&["SET", "remote", "also"]), Reply::ok());
+    assert_eq!(server.server.store().get(0, b"remote"), Some(b"also".to_vec()));
+}
+
+#[test]
+fn config_get_reports_the_picked_port() {
+    let server = TestServer::start();
+    let mut client = server.connect();
+    let port = server.port().to_string();
+    assert_eq!(
+        client.call(&["CONFIG", "GET", "port"]),
+        Reply::Array(vec![Reply::bulk("port"), Reply::bulk(&port)])
+    );
+}
+
+#[test]
+fn shutdown_closes_connections_and_listeners() {
+    let server = TestServer::start();
+    let port = server.port();
+    let mut client = server.connect();
+    assert_eq!(client.call(&["SET", "k", "v"]), Reply::ok());
+    server.stop();
+    assert!(server.server.is_shutting_down());
+    assert_eq!(client.read(), None);
+    assert!(TcpStream::connect(("127.0.0.1", port as u16)).is_err());
+}
+
+#[test]
+fn shutdown_flushes_pending_replies() {
+    let server = TestServer::start();
+    let mut client = server.connect();
+    let mut batch = Vec::new();
+    for _ in 0..100 {
+        batch.extend(common::encode(&[b"PING"]));
+    }
+    client.write(&batch);
+    thread::sleep(Duration::from_millis(100));
+    server.stop();
+    for _ in 0..100 {
+        assert_eq!(client.read(), Some(Reply::Status("PONG".to_string())));
+    }
+    assert_eq!(client.read(), None);
+}
+
+#[test]
+fn shutdown_nosave_stops_a_busy_script() {
+    // io_uring workers accept on shared listeners, so the second client may
+    // land on the worker stuck in the script and never be read.
+    if std::env::var("CACHE_SERVER_TEST_IO_BACKEND").ok().as_deref() == Some("io_uring") {
+        return;
+    }
+    let pidfile = std::env::temp_dir().join(format!("cache-server-test-{}.pid", std::process::id()));
+    let server = TestServer::with_config(|c| {
+        c.lua_time_limit = 50;
+        c.pidfile = pidfile.to_string_lossy().into_owned();
+    });
+    assert!(pidfile.exists());
+    let mut script = server.connect();
+    let mut client = server.connect();
+    let busy = "redis.call('SET', 'k', 'v') while true do end";
+    script.write(&common::encode(&[b"EVAL", busy.as_bytes(), b"0"]));
+    thread::sleep(Duration::from_millis(200));
+    match client.call(&["SCRIPT", "KILL"]) {
+        Reply::Error(ref err) => assert!(err.starts_with("UNKILLABLE"), "{}", err),
+        other => panic!("unexpected reply {:?}", other),
+    }
+    client.write(&common::encode(&[b"SHUTDOWN", b"NOSAVE"]));
+    assert_eq!(client.read(), None);
+    server.server.wait();
+    assert!(server.server.is_shutting_down());
+    assert!(!pidfile.exists());
+}
+
+#[test]
+fn servers_run_side_by_side() {
+    let first = TestServer::start();
+    let second = TestServer::start();
+    assert!(first.port() != second.port());
+    let mut a = first.connect();
+    let mut b = second.connect();
+    assert_eq!(a.call(&["SET", "k", "first"]), Reply::ok());
+    assert_eq!(b.call(&["GET", "k"]), Reply::Nil);
+}
+
+#[test]
+fn write_after_peer_closes_is_harmless() {
+    let server = TestServer::start();
+    {
+        let mut stream = TcpStream::connect(("127.0.0.1", server.port() as u16)).unwrap();
+        stream.write_all(&common::encode(&[b"SET", b"k", b"v"])).unwrap();
+    }
+    thread::sleep(Duration::from_millis(50));
+    let mut client = server.connect();
+    assert_eq!(client.call(&["PING"]), Reply::Status("PONG".to_string()));
+}
+
+#[test]
+fn no_touch_clients_leave_access_frequency_alone() {
+    let server = TestServer::start();
+    let mut client = server.connect();
+    assert_eq!(client.call(&["CONFIG", "SET", "maxmemory-policy", "allkeys-lfu"]), Reply::ok());
+    assert_eq!(client.call(&["CONFIG", "SET", "lfu-log-factor", "0"]), Reply::ok());
+    assert_eq!(client.call(&["SET", "k", "v"]), Reply::ok());
+    let freq = client.call(&["OBJECT", "FREQ", "k"]);
+    assert_eq!(client.call(&["CLIENT", "NO-TOUCH", "ON"]), Reply::ok());
+    for _ in 0..10 {
+        assert_eq!(client.call(&["GET", "k"]), Reply::bulk("v"));
+    }
+    assert_eq!(client.call(&["OBJECT", "FREQ", "k"]), freq);
+    match client.call(&["CLIENT", "INFO"]) {
+        Reply::Bulk(line) => assert!(String::from_utf8_lossy(&line).contains(" flags=NT ")),
+        other => panic!("unexpected reply {:?}", other),
+    }
+    assert_eq!(client.call(&["CLIENT", "NO-TOUCH", "OFF"]), Reply::ok());
+    assert_eq!(client.call(&["GET", "k"]), Reply::bulk("v"));
+    assert!(client.call(&["OBJECT", "FREQ", "k"]) != freq);
+}
+
+#[test]
+fn idle_clients_time_out() {
+    let server = TestServer::with_config(|config| config.timeout = 1);
+    let mut idle = server.connect();
+    let mut subscriber = server.connect();
+    assert_eq!(idle.call(&["PING"]), Reply::Status("PONG".to_string()));
+    match subscriber.call(&["SUBSCRIBE", "news"]) {
+        Reply::Array(_) => {}
+        other => panic!("unexpected reply {:?}", other),
+    }
+    thread::sleep(Duration::from_millis(2500));
+    assert_eq!(idle.read(), None);
+    let mut publisher = server.connect();
+    assert_eq!(publisher.call(&["PUBLISH", "news", "hello"]), Reply::Integer(1));
+    match subscriber.read() {
+        Some(Reply::Array(message)) => assert_eq!(message[2], Reply::bulk("hello")),
+        other => panic!("unexpected reply {:?}", other),
+    }
+}
+
+#[test]
+fn rate_limits_hold_commands_back() {
+    let server = TestServer::with_config(|config| config.client_max_commands_per_sec = 1000);
+    let mut client = server.connect();
+    assert_eq!(
+        client.call(&["ACL", "SETUSER", "default", "max-commands-per-sec=50"]),
+        Reply::ok()
+    );
+    let mut batch = Vec::new();
+    for _ in 0..100 {
+        batch.extend(common::encode(&[b"PING"]));
+    }
+    let start = Instant::now();
+    client.write(&batch);
+    for _ in 0..100 {
+        assert_eq!(client.read(), Some(Reply::Status("PONG".to_string())));
+    }
+    assert!(start.elapsed() >= Duration::from_millis(800), "took {:?}", start.elapsed());
+    match client.call(&["ACL", "LIST"]) {
+        Reply::Array(ref users) => match users[0] {
+            Reply::Bulk(ref user) => assert!(String::from_utf8_lossy(user).ends_with(" max-commands-per-sec=50")),
+            ref other => panic!("unexpected user {:?}", other),
+        },
+        other => panic!("unexpected reply {:?}", other),
+    }
+}
+
+#[test]
+fn connections_are_limited_by_address() {
+    let server = TestServer::with_config(|config| config.max_connections_per_ip = 2);
+    let mut first = server.connect();
+    let mut second = server.connect();
+    assert_eq!(first.call(&["PING"]), Reply::Status("PONG".to_string()));
+    assert_eq!(second.call(&["PING"]), Reply::Status("PONG".to_string()));
+    let mut third = server.connect();
+    assert_eq!(
+        third.read(),
+        Some(Reply::Error("ERR max number of connections from this address reached".to_string()))
+    );
+    assert_eq!(third.read(), None);
+    assert_eq!(first.call(&["CONFIG", "SET", "deny-from", "127.0.0.0/8"]), Reply::ok());
+    let mut denied = server.connect();
+    assert_eq!(
+        denied.read(),
+        Some(Reply::Error("DENIED Connections from this address are not allowed".to_string()))
+    );
+    assert_eq!(first.call(&["CONFIG", "SET", "deny-from", "", "allow-from", "10.0.0.0/8 ::1"]), Reply::ok());
+    let mut outside = server.connect();
+    assert!(outside.read().is_some_and(|reply| reply.is_error()));
+}
+
+#[test]
+fn role_reports_a_master_without_replicas() {
+    let server = TestServer::start();
+    let mut client = server.connect();
+    assert_eq!(
+        client.call(&["ROLE"]),
+        Reply::Array(vec![Reply::bulk("master"), Reply::Integer(0), Reply::Array(Vec::new())])
+    );
+    match client.call(&["INFO", "replication"]) {
+        Reply::Bulk(info) => {
+            let info = String::from_utf8_lossy(&info);
+            assert!(info.contains("role:master\r\n"));
+            assert!(info.contains("connected_slaves:0\r\n"));
+        }
+        other => panic!("unexpected reply {:?}", other),
+    }
+}
+
+#[test]
+fn transactions_follow_redis_error_semantics() {
+    let server = TestServer::start();
+    let mut client = server.connect();
+    let ok = Reply::Status("OK".to_string());
+    let queued = Reply::Status("QUEUED".to_string());
+    assert_eq!(
+        client.call(&["EXEC"]),
+        Reply::Error("ERR EXEC without MULTI".to_string())
+    );
+    assert_eq!(
+        client.call(&["DISCARD"]),
+        Reply::Error("ERR DISCARD without MULTI".to_string())
+    );
+
+    // Errors while queueing abort the whole transaction.
+    assert_eq!(client.call(&["MULTI"]), ok);
+    assert_eq!(
+        client.call(&["MULTI"]),
+        Reply::Error("ERR MULTI calls can not be nested".to_string())
+    );
+    assert_eq!(client.call(&["SET", "k", "v"]), queued);
+    assert!(client.call(&["GET"]).is_error());
+    assert_eq!(
+        client.call(&["EXEC"]),
+        Reply::Error("EXECABORT Transaction discarded because of previous errors.".to_string())
+    );
+    assert_eq!(client.call(&["GET", "k"]), Reply::Nil);
+
+    // Errors while running leave the other commands alone.
+    assert_eq!(client.call(&["MULTI"]), ok);
+    assert_eq!(client.call(&["SET", "k", "v"]), queued);
+    assert_eq!(client.call(&["SELECT", "100"]), queued);
+    assert_eq!(client.call(&["GET", "k"]), queued);
+    match client.call(&["EXEC"]) {
+        Reply::Array(replies) => {
+            assert_eq!(replies.len(), 3);
+            assert_eq!(replies[0], ok);
+            assert!(replies[1].is_error());
+            assert_eq!(replies[2], Reply::bulk("v"));
+        }
+        other => panic!("unexpected reply {:?}", other),
+    }
+
+    assert_eq!(client.call(&["MULTI"]), ok);
+    assert_eq!(client.call(&["DEL", "k"]), queued);
+    assert_eq!(client.call(&["DISCARD"]), ok);
+    assert_eq!(client.call(&["GET", "k"]), Reply::bulk("v"));
+}
+
+#[test]
+fn multi_refuses_commands_over_maxmemory() {
+    let server = TestServer::start();
+    let mut client = server.connect();
+    assert_eq!(client.call(&["SET", "k", "v"]), Reply::ok());
+    assert_eq!(client.call(&["CONFIG", "SET", "maxmemory-policy", "noeviction"]), Reply::ok());
+    assert_eq!(client.call(&["CONFIG", "SET", "maxmemory", "1"]), Reply::ok());
+    assert_eq!(client.call(&["MULTI"]), Reply::ok());
+    assert_eq!(client.call(&["GET", "k"]), Reply::Status("QUEUED".to_string()));
+    match client.call(&["SET", "k", "v2"]) {
+        Reply::Error(ref err) => assert!(err.starts_with("OOM "), "{}", err),
+        other => panic!("unexpected reply {:?}", other),
+    }
+    assert_eq!(
+        client.call(&["EXEC"]),
+        Reply::Error("EXECABORT Transaction discarded because of previous errors.".to_string())
+    );
+    assert_eq!(client.call(&["CONFIG", "SET", "maxmemory", "0"]), Reply::ok());
+    assert_eq!(client.call(&["GET", "k"]), Reply::bulk("v"));
+}
+
+#[test]
+fn backed_up_replies_hold_the_pipeline() {
+    let server = TestServer::with_config(|c| c.client_output_backlog_limit = 64 * 1024);
+    let mut client = server.connect();
+    let value = vec![b'v'; 32 * 1024];
+    assert_eq!(client.cmd(&[b"SET", b"big", &value]), Reply::ok());
+    let mut batch = Vec::new();
+    for _ in 0..400 {
+        batch.extend(common::encode(&[b"GET", b"big"]));
+    }
+    client.write(&batch);
+    thread::sleep(Duration::from_millis(300));
+    // The replies the client isn't reading stay in the socket, not in
+    // the server.
+    let mut other = server.connect();
+    let clients = match other.call(&["MEMORY", "STATS"]) {
+        Reply::Array(stats) => stats
+            .chunks(2)
+            .find(|pair| pair[0] == Reply::bulk("clients.normal"))
+            .map(|pair| match pair[1] {
+                Reply::Integer(n) => n,
+                ref other => panic!("unexpected reply {:?}", other),
+            }),
+        other => panic!("unexpected reply {:?}", other),
+    };
+    let clients = clients.expect("no clients.normal");
+    assert!(clients < 1024 * 1024, "clients hold {} bytes", clients);
+    for _ in 0..400 {
+        assert_eq!(client.read(), Some(Reply::Bulk(value.clone())));
+    }
+}
+
+#[test]
+fn long_key_walks_give_up_past_the_time_limit() {
+    let server = TestServer::with_config(|c| c.command_time_limit = 1);
+    let mut client = server.connect();
+    let mut batch = Vec::new();
+    for i in 0..100000 {
+        batch.extend(common::encode(&[b"SET", format!("key:{}", i).as_bytes(), b"v"]));
+    }
+    client.write(&batch);
+    for _ in 0..100000 {
+        assert_eq!(client.read(), Some(Reply::ok()));
+    }
+    match client.call(&["KEYS", "*"]) {
+        Reply::Error(ref err) => assert!(err.contains("command-time-limit"), "{}", err),
+        other => panic!("unexpected reply {:?}", other),
+    }
+    assert!(client.call(&["MEMORY", "BIGKEYS"]).is_error());
+    assert_eq!(client.call(&["CONFIG", "SET", "command-time-limit", "0"]), Reply::ok());
+    match client.call(&["KEYS", "*"]) {
+        Reply::Array(keys) => assert_eq!(keys.len(), 100000),
+        other => panic!("unexpected reply {:?}", other),
+    }
+}
+
+#[test]
+fn resp3_clients_get_typed_replies() {
+    let server = TestServer::start();
+    let mut client = server.connect();
+    assert!(matches!(client.call(&["HELLO", "3"]), Reply::Map(_)));
+    match client.call(&["CONFIG", "GET", "maxmemory"]) {
+        Reply::Map(pairs) => assert_eq!(pairs, vec![(Reply::bulk("maxmemory"), Reply::bulk("0"))]),
+        other => panic!("unexpected reply {:?}", other),
+    }
+    assert_eq!(client.call(&["GET", "missing"]), Reply::Nil);
+    match client.call(&["MEMORY", "STATS"]) {
+        Reply::Map(pairs) => {
+            let fragmentation = pairs.iter().find(|pair| pair.0 == Reply::bulk("fragmentation"));
+            match fragmentation {
+                Some(&(_, Reply::Double(_))) => {}
+                other => panic!("unexpected fragmentation {:?}", other),
+            }
+        }
+        other => panic!("unexpected reply {:?}", other),
+    }
+    match client.call(&["INFO", "server"]) {
+        Reply::Verbatim(ref format, ref text) => {
+            assert_eq!(format, "txt");
+            assert!(text.starts_with(b"# Server"));
+        }
+        other => panic!("unexpected reply {:?}", other),
+    }
+    // Back on RESP2 the same replies are flattened again.
+    client.call(&["HELLO", "2"]);
+    match client.call(&["CONFIG", "GET", "maxmemory"]) {
+        Reply::Array(items) => assert_eq!(items, vec![Reply::bulk("maxmemory"), Reply::bulk("0")]),
+        other => panic!("unexpected reply {:?}", other),
+    }
+    match client.call(&["INFO", "server"]) {
+        Reply::Bulk(text) => assert!(text.starts_with(b"# Server")),
+        other => panic!("unexpected reply {:?}", other),
+    }
+}
+
+#[test]
+fn scripts_get_resp2_replies_on_resp3_connections() {
+    let server = TestServer::start();
+    let mut client = server.connect();
+    assert!(matches!(client.call(&["HELLO", "3"]), Reply::Map(_)));
+    match client.call(&["EVAL", "return redis.call('COMMAND', 'DOCS', 'get')", "0"]) {
+        Reply::Array(ref docs) => assert_eq!(docs[0], Reply::bulk("get")),
+        other => panic!("unexpected reply {:?}", other),
+    }
+    match client.call(&["EVAL", "return redis.call('COMMAND', 'INFO', 'get')", "0"]) {
+        Reply::Array(ref infos) => match infos[..] {
+            [Reply::Array(ref info)] => assert_eq!(info[..2], [Reply::bulk("get"), Reply::Integer(2)]),
+            ref other => panic!("unexpected reply {:?}", other),
+        },
+        other => panic!("unexpected reply {:?}", other),
+    }
+    assert_eq!(client.call(&["EVAL", "return redis.call('GET', 'missing')", "0"]), Reply::Nil);
+}
+
+#[test]
+fn scripts_run_sandboxed() {
+    let server = TestServer::start();
+    let mut client = server.connect();
+    let probe = "return {type(os), type(io), type(package), type(loadfile), type(dofile)}";
+    let nils = || Reply::Array((0..5).map(|_| Reply::bulk("nil")).collect());
+    assert_eq!(client.call(&["EVAL", probe, "0"]), nils());
+    let library = format!(
+        "#!lua name=probe\nredis.register_function('probe', function() {} end)",
+        probe
+    );
+    assert_eq!(client.call(&["FUNCTION", "LOAD", &library]), Reply::bulk("probe"));
+    assert_eq!(client.call(&["FCALL", "probe", "0"]), nils());
+    let bytecode = "return loadstring(string.dump(function() return 1 end)) == nil";
+    assert_eq!(client.call(&["EVAL", bytecode, "0"]), Reply::Integer(1));
+    assert_eq!(client.call(&["EVAL", "return string.len('abc')", "0"]), Reply::Integer(3));
+}
+
+#[test]
+fn short_movablekeys_commands_are_sampled_safely() {
+    let server = TestServer::with_config(|c| c.hotkeys_sample_ratio = 1);
+    let mut client = server.connect();
+    for _ in 0..10 {
+        assert!(client.call(&["EVAL", "x"]).is_error());
+        assert!(client.call(&["FCALL", "f"]).is_error());
+    }
+    assert_eq!(client.call(&["PING"]), Reply::Status("PONG".to_string()));
+}
+
+#[test]
+fn module_command_is_refused_by_default() {
+    let server = TestServer::start();
+    let mut client = server.connect();
+    match client.call(&["MODULE", "LOAD", "/tmp/module.so"]) {
+        Reply::Error(ref err) => assert!(err.contains("not allowed"), "{}", err),
+        other => panic!("unexpected reply {:?}", other),
+    }
+    assert!(client.call(&["MODULE", "LIST"]).is_error());
+    assert!(client.call(&["CONFIG", "SET", "enable-module-command", "yes"]).is_error());
+    let server = TestServer::with_config(|c| c.enable_module_command = "local".to_string());
+    let mut client = server.connect();
+    assert_eq!(client.call(&["MODULE", "LIST"]), Reply::Array(Vec::new()));
+}
+
+#[test]
+fn scripts_cannot_call_noscript_commands() {
+    let server = TestServer::start();
+    let mut client = server.connect();
+    for call in &[
+        "redis.call('MULTI')",
+        "redis.call('EXEC')",
+        "redis.call('SHUTDOWN', 'NOSAVE')",
+        "redis.call('CLIENT', 'PAUSE', '1000')",
+        "redis.call('DEBUG', 'SLEEP', '0')",
+        "redis.call('CONFIG', 'SET', 'maxmemory', '1')",
+        "redis.call('ACL', 'SETUSER', 'eve', 'on', '>pw', '+@all')",
+        "redis.call('MODULE', 'LIST')",
+    ] {
+        match client.call(&["EVAL", call, "0"]) {
+            Reply::Error(ref err) => assert!(err.contains("not allowed from script"), "{}: {}", call, err),
+            other => panic!("{}: unexpected reply {:?}", call, other),
+        }
+    }
+    assert_eq!(client.call(&["CONFIG", "GET", "maxmemory"]), Reply::Array(vec![Reply::bulk("maxmemory"), Reply::bulk("0")]));
+    assert_eq!(client.call(&["EVAL", "return redis.call('SET', 'k', 'v')", "0"]), Reply::ok());
+}
+
+#[test]
+fn read_only_functions_cannot_write() {
+    let server = TestServer::start();
+    let mut client = server.connect();
+    let library = "#!lua name=ro\n\
+        redis.register_function{function_name='put', callback=function(keys) \
+            return redis.call('SET', keys[1], 'v') end, flags={'no-writes'}}\n\
+        redis.register_function{function_name='peek', callback=function(keys) \
+            return redis.call('GET', keys[1]) end, flags={'no-writes'}}";
+    assert_eq!(client.call(&["FUNCTION", "LOAD", library]), Reply::bulk("ro"));
+    for cmd in &["FCALL_RO", "FCALL"] {
+        match client.call(&[cmd, "put", "1", "k"]) {
+            Reply::Error(ref err) => assert!(err.contains("not allowed from read-only scripts"), "{}", err),
+            other => panic!("{}: unexpected reply {:?}", cmd, other),
+        }
+    }
+    assert_eq!(client.call(&["GET", "k"]), Reply::Nil);
+    assert_eq!(client.call(&["SET", "k", "v"]), Reply::ok());
+    assert_eq!(client.call(&["FCALL_RO", "peek", "1", "k"]), Reply::bulk("v"));
+}
+
+#[test]
+fn acl_passwords_are_sha256_hashes() {
+    let server = TestServer::start();
+    let mut client = server.connect();
+    let hash = "2bb80d537b1da3e38bd30361aa855686bde0eacd7162fef6a25fe97bf527a25b";
+    let rule = format!("#{}", hash);
+    assert_eq!(client.call(&["ACL", "SETUSER", "alice", "on", &rule, "+@all", "~*"]), Reply::ok());
+    assert_eq!(client.call(&["AUTH", "alice", "secret"]), Reply::ok());
+    assert_eq!(client.call(&["ACL", "SETUSER", "bob", "on", ">secret"]), Reply::ok());
+    match client.call(&["ACL", "LIST"]) {
+        Reply::Array(users) => assert!(users.iter().any(|user| match *user {
+            Reply::Bulk(ref line) => String::from_utf8_lossy(line).starts_with("user bob ")
+                && String::from_utf8_lossy(line).contains(&rule),
+            _ => false,
+        })),
+        other => panic!("unexpected reply {:?}", other),
+    }
+    let sha1 = "#e5e9fa1ba31ecd1ae84f75caaa474f3a663f05f4";
+    assert!(client.call(&["ACL", "SETUSER", "carol", sha1]).is_error());
+    assert!(client.call(&["ACL", "SETUSER", "carol", "!e5e9fa1ba31ecd1ae84f75caaa474f3a663f05f4"]).is_error());
+}
+
+#[test]
+fn acl_deluser_disconnects_its_clients() {
+    let server = TestServer::start();
+    let mut admin = server.connect();
+    assert_eq!(admin.call(&["ACL", "SETUSER", "alice", "on", ">secret", "+@all", "~*"]), Reply::ok());
+    assert_eq!(admin.call(&["ACL", "SETUSER", "bob", "on", ">secret", "+@all", "~*"]), Reply::ok());
+    let mut alice = server.connect();
+    assert_eq!(alice.call(&["AUTH", "alice", "secret"]), Reply::ok());
+    let mut bob = server.connect();
+    assert_eq!(bob.call(&["AUTH", "bob", "secret"]), Reply::ok());
+    let start = Instant::now();
+    assert_eq!(admin.call(&["ACL", "DELUSER", "alice", "nobody"]), Reply::Integer(1));
+    // Closed, rather than left to the read timeout.
+    assert_eq!(alice.read(), None);
+    assert!(start.elapsed() < Duration::from_secs(5));
+    assert_eq!(bob.call(&["PING"]), Reply::Status("PONG".to_string()));
+    assert_eq!(admin.call(&["PING"]), Reply::Status("PONG".to_string()));
+    // Deleting the caller's own user answers before closing.
+    let start = Instant::now();
+    assert_eq!(bob.call(&["ACL", "DELUSER", "bob"]), Reply::Integer(1));
+    assert_eq!(bob.read(), None);
+    assert!(start.elapsed() < Duration::from_secs(5));
+}
+
+// A client port whose bus port is free as well.
+fn free_cluster_port() -> usize {
+    (20000..30000)
+        .find(|&port| {
+            std::net::TcpListener::bind(("127.0.0.1", port)).is_ok()
+                && std::net::TcpListener::bind(("127.0.0.1", port + 10000)).is_ok()
+        })
+        .expect("no free cluster port") as usize
+}
+
+#[test]
+fn cluster_keys_are_routed_by_slot() {
+    // This node serves the lower half of the slots, a peer the rest.
+    let nodes = std::env::temp_dir().join(format!("cache-server-test-{}-nodes.conf", std::process::id()));
+    let me = "a".repeat(40);
+    let peer = "b".repeat(40);
+    std::fs::write(
+        &nodes,
+        format!(
+            "{} :0@0 myself,master - 0 0 1 connected 0-8191\n\
+             {} 127.0.0.1:7001@17001 master - 0 0 2 connected 8192-16383\n\
+             vars currentEpoch 2 lastVoteEpoch 0\n",
+            me, peer
+        ),
+    )
+    .unwrap();
+    let port = free_cluster_port();
+    let server = TestServer::with_config(|c| {
+        c.port = port;
+        c.cluster_enabled = true;
+        c.cluster_config_file = nodes.to_string_lossy().into_owned();
+        c.cluster_node_timeout = 60000;
+    });
+    let mut client = server.connect();
+    // user1000 hashes to slot 3443, foo to 12182.
+    assert_eq!(client.call(&["SET", "{user1000}.following", "1"]), Reply::ok());
+    assert_eq!(client.call(&["SET", "{user1000}.followers", "2"]), Reply::ok());
+    assert_eq!(
+        client.call(&["GET", "foo"]),
+        Reply::Error("MOVED 12182 127.0.0.1:7001".to_string())
+    );
+    assert_eq!(
+        client.call(&["UNLINK", "{user1000}.following", "user1000.following"]),
+        Reply::Error("CROSSSLOT Keys in request don't hash to the same slot".to_string())
+    );
+    assert_eq!(client.call(&["UNLINK", "{user1000}.following", "{user1000}.followers"]), Reply::Integer(2));
+    assert_eq!(client.call(&["CLUSTER", "KEYSLOT", "{user1000}.following"]), Reply::Integer(3443));
+
+    // Transactions are checked as commands are queued.
+    assert_eq!(client.call(&["MULTI"]), Reply::ok());
+    assert_eq!(client.call(&["SET", "{user1000}.following", "3"]), Reply::Status("QUEUED".to_string()));
+    assert_eq!(
+        client.call(&["SET", "foo", "bar"]),
+        Reply::Error("MOVED 12182 127.0.0.1:7001".to_string())
+    );
+    assert_eq!(
+        client.call(&["EXEC"]),
+        Reply::Error("EXECABORT Transaction discarded because of previous errors.".to_string())
+    );
+    assert_eq!(client.call(&["MULTI"]), Reply::ok());
+    assert!(client.call(&["UNLINK", "{user1000}.following", "user1000.following"]).is_error());
+    assert!(client.call(&["EXEC"]).is_error());
+    assert_eq!(client.call(&["GET", "{user1000}.following"]), Reply::Nil);
+    drop(server);
+    let _ = std::fs::remove_file(&nodes);
+}